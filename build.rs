@@ -0,0 +1,10 @@
+// proto/queueticket.proto から gRPC のコードを生成する
+// protoc を入れなくてもビルドできるよう、protox で読み込む
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto");
+    let descriptors = protox::compile(["queueticket.proto"], ["proto"])?;
+    tonic_prost_build::configure()
+        .build_client(false)
+        .compile_fds(descriptors)?;
+    Ok(())
+}
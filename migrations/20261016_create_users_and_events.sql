@@ -0,0 +1,20 @@
+-- スタッフ (呼び出し端末で PIN により担当者を切り替える)
+CREATE TABLE IF NOT EXISTS users (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name TEXT NOT NULL,                            -- 表示名
+    pin_hash TEXT NOT NULL,                        -- PINのHMAC (平文では保存しない)
+    role TEXT NOT NULL DEFAULT 'staff',            -- staff, manager
+    active BOOLEAN NOT NULL DEFAULT TRUE,          -- 無効化したスタッフは切り替え候補に出さない
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- 整理券ごとの操作履歴 (誰がいつ呼び出し・完了したか)
+CREATE TABLE IF NOT EXISTS ticket_events (
+    id BIGSERIAL PRIMARY KEY,                      -- 挿入順
+    ticket_id UUID NOT NULL REFERENCES tickets(id) ON DELETE CASCADE,
+    event_type TEXT NOT NULL,                      -- created, called, completed, reverted
+    user_id UUID REFERENCES users(id) ON DELETE SET NULL, -- 操作したスタッフ (未選択ならNULL)
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS ticket_events_ticket_id_idx ON ticket_events (ticket_id);
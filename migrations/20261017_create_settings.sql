@@ -0,0 +1,6 @@
+-- 店舗ごとの運用設定 (管理画面から変更する。キーと値の組で保存)
+CREATE TABLE IF NOT EXISTS settings (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
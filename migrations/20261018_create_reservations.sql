@@ -0,0 +1,24 @@
+-- 予約枠との併用: 予約から発券したチケットは待機列で優先する
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS priority INT NOT NULL DEFAULT 0; -- 大きいほど先に案内
+
+-- 予約枠 (管理者が時刻と受け入れ組数を設定する)
+CREATE TABLE IF NOT EXISTS reservation_slots (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    starts_at TIMESTAMPTZ NOT NULL,                -- 枠の開始時刻
+    capacity INT NOT NULL,                         -- 受け入れ可能な組数
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- 予約 (枠の時刻になると整理券に変換して待機列に入れる)
+-- リセット (TRUNCATE tickets CASCADE) で予約まで消えないよう、tickets への外部キーは張らない
+CREATE TABLE IF NOT EXISTS reservations (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    slot_id UUID NOT NULL REFERENCES reservation_slots(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,                            -- 予約者名
+    group_size INT NOT NULL,                       -- 人数
+    status TEXT NOT NULL DEFAULT 'booked',         -- booked, queued, cancelled
+    ticket_id UUID,                                -- 発券済みの場合のチケット
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS reservations_slot_id_idx ON reservations (slot_id);
@@ -0,0 +1,16 @@
+-- 来店前のリモート受付: 到着後のチェックインで順番を確定する
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS remote BOOLEAN NOT NULL DEFAULT FALSE; -- リモート受付で発券したか
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS checked_in_at TIMESTAMPTZ;             -- 到着確認の時刻
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS phone TEXT;                            -- 認証済みの電話番号 (任意)
+
+-- 電話番号の確認コード (SMSで送ったコードを入力してもらう)
+CREATE TABLE IF NOT EXISTS phone_verifications (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    phone TEXT NOT NULL,
+    code_hash TEXT NOT NULL,                       -- 確認コードのHMAC (平文では保存しない)
+    group_size INT NOT NULL,                       -- 確認後に発券する人数
+    attempts INT NOT NULL DEFAULT 0,               -- 入力を間違えた回数
+    expires_at TIMESTAMPTZ NOT NULL,
+    verified_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
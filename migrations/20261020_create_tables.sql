@@ -0,0 +1,11 @@
+-- 客席 (テーブル) の管理
+CREATE TABLE IF NOT EXISTS tables (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name TEXT NOT NULL,                            -- 表示名 (例: "7", "カウンター1")
+    capacity INT NOT NULL,                         -- 座れる人数
+    status TEXT NOT NULL DEFAULT 'free',           -- free, occupied
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- 呼び出し時に案内したテーブル
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS table_id UUID REFERENCES tables(id) ON DELETE SET NULL;
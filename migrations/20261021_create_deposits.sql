@@ -0,0 +1,17 @@
+-- 大人数のリモート受付で預かるデポジット (Stripe Checkout)
+-- 支払いが確認できてから整理券を発券するので、受付内容もここに持つ
+-- 支払いの記録を残すため、tickets のリセットで消えないよう外部キーは張らない
+CREATE TABLE IF NOT EXISTS deposits (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    group_size INT NOT NULL,
+    phone TEXT,
+    amount INT NOT NULL,                           -- 金額 (円)
+    status TEXT NOT NULL DEFAULT 'pending',        -- pending, paid, refunded, forfeited, expired
+    checkout_session_id TEXT UNIQUE,
+    payment_intent_id TEXT,
+    ticket_id UUID,                                -- 支払い後に発券したチケット
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS deposits_ticket_id_idx ON deposits (ticket_id);
@@ -0,0 +1,15 @@
+-- 複数の列 (例: 店内飲食・テイクアウト)
+CREATE TABLE IF NOT EXISTS queues (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,                            -- 表示名
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- 既存のチケットはすべて最初の列に属する
+INSERT INTO queues (id, name) VALUES (1, '店内') ON CONFLICT (id) DO NOTHING;
+SELECT setval(pg_get_serial_sequence('queues', 'id'), (SELECT MAX(id) FROM queues));
+
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS queue_id INT NOT NULL DEFAULT 1 REFERENCES queues(id);
+
+-- 操作履歴の補足 (例: 列の移動元と移動先)
+ALTER TABLE ticket_events ADD COLUMN IF NOT EXISTS detail TEXT;
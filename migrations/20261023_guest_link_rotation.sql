@@ -0,0 +1,2 @@
+-- 来場者画面のリンクの署名に使う発行時刻 (更新すると以前のリンクとQRは無効になる)
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS link_issued_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
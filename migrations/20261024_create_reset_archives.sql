@@ -0,0 +1,8 @@
+-- データの全消去 (リセット) の前に自動で取る控え。誰がいつ消したかの記録も兼ねる
+CREATE TABLE IF NOT EXISTS reset_archives (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID REFERENCES users(id) ON DELETE SET NULL, -- リセットした店長
+    ticket_count BIGINT NOT NULL,                  -- 消した整理券の数
+    tickets_csv TEXT NOT NULL,                     -- 消す直前の整理券 (CSVダウンロードと同じ形式)
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,13 @@
+-- 管理画面での書き換え操作の記録 (誰が・いつ・どこから・何をしたか)
+CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGSERIAL PRIMARY KEY,                      -- 挿入順
+    user_id UUID REFERENCES users(id) ON DELETE SET NULL, -- 操作していたスタッフ (未選択ならNULL)
+    method TEXT NOT NULL,
+    path TEXT NOT NULL,
+    detail TEXT,                                   -- 送信されたフォームの内容 (PIN等は伏せる)
+    status INTEGER NOT NULL,                       -- レスポンスのステータスコード
+    ip TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_created_at_idx ON audit_log (created_at);
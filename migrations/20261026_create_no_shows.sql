@@ -0,0 +1,10 @@
+-- 来店しなかった記録 (電話番号がわかるお客様のみ)
+-- 来店をまたいで数えるので、リセット (TRUNCATE tickets CASCADE) で消えないよう外部キーは張らない
+CREATE TABLE IF NOT EXISTS no_shows (
+    id BIGSERIAL PRIMARY KEY,
+    phone TEXT NOT NULL,
+    ticket_id UUID NOT NULL UNIQUE,                -- 同じ整理券を二重に数えない
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS no_shows_phone_idx ON no_shows (phone, created_at);
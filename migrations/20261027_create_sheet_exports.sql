@@ -0,0 +1,5 @@
+-- Google スプレッドシートへ書き出し済みの整理券 (同じ行を二重に追加しない)
+CREATE TABLE IF NOT EXISTS sheet_exports (
+    ticket_id UUID PRIMARY KEY REFERENCES tickets(id) ON DELETE CASCADE,
+    exported_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
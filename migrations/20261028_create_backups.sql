@@ -0,0 +1,12 @@
+-- S3互換ストレージへのバックアップの実行記録 (メンテナンス画面に表示する)
+CREATE TABLE IF NOT EXISTS backups (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    status TEXT NOT NULL DEFAULT 'running',        -- running, succeeded, failed
+    objects TEXT,                                  -- アップロードしたオブジェクトのキー (改行区切り)
+    bytes BIGINT,                                  -- アップロードした合計サイズ (圧縮後)
+    error TEXT,
+    started_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS backups_started_at_idx ON backups (started_at);
@@ -0,0 +1,70 @@
+-- 整理券・テーブルが変わったら NOTIFY で知らせる
+-- アプリを複数台で動かしても、どの台で書き換えたかに関係なく全台の画面 (SSE) やMQTTに届くようにする
+-- 受け取る側 (live_event_hub) は payload の kind をそのまま SSE のイベント名にする
+
+CREATE OR REPLACE FUNCTION notify_ticket_change() RETURNS trigger AS $$
+DECLARE
+    kind TEXT;
+    ticket RECORD;
+BEGIN
+    IF TG_OP = 'TRUNCATE' THEN
+        PERFORM pg_notify('queueticket_live', json_build_object('kind', 'reset')::text);
+        RETURN NULL;
+    END IF;
+
+    IF TG_OP = 'INSERT' THEN
+        kind := 'created';
+        ticket := NEW;
+    ELSIF TG_OP = 'DELETE' THEN
+        kind := 'deleted';
+        ticket := OLD;
+    ELSE
+        ticket := NEW;
+        IF NEW.status = 'called' AND OLD.status = 'completed' THEN
+            kind := 'reverted'; -- 完了の取り消しではチャイムを鳴らさない
+        ELSIF NEW.status = 'waiting' AND OLD.status = 'called' THEN
+            kind := 'reverted';
+        ELSIF NEW.status = 'called' AND NEW.called_at IS DISTINCT FROM OLD.called_at THEN
+            kind := 'called';
+        ELSIF NEW.status <> OLD.status THEN
+            kind := NEW.status; -- completed, merged
+        ELSIF NEW.checked_in_at IS DISTINCT FROM OLD.checked_in_at THEN
+            kind := 'checked_in';
+        ELSIF NEW.queue_id <> OLD.queue_id THEN
+            kind := 'transferred';
+        ELSIF NEW.group_size <> OLD.group_size THEN
+            kind := 'split';
+        ELSE
+            kind := 'updated';
+        END IF;
+    END IF;
+
+    PERFORM pg_notify(
+        'queueticket_live',
+        json_build_object('kind', kind, 'id', ticket.id, 'number', ticket.number)::text
+    );
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS tickets_notify_row ON tickets;
+CREATE TRIGGER tickets_notify_row
+    AFTER INSERT OR UPDATE OR DELETE ON tickets
+    FOR EACH ROW EXECUTE FUNCTION notify_ticket_change();
+
+DROP TRIGGER IF EXISTS tickets_notify_truncate ON tickets;
+CREATE TRIGGER tickets_notify_truncate
+    AFTER TRUNCATE ON tickets
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_ticket_change();
+
+CREATE OR REPLACE FUNCTION notify_table_change() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('queueticket_live', json_build_object('kind', 'table_updated')::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS tables_notify ON tables;
+CREATE TRIGGER tables_notify
+    AFTER INSERT OR UPDATE OR DELETE ON tables
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_table_change();
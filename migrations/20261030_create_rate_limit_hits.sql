@@ -0,0 +1,9 @@
+-- 公開フォームのレート制限の記録
+-- アプリを複数台で動かしても、送信元ごとの回数を全台で合算して数えられるようDBに置く
+CREATE TABLE IF NOT EXISTS rate_limit_hits (
+    key TEXT NOT NULL,                           -- 例: "join:203.0.113.5"
+    hit_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS rate_limit_hits_key_idx ON rate_limit_hits (key, hit_at);
+CREATE INDEX IF NOT EXISTS rate_limit_hits_hit_at_idx ON rate_limit_hits (hit_at);
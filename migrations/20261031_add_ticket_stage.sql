@@ -0,0 +1,67 @@
+-- 店舗独自の状態 (例: 準備中・受け取り可能)
+-- status (waiting / called) はそのままに、その中の段階を stage に持つ
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS stage TEXT;
+
+-- 呼び出し・完了・取り消しなどで status だけが変わったら、独自の状態は外す
+-- (stage も同時に書き換えた更新はそのまま通す)
+CREATE OR REPLACE FUNCTION clear_ticket_stage() RETURNS trigger AS $$
+BEGIN
+    IF NEW.status IS DISTINCT FROM OLD.status AND NEW.stage IS NOT DISTINCT FROM OLD.stage THEN
+        NEW.stage := NULL;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS tickets_clear_stage ON tickets;
+CREATE TRIGGER tickets_clear_stage
+    BEFORE UPDATE ON tickets
+    FOR EACH ROW EXECUTE FUNCTION clear_ticket_stage();
+
+-- 独自の状態だけが変わった場合も画面に知らせる
+CREATE OR REPLACE FUNCTION notify_ticket_change() RETURNS trigger AS $$
+DECLARE
+    kind TEXT;
+    ticket RECORD;
+BEGIN
+    IF TG_OP = 'TRUNCATE' THEN
+        PERFORM pg_notify('queueticket_live', json_build_object('kind', 'reset')::text);
+        RETURN NULL;
+    END IF;
+
+    IF TG_OP = 'INSERT' THEN
+        kind := 'created';
+        ticket := NEW;
+    ELSIF TG_OP = 'DELETE' THEN
+        kind := 'deleted';
+        ticket := OLD;
+    ELSE
+        ticket := NEW;
+        IF NEW.status = 'called' AND OLD.status = 'completed' THEN
+            kind := 'reverted'; -- 完了の取り消しではチャイムを鳴らさない
+        ELSIF NEW.status = 'waiting' AND OLD.status = 'called' THEN
+            kind := 'reverted';
+        ELSIF NEW.status = 'called' AND NEW.called_at IS DISTINCT FROM OLD.called_at THEN
+            kind := 'called';
+        ELSIF NEW.status <> OLD.status THEN
+            kind := NEW.status; -- completed, merged
+        ELSIF NEW.checked_in_at IS DISTINCT FROM OLD.checked_in_at THEN
+            kind := 'checked_in';
+        ELSIF NEW.queue_id <> OLD.queue_id THEN
+            kind := 'transferred';
+        ELSIF NEW.group_size <> OLD.group_size THEN
+            kind := 'split';
+        ELSIF NEW.stage IS DISTINCT FROM OLD.stage THEN
+            kind := 'stage_changed';
+        ELSE
+            kind := 'updated';
+        END IF;
+    END IF;
+
+    PERFORM pg_notify(
+        'queueticket_live',
+        json_build_object('kind', kind, 'id', ticket.id, 'number', ticket.number)::text
+    );
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,13 @@
+-- 列ごとの整理番号の付け方 (例: キッチンカーは2桁、病院は4桁)
+-- start から step ずつ進め、max を超えたら start に戻る
+ALTER TABLE queues ADD COLUMN IF NOT EXISTS number_start INT NOT NULL DEFAULT 1 CHECK (number_start >= 1);
+ALTER TABLE queues ADD COLUMN IF NOT EXISTS number_step INT NOT NULL DEFAULT 1 CHECK (number_step >= 1);
+ALTER TABLE queues ADD COLUMN IF NOT EXISTS number_max INT NOT NULL DEFAULT 999 CHECK (number_max >= number_start);
+
+-- 列ごとに最後に発券した番号 (NULL なら次は number_start から)
+-- 分割したチケットは発券時刻を引き継ぐため、チケットの並びからは求めずここに持つ
+ALTER TABLE queues ADD COLUMN IF NOT EXISTS last_number INT;
+
+UPDATE queues q SET last_number = (
+    SELECT t.number FROM tickets t WHERE t.queue_id = q.id ORDER BY t.created_at DESC LIMIT 1
+);
@@ -0,0 +1,11 @@
+-- 受付窓口 (例: クリニックの1番窓口・2番窓口)
+-- 窓口ごとに担当する列を決めておき、窓口専用の呼び出し画面 (/admin/call/{id}) ではその列だけを扱う
+CREATE TABLE IF NOT EXISTS counters (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,                       -- 表示名 (お客様の画面にも出る)
+    queue_ids INT[] NOT NULL DEFAULT '{}',    -- 担当する列 (空ならすべての列)
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- どの窓口から呼び出したか (全体の呼び出し画面から呼んだ場合は NULL)
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS counter_id INT REFERENCES counters(id) ON DELETE SET NULL;
@@ -0,0 +1,3 @@
+-- 配慮が必要なお客様の印 (wheelchair / stroller / elderly / interpreter)
+-- 呼び出す前に席や通訳の準備ができるよう、呼び出し管理画面にアイコンで表示する
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS needs TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,25 @@
+-- スタッフ向けのお知らせ (外部連携の失敗・長くお待ちのお客様・お客様からの変更など)
+-- ログに出すだけでは気づかれないので、管理画面のベルに未読数を表示する
+CREATE TABLE IF NOT EXISTS staff_notifications (
+    id BIGSERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,           -- sms_failed, refund_failed, sheets_failed, backup_failed, wait_exceeded, guest_resized
+    message TEXT NOT NULL,
+    ticket_id UUID,               -- 整理券に関するお知らせの場合 (リセットで消えないよう外部キーにはしない)
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    read_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS staff_notifications_unread_idx ON staff_notifications (created_at) WHERE read_at IS NULL;
+
+-- 追加・既読にした時に、開いている管理画面のベルを更新する
+CREATE OR REPLACE FUNCTION notify_staff_notification() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('queueticket_live', json_build_object('kind', 'notification')::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS staff_notifications_notify ON staff_notifications;
+CREATE TRIGGER staff_notifications_notify
+    AFTER INSERT OR UPDATE ON staff_notifications
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_staff_notification();
@@ -0,0 +1,12 @@
+-- 店頭の端末 (発券機・表示用モニター・プリンター) の死活監視
+-- 端末は定期的にハートビートを送り、途絶えたらスタッフにお知らせする
+CREATE TABLE IF NOT EXISTS devices (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('kiosk', 'display', 'printer')),
+    token_hash TEXT NOT NULL UNIQUE, -- 端末のトークンのHMAC (トークンそのものは追加した時に一度だけ表示する)
+    last_seen_at TIMESTAMPTZ,        -- 最後にハートビートを受けた時刻
+    last_status TEXT,                -- 端末が報告した状態 (例: プリンターの用紙切れ)
+    alerted_at TIMESTAMPTZ,          -- 応答がないとお知らせした時刻 (ハートビートが戻ったら消す)
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,6 @@
+-- 端末ごとの設定 (主に発券機)。端末は /api/devices/config で取得する
+-- 例: テイクアウト用の発券機はテイクアウトの列だけ、人数ボタンは1〜2名だけにする
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS queue_ids INT[] NOT NULL DEFAULT '{}';   -- 発券できる列 (空ならすべて)
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS group_sizes INT[] NOT NULL DEFAULT '{}'; -- 人数のボタン (空なら既定の並び)
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS language TEXT NOT NULL DEFAULT 'ja';    -- 画面の言語
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS auto_print BOOLEAN NOT NULL DEFAULT FALSE; -- 発券したら自動で印刷する
@@ -0,0 +1,3 @@
+-- 発券機から発券した整理券に、どの端末からかを記録する
+-- オフライン中に受け付けた整理券の送り直しを、同じ端末からのものか確かめるのにも使う
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS device_id INT REFERENCES devices(id) ON DELETE SET NULL;
@@ -0,0 +1,3 @@
+-- 練習モード中に発券した整理券の印
+-- 集計・出力の対象から外し、翌日になったら自動で削除する
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS is_test BOOLEAN NOT NULL DEFAULT FALSE;
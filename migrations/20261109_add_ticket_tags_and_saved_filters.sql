@@ -0,0 +1,13 @@
+-- 整理券に自由につけるタグ (例: 誕生日・常連・アレルギー)
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+CREATE INDEX IF NOT EXISTS tickets_tags_idx ON tickets USING GIN (tags);
+
+-- スタッフごとに保存した、呼び出し管理画面の絞り込み条件
+CREATE TABLE IF NOT EXISTS saved_filters (
+    id SERIAL PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,           -- ボタンに表示する名前
+    query TEXT NOT NULL,          -- 絞り込み条件 (呼び出し管理画面のクエリ文字列)
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (user_id, name)
+);
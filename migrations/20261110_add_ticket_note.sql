@@ -0,0 +1,2 @@
+-- スタッフが整理券に残すメモ (例: 窓際の席を希望・取り分け用の皿)
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS note TEXT;
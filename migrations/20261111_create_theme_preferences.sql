@@ -0,0 +1,9 @@
+-- スタッフ向け画面の配色 ("light" / "dark" / "contrast")
+-- スタッフごとの設定と、端末 (呼び出し画面を開いているタブレットなど) ごとの設定を持ち、端末の設定を優先する
+ALTER TABLE users ADD COLUMN IF NOT EXISTS theme TEXT;
+
+CREATE TABLE IF NOT EXISTS station_preferences (
+    station_id UUID PRIMARY KEY,  -- 端末のCookieに入れたID
+    theme TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,10 @@
+-- 見るだけの共有リンク (Basic認証なしで、期限まで呼び出し管理画面と状況を閲覧できる)
+-- スタッフの権限 (users.role) にも、書き換えのできない 'observer' (見るだけ) を加える
+CREATE TABLE IF NOT EXISTS observer_links (
+    id UUID PRIMARY KEY,
+    label TEXT NOT NULL,                              -- 誰に渡したか (例: "オーナー")
+    created_by UUID REFERENCES users(id),
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ,                           -- 取り消した時刻 (取り消していなければ NULL)
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,10 @@
+-- 管理画面のセッション (Basic認証で入った端末ごと)
+-- 一覧から取り消した端末や、運用設定の期限を過ぎた端末には、もう一度ログインさせる
+CREATE TABLE IF NOT EXISTS admin_sessions (
+    id UUID PRIMARY KEY,                               -- 端末のCookieに入れたID
+    user_agent TEXT NOT NULL DEFAULT '',
+    ip TEXT NOT NULL DEFAULT '',                       -- 最後に操作したIPアドレス
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),     -- ログインした時刻
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),   -- 最後に操作した時刻 (1分単位で更新)
+    revoked_at TIMESTAMPTZ                             -- 取り消した時刻 (取り消していなければ NULL)
+);
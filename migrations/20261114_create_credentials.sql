@@ -0,0 +1,11 @@
+-- 管理画面から追加した認証情報 (管理者パスワード・POS連携APIのキー・メトリクスのトークン)
+-- 値そのものは保存せず、COOKIE_SECRET による鍵つきハッシュだけを持つ
+-- Secrets.toml の値に期限をつける時も、その値のハッシュで1行作る
+CREATE TABLE IF NOT EXISTS credentials (
+    id SERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,                              -- 'admin' / 'pos' / 'metrics'
+    label TEXT NOT NULL,
+    secret_hash TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    retire_at TIMESTAMPTZ                            -- この時刻から受け付けない (NULLなら無期限)
+);
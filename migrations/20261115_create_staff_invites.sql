@@ -0,0 +1,13 @@
+-- スタッフの招待リンク (責任者が発行し、新しいスタッフが自分でPINを決めて登録する)
+-- 1回使うか、期限が来るか、取り消すと使えなくなる
+CREATE TABLE IF NOT EXISTS staff_invites (
+    id UUID PRIMARY KEY,
+    name TEXT NOT NULL,                               -- 登録するスタッフの名前
+    role TEXT NOT NULL DEFAULT 'staff',               -- 登録する時の権限
+    created_by UUID REFERENCES users(id),
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,                              -- 登録に使った時刻 (未使用なら NULL)
+    user_id UUID REFERENCES users(id),                -- 登録されたスタッフ
+    revoked_at TIMESTAMPTZ,                           -- 取り消した時刻 (取り消していなければ NULL)
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,27 @@
+-- 整理券のWebhook (POSなど外部システムへの通知) の送信記録
+-- 失敗したものは間隔を延ばしながら送り直し、管理画面から中身を確認して手動でも送り直せる
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id UUID PRIMARY KEY,                              -- 送り直しても同じ (受け取る側で重複を除ける)
+    event TEXT NOT NULL,                              -- 例: "ticket.called"
+    ticket_id UUID,                                   -- リセット後も記録を残すので外部キーにしない
+    payload TEXT NOT NULL,                            -- 送った本文 (JSON)
+    status TEXT NOT NULL DEFAULT 'pending',           -- 'pending' (送信待ち) / 'delivered' / 'failed' (送り直しをあきらめた)
+    attempts INT NOT NULL DEFAULT 0,                  -- 今回の送信での試行回数 (手動で送り直すと0に戻る)
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    delivered_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_webhook_deliveries_due ON webhook_deliveries (status, next_attempt_at);
+
+-- 1回ごとの試行 (応答コードと所要時間)
+CREATE TABLE IF NOT EXISTS webhook_attempts (
+    id BIGSERIAL PRIMARY KEY,
+    delivery_id UUID NOT NULL REFERENCES webhook_deliveries(id) ON DELETE CASCADE,
+    attempted_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    status_code INT,                                  -- 応答がなかった (接続できない・時間切れ) 時は NULL
+    latency_ms INT NOT NULL,
+    error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_webhook_attempts_delivery ON webhook_attempts (delivery_id, attempted_at);
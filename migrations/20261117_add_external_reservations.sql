@@ -0,0 +1,9 @@
+-- 外部の予約サービス (TableCheck・トレタなど) から Webhook で受け取った予約
+-- 予約枠を使わないので slot_id は空にし、来店時刻を予約そのものに持つ
+ALTER TABLE reservations ALTER COLUMN slot_id DROP NOT NULL;
+ALTER TABLE reservations ADD COLUMN IF NOT EXISTS starts_at TIMESTAMPTZ;  -- 外部の予約の来店時刻 (予約枠の予約は NULL)
+ALTER TABLE reservations ADD COLUMN IF NOT EXISTS source TEXT;           -- 受け取った予約サービスの名前 (この画面で受けた予約は NULL)
+ALTER TABLE reservations ADD COLUMN IF NOT EXISTS external_id TEXT;      -- 予約サービス側の予約ID
+
+-- 同じ予約の作成・変更が何度届いても1件にまとめる
+CREATE UNIQUE INDEX IF NOT EXISTS reservations_external_idx ON reservations (source, external_id) WHERE source IS NOT NULL;
@@ -0,0 +1,6 @@
+-- 週ごとの集計 (月曜の朝に前の週の分を作り、ベルでお知らせする)
+CREATE TABLE IF NOT EXISTS weekly_digests (
+    week_start DATE PRIMARY KEY,                   -- 集計した週の月曜 (店舗の現地の日付)
+    body TEXT NOT NULL,                            -- 本文 (管理画面にそのまま表示する)
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,8 @@
+-- 待ち時間の見込みが上限を超えて、新しい発券を自動で止めていた期間
+-- (止めた時・再開した時に1回ずつスタッフへお知らせするため、今止めているかを記録する)
+CREATE TABLE IF NOT EXISTS queue_pauses (
+    id BIGSERIAL PRIMARY KEY,
+    wait_minutes BIGINT NOT NULL,                  -- 止めた時の待ち時間の見込み
+    started_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    ended_at TIMESTAMPTZ                           -- 再開した時刻 (止めている間は NULL)
+);
@@ -0,0 +1,31 @@
+-- 列ごとの「ただいまご案内中の番号」
+-- 整理券の状態とは別に持ち、待機中の整理券を呼び出した時だけ進める
+-- (順番どおりに完了しなかった場合や、呼び直した場合でも表示が前後しない)
+ALTER TABLE queues ADD COLUMN IF NOT EXISTS now_serving INT;
+ALTER TABLE queues ADD COLUMN IF NOT EXISTS now_serving_at TIMESTAMPTZ; -- 進めた時刻 (前の日の番号を表示しないため)
+
+CREATE OR REPLACE FUNCTION advance_now_serving() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'TRUNCATE' THEN
+        UPDATE queues SET now_serving = NULL, now_serving_at = NULL;
+        RETURN NULL;
+    END IF;
+
+    UPDATE queues SET now_serving = NEW.number, now_serving_at = NEW.called_at WHERE id = NEW.queue_id;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- 練習用の整理券では進めない
+DROP TRIGGER IF EXISTS tickets_now_serving ON tickets;
+CREATE TRIGGER tickets_now_serving
+    AFTER UPDATE ON tickets
+    FOR EACH ROW
+    WHEN (OLD.status = 'waiting' AND NEW.status = 'called' AND NOT NEW.is_test)
+    EXECUTE FUNCTION advance_now_serving();
+
+-- データのリセットで消す
+DROP TRIGGER IF EXISTS tickets_now_serving_truncate ON tickets;
+CREATE TRIGGER tickets_now_serving_truncate
+    AFTER TRUNCATE ON tickets
+    FOR EACH STATEMENT EXECUTE FUNCTION advance_now_serving();
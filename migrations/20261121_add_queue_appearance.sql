@@ -0,0 +1,4 @@
+-- 列ごとの色とアイコン (呼び出し管理画面・表示用モニター・整理券で列を見分けやすくする)
+-- 空文字なら付けない
+ALTER TABLE queues ADD COLUMN IF NOT EXISTS color TEXT NOT NULL DEFAULT ''; -- "#rrggbb"
+ALTER TABLE queues ADD COLUMN IF NOT EXISTS icon TEXT NOT NULL DEFAULT '';  -- 絵文字など (2文字まで)
@@ -0,0 +1,57 @@
+-- 整理券の削除を取り消せるようにする (ゴミ箱)
+-- 削除した整理券は status = 'deleted' にして行は残し、削除した時刻を deleted_at に持つ
+-- 復元すると番号と発券時刻はそのままで待機中に戻る
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+CREATE INDEX IF NOT EXISTS tickets_deleted_at ON tickets (deleted_at) WHERE deleted_at IS NOT NULL;
+
+-- 削除は画面から消えるので "deleted"、復元は新しく並んだのと同じ扱いで "created" として知らせる
+CREATE OR REPLACE FUNCTION notify_ticket_change() RETURNS trigger AS $$
+DECLARE
+    kind TEXT;
+    ticket RECORD;
+BEGIN
+    IF TG_OP = 'TRUNCATE' THEN
+        PERFORM pg_notify('queueticket_live', json_build_object('kind', 'reset')::text);
+        RETURN NULL;
+    END IF;
+
+    IF TG_OP = 'INSERT' THEN
+        kind := 'created';
+        ticket := NEW;
+    ELSIF TG_OP = 'DELETE' THEN
+        kind := 'deleted';
+        ticket := OLD;
+    ELSE
+        ticket := NEW;
+        IF NEW.status = 'deleted' AND OLD.status <> 'deleted' THEN
+            kind := 'deleted';
+        ELSIF OLD.status = 'deleted' AND NEW.status <> 'deleted' THEN
+            kind := 'created';
+        ELSIF NEW.status = 'called' AND OLD.status = 'completed' THEN
+            kind := 'reverted'; -- 完了の取り消しではチャイムを鳴らさない
+        ELSIF NEW.status = 'waiting' AND OLD.status = 'called' THEN
+            kind := 'reverted';
+        ELSIF NEW.status = 'called' AND NEW.called_at IS DISTINCT FROM OLD.called_at THEN
+            kind := 'called';
+        ELSIF NEW.status <> OLD.status THEN
+            kind := NEW.status; -- completed, merged
+        ELSIF NEW.checked_in_at IS DISTINCT FROM OLD.checked_in_at THEN
+            kind := 'checked_in';
+        ELSIF NEW.queue_id <> OLD.queue_id THEN
+            kind := 'transferred';
+        ELSIF NEW.group_size <> OLD.group_size THEN
+            kind := 'split';
+        ELSIF NEW.stage IS DISTINCT FROM OLD.stage THEN
+            kind := 'stage_changed';
+        ELSE
+            kind := 'updated';
+        END IF;
+    END IF;
+
+    PERFORM pg_notify(
+        'queueticket_live',
+        json_build_object('kind', kind, 'id', ticket.id, 'number', ticket.number)::text
+    );
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,24 @@
+-- 整理番号の採番を、列と営業日 (店舗の現地日付) ごとの行で行う
+-- 発券のたびに UPDATE ... RETURNING で1つ進めるので、同時に発券されても行のロックで順番になる
+-- 営業日が変わると新しい行を作る (前の日の行は書き換えずに残す)
+CREATE TABLE IF NOT EXISTS number_sequences (
+    queue_id INT NOT NULL REFERENCES queues(id) ON DELETE CASCADE,
+    business_date DATE NOT NULL,
+    last_number INT, -- その日に最後に発券した番号 (NULL なら次は number_start から)
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (queue_id, business_date)
+);
+
+-- 列ごとの番号の頭文字 (例: テイクアウトは "T")。同じ頭文字の列どうしでだけ番号の重なりを避ける
+ALTER TABLE queues ADD COLUMN IF NOT EXISTS number_prefix TEXT NOT NULL DEFAULT '';
+-- 営業日が変わったら最初の番号からやり直すか (しなければ前の日の続きから)
+ALTER TABLE queues ADD COLUMN IF NOT EXISTS number_reset_daily BOOLEAN NOT NULL DEFAULT FALSE;
+-- 発券した時の頭文字 (列を移動しても番号と一緒に変わらない)
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS number_prefix TEXT NOT NULL DEFAULT '';
+
+-- これまでの最後の番号は前の日の行として移し、次の発券はその続きから採番する
+INSERT INTO number_sequences (queue_id, business_date, last_number)
+SELECT id, CURRENT_DATE - 1, last_number FROM queues WHERE last_number IS NOT NULL
+ON CONFLICT (queue_id, business_date) DO NOTHING;
+
+ALTER TABLE queues DROP COLUMN IF EXISTS last_number;
@@ -0,0 +1,14 @@
+-- 発券機・プリンターからのAPIのリクエストに、時刻と署名をつけてもらう
+-- これまでに登録した端末は署名に対応していないので、トークンを発行し直すまでは署名なしでも受け付ける
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS allow_unsigned BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE devices ALTER COLUMN allow_unsigned SET DEFAULT FALSE;
+
+-- 受け付けた署名 (同じリクエストの送り直しを断るため)。時刻のずれを許す間だけ残す
+CREATE TABLE IF NOT EXISTS device_request_signatures (
+    device_id INT NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+    signature TEXT NOT NULL,
+    seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (device_id, signature)
+);
+
+CREATE INDEX IF NOT EXISTS device_request_signatures_seen_at_idx ON device_request_signatures (seen_at);
@@ -0,0 +1,9 @@
+-- 月ごとの利用量 (フランチャイズ店への請求のもと)
+-- 整理券やSMSは発生した時に足し込むので、データの初期化や整理券の削除をしても数は減らない
+CREATE TABLE IF NOT EXISTS usage_meter (
+    month DATE NOT NULL,            -- 月の初日 (店舗の営業日で数える)
+    metric TEXT NOT NULL,           -- tickets (発券した整理券) / notifications (送ったSMS) / storage_bytes (DBの容量。その月の最大)
+    value BIGINT NOT NULL DEFAULT 0,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (month, metric)
+);
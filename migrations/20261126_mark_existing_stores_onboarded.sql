@@ -0,0 +1,4 @@
+-- 既に営業している店舗には初期設定 (/admin/onboarding) の案内を出さない
+INSERT INTO settings (key, value)
+SELECT 'onboarded', 'true' WHERE EXISTS (SELECT 1 FROM tickets) OR EXISTS (SELECT 1 FROM devices)
+ON CONFLICT (key) DO NOTHING;
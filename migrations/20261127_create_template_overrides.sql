@@ -0,0 +1,8 @@
+-- 店舗ごとの画面の差し替え (来場者画面の本文・呼び出し時の案内文・整理券の印刷レイアウト)
+-- 再ビルドせずに文言や見た目を変えるため、テンプレートの本文をDBに持つ。行がなければ組み込みの画面を使う
+CREATE TABLE IF NOT EXISTS template_overrides (
+    name TEXT PRIMARY KEY,                                   -- guest_body / called_message / slip
+    source TEXT NOT NULL,                                    -- テンプレートの本文 (Jinja 形式)
+    updated_by UUID REFERENCES users(id) ON DELETE SET NULL, -- 最後に変更したスタッフ (未選択ならNULL)
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
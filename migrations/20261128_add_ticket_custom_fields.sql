@@ -0,0 +1,3 @@
+-- 店舗で定義した追加の項目 (例: 喫煙・禁煙、お子様用の椅子) の、整理券ごとの値
+-- 項目は運用設定で増減するので、列を足さずに「キー: 値」の形でまとめて持つ
+ALTER TABLE tickets ADD COLUMN IF NOT EXISTS custom_fields JSONB NOT NULL DEFAULT '{}'::jsonb;
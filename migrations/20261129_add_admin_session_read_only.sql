@@ -0,0 +1,3 @@
+-- 見るだけの担当者に切り替えた端末のセッション
+-- 担当者の選択を外しても管理者の操作に戻らないよう、見るだけはセッションに残す (外すのは別の担当者へのPINでの切り替えだけ)
+ALTER TABLE admin_sessions ADD COLUMN IF NOT EXISTS read_only BOOLEAN NOT NULL DEFAULT FALSE;
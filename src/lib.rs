@@ -0,0 +1,13618 @@
+use axum::{
+    extract::{ConnectInfo, Multipart, Path, Query, State, Request},
+    http::{header::AUTHORIZATION, StatusCode, Method},  // 追加: Method
+    middleware::{self, Next}, // ミドルウェア用に追加
+    response::{Html, IntoResponse, Redirect, Response, sse::{Event, KeepAlive, Sse}},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use askama::Template;
+use base64::prelude::*;
+use qrcodegen::{QrCode, QrCodeEcc};
+use serde::{Deserialize, Serialize};
+use shuttle_runtime::SecretStore;
+use sqlx::{postgres::PgListener, ConnectOptions, FromRow, PgPool};
+use uuid::Uuid;
+use constant_time_eq::constant_time_eq;   // 追加
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc}; // 日付操作用
+use axum::http::header::CONTENT_TYPE; // CSV出力用
+use axum::http::{HeaderMap, header::{COOKIE, SET_COOKIE}}; // 端末識別Cookie用
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tower_http::trace::TraceLayer; // 追加: リクエストごとのトレース
+use tower_http::{compression::CompressionLayer, services::{ServeDir, ServeFile}, set_header::SetResponseHeader}; // 静的ファイル配信用
+use tower_http::cors::{AllowOrigin, Any, CorsLayer}; // 待ち状況ウィジェット・公開の待ち時間API用
+use rumqttc::{AsyncClient, MqttOptions, QoS, Transport}; // LED番号表示器への配信用
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+use tokio_stream::{wrappers::{BroadcastStream, ReceiverStream}, Stream, StreamExt}; // SSE・gRPC の配信用
+
+// 静的ファイル (/static) のURLにつける版。長期間キャッシュさせるので、
+// static/ の中身を変えたらバージョンを上げてブラウザに取り直させる
+const ASSET_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// 他のアプリに組み込んだ時のパス (例: "/queue")。画面内のリンクやリダイレクト先の先頭につける
+// 元は AppState の base_path。テンプレートからも読めるよう、リクエストの処理中だけここに置く (scope_base_path)
+tokio::task_local! {
+    static BASE_PATH: Arc<str>;
+}
+
+fn base_path() -> Arc<str> {
+    BASE_PATH.try_with(Arc::clone).unwrap_or_else(|_| Arc::from(""))
+}
+
+// アプリ内のパスを、組み込み先でのパスにする (リクエストの処理中のみ。定期処理や gRPC では AppState::url を使う)
+fn url(path: &str) -> String {
+    format!("{}{}", base_path(), path)
+}
+
+#[derive(Clone)]
+struct AppState {
+    pool: PgPool,
+    base_url: String,
+    base_path: Arc<str>,           // 組み込み先でのパス (例: "/queue")。単体で動かす時は空
+    credentials: Credentials,      // 追加: 管理者パスワード・APIキー (Secrets の値と、管理画面から追加した値)
+    cookie_key: Vec<u8>,           // 追加: 署名付きCookie用の鍵
+    device_guard: DeviceGuardMode, // 追加: 1端末1枚ガードの動作モード
+    page_size: i64,                // 追加: 一覧画面の1ページあたりの件数
+    live: broadcast::Sender<LiveEvent>, // 追加: 画面へのリアルタイム通知チャネル
+    chime_url: Option<String>,     // 追加: 呼び出し時に鳴らす音声ファイルのURL (未設定なら電子音)
+    highlight_seconds: i64,        // 追加: 呼び出し直後のチケットを強調表示する秒数
+    display_key: Option<String>,   // 追加: 表示用モニター (/display) の端末キー
+    display_waiting_count: i64,    // 追加: 表示用モニターに出す待機中の件数
+    http: reqwest::Client,         // 追加: 外部サービス (SMS送信など) への通信用
+    webhook: Option<Arc<WebhookConfig>>, // 追加: 外部システムへの Webhook (未設定なら無効)
+    reservation_sources: Arc<HashMap<String, ReservationMapping>>, // 追加: 予約サービスごとの、受け取る JSON の項目の場所
+    sms_webhook_url: Option<String>, // 追加: SMS送信ゲートウェイのURL
+    stripe: Option<StripeConfig>, // 追加: デポジットの決済 (未設定なら無効)
+    digest_mail: Option<Arc<DigestMailConfig>>, // 追加: 週ごとの集計のメール送信 (未設定なら画面とベルだけ)
+    guest_link_hours: i64, // 追加: 来場者画面のリンクの有効時間
+    metrics: Metrics,      // 追加: 接続プールとクエリのメトリクス
+    snapshot: SnapshotCache,       // 追加: 有効なチケットのメモリ上のスナップショット
+    public_wait: PublicWaitCache,  // 追加: 公開の待ち時間APIの応答のキャッシュ
+    template_overrides: TemplateOverrideCache, // 追加: 差し替えテンプレート (保存した時に読み直す)
+    security_headers: SecurityHeaders, // 追加: CSPなどのセキュリティ関連ヘッダー
+    route_policy: Arc<RoutePolicy>,    // 追加: 画面・操作ごとに使える担当者の役割
+    backup: Option<Arc<BackupConfig>>, // 追加: S3互換ストレージへのバックアップ (未設定なら無効)
+    hooks: Arc<[Box<dyn TicketHook>]>, // 追加: 整理券のフック (MQTT配信・組み込み先の処理)
+    store_code: String, // 追加: 本部への利用量の報告で店舗を見分けるコード (未設定なら空)
+    migrations_confirmed: Vec<i64>, // 追加: 起動時に適用を確認した、データを消す・変えるマイグレーション
+    trusted_proxy_hops: usize, // 追加: 手前にある信頼できるプロキシの段数 (送信元のIPアドレスの判断用)
+}
+
+// 送信元ごとの簡易レート制限 (一定時間内の回数を数える)
+// 複数台で動かしても合算して数えられるよう、記録はDBに置く
+// 制限内なら記録して true、超えていれば false
+async fn check_rate_limit(pool: &PgPool, key: &str, limit: usize, window: Duration) -> bool {
+    let mut tx = pool.begin().await.expect("Failed to begin transaction");
+    // 同じ送信元からの同時送信で、両方が「まだ制限内」と判定しないようにする
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind(key)
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to lock rate limit key");
+    let allowed = sqlx::query(
+        "INSERT INTO rate_limit_hits (key) 
+         SELECT $1 WHERE (
+             SELECT COUNT(*) FROM rate_limit_hits 
+             WHERE key = $1 AND hit_at > NOW() - make_interval(secs => $3)
+         ) < $2"
+    )
+    .bind(key)
+    .bind(limit as i64)
+    .bind(window.as_secs_f64())
+    .execute(&mut *tx)
+    .await
+    .expect("Failed to check rate limit")
+    .rows_affected()
+        > 0;
+    tx.commit().await.expect("Failed to commit transaction");
+    allowed
+}
+
+// 失敗した時だけ数える制限用。ここでは数えずに、制限に達しているかだけを見る (数えるのは record_rate_limit_hit)
+async fn rate_limit_reached(pool: &PgPool, key: &str, limit: usize, window: Duration) -> bool {
+    let hits: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM rate_limit_hits WHERE key = $1 AND hit_at > NOW() - make_interval(secs => $2)"
+    )
+    .bind(key)
+    .bind(window.as_secs_f64())
+    .fetch_one(pool)
+    .await
+    .unwrap_or(0);
+    hits >= limit as i64
+}
+
+async fn record_rate_limit_hit(pool: &PgPool, key: &str) {
+    sqlx::query("INSERT INTO rate_limit_hits (key) VALUES ($1)")
+        .bind(key)
+        .execute(pool)
+        .await
+        .expect("Failed to record rate limit hit");
+}
+
+// 定期処理を複数台のうち1台だけで実行するためのロック
+// 取れなければ None (他の台が実行中)。戻り値のトランザクションを捨てるとロックも外れる
+async fn try_job_lock(pool: &PgPool, job: &str) -> Option<sqlx::Transaction<'static, sqlx::Postgres>> {
+    let mut tx = pool.begin().await.ok()?;
+    let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_xact_lock(hashtext($1))")
+        .bind(job)
+        .fetch_one(&mut *tx)
+        .await
+        .ok()?;
+    locked.then_some(tx)
+}
+
+// --- メトリクス (Prometheus 形式で /metrics から取得する) ---
+// 秒単位のヒストグラムの区切り
+const LATENCY_BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+// クエリの種類が増えすぎてメモリを使い切らないよう、記録する種類に上限を設ける
+const MAX_QUERY_LABELS: usize = 200;
+
+#[derive(Clone, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, le) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= le {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (bucket, le) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            out.push_str(&format!("{}_bucket{{{}{}le=\"{}\"}} {}\n", name, labels, sep, le, bucket));
+        }
+        out.push_str(&format!("{}_bucket{{{}{}le=\"+Inf\"}} {}\n", name, labels, sep, self.count));
+        let braces = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        out.push_str(&format!("{}_sum{} {}\n", name, braces, self.sum));
+        out.push_str(&format!("{}_count{} {}\n", name, braces, self.count));
+    }
+}
+
+#[derive(Default)]
+struct MetricsData {
+    queries: HashMap<String, Histogram>, // クエリの要約ごとの所要時間
+    acquire: Histogram,                  // 接続プールからの取得にかかった時間 (定期的に計測)
+    slow_queries: u64,
+    polling: HashMap<&'static str, PollingStats>, // 自動更新の取得先ごとの通信量
+    polling_since: Option<DateTime<Utc>>,         // 自動更新の集計を始めた時刻 (リセットした時刻)
+}
+
+// --- 自動更新 (ポーリング) の通信量 ---
+// 画面が定期的に取得しに来る部品と、リアルタイム通知 (SSE) の接続を数え、
+// 間隔の調整や、通知・条件付き取得 (304) で負荷が減ったかの確認に使う
+// (キー, 表示名, パスの末尾)。来場者画面はパスの途中に整理券のIDが入るので別に判定する
+const POLLING_ENDPOINTS: [(&str, &str, &str); 5] = [
+    ("guest", "来場者画面", ""),
+    ("call_list", "呼び出し管理画面", "/admin/call/list"),
+    ("display", "表示用モニター", "/display/content"),
+    ("dashboard", "管理メニューの状況欄", "/admin/stats"),
+    ("widget", "待ち状況ウィジェット", "/widget.json"),
+];
+
+// リアルタイム通知の接続 (1回の接続が長く続くので、回数は接続した回数)
+const SSE_PATHS: [&str; 2] = ["/admin/call/events", "/display/events"];
+
+// 来場者画面の1組あたりの回数を出すために覚えておく整理券の数の上限 (超えたら数えない)
+const MAX_POLLING_GUESTS: usize = 50_000;
+
+// 運用設定で選べる自動更新の間隔 (秒)
+const GUEST_POLL_RANGE: (i64, i64) = (3, 60);
+// 自動更新の応答につける、今の間隔 (開いたままの画面が static/poll.js で間隔を合わせる)
+const POLL_SECONDS_HEADER: &str = "x-poll-seconds";
+const STAFF_POLL_RANGE: (i64, i64) = (10, 300);
+
+// 運用設定で選べる、案内時間の見込みに使う過去の日数とパーセンタイル
+const ETA_WINDOW_RANGE: (i64, i64) = (7, 90);
+const ETA_PERCENTILE_RANGE: (i64, i64) = (50, 95);
+
+#[derive(Clone, Default)]
+struct PollingStats {
+    requests: u64,
+    ok: u64,           // 200 (中身を返した)
+    not_modified: u64, // 304 (変わっていないので中身を返さなかった)
+    bytes: u64,        // 返した本文の大きさの合計 (圧縮前)
+    guests: HashSet<Uuid>, // 取得しに来た整理券 (来場者画面のみ)
+}
+
+// 管理メニューに出す1行分
+struct PollingRow {
+    label: &'static str,
+    requests: u64,
+    ok_percent: u64,
+    not_modified_percent: u64,
+    kilobytes: u64,
+    per_guest: Option<String>, // 1組あたりの回数 (来場者画面のみ。小数1桁)
+}
+
+impl PollingRow {
+    fn new(label: &'static str, stats: &PollingStats) -> Self {
+        let percent = |n: u64| (n * 100).checked_div(stats.requests).unwrap_or(0);
+        PollingRow {
+            label,
+            requests: stats.requests,
+            ok_percent: percent(stats.ok),
+            not_modified_percent: percent(stats.not_modified),
+            kilobytes: stats.bytes.div_ceil(1024),
+            per_guest: (!stats.guests.is_empty())
+                .then(|| format!("{:.1}", stats.requests as f64 / stats.guests.len() as f64)),
+        }
+    }
+}
+
+struct PollingSummary {
+    rows: Vec<PollingRow>,
+    sse_connections: u64,
+    since: Option<DateTime<Utc>>,
+}
+
+// リクエストのパスから、数える対象の取得先を決める (対象外なら None)
+// 組み込み先でパスの前に何かつく場合にも当てはまるよう、末尾で判定する
+fn polling_endpoint(path: &str) -> Option<(&'static str, Option<Uuid>)> {
+    if let Some(rest) = path.rsplit_once("/guest/").map(|(_, rest)| rest) {
+        let (id, tail) = rest.split_once('/')?;
+        if tail == "content" || tail == "status" {
+            return Some(("guest", Uuid::parse_str(id).ok()));
+        }
+        return None;
+    }
+    if SSE_PATHS.iter().any(|p| path.ends_with(p)) {
+        return Some(("sse", None));
+    }
+    POLLING_ENDPOINTS
+        .iter()
+        .find(|(key, _, suffix)| *key != "guest" && path.ends_with(suffix))
+        .map(|(key, _, _)| (*key, None))
+}
+
+#[derive(Clone, Default)]
+struct Metrics {
+    data: Arc<Mutex<MetricsData>>,
+    slow_query_seconds: f64, // これを超えたクエリを遅いクエリとして数える
+}
+
+impl Metrics {
+    fn observe_query(&self, summary: &str, seconds: f64) {
+        let mut data = self.data.lock().expect("metrics lock poisoned");
+        if seconds > self.slow_query_seconds {
+            data.slow_queries += 1;
+        }
+        let label = if data.queries.contains_key(summary) || data.queries.len() < MAX_QUERY_LABELS {
+            summary
+        } else {
+            "other"
+        };
+        data.queries.entry(label.to_string()).or_default().observe(seconds);
+    }
+
+    fn observe_acquire(&self, seconds: f64) {
+        self.data.lock().expect("metrics lock poisoned").acquire.observe(seconds);
+    }
+
+    fn observe_polling(&self, endpoint: &'static str, guest: Option<Uuid>, status: StatusCode, bytes: u64) {
+        let mut data = self.data.lock().expect("metrics lock poisoned");
+        data.polling_since.get_or_insert_with(Utc::now);
+        let stats = data.polling.entry(endpoint).or_default();
+        stats.requests += 1;
+        if status == StatusCode::OK {
+            stats.ok += 1;
+        } else if status == StatusCode::NOT_MODIFIED {
+            stats.not_modified += 1;
+        }
+        stats.bytes += bytes;
+        if let Some(id) = guest {
+            if stats.guests.len() < MAX_POLLING_GUESTS {
+                stats.guests.insert(id);
+            }
+        }
+    }
+
+    fn polling_summary(&self) -> PollingSummary {
+        let data = self.data.lock().expect("metrics lock poisoned");
+        let empty = PollingStats::default();
+        PollingSummary {
+            rows: POLLING_ENDPOINTS
+                .iter()
+                .map(|(key, label, _)| PollingRow::new(label, data.polling.get(key).unwrap_or(&empty)))
+                .collect(),
+            sse_connections: data.polling.get("sse").map_or(0, |s| s.requests),
+            since: data.polling_since,
+        }
+    }
+
+    fn reset_polling(&self) {
+        let mut data = self.data.lock().expect("metrics lock poisoned");
+        data.polling.clear();
+        data.polling_since = Some(Utc::now());
+    }
+
+    fn render(&self, pool: &PgPool) -> String {
+        let data = self.data.lock().expect("metrics lock poisoned");
+        let mut out = String::new();
+
+        out.push_str("# HELP queueticket_db_pool_connections Connections in the database pool.\n");
+        out.push_str("# TYPE queueticket_db_pool_connections gauge\n");
+        let size = pool.size() as usize;
+        let idle = pool.num_idle();
+        out.push_str(&format!("queueticket_db_pool_connections{{state=\"total\"}} {}\n", size));
+        out.push_str(&format!("queueticket_db_pool_connections{{state=\"idle\"}} {}\n", idle));
+        out.push_str(&format!("queueticket_db_pool_connections{{state=\"in_use\"}} {}\n", size.saturating_sub(idle)));
+        out.push_str("# HELP queueticket_db_pool_max_connections Maximum connections of the database pool.\n");
+        out.push_str("# TYPE queueticket_db_pool_max_connections gauge\n");
+        out.push_str(&format!("queueticket_db_pool_max_connections {}\n", pool.options().get_max_connections()));
+
+        out.push_str("# HELP queueticket_db_pool_acquire_seconds Time to acquire a pooled connection (sampled periodically).\n");
+        out.push_str("# TYPE queueticket_db_pool_acquire_seconds histogram\n");
+        data.acquire.render(&mut out, "queueticket_db_pool_acquire_seconds", "");
+
+        out.push_str("# HELP queueticket_db_query_seconds Query latency by statement summary.\n");
+        out.push_str("# TYPE queueticket_db_query_seconds histogram\n");
+        let mut queries: Vec<_> = data.queries.iter().collect();
+        queries.sort_by(|a, b| a.0.cmp(b.0));
+        for (summary, histogram) in queries {
+            let label = format!("query=\"{}\"", escape_label(summary));
+            histogram.render(&mut out, "queueticket_db_query_seconds", &label);
+        }
+
+        out.push_str("# HELP queueticket_db_slow_queries_total Queries slower than SLOW_QUERY_MS.\n");
+        out.push_str("# TYPE queueticket_db_slow_queries_total counter\n");
+        out.push_str(&format!("queueticket_db_slow_queries_total {}\n", data.slow_queries));
+
+        out.push_str("# HELP queueticket_polling_requests_total Auto-refresh requests and SSE connections by endpoint and status.\n");
+        out.push_str("# TYPE queueticket_polling_requests_total counter\n");
+        out.push_str("# HELP queueticket_polling_bytes_total Uncompressed response bytes served to auto-refresh requests.\n");
+        out.push_str("# TYPE queueticket_polling_bytes_total counter\n");
+        let mut polling: Vec<_> = data.polling.iter().collect();
+        polling.sort_by_key(|(endpoint, _)| **endpoint);
+        for (endpoint, stats) in polling {
+            let other = stats.requests - stats.ok - stats.not_modified;
+            for (status, count) in [("200", stats.ok), ("304", stats.not_modified), ("other", other)] {
+                out.push_str(&format!("queueticket_polling_requests_total{{endpoint=\"{}\",status=\"{}\"}} {}\n", endpoint, status, count));
+            }
+            out.push_str(&format!("queueticket_polling_bytes_total{{endpoint=\"{}\"}} {}\n", endpoint, stats.bytes));
+        }
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// sqlx が出すクエリのイベント (target: sqlx::query) から所要時間を集計する
+struct QueryMetricsLayer {
+    metrics: Metrics,
+}
+
+#[derive(Default)]
+struct QueryEventVisitor {
+    summary: Option<String>,
+    elapsed_secs: Option<f64>,
+}
+
+impl tracing::field::Visit for QueryEventVisitor {
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        if field.name() == "elapsed_secs" {
+            self.elapsed_secs = Some(value);
+        }
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        if field.name() == "summary" {
+            self.summary = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "summary" {
+            self.summary = Some(format!("{:?}", value).trim_matches('"').to_string());
+        }
+    }
+}
+
+impl<S: tracing::Subscriber> Layer<S> for QueryMetricsLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if event.metadata().target() != "sqlx::query" {
+            return;
+        }
+        let mut visitor = QueryEventVisitor::default();
+        event.record(&mut visitor);
+        if let (Some(summary), Some(seconds)) = (visitor.summary, visitor.elapsed_secs) {
+            self.metrics.observe_query(&summary, seconds);
+        }
+    }
+}
+
+// 接続プールの混み具合を見るため、定期的に接続の取得時間を測る
+async fn pool_metrics_worker(pool: PgPool, metrics: Metrics) {
+    let mut interval = tokio::time::interval(Duration::from_secs(15));
+    loop {
+        interval.tick().await;
+        let started = Instant::now();
+        if pool.acquire().await.is_ok() {
+            metrics.observe_acquire(started.elapsed().as_secs_f64());
+        }
+    }
+}
+
+// Authorization: Bearer <key> が、その種類の有効なキーのどれかと一致するか
+fn bearer_matches(state: &AppState, headers: &HeaderMap, kind: &str) -> bool {
+    let given = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    match given {
+        Some(given) => state.credentials.accepts(kind, given),
+        None => false,
+    }
+}
+
+// --- 認証情報 (管理者パスワード・APIキー) の切り替え ---
+// Secrets の値に加えて、管理画面から新しい値を追加できる。古い値には期限 (retire_at) をつけ、
+// 期限までは新旧どちらも受け付けるので、営業中でも再デプロイなしに切り替えられる
+// 照合は鍵つきハッシュで行い、DBには値そのものを残さない
+const CREDENTIAL_KINDS: [(&str, &str); 6] = [
+    ("admin", "管理者パスワード"),
+    ("pos", "POS連携APIのキー"),
+    ("metrics", "メトリクスのトークン"),
+    ("reservations", "予約サービスの Webhook のキー"),
+    ("calendar", "予約カレンダー (iCal) のキー"),
+    ("events", "イベント取得APIのキー"),
+];
+// 管理画面から設定する管理者パスワードの最低の長さ
+const MIN_ADMIN_PASSWORD_CHARS: usize = 12;
+// 古い値を受け付け続ける期間の上限 (時間)
+const CREDENTIAL_OVERLAP_MAX_HOURS: i64 = 168;
+
+#[derive(Clone, FromRow)]
+struct Credential {
+    id: i32,
+    kind: String,
+    label: String,
+    secret_hash: String,
+    created_at: DateTime<Utc>,
+    retire_at: Option<DateTime<Utc>>,
+}
+
+impl Credential {
+    fn is_active(&self, now: DateTime<Utc>) -> bool {
+        !matches!(self.retire_at, Some(at) if at <= now)
+    }
+}
+
+#[derive(Clone)]
+struct Credentials {
+    key: Vec<u8>,
+    config: HashMap<&'static str, String>, // 種類ごとの Secrets の値のハッシュ (未設定の種類は入れない)
+    added: Arc<Mutex<Vec<Credential>>>,    // DBの行 (変更した時と、1分おきに読み直す)
+}
+
+impl Credentials {
+    // secrets: 種類ごとの Secrets の値 (未設定なら None)
+    fn new(key: &[u8], secrets: &[(&'static str, Option<&str>)]) -> Self {
+        let mut config = HashMap::new();
+        for &(kind, secret) in secrets {
+            if let Some(secret) = secret {
+                config.insert(kind, credential_hash(key, kind, secret));
+            }
+        }
+        Credentials { key: key.to_vec(), config, added: Arc::default() }
+    }
+
+    // 一致する値があり、その期限が来ていなければ受け付ける
+    // (Secrets の値も、期限をつけた行があればその期限に従う)
+    fn accepts(&self, kind: &str, secret: &str) -> bool {
+        let hash = credential_hash(&self.key, kind, secret);
+        let added = self.added.lock().expect("credentials lock poisoned");
+        match added.iter().find(|c| c.kind == kind && constant_time_eq(c.secret_hash.as_bytes(), hash.as_bytes())) {
+            Some(credential) => credential.is_active(Utc::now()),
+            None => self.config.get(kind).is_some_and(|h| constant_time_eq(h.as_bytes(), hash.as_bytes())),
+        }
+    }
+
+    fn config_hash(&self, kind: &str) -> Option<&str> {
+        self.config.get(kind).map(String::as_str)
+    }
+
+    fn snapshot(&self) -> Vec<Credential> {
+        self.added.lock().expect("credentials lock poisoned").clone()
+    }
+
+    async fn reload(&self, pool: &PgPool) {
+        let rows = sqlx::query_as::<_, Credential>(
+            "SELECT id, kind, label, secret_hash, created_at, retire_at FROM credentials ORDER BY created_at ASC"
+        )
+        .fetch_all(pool)
+        .await;
+        match rows {
+            Ok(rows) => *self.added.lock().expect("credentials lock poisoned") = rows,
+            // 読めなかった時は前の内容のまま使う
+            Err(e) => tracing::error!("Failed to load credentials: {}", e),
+        }
+    }
+}
+
+fn credential_hash(key: &[u8], kind: &str, secret: &str) -> String {
+    hmac_hex(key, &format!("credential:{}:{}", kind, secret))
+}
+
+// 他のサーバーで追加・期限設定した認証情報を取り込む (サーバーごとに動かす)
+async fn credentials_worker(state: AppState) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        state.credentials.reload(&state.pool).await;
+    }
+}
+
+// --- セキュリティ関連のレスポンスヘッダー ---
+// 既定のCSP。テンプレートはインラインの <style>/<script> と unpkg の htmx を使っている
+// (デポジットの支払いでは Stripe Checkout へフォームから移動する)
+const DEFAULT_CSP: &str = "default-src 'self'; script-src 'self' 'unsafe-inline' https://unpkg.com; \
+    style-src 'self' 'unsafe-inline'; img-src 'self' data:; media-src 'self' https:; connect-src 'self'; \
+    base-uri 'self'; form-action 'self' https://checkout.stripe.com";
+
+// 他のサイトに iframe で埋め込んでよい画面
+const EMBEDDABLE_PATHS: [&str; 2] = ["/display", "/widget"];
+
+#[derive(Clone)]
+struct SecurityHeaders {
+    csp: String,            // frame-ancestors 以外の Content-Security-Policy
+    embed_origins: String,  // 埋め込みを許す画面の frame-ancestors
+    referrer_policy: String,
+    hsts_max_age: u64,      // 0 なら Strict-Transport-Security を送らない
+}
+
+impl SecurityHeaders {
+    fn from_lookup(lookup: &dyn Fn(&str) -> Option<String>) -> Self {
+        let get = |key: &str| lookup(key).map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+        SecurityHeaders {
+            csp: get("CONTENT_SECURITY_POLICY").unwrap_or_else(|| DEFAULT_CSP.to_string()),
+            embed_origins: get("EMBED_ORIGINS").unwrap_or_else(|| "*".to_string()),
+            // 来場者画面のURLには署名が入るので、既定では他のサイトへ送らない
+            referrer_policy: get("REFERRER_POLICY").unwrap_or_else(|| "same-origin".to_string()),
+            // http で公開している時に送ると、ブラウザが以後 https でしか開かなくなるので送らない
+            hsts_max_age: match get("BASE_URL") {
+                Some(base_url) if base_url.starts_with("https://") => get("HSTS_MAX_AGE").and_then(|v| v.parse().ok()).unwrap_or(31_536_000),
+                _ => 0,
+            },
+        }
+    }
+}
+
+// 自動更新の取得とリアルタイム通知の接続を数える (それ以外のリクエストはそのまま通す)
+// 圧縮より内側に置くので、本文の大きさは圧縮前の値になる
+async fn polling_metrics(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some((endpoint, guest)) = polling_endpoint(req.uri().path()) else {
+        return next.run(req).await;
+    };
+    let res = next.run(req).await;
+    let bytes = axum::body::HttpBody::size_hint(res.body()).exact().unwrap_or(0);
+    state.metrics.observe_polling(endpoint, guest, res.status(), bytes);
+    res
+}
+
+// 検索エンジンに載せないパス (お客様の整理券のリンクや管理画面)
+const ROBOTS_DISALLOWED_PATHS: [&str; 2] = ["/guest/", "/admin/"];
+
+fn site_file(path: impl AsRef<std::path::Path>) -> SetResponseHeader<ServeFile, axum::http::HeaderValue> {
+    SetResponseHeader::overriding(
+        ServeFile::new(path),
+        axum::http::header::CACHE_CONTROL,
+        axum::http::HeaderValue::from_static("public, max-age=86400"),
+    )
+}
+
+async fn robots_txt() -> impl IntoResponse {
+    let mut body = String::from("User-agent: *\n");
+    for path in ROBOTS_DISALLOWED_PATHS {
+        body.push_str(&format!("Disallow: {}\n", url(path)));
+    }
+    (
+        [(CONTENT_TYPE, "text/plain; charset=utf-8"), (axum::http::header::CACHE_CONTROL, "public, max-age=86400")],
+        body,
+    )
+}
+
+async fn security_headers(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let embeddable = EMBEDDABLE_PATHS.iter().any(|path| req.uri().path().starts_with(path));
+    let robots_disallowed = ROBOTS_DISALLOWED_PATHS.iter().any(|path| req.uri().path().starts_with(path));
+    let config = &state.security_headers;
+    let mut res = next.run(req).await;
+
+    let frame_ancestors = if embeddable { config.embed_origins.as_str() } else { "'none'" };
+    let mut values = vec![
+        ("content-security-policy", format!("{}; frame-ancestors {}", config.csp.trim_end_matches(';'), frame_ancestors)),
+        ("x-content-type-options", "nosniff".to_string()),
+        ("referrer-policy", config.referrer_policy.clone()),
+    ];
+    if !embeddable {
+        // 古いブラウザ向け (新しいブラウザは frame-ancestors を見る)
+        values.push(("x-frame-options", "DENY".to_string()));
+    }
+    if robots_disallowed {
+        // robots.txt だけでは、他のページからリンクされたURLが検索結果に出ることがあるので、ページ自体にも付ける
+        values.push(("x-robots-tag", "noindex, nofollow".to_string()));
+    }
+    if config.hsts_max_age > 0 {
+        values.push(("strict-transport-security", format!("max-age={}; includeSubDomains", config.hsts_max_age)));
+    }
+
+    // ハンドラーが個別に設定したものは上書きしない
+    let headers = res.headers_mut();
+    for (name, value) in values {
+        if let Ok(value) = axum::http::HeaderValue::from_str(&value) {
+            headers.entry(name).or_insert(value);
+        }
+    }
+    res
+}
+
+// --- 整理券のフック ---
+// 発券・呼び出し・完了・取り消しの時に、書き込んだ台で1回だけ呼ばれる
+// 組み込む側は Config::with_hook で登録して、社内システムへの送信などの処理を足せる
+// ハンドラーの中から呼ぶので、時間のかかる処理は tokio::spawn で別に動かすこと
+pub trait TicketHook: Send + Sync {
+    fn on_created(&self, _ticket: &HookTicket) {}
+    fn on_called(&self, _ticket: &HookTicket) {}
+    fn on_completed(&self, _ticket: &HookTicket) {}
+    // 合流された側や、呼び出しても来なかった整理券
+    fn on_cancelled(&self, _ticket: &HookTicket) {}
+    // 管理画面からの全データのリセット
+    fn on_reset(&self) {}
+}
+
+// フックに渡す整理券の情報
+#[derive(Clone, Debug, Serialize)]
+pub struct HookTicket {
+    pub id: Uuid,
+    pub number: i32,
+    pub group_size: i32,
+    pub queue_id: i32,
+    pub remote: bool,
+    pub table_id: Option<Uuid>,
+    pub is_test: bool, // 練習モード中に発券した整理券 (外部への通知は不要なことが多い)
+}
+
+impl From<&Ticket> for HookTicket {
+    fn from(ticket: &Ticket) -> Self {
+        HookTicket {
+            id: ticket.id,
+            number: ticket.number,
+            group_size: ticket.group_size,
+            queue_id: ticket.queue_id,
+            remote: ticket.remote,
+            table_id: ticket.table_id,
+            is_test: ticket.is_test,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum TicketLifecycle {
+    Created,
+    Called,
+    Completed,
+    Cancelled,
+}
+
+impl AppState {
+    // アプリ内のパスを、組み込み先でのパスにする
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_path, path)
+    }
+
+    fn run_hooks(&self, lifecycle: TicketLifecycle, ticket: &Ticket) {
+        let ticket = HookTicket::from(ticket);
+        for hook in self.hooks.iter() {
+            match lifecycle {
+                TicketLifecycle::Created => hook.on_created(&ticket),
+                TicketLifecycle::Called => hook.on_called(&ticket),
+                TicketLifecycle::Completed => hook.on_completed(&ticket),
+                TicketLifecycle::Cancelled => hook.on_cancelled(&ticket),
+            }
+        }
+    }
+}
+
+// --- MQTT (LED番号表示器など、ブラウザを持たない機器への配信) ---
+struct MqttConfig {
+    host: String,
+    port: u16,
+    username: Option<String>,
+    password: Option<String>,
+    topic: String, // 呼び出し中の番号を送るトピック
+    tls: bool,
+}
+
+// 呼び出した番号を retain つきで送り、後から接続した機器にも今の番号が届くようにする
+// (リセット時は空のメッセージで消す)
+struct MqttHook {
+    client: AsyncClient,
+    topic: String,
+}
+
+impl MqttHook {
+    fn connect(config: MqttConfig) -> Self {
+        let client_id = format!("queueticket-{}", Uuid::new_v4().simple());
+        let mut options = MqttOptions::new(client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+        if config.tls {
+            options.set_transport(Transport::tls_with_default_config());
+        }
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+        // 接続の維持。切れた時はしばらく待ってから poll し直すと再接続される
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = eventloop.poll().await {
+                    tracing::warn!("MQTT connection error: {}", e);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        });
+
+        MqttHook { client, topic: config.topic }
+    }
+
+    // 切断中に溜まりすぎた分は捨てる (古い番号を後から表示しても意味がない)
+    fn publish(&self, payload: String) {
+        if let Err(e) = self.client.try_publish(&self.topic, QoS::AtLeastOnce, true, payload) {
+            tracing::warn!("Failed to publish MQTT message: {}", e);
+        }
+    }
+}
+
+impl TicketHook for MqttHook {
+    fn on_called(&self, ticket: &HookTicket) {
+        self.publish(ticket.number.to_string());
+    }
+
+    fn on_reset(&self) {
+        self.publish(String::new());
+    }
+}
+
+// --- 整理券のWebhook (POSなど外部システムへの通知) ---
+// 送る内容は先にDBへ記録してから送り、失敗したものは webhook_worker が間隔を延ばしながら送り直す
+// 送信が黙って抜けると外部システムとずれるので、試行ごとの応答コードと所要時間も残す
+struct WebhookConfig {
+    url: String,
+    secret: Option<String>, // 設定すると本文の HMAC-SHA256 を X-QueueTicket-Signature につける
+}
+
+// 送り直しの回数の上限 (間隔は30秒から倍ずつ延ばす。8回目でおよそ1時間後)
+const WEBHOOK_MAX_ATTEMPTS: i32 = 8;
+const WEBHOOK_RETRY_BASE_SECONDS: i64 = 30;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn webhook_retry_delay(attempts: i32) -> i64 {
+    WEBHOOK_RETRY_BASE_SECONDS << (attempts - 1).clamp(0, 10)
+}
+
+struct WebhookHook {
+    pool: PgPool,
+    http: reqwest::Client,
+    config: Arc<WebhookConfig>,
+}
+
+impl WebhookHook {
+    // 記録してすぐに1回目を送る (ハンドラーを待たせないよう別のタスクで)
+    fn enqueue(&self, event: &'static str, ticket: Option<&HookTicket>) {
+        let (pool, http, config) = (self.pool.clone(), self.http.clone(), self.config.clone());
+        let ticket = ticket.cloned();
+        tokio::spawn(async move {
+            let id = Uuid::new_v4();
+            let payload = serde_json::json!({
+                "id": id,
+                "event": event,
+                "occurred_at": Utc::now(),
+                "ticket": ticket,
+            });
+            sqlx::query("INSERT INTO webhook_deliveries (id, event, ticket_id, payload) VALUES ($1, $2, $3, $4)")
+                .bind(id)
+                .bind(event)
+                .bind(ticket.as_ref().map(|t| t.id))
+                .bind(payload.to_string())
+                .execute(&pool)
+                .await
+                .expect("Failed to record webhook delivery");
+            deliver_webhook(&pool, &http, &config, id).await;
+        });
+    }
+}
+
+impl TicketHook for WebhookHook {
+    fn on_created(&self, ticket: &HookTicket) {
+        self.enqueue("ticket.created", Some(ticket));
+    }
+
+    fn on_called(&self, ticket: &HookTicket) {
+        self.enqueue("ticket.called", Some(ticket));
+    }
+
+    fn on_completed(&self, ticket: &HookTicket) {
+        self.enqueue("ticket.completed", Some(ticket));
+    }
+
+    fn on_cancelled(&self, ticket: &HookTicket) {
+        self.enqueue("ticket.cancelled", Some(ticket));
+    }
+
+    fn on_reset(&self) {
+        self.enqueue("reset", None);
+    }
+}
+
+// 送信待ちで時刻の来た1件を送る。複数台で同じものを送らないよう、先に次の時刻を進めてから送る
+async fn deliver_webhook(pool: &PgPool, http: &reqwest::Client, config: &WebhookConfig, id: Uuid) {
+    let claimed: Option<(String, i32)> = sqlx::query_as(
+        "UPDATE webhook_deliveries SET attempts = attempts + 1, next_attempt_at = NOW() + INTERVAL '1 minute' 
+         WHERE id = $1 AND status = 'pending' AND next_attempt_at <= NOW() 
+         RETURNING payload, attempts"
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+    .unwrap_or(None);
+    let Some((payload, attempts)) = claimed else {
+        return;
+    };
+
+    let mut request = http
+        .post(&config.url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-QueueTicket-Delivery", id.to_string());
+    if let Some(secret) = &config.secret {
+        request = request.header("X-QueueTicket-Signature", format!("sha256={}", hmac_hex(secret.as_bytes(), &payload)));
+    }
+    let started = Instant::now();
+    let result = request.body(payload).send().await;
+    let latency_ms = started.elapsed().as_millis() as i32;
+    let (status_code, error) = match &result {
+        Ok(res) if res.status().is_success() => (Some(res.status().as_u16() as i32), None),
+        Ok(res) => (Some(res.status().as_u16() as i32), Some(format!("HTTP {}", res.status()))),
+        Err(e) => (None, Some(e.to_string())),
+    };
+
+    sqlx::query("INSERT INTO webhook_attempts (delivery_id, status_code, latency_ms, error) VALUES ($1, $2, $3, $4)")
+        .bind(id)
+        .bind(status_code)
+        .bind(latency_ms)
+        .bind(&error)
+        .execute(pool)
+        .await
+        .expect("Failed to record webhook attempt");
+
+    if error.is_none() {
+        sqlx::query("UPDATE webhook_deliveries SET status = 'delivered', delivered_at = NOW() WHERE id = $1")
+            .bind(id)
+            .execute(pool)
+            .await
+            .expect("Failed to update webhook delivery");
+    } else if attempts >= WEBHOOK_MAX_ATTEMPTS {
+        sqlx::query("UPDATE webhook_deliveries SET status = 'failed' WHERE id = $1")
+            .bind(id)
+            .execute(pool)
+            .await
+            .expect("Failed to update webhook delivery");
+        tracing::warn!("Webhook delivery {} failed after {} attempts", id, attempts);
+        notify_staff(pool, "webhook_failed", "外部システムへのWebhookの送信に失敗したものがあります。管理メニューの「Webhookの送信記録」から送り直してください。", None).await;
+    } else {
+        sqlx::query("UPDATE webhook_deliveries SET next_attempt_at = NOW() + make_interval(secs => $2) WHERE id = $1")
+            .bind(id)
+            .bind(webhook_retry_delay(attempts) as f64)
+            .execute(pool)
+            .await
+            .expect("Failed to update webhook delivery");
+    }
+}
+
+// 送り直しの時刻が来たものを、発生した順に送る
+async fn webhook_worker(state: AppState, config: Arc<WebhookConfig>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(15));
+    loop {
+        interval.tick().await;
+        let due: Vec<Uuid> = sqlx::query_scalar(
+            "SELECT id FROM webhook_deliveries WHERE status = 'pending' AND next_attempt_at <= NOW() 
+             ORDER BY created_at ASC LIMIT 50"
+        )
+        .fetch_all(&state.pool)
+        .await
+        .unwrap_or(vec![]);
+        for id in due {
+            deliver_webhook(&state.pool, &state.http, &config, id).await;
+        }
+    }
+}
+
+// resolve_client_ip が付け直す、リクエスト元のIPアドレスのヘッダー (送信元が付けてきた値は消す)
+const CLIENT_IP_HEADER: &str = "x-queueticket-client-ip";
+
+// リクエスト元のIPアドレス (レート制限・セッションの記録用)
+fn client_ip(headers: &HeaderMap) -> String {
+    headers
+        .get(CLIENT_IP_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+// X-Forwarded-For の左側は送信元が自由に書けるので、信頼できるプロキシが付け足した右側だけを見る
+// (TRUSTED_PROXY_HOPS 段なら右から数えてその段数目。プロキシがなければ接続元のアドレス)
+async fn resolve_client_ip(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let peer = req.extensions().get::<ConnectInfo<std::net::SocketAddr>>().map(|ConnectInfo(addr)| addr.ip().to_string());
+    let ip = forwarded_client_ip(req.headers(), state.trusted_proxy_hops).or(peer);
+    let headers = req.headers_mut();
+    headers.remove(CLIENT_IP_HEADER);
+    if let Some(value) = ip.and_then(|ip| axum::http::HeaderValue::from_str(&ip).ok()) {
+        headers.insert(CLIENT_IP_HEADER, value);
+    }
+    next.run(req).await
+}
+
+// 画面内のリンクはテンプレートから base_path() で読むので、このアプリの組み込み先のパスをリクエストの間だけ渡す
+// (1つのプロセスで、別々のパスに組み込んだ複数のアプリを動かせる)
+async fn scope_base_path(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    BASE_PATH.scope(state.base_path.clone(), next.run(req)).await
+}
+
+fn forwarded_client_ip(headers: &HeaderMap, hops: usize) -> Option<String> {
+    if hops == 0 {
+        return None;
+    }
+    let forwarded: Vec<&str> = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect();
+    forwarded.len().checked_sub(hops).map(|i| forwarded[i].to_string())
+}
+
+// 画面へリアルタイムに通知するイベント (SSEで配信)
+// tickets / tables のトリガーが NOTIFY した payload をそのまま読む
+#[derive(Clone, Serialize, Deserialize)]
+struct LiveEvent {
+    kind: String, // "created" / "called" / "completed" / "reverted" / "checked_in" / "transferred" / "split" / "merged" / "stage_changed" / "deleted" / "updated" / "table_updated" / "notification" / "reset" / "resync"
+    id: Option<Uuid>,
+    number: Option<i32>,
+}
+
+// トリガーが通知するチャネル (migrations/20261029_create_live_notify_triggers.sql)
+const LIVE_CHANNEL: &str = "queueticket_live";
+
+// DB の NOTIFY を受けて、この台の画面 (SSE)・MQTT などへ配る
+// どの台で書き換えても全台に届くので、複数台で動かしても画面の表示が揃う
+// (LISTEN 用に接続プールから1本を使い続ける)
+async fn live_event_hub(state: AppState) {
+    loop {
+        let mut listener = match PgListener::connect_with(&state.pool).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::warn!("Failed to connect live event listener: {}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+        if let Err(e) = listener.listen(LIVE_CHANNEL).await {
+            tracing::warn!("Failed to listen on {}: {}", LIVE_CHANNEL, e);
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            continue;
+        }
+
+        loop {
+            match listener.try_recv().await {
+                Ok(Some(notification)) => {
+                    let Ok(event) = serde_json::from_str::<LiveEvent>(notification.payload()) else {
+                        tracing::warn!("Unexpected live event payload: {}", notification.payload());
+                        continue;
+                    };
+                    // 他の台での書き込みでも、ここでスナップショットを捨てる (お知らせは待ち行列と関係ない)
+                    if event.kind != "notification" {
+                        state.snapshot.invalidate();
+                    }
+                    // 接続中の画面がなければ送信に失敗するが、問題ないので無視する
+                    let _ = state.live.send(event);
+                }
+                // 接続が切れた (次の try_recv で再接続される)
+                // その間の通知は取りこぼしているので、画面には全体を読み直させる
+                Ok(None) => {
+                    tracing::warn!("Live event listener disconnected; reconnecting");
+                    state.snapshot.invalidate();
+                    let _ = state.live.send(LiveEvent { kind: "resync".to_string(), id: None, number: None });
+                }
+                Err(e) => {
+                    tracing::warn!("Live event listener error: {}", e);
+                    break;
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+// --- 待ち行列のスナップショット ---
+// 来場者画面と表示用モニターは数秒おきにポーリングされるので、
+// 有効なチケットをメモリに持っておき、書き込みがあるまで (または一定時間) 使い回す
+const SNAPSHOT_MAX_AGE: Duration = Duration::from_secs(30);
+
+struct QueueSnapshot {
+    loaded_at: Instant,
+    tickets: Vec<Ticket>,                  // 有効なチケット (列ごとに呼び出し順)
+    positions: HashMap<Uuid, (usize, i64)>, // id → (tickets内の位置, 前に待っている組数)
+    called_counts: HashMap<(i32, bool), i64>, // (列, 練習用か) ごとの呼出中 (案内中) の組数
+    table_names: HashMap<Uuid, String>,
+    counter_names: HashMap<i32, String>,
+    counters: Vec<Counter>,
+    service_minutes: Option<f64>,          // 1組あたりの案内時間の見込み (運用設定の見込み方で計算。実績がなければ None)
+    service_slope: f64,                    // 人数が1名増えるごとに延びる案内時間 (分。「人数ごと」の見込み方のときだけ)
+    mean_group_size: f64,                  // 案内時間の実績にした組の平均人数
+    call_times: HashMap<i32, Vec<DateTime<Utc>>>, // 列ごとの直近の呼び出し時刻 (古い順)
+    counter_calls: HashMap<i32, Vec<DateTime<Utc>>>, // 窓口ごとの直近の呼び出し時刻 (古い順)
+    now_serving: Vec<(i32, String, i32)>, // 列ごとの「ただいまご案内中の番号」(列, 列の名前, 番号)。今日進めたものだけ
+}
+
+// 呼び出しペースを測る期間 (分)
+const CALL_RATE_WINDOW_MINUTES: i64 = 60;
+// この時間内に呼び出しをした窓口を「開いている」とみなす (分)
+const ACTIVE_COUNTER_MINUTES: i64 = 15;
+// 人数ごとの見込みで、少人数の組の案内時間をこれより短く見込まない (分)
+const MIN_SERVICE_MINUTES: f64 = 1.0;
+
+impl QueueSnapshot {
+    async fn load(pool: &PgPool) -> Self {
+        let tickets = sqlx::query_as::<_, Ticket>(
+            "SELECT * FROM tickets WHERE status IN ('waiting', 'called') 
+             ORDER BY queue_id, priority DESC, created_at ASC, id ASC"
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap_or(vec![]);
+
+        let table_names: HashMap<Uuid, String> = sqlx::query_as::<_, (Uuid, String)>("SELECT id, name FROM tables")
+            .fetch_all(pool)
+            .await
+            .unwrap_or(vec![])
+            .into_iter()
+            .collect();
+
+        let counters = fetch_counters(pool).await;
+        let counter_names: HashMap<i32, String> = counters.iter().map(|c| (c.id, c.name.clone())).collect();
+
+        let settings = Settings::load(pool).await;
+
+        // 来店しなかった組 (呼び出してから「来店なし」で完了にした組) は案内していないので実績に入れない
+        // 平均・パーセンタイル・人数に対する傾きをまとめて計算し、運用設定の見込み方で使い分ける
+        let (average, percentile, slope, mean_group_size): (Option<f64>, Option<f64>, Option<f64>, Option<f64>) = sqlx::query_as(
+            "SELECT AVG(minutes)::float8, 
+                    (percentile_cont($2) WITHIN GROUP (ORDER BY minutes))::float8, 
+                    regr_slope(minutes, group_size)::float8, 
+                    AVG(group_size)::float8 
+             FROM (SELECT EXTRACT(EPOCH FROM (completed_at - called_at))::float8 / 60 AS minutes, group_size::float8 AS group_size 
+                   FROM tickets t 
+                   WHERE status = 'completed' AND called_at IS NOT NULL AND completed_at > NOW() - make_interval(days => $1) AND NOT is_test 
+                     AND NOT EXISTS (SELECT 1 FROM ticket_events e WHERE e.ticket_id = t.id AND e.event_type = 'no_show')) s"
+        )
+        .bind(settings.eta_window_days as i32)
+        .bind(settings.eta_percentile as f64 / 100.0)
+        .fetch_one(pool)
+        .await
+        .unwrap_or((None, None, None, None));
+        let (service_minutes, service_slope) = match settings.eta_algorithm.as_str() {
+            "percentile" => (percentile, 0.0),
+            "group_size" => (average, slope.unwrap_or(0.0)),
+            _ => (average, 0.0),
+        };
+
+        let mut call_times: HashMap<i32, Vec<DateTime<Utc>>> = HashMap::new();
+        let mut counter_calls: HashMap<i32, Vec<DateTime<Utc>>> = HashMap::new();
+        let calls = sqlx::query_as::<_, (i32, Option<i32>, DateTime<Utc>)>(
+            "SELECT queue_id, counter_id, called_at FROM tickets 
+             WHERE called_at > NOW() - make_interval(mins => $1) AND NOT is_test ORDER BY called_at"
+        )
+        .bind(CALL_RATE_WINDOW_MINUTES as i32)
+        .fetch_all(pool)
+        .await
+        .unwrap_or(vec![]);
+        for (queue_id, counter_id, called_at) in calls {
+            call_times.entry(queue_id).or_default().push(called_at);
+            if let Some(counter_id) = counter_id {
+                counter_calls.entry(counter_id).or_default().push(called_at);
+            }
+        }
+
+        // 前の日に進めた番号は出さない
+        let now_serving = sqlx::query_as::<_, (i32, String, i32)>(
+            "SELECT id, name, now_serving FROM queues WHERE now_serving IS NOT NULL AND now_serving_at >= $1 ORDER BY id"
+        )
+        .bind(settings.today_start(&Utc::now()))
+        .fetch_all(pool)
+        .await
+        .unwrap_or(vec![]);
+
+        // 同じ列で、自分より前にいる待機中の組を数える
+        // (練習用の整理券と本番の整理券は、お互いの待ち組数に含めない)
+        let mut positions = HashMap::new();
+        let mut called_counts = HashMap::new();
+        let mut waiting_ahead: HashMap<(i32, bool), i64> = HashMap::new();
+        for (i, ticket) in tickets.iter().enumerate() {
+            let key = (ticket.queue_id, ticket.is_test);
+            let ahead = waiting_ahead.entry(key).or_insert(0);
+            positions.insert(ticket.id, (i, *ahead));
+            if ticket.status == "waiting" {
+                *ahead += 1;
+            } else {
+                *called_counts.entry(key).or_insert(0) += 1;
+            }
+        }
+
+        QueueSnapshot {
+            loaded_at: Instant::now(),
+            tickets,
+            positions,
+            called_counts,
+            table_names,
+            counter_names,
+            counters,
+            service_minutes: service_minutes.filter(|m| *m > 0.0),
+            service_slope,
+            mean_group_size: mean_group_size.unwrap_or(1.0),
+            call_times,
+            counter_calls,
+            now_serving,
+        }
+    }
+
+    // 列の「ただいまご案内中の番号」(None なら列を問わず、最後に進めた列の番号)
+    fn now_serving(&self, queue_id: Option<i32>) -> Option<i32> {
+        match queue_id {
+            Some(queue_id) => self.now_serving.iter().find(|(id, ..)| *id == queue_id).map(|(_, _, number)| *number),
+            None => match self.now_serving.as_slice() {
+                [(_, _, number)] => Some(*number),
+                _ => None, // 複数の列があると、どの列の番号かわからない
+            },
+        }
+    }
+
+    // 本番の整理券だけ (待ち組数・待ち時間の見込みなど、お客様に見せる数字に使う)
+    fn real_tickets(&self) -> impl Iterator<Item = &Ticket> {
+        self.tickets.iter().filter(|t| !t.is_test)
+    }
+
+    // 直近に呼び出しをした (開いている) 窓口のうち、その列を担当しているもの (None なら全体)
+    fn active_counters(&self, queue_id: Option<i32>, now: DateTime<Utc>) -> Vec<&Counter> {
+        let since = now - chrono::Duration::minutes(ACTIVE_COUNTER_MINUTES);
+        self.counters
+            .iter()
+            .filter(|c| queue_id.is_none_or(|q| c.serves(q)))
+            .filter(|c| self.counter_calls.get(&c.id).and_then(|calls| calls.last()).is_some_and(|last| *last > since))
+            .collect()
+    }
+
+    // 同時に案内できる組数 (テーブル数と開いている窓口数の多い方。どちらもなければ1)
+    // 窓口を開けるとすぐに反映されるよう、過去の実績ではなく直近の呼び出しから数える
+    fn servers(&self, queue_id: Option<i32>) -> i64 {
+        let counters = self.active_counters(queue_id, Utc::now()).len() as i64;
+        (self.table_names.len() as i64).max(counters).max(1)
+    }
+
+    // 1組の案内時間の見込み (分)。「人数ごと」の見込み方では、平均の人数との差の分だけ延び縮みする
+    fn service_minutes_for(&self, group_size: i32) -> Option<f64> {
+        let base = self.service_minutes?;
+        Some((base + self.service_slope * (group_size as f64 - self.mean_group_size)).max(MIN_SERVICE_MINUTES))
+    }
+
+    // 並んでいる組がすべて案内を終えるまでの見込み (分)
+    fn wait_minutes<'a>(&self, groups: impl IntoIterator<Item = &'a Ticket>, queue_id: Option<i32>) -> Option<i64> {
+        self.service_minutes?; // 実績がなければ、並んでいる組がいなくても見込みは出さない
+        let mut total = 0.0;
+        for ticket in groups {
+            total += self.service_minutes_for(ticket.group_size)?;
+        }
+        Some((total / self.servers(queue_id) as f64).ceil() as i64)
+    }
+
+    // 開いている窓口ごとの呼び出し間隔から、列全体で1組呼ぶのにかかる時間 (分) を見込む
+    // (普段のペース, 最後の呼び出しから間が空いている分を含めた遅い側のペース)
+    // 開いたばかりで実績の少ない窓口は、他の窓口の平均 (なければ過去の平均案内時間) で呼ぶものとする
+    fn counter_pace(&self, queue_id: i32, now: DateTime<Utc>) -> Option<(f64, f64)> {
+        let active = self.active_counters(Some(queue_id), now);
+        if active.is_empty() {
+            return None;
+        }
+
+        let mut known = Vec::new();
+        for counter in &active {
+            let calls = &self.counter_calls[&counter.id];
+            if calls.len() >= 2 {
+                let span = (calls[calls.len() - 1] - calls[0]).num_seconds() as f64 / 60.0;
+                let since_last = (now - calls[calls.len() - 1]).num_seconds() as f64 / 60.0;
+                let pace = (span / (calls.len() - 1) as f64).max(0.5);
+                known.push((pace, pace.max(since_last)));
+            }
+        }
+        let default = if known.is_empty() {
+            self.service_minutes?
+        } else {
+            known.iter().map(|(pace, _)| pace).sum::<f64>() / known.len() as f64
+        };
+
+        // 窓口ごとの1分あたりの呼び出し数を足し合わせる
+        let unknown = (active.len() - known.len()) as f64;
+        let rate = known.iter().map(|(pace, _)| 1.0 / pace).sum::<f64>() + unknown / default;
+        let slow_rate = known.iter().map(|(_, slow)| 1.0 / slow).sum::<f64>() + unknown / default;
+        Some((1.0 / rate, 1.0 / slow_rate))
+    }
+
+    // 前に ahead 組いる場合に呼ばれる時刻の幅 (早い見込み, 遅い見込み, ペースが落ちているか)
+    // 直近の呼び出し間隔から計算し、最後の呼び出しから間が空いているほど遅い側に幅を広げる
+    fn call_window(&self, queue_id: i32, ahead: i64, now: DateTime<Utc>) -> Option<(DateTime<Utc>, DateTime<Utc>, bool)> {
+        let turns = (ahead + 1) as f64;
+        let after = |minutes: f64| now + chrono::Duration::seconds((minutes * 60.0) as i64);
+
+        // 窓口から呼び出している列は、いま開いている窓口の数とそれぞれのペースで見込む
+        // (2つ目の窓口を開けると、1時間分の実績を待たずに見込みが縮む)
+        if let Some((fast, slow)) = self.counter_pace(queue_id, now) {
+            return Some((after(turns * fast), after(turns * slow), slow > fast * 1.5));
+        }
+
+        let calls = self.call_times.get(&queue_id).map(Vec::as_slice).unwrap_or(&[]);
+        if calls.len() < 3 {
+            // 呼び出しの実績が少ない間は、過去の案内時間から広めに見込む (前の組と自分の人数で数える)
+            let groups = self
+                .real_tickets()
+                .filter(|t| t.queue_id == queue_id && t.status == "waiting")
+                .take((ahead + 1) as usize);
+            let minutes = self.wait_minutes(groups, Some(queue_id))? as f64;
+            return Some((after(minutes * 0.5), after(minutes * 1.5), false));
+        }
+
+        let pace = |calls: &[DateTime<Utc>]| {
+            let span = (calls[calls.len() - 1] - calls[0]).num_seconds() as f64 / 60.0;
+            span / (calls.len() - 1) as f64
+        };
+        let overall = pace(calls);
+        let recent = pace(&calls[calls.len() / 2..]); // 後半だけのペース
+        let since_last = (now - calls[calls.len() - 1]).num_seconds() as f64 / 60.0;
+
+        let fast = overall.min(recent).max(0.5);
+        let slow = overall.max(recent).max(since_last).max(fast);
+        Some((after(turns * fast), after(turns * slow), slow > fast * 1.5))
+    }
+
+    fn guest_view(&self, id: Uuid) -> Option<GuestView> {
+        let (i, waiting_count) = *self.positions.get(&id)?;
+        let ticket = self.tickets[i].clone();
+        let called_count = self.called_counts.get(&(ticket.queue_id, ticket.is_test)).copied().unwrap_or(0);
+        let table_name = ticket.table_id.and_then(|t| self.table_names.get(&t).cloned());
+        let counter_name = ticket.counter_id.and_then(|c| self.counter_names.get(&c).cloned());
+        Some(GuestView { ticket, waiting_count, called_count, table_name, counter_name })
+    }
+}
+
+#[derive(Default)]
+struct SnapshotSlot {
+    generation: u64, // 捨てるたびに増やし、読み込み中に書き込みがあった結果を保存しないようにする
+    snapshot: Option<Arc<QueueSnapshot>>,
+}
+
+#[derive(Clone, Default)]
+struct SnapshotCache {
+    slot: Arc<Mutex<SnapshotSlot>>,
+    loading: Arc<tokio::sync::Mutex<()>>, // 同時に何人もDBから読み直さないようにする
+}
+
+impl SnapshotCache {
+    fn cached(&self) -> Result<Arc<QueueSnapshot>, u64> {
+        let slot = self.slot.lock().expect("snapshot lock poisoned");
+        match &slot.snapshot {
+            Some(snapshot) if snapshot.loaded_at.elapsed() < SNAPSHOT_MAX_AGE => Ok(snapshot.clone()),
+            _ => Err(slot.generation),
+        }
+    }
+
+    async fn get(&self, pool: &PgPool) -> Arc<QueueSnapshot> {
+        if let Ok(snapshot) = self.cached() {
+            return snapshot;
+        }
+
+        let _loading = self.loading.lock().await;
+        // 待っている間に他のリクエストが読み込んでいれば、それを使う
+        let generation = match self.cached() {
+            Ok(snapshot) => return snapshot,
+            Err(generation) => generation,
+        };
+
+        let snapshot = Arc::new(QueueSnapshot::load(pool).await);
+        let mut slot = self.slot.lock().expect("snapshot lock poisoned");
+        if slot.generation == generation {
+            slot.snapshot = Some(snapshot.clone());
+        }
+        snapshot
+    }
+
+    // 書き込んだ台ではすぐに捨てる (他の台では live_event_hub が通知を受けて捨てる)
+    fn invalidate(&self) {
+        let mut slot = self.slot.lock().expect("snapshot lock poisoned");
+        slot.generation += 1;
+        slot.snapshot = None;
+    }
+}
+
+// 1端末で複数の有効な整理券を取ろうとした時の扱い
+#[derive(Clone, Copy, PartialEq)]
+enum DeviceGuardMode {
+    Off,   // チェックしない
+    Warn,  // 警告を表示するが、そのまま表示する
+    Block, // 2枚目の整理券画面を表示しない
+}
+
+impl DeviceGuardMode {
+    fn from_setting(value: Option<String>) -> Self {
+        match value.as_deref() {
+            Some("off") => DeviceGuardMode::Off,
+            Some("block") => DeviceGuardMode::Block,
+            _ => DeviceGuardMode::Warn, // 未設定・不明な値は警告のみ
+        }
+    }
+}
+
+#[derive(FromRow, Clone)]
+struct Ticket {
+    id: Uuid,
+    number: i32,
+    group_size: i32,
+    status: String,
+    // created_at はDB定義にはあるがStructになかったので追加（分析に必須）
+    created_at: DateTime<Utc>, 
+    // 追加: NULLの可能性があるので Option で包む
+    called_at: Option<DateTime<Utc>>, 
+    completed_at: Option<DateTime<Utc>>, 
+    priority: i32, // 追加: 大きいほど先に案内する (予約からの発券は1)
+    remote: bool,  // 追加: リモート受付で発券したか
+    checked_in_at: Option<DateTime<Utc>>, // 追加: リモート受付の到着確認の時刻
+    table_id: Option<Uuid>, // 追加: 呼び出し時に案内したテーブル
+    queue_id: i32,          // 追加: 並んでいる列
+    link_issued_at: DateTime<Utc>, // 追加: 来場者画面のリンクの発行時刻 (署名と有効期限に使う)
+    stage: Option<String>,  // 追加: 店舗で定義した独自の状態 (例: 準備中)。status を変えると自動で消える
+    counter_id: Option<i32>, // 追加: 呼び出した窓口
+    needs: Vec<String>,      // 追加: 配慮が必要なこと (NEEDS のキー)
+    device_id: Option<i32>,  // 追加: 発券した発券機
+    is_test: bool,           // 追加: 練習モード中に発券した (集計に含めず、翌日に削除する)
+    tags: Vec<String>,       // 追加: スタッフが自由につけるタグ (parse_tags で整えたもの)
+    note: Option<String>,    // 追加: スタッフのメモ
+    number_prefix: String,   // 追加: 番号の頭文字 (発券した列の設定。なければ空)
+    deleted_at: Option<DateTime<Utc>>, // 追加: ゴミ箱に入れた時刻 (status は 'deleted')
+    custom_fields: sqlx::types::Json<serde_json::Map<String, serde_json::Value>>, // 追加: 店舗で定義した追加の項目の値
+}
+
+// 発券時に選べる、配慮が必要なこと (キー, アイコン, 表示名, 優先案内の対象か)
+const NEEDS: [(&str, &str, &str, bool); 4] = [
+    ("wheelchair", "♿", "車椅子", true),
+    ("stroller", "👶", "ベビーカー", false),
+    ("elderly", "🧓", "ご高齢", true),
+    ("interpreter", "🌐", "通訳", false),
+];
+
+// 呼び出し管理画面のカードに出せる項目 (キー, 表示名)。運用設定で選んだ順に表示する
+const CALL_COLUMNS: [(&str, &str); 7] = [
+    ("name", "予約者名"),
+    ("note", "メモ"),
+    ("queue", "列"),
+    ("size", "人数"),
+    ("wait", "経過時間"),
+    ("tags", "タグ"),
+    ("counter", "窓口"),
+];
+const MAX_NOTE_CHARS: usize = 200;
+
+// 1枚の整理券につけられるタグの数と、1つのタグの長さ (文字数) の上限
+const MAX_TAGS: usize = 10;
+const MAX_TAG_CHARS: usize = 20;
+
+// タグの入力 (空白・カンマ区切り。先頭の # は省く) を整える
+// 英字は小文字にそろえ、同じタグは1つにまとめる
+fn parse_tags(input: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for word in input.split(|c: char| c.is_whitespace() || matches!(c, ',' | '、' | '，')) {
+        let tag: String = word.trim_start_matches(['#', '＃']).to_lowercase().chars().take(MAX_TAG_CHARS).collect();
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+        if tags.len() == MAX_TAGS {
+            break;
+        }
+    }
+    tags
+}
+
+// 列 (例: 店内飲食・テイクアウト)
+#[derive(FromRow, Clone)]
+struct Queue {
+    id: i32,
+    name: String,
+    color: String, // 列の色 ("#rrggbb"。空なら付けない)
+    icon: String,  // 列のアイコン (空なら付けない)
+}
+
+// 列のアイコンの長さ (文字数) の上限。絵文字1つ (国旗などの2文字のものも) を想定する
+const MAX_QUEUE_ICON_CHARS: usize = 2;
+
+// "#rrggbb" の形の色なら小文字にして返す
+fn parse_hex_color(value: &str) -> Option<String> {
+    let ok = value.len() == 7 && value.starts_with('#') && value[1..].chars().all(|c| c.is_ascii_hexdigit());
+    ok.then(|| value.to_ascii_lowercase())
+}
+
+// 最初から用意されている列 (列を作らない店舗ではすべてのチケットがここに入る)
+const DEFAULT_QUEUE_ID: i32 = 1;
+
+async fn fetch_queues(pool: &PgPool) -> Vec<Queue> {
+    sqlx::query_as::<_, Queue>("SELECT id, name, color, icon FROM queues ORDER BY id ASC")
+        .fetch_all(pool)
+        .await
+        .unwrap_or(vec![])
+}
+
+// 受付窓口 (担当する列が空ならすべての列を扱う)
+#[derive(FromRow, Clone)]
+struct Counter {
+    id: i32,
+    name: String,
+    queue_ids: Vec<i32>,
+}
+
+impl Counter {
+    fn serves(&self, queue_id: i32) -> bool {
+        self.queue_ids.is_empty() || self.queue_ids.contains(&queue_id)
+    }
+}
+
+// 店頭の端末 (発券機・表示用モニター・プリンター)
+#[derive(FromRow, Clone)]
+struct Device {
+    id: i32,
+    name: String,
+    kind: String, // "kiosk" / "display" / "printer"
+    last_seen_at: Option<DateTime<Utc>>,
+    last_status: Option<String>,
+    queue_ids: Vec<i32>,   // 発券できる列 (空ならすべての列)
+    group_sizes: Vec<i32>, // 人数のボタン (空なら下限から4つ)
+    language: String,      // 画面の言語 (DEVICE_LANGUAGES のキー)
+    auto_print: bool,      // 発券したら自動で整理券を印刷する
+    allow_unsigned: bool,  // 署名の仕組みより前に登録した端末 (トークンを発行し直すまでは署名なしでも受け付ける)
+}
+
+const DEVICE_KINDS: [(&str, &str); 3] = [("kiosk", "発券機"), ("display", "表示用モニター"), ("printer", "プリンター")];
+
+const DEVICE_LANGUAGES: [(&str, &str); 4] = [("ja", "日本語"), ("en", "English"), ("zh", "中文"), ("ko", "한국어")];
+
+// この時間ハートビートがなければ応答なしとみなす (端末は1分おきに送る)
+const DEVICE_SILENT_MINUTES: i64 = 5;
+
+impl Device {
+    fn kind_label(&self) -> &'static str {
+        DEVICE_KINDS.iter().find(|(k, _)| *k == self.kind).map_or("端末", |(_, label)| label)
+    }
+
+    // リクエストに署名が必要か
+    // 表示用モニターはブラウザで動き、HTTP では整理券を動かさないので求めない。gRPC はブラウザから使えず、
+    // 番号表示器が呼び出し・完了をするので、表示用モニターにも求める (盗まれたトークンで送り直されないように)
+    fn signs_requests(&self, grpc: bool) -> bool {
+        !self.allow_unsigned && (grpc || self.kind != "display")
+    }
+
+    fn is_online(&self) -> bool {
+        self.last_seen_at.is_some_and(|at| at > Utc::now() - chrono::Duration::minutes(DEVICE_SILENT_MINUTES))
+    }
+
+    fn serves(&self, queue_id: i32) -> bool {
+        self.queue_ids.is_empty() || self.queue_ids.contains(&queue_id)
+    }
+
+    // 設定画面のチェックボックス用 (空の場合はどれにも印をつけない)
+    fn has_queue(&self, queue_id: i32) -> bool {
+        self.queue_ids.contains(&queue_id)
+    }
+
+    fn language_is(&self, language: &str) -> bool {
+        self.language == language
+    }
+
+    // 人数のボタン (未設定なら、運用設定の下限から4つ並べる)
+    fn group_size_buttons(&self, settings: &Settings) -> Vec<i32> {
+        if !self.group_sizes.is_empty() {
+            return self.group_sizes.clone();
+        }
+        (settings.min_group_size..=settings.max_group_size).take(4).collect()
+    }
+
+    fn group_sizes_value(&self) -> String {
+        self.group_sizes.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(", ")
+    }
+}
+
+async fn fetch_counters(pool: &PgPool) -> Vec<Counter> {
+    sqlx::query_as::<_, Counter>("SELECT id, name, queue_ids FROM counters ORDER BY id ASC")
+        .fetch_all(pool)
+        .await
+        .unwrap_or(vec![])
+}
+
+// 客席 (テーブル)。空席の一覧にだけ使うので、状態は読まない (状態つきは TableRow)
+#[derive(FromRow, Clone)]
+struct Table {
+    id: Uuid,
+    name: String,
+    capacity: i32,
+}
+
+// テーブル管理画面用 (案内中のチケット番号つき)
+#[derive(FromRow)]
+struct TableRow {
+    id: Uuid,
+    name: String,
+    capacity: i32,
+    status: String,
+    ticket_number: Option<i32>,
+}
+
+// 予約
+#[derive(FromRow, Clone)]
+struct Reservation {
+    id: Uuid,
+    slot_id: Option<Uuid>, // 予約サービスから受け取った予約は None
+    name: String,
+    group_size: i32,
+    status: String, // booked, queued, cancelled
+    ticket_id: Option<Uuid>,
+    starts_at: Option<DateTime<Utc>>, // 予約サービスから受け取った予約の来店時刻
+    source: Option<String>,           // 予約サービスの名前
+}
+
+// 予約枠 (予約済みの組数つき)
+#[derive(FromRow)]
+struct SlotRow {
+    id: Uuid,
+    starts_at: DateTime<Utc>,
+    capacity: i32,
+    booked: i64,
+}
+
+// 画面表示用の予約枠 (時刻は店舗の現地時刻に整形済み)
+struct SlotView {
+    id: Uuid,
+    starts_at: DateTime<Utc>,
+    label: String,
+    capacity: i32,
+    booked: i64,
+    reservations: Vec<Reservation>,
+}
+
+impl SlotView {
+    fn remaining(&self) -> i64 {
+        (self.capacity as i64 - self.booked).max(0)
+    }
+}
+
+// スタッフ (PINのハッシュは画面に出さないので含めない)
+#[derive(FromRow, Clone)]
+struct User {
+    id: Uuid,
+    name: String,
+    role: String, // staff, manager, observer (見るだけ)
+    active: bool,
+}
+
+impl Ticket {
+    // 画面・整理券に出す番号 (頭文字つき)
+    fn label(&self) -> String {
+        format!("{}{}", self.number_prefix, self.number)
+    }
+
+    // 指定時刻以降に呼び出されたか (呼び出し直後の強調表示用)
+    fn is_called_since(&self, since: &DateTime<Utc>) -> bool {
+        self.status == "called" && self.called_at.is_some_and(|t| t >= *since)
+    }
+
+    // 配慮が必要なことのアイコンと表示名
+    fn need_icons(&self) -> Vec<(&'static str, &'static str)> {
+        NEEDS
+            .iter()
+            .filter(|(key, ..)| self.needs.iter().any(|n| n == key))
+            .map(|(_, icon, label, _)| (*icon, *label))
+            .collect()
+    }
+
+    // 追加の項目のうち、値の入っているものの表示 (定義から消された項目は出さない)
+    fn custom_field_labels(&self, fields: &[CustomField]) -> Vec<String> {
+        fields
+            .iter()
+            .filter_map(|f| self.custom_fields.get(&f.key).and_then(|v| f.display(v)))
+            .collect()
+    }
+
+    // タグの編集欄の初期値
+    fn tags_value(&self) -> String {
+        self.tags.join(" ")
+    }
+
+    // リモート受付で、まだ到着確認が済んでいないか
+    fn needs_checkin(&self) -> bool {
+        self.remote && self.checked_in_at.is_none() && self.status == "waiting"
+    }
+
+    // 現在のステータスになった時刻 (待機中は発券時刻、呼び出し中は呼出時刻)
+    fn status_since(&self) -> DateTime<Utc> {
+        match (self.status.as_str(), self.called_at) {
+            ("called", Some(called_at)) => called_at,
+            _ => self.created_at,
+        }
+    }
+
+    // 現在のステータスのまま経過した分数
+    fn minutes_in_status(&self, now: &DateTime<Utc>) -> i64 {
+        (*now - self.status_since()).num_minutes().max(0)
+    }
+
+    // 設定した時間を超えて同じステータスのままか (0分の設定は無効扱い)
+    fn is_overdue(&self, now: &DateTime<Utc>, settings: &Settings) -> bool {
+        let limit = match self.status.as_str() {
+            "waiting" => settings.waiting_overdue_minutes,
+            "called" => settings.called_overdue_minutes,
+            _ => 0,
+        };
+        limit > 0 && self.minutes_in_status(now) >= limit
+    }
+}
+
+// --- 運用設定 ---
+// 管理画面 (/admin/settings) から変更でき、DBの settings テーブルにキーと値で保存する。
+// 再デプロイが必要な項目 (パスワード等) は Secrets.toml 側に置く。
+#[derive(Clone)]
+struct Settings {
+    store_name: String,           // 店舗名 (整理券・発券機・管理メニューに出す。空なら出さない)
+    onboarded: bool,              // 初期設定 (/admin/onboarding) を終えた
+    maintenance_mode: bool,       // メンテナンス中: 書き込みを断り、画面は見るだけにする (/admin/maintenance で切り替える)
+    maintenance_message: String,  // メンテナンス中に画面とエラーに出す説明 (空なら既定の文)
+    called_overdue_minutes: i64,  // 呼び出してからこの分数を超えたら強調表示 (0で無効)
+    waiting_overdue_minutes: i64, // 発券からこの分数を超えて待機中なら強調表示 (0で無効)
+    overdue_alert: bool,          // 超過したチケットを一覧の上部で警告する
+    utc_offset_minutes: i32,      // 店舗のタイムゾーン (UTCからの分数。日本は540)
+    day_start_hour: i32,          // 営業日の切り替わる時刻 (店舗の現地時刻の時。深夜営業の店舗は 5 など)
+    public_reservations: bool,    // 来場者自身による予約 (/reserve) を受け付ける
+    reservation_lead_minutes: i64, // 予約枠の開始 (予約サービスの予約は来店時刻) の何分前に整理券へ変換するか
+    remote_join: bool,            // 来店前のリモート受付 (/join) を受け付ける
+    remote_join_phone_required: bool, // リモート受付でSMSによる電話番号確認を必須にする
+    remote_join_limit_per_hour: i64,  // 同じ送信元からの1時間あたりのリモート受付の上限
+    checkin_code: String,         // 来場者自身で到着確認するためのコード (空ならスタッフのみ)
+    deposit_min_group_size: i32,  // リモート受付でこの人数以上ならデポジットを求める (0で無効)
+    deposit_amount: i32,          // デポジットの金額 (円)
+    opening_time: String,         // 開店時刻 (店舗の現地時刻 "HH:MM"。空なら今日の見通しを今の時刻から出す)
+    closing_time: String,         // 閉店時刻 (店舗の現地時刻 "HH:MM"。空なら見込みの警告をしない)
+    overbooking_guard: String,    // 閉店までに案内できない見込みの発券: "warn" (警告のみ) / "block" (発券しない)
+    max_wait_minutes: i64,        // 待ち時間の見込みがこの分数を超えたら、新しい発券を自動で止める (0で無効)
+    eta_algorithm: String,        // 案内時間の見込み方: "average" (平均) / "group_size" (人数ごと) / "percentile" (遅めに見込む)
+    eta_window_days: i64,         // 案内時間の見込みに使う過去の日数
+    eta_percentile: i64,          // "percentile" で使うパーセンタイル (80なら、8割の組がこの時間内に終わる)
+    no_show_threshold: i64,       // 直近30日にこの回数以上来店しなかった電話番号に印をつける (0で無効)
+    no_show_policy: String,       // 印のついたお客様のリモート受付: "warn" (印のみ) / "deposit" (デポジットを求める)
+    min_group_size: i32,          // 1組の人数の下限 (発券・リモート受付・予約で共通)
+    max_group_size: i32,          // 1組の人数の上限
+    custom_statuses: String,      // 店舗独自の状態の定義 (1行に1つ。CustomStatus::parse_all で読む)
+    custom_fields: String,        // 発券時に入力する追加の項目の定義 (1行に1つ。CustomField::parse_all で読む)
+    privacy_mode: bool,           // 医療機関向け: スタッフの画面でもお名前はイニシャルだけにし、個人データを短期間で消す
+    privacy_retention_days: i64,  // プライバシーモードで、個人データ (電話番号・予約者名など) を残す日数
+    prioritize_needs: bool,       // 車椅子・ご高齢のお客様を優先して案内する
+    priority_fairness: i64,       // 優先のお客様を続けて呼ぶ前に、通常のお客様をこの組数だけ案内する (0で常に優先)
+    count_called_ahead: bool,     // 来場者画面の「前に待っている組数」に、呼び出し済みでまだ案内中の組も含める
+    guest_resize_enabled: bool,   // 来場者画面からお客様自身が人数を変更できる
+    guest_handoff_enabled: bool,  // 来場者画面からお客様同士で整理券を受け渡しできる
+    alert_wait_minutes: i64,      // この分数以上お待ちのお客様がいたらスタッフにお知らせする (0で無効)
+    call_columns: String,         // 呼び出し管理画面のカードに出す項目 (CALL_COLUMNS のキーをカンマ区切りで、表示する順に)
+    training_mode: bool,          // 練習モード: 発券した整理券を練習用として扱う (集計に含めず、翌日に削除する)
+    guest_poll_seconds: i64,      // 来場者画面の自動更新の間隔 (秒)
+    staff_poll_seconds: i64,      // 呼び出し管理画面・表示用モニターの再取得の間隔 (秒。リアルタイム通知が途切れた時の備え)
+    session_idle_minutes: i64,    // 管理画面でこの分数操作のない端末は、ログインし直させる (0で無効)
+    session_max_hours: i64,       // 管理画面にログインしてからこの時間が経った端末は、ログインし直させる (0で無効)
+}
+
+// 店舗で定義した独自の状態 (例: テイクアウトの「準備中」「受け取り可能」)
+// 待機中・呼出中のどちらかに属し、一覧と来場者画面ではこの表示名と色で表示する
+#[derive(Clone)]
+struct CustomStatus {
+    key: String,
+    label: String,
+    color: String,     // "#rrggbb"
+    base: String,      // "waiting" か "called"
+    next: Vec<String>, // ここから進める先 (他の独自の状態のキーか "completed")
+}
+
+impl CustomStatus {
+    // "キー, 表示名, 色, waiting/called, 次に進める先..." の形式。読めない行は飛ばす
+    fn parse_all(text: &str) -> Vec<CustomStatus> {
+        let mut statuses: Vec<CustomStatus> = Vec::new();
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let [key, label, color, base, next @ ..] = fields.as_slice() else {
+                continue;
+            };
+            let key_ok = !key.is_empty()
+                && key.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+                && !["waiting", "called", "completed", "merged"].contains(key);
+            let Some(color) = parse_hex_color(color) else {
+                continue;
+            };
+            if !key_ok || label.is_empty() || !["waiting", "called"].contains(base)
+                || statuses.iter().any(|s| s.key == *key)
+            {
+                continue;
+            }
+            statuses.push(CustomStatus {
+                key: key.to_string(),
+                label: label.to_string(),
+                color,
+                base: base.to_string(),
+                next: next.iter().filter(|v| !v.is_empty()).map(|v| v.to_string()).collect(),
+            });
+        }
+        // 定義されていない行き先は落とす
+        let keys: Vec<String> = statuses.iter().map(|s| s.key.clone()).collect();
+        for status in &mut statuses {
+            status.next.retain(|n| n == "completed" || (keys.contains(n) && *n != status.key));
+        }
+        statuses
+    }
+
+    fn to_line(&self) -> String {
+        let mut fields = vec![self.key.clone(), self.label.clone(), self.color.clone(), self.base.clone()];
+        fields.extend(self.next.iter().cloned());
+        fields.join(", ")
+    }
+}
+
+// 発券時に入力する、店舗で定義した追加の項目 (例: 喫煙・禁煙、お子様用の椅子)
+// 値はチケットの custom_fields (JSONB) に「キー: 値」で保存し、呼び出し管理画面のカードに表示する
+#[derive(Clone, Serialize)]
+struct CustomField {
+    key: String,
+    label: String,
+    kind: String,         // "select" / "text" / "checkbox"
+    options: Vec<String>, // select の選択肢
+}
+
+// 追加の項目の数と、自由入力の項目の文字数の上限
+const MAX_CUSTOM_FIELDS: usize = 10;
+const MAX_CUSTOM_FIELD_CHARS: usize = 50;
+
+impl CustomField {
+    // "キー, 表示名, select/text/checkbox, 選択肢..." の形式。読めない行は飛ばす
+    fn parse_all(text: &str) -> Vec<CustomField> {
+        let mut fields: Vec<CustomField> = Vec::new();
+        for line in text.lines() {
+            let parts: Vec<&str> = line.split(',').map(str::trim).collect();
+            let [key, label, kind, options @ ..] = parts.as_slice() else {
+                continue;
+            };
+            let key_ok = !key.is_empty()
+                && key.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+            let options: Vec<String> = options.iter().filter(|v| !v.is_empty()).map(|v| v.to_string()).collect();
+            // 選択肢のない select は選べないので飛ばす
+            if !key_ok || label.is_empty() || !["select", "text", "checkbox"].contains(kind)
+                || (*kind == "select" && options.is_empty())
+                || fields.iter().any(|f| f.key == *key)
+            {
+                continue;
+            }
+            fields.push(CustomField {
+                key: key.to_string(),
+                label: label.to_string(),
+                kind: kind.to_string(),
+                options: if *kind == "select" { options } else { Vec::new() },
+            });
+        }
+        fields.truncate(MAX_CUSTOM_FIELDS);
+        fields
+    }
+
+    fn to_line(&self) -> String {
+        let mut parts = vec![self.key.clone(), self.label.clone(), self.kind.clone()];
+        parts.extend(self.options.iter().cloned());
+        parts.join(", ")
+    }
+
+    // 送信された値を保存する値にする (未入力・選択肢にない値は保存しない)
+    fn value_from(&self, raw: Option<&str>) -> Option<serde_json::Value> {
+        let raw = raw.map(str::trim).filter(|v| !v.is_empty())?;
+        match self.kind.as_str() {
+            "checkbox" => Some(serde_json::Value::Bool(true)),
+            "select" => self.options.iter().any(|o| o == raw).then(|| raw.into()),
+            _ => Some(raw.chars().take(MAX_CUSTOM_FIELD_CHARS).collect::<String>().into()),
+        }
+    }
+
+    // カードに出す文言 (チェックボックスは表示名だけ)
+    fn display(&self, value: &serde_json::Value) -> Option<String> {
+        match value {
+            serde_json::Value::Bool(true) => Some(self.label.clone()),
+            serde_json::Value::String(v) if !v.is_empty() => Some(format!("{}: {}", self.label, v)),
+            _ => None,
+        }
+    }
+}
+
+// 定義に沿って追加の項目の値を集める (get はキーから送信された値を引く)
+fn collect_custom_fields<'a>(
+    fields: &[CustomField],
+    get: impl Fn(&str) -> Option<&'a str>,
+) -> serde_json::Map<String, serde_json::Value> {
+    fields
+        .iter()
+        .filter_map(|f| f.value_from(get(&f.key)).map(|v| (f.key.clone(), v)))
+        .collect()
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            store_name: String::new(),
+            onboarded: false,
+            maintenance_mode: false,
+            maintenance_message: String::new(),
+            called_overdue_minutes: 10,
+            waiting_overdue_minutes: 0,
+            overdue_alert: true,
+            utc_offset_minutes: 540,
+            day_start_hour: 0,
+            public_reservations: false,
+            reservation_lead_minutes: 0,
+            remote_join: false,
+            remote_join_phone_required: false,
+            remote_join_limit_per_hour: 3,
+            checkin_code: String::new(),
+            deposit_min_group_size: 0,
+            deposit_amount: 1000,
+            opening_time: String::new(),
+            closing_time: String::new(),
+            overbooking_guard: "warn".to_string(),
+            max_wait_minutes: 0,
+            eta_algorithm: "average".to_string(),
+            eta_window_days: 28,
+            eta_percentile: 80,
+            no_show_threshold: 0,
+            no_show_policy: "warn".to_string(),
+            min_group_size: 1,
+            max_group_size: 50,
+            custom_statuses: String::new(),
+            custom_fields: String::new(),
+            privacy_mode: false,
+            privacy_retention_days: 1,
+            prioritize_needs: false,
+            priority_fairness: 0,
+            count_called_ahead: false,
+            guest_resize_enabled: true,
+            guest_handoff_enabled: true,
+            alert_wait_minutes: 0,
+            call_columns: "queue,size,wait,counter,tags".to_string(),
+            training_mode: false,
+            guest_poll_seconds: 5,
+            staff_poll_seconds: 30,
+            session_idle_minutes: 0,
+            session_max_hours: 0,
+        }
+    }
+}
+
+impl Settings {
+    // 保存されていない項目は既定値のまま
+    #[tracing::instrument(skip_all)]
+    async fn load(pool: &PgPool) -> Settings {
+        let rows: Vec<(String, String)> = sqlx::query_as("SELECT key, value FROM settings")
+            .fetch_all(pool)
+            .await
+            .unwrap_or(vec![]);
+
+        let mut settings = Settings::default();
+        for (key, value) in rows {
+            settings.apply(&key, &value);
+        }
+        settings
+    }
+
+    // 解釈できない値は無視して既定値を残す
+    fn apply(&mut self, key: &str, value: &str) {
+        match key {
+            "called_overdue_minutes" => {
+                if let Ok(v) = value.parse() { self.called_overdue_minutes = v; }
+            }
+            "waiting_overdue_minutes" => {
+                if let Ok(v) = value.parse() { self.waiting_overdue_minutes = v; }
+            }
+            "overdue_alert" => self.overdue_alert = value == "true",
+            "utc_offset_minutes" => {
+                if let Ok(v) = value.parse() { self.utc_offset_minutes = v; }
+            }
+            "day_start_hour" => {
+                if let Ok(v) = value.parse() { self.day_start_hour = v; }
+            }
+            "public_reservations" => self.public_reservations = value == "true",
+            "reservation_lead_minutes" => {
+                if let Ok(v) = value.parse() { self.reservation_lead_minutes = v; }
+            }
+            "remote_join" => self.remote_join = value == "true",
+            "remote_join_phone_required" => self.remote_join_phone_required = value == "true",
+            "remote_join_limit_per_hour" => {
+                if let Ok(v) = value.parse() { self.remote_join_limit_per_hour = v; }
+            }
+            "checkin_code" => self.checkin_code = value.to_string(),
+            "deposit_min_group_size" => {
+                if let Ok(v) = value.parse() { self.deposit_min_group_size = v; }
+            }
+            "deposit_amount" => {
+                if let Ok(v) = value.parse() { self.deposit_amount = v; }
+            }
+            "store_name" => self.store_name = value.to_string(),
+            "onboarded" => self.onboarded = value == "true",
+            "maintenance_mode" => self.maintenance_mode = value == "true",
+            "maintenance_message" => self.maintenance_message = value.to_string(),
+            "opening_time" => self.opening_time = value.to_string(),
+            "closing_time" => self.closing_time = value.to_string(),
+            "overbooking_guard" => self.overbooking_guard = value.to_string(),
+            "max_wait_minutes" => {
+                if let Ok(v) = value.parse() { self.max_wait_minutes = v; }
+            }
+            "eta_algorithm" => self.eta_algorithm = value.to_string(),
+            "eta_window_days" => {
+                if let Ok(v) = value.parse() { self.eta_window_days = v; }
+            }
+            "eta_percentile" => {
+                if let Ok(v) = value.parse() { self.eta_percentile = v; }
+            }
+            "no_show_threshold" => {
+                if let Ok(v) = value.parse() { self.no_show_threshold = v; }
+            }
+            "no_show_policy" => self.no_show_policy = value.to_string(),
+            "min_group_size" => {
+                if let Ok(v) = value.parse() { self.min_group_size = v; }
+            }
+            "max_group_size" => {
+                if let Ok(v) = value.parse() { self.max_group_size = v; }
+            }
+            "custom_statuses" => self.custom_statuses = value.to_string(),
+            "custom_fields" => self.custom_fields = value.to_string(),
+            "privacy_mode" => self.privacy_mode = value == "true",
+            "privacy_retention_days" => {
+                if let Ok(v) = value.parse() { self.privacy_retention_days = v; }
+            }
+            "prioritize_needs" => self.prioritize_needs = value == "true",
+            "priority_fairness" => {
+                if let Ok(v) = value.parse() { self.priority_fairness = v; }
+            }
+            "count_called_ahead" => self.count_called_ahead = value == "true",
+            "guest_resize_enabled" => self.guest_resize_enabled = value == "true",
+            "guest_handoff_enabled" => self.guest_handoff_enabled = value == "true",
+            "alert_wait_minutes" => {
+                if let Ok(v) = value.parse() { self.alert_wait_minutes = v; }
+            }
+            "training_mode" => self.training_mode = value == "true",
+            "guest_poll_seconds" => {
+                if let Ok(v) = value.parse() { self.guest_poll_seconds = v; }
+            }
+            "staff_poll_seconds" => {
+                if let Ok(v) = value.parse() { self.staff_poll_seconds = v; }
+            }
+            "session_idle_minutes" => {
+                if let Ok(v) = value.parse() { self.session_idle_minutes = v; }
+            }
+            "session_max_hours" => {
+                if let Ok(v) = value.parse() { self.session_max_hours = v; }
+            }
+            "call_columns" => self.call_columns = value.to_string(),
+            _ => {}
+        }
+    }
+
+    fn to_pairs(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("called_overdue_minutes", self.called_overdue_minutes.to_string()),
+            ("waiting_overdue_minutes", self.waiting_overdue_minutes.to_string()),
+            ("overdue_alert", self.overdue_alert.to_string()),
+            ("utc_offset_minutes", self.utc_offset_minutes.to_string()),
+            ("day_start_hour", self.day_start_hour.to_string()),
+            ("public_reservations", self.public_reservations.to_string()),
+            ("reservation_lead_minutes", self.reservation_lead_minutes.to_string()),
+            ("remote_join", self.remote_join.to_string()),
+            ("remote_join_phone_required", self.remote_join_phone_required.to_string()),
+            ("remote_join_limit_per_hour", self.remote_join_limit_per_hour.to_string()),
+            ("checkin_code", self.checkin_code.clone()),
+            ("deposit_min_group_size", self.deposit_min_group_size.to_string()),
+            ("deposit_amount", self.deposit_amount.to_string()),
+            ("store_name", self.store_name.clone()),
+            ("onboarded", self.onboarded.to_string()),
+            ("maintenance_mode", self.maintenance_mode.to_string()),
+            ("maintenance_message", self.maintenance_message.clone()),
+            ("opening_time", self.opening_time.clone()),
+            ("closing_time", self.closing_time.clone()),
+            ("overbooking_guard", self.overbooking_guard.clone()),
+            ("max_wait_minutes", self.max_wait_minutes.to_string()),
+            ("eta_algorithm", self.eta_algorithm.clone()),
+            ("eta_window_days", self.eta_window_days.to_string()),
+            ("eta_percentile", self.eta_percentile.to_string()),
+            ("no_show_threshold", self.no_show_threshold.to_string()),
+            ("no_show_policy", self.no_show_policy.clone()),
+            ("min_group_size", self.min_group_size.to_string()),
+            ("max_group_size", self.max_group_size.to_string()),
+            ("custom_statuses", self.custom_statuses.clone()),
+            ("custom_fields", self.custom_fields.clone()),
+            ("privacy_mode", self.privacy_mode.to_string()),
+            ("privacy_retention_days", self.privacy_retention_days.to_string()),
+            ("prioritize_needs", self.prioritize_needs.to_string()),
+            ("priority_fairness", self.priority_fairness.to_string()),
+            ("count_called_ahead", self.count_called_ahead.to_string()),
+            ("guest_resize_enabled", self.guest_resize_enabled.to_string()),
+            ("guest_handoff_enabled", self.guest_handoff_enabled.to_string()),
+            ("alert_wait_minutes", self.alert_wait_minutes.to_string()),
+            ("training_mode", self.training_mode.to_string()),
+            ("call_columns", self.call_columns.clone()),
+            ("guest_poll_seconds", self.guest_poll_seconds.to_string()),
+            ("staff_poll_seconds", self.staff_poll_seconds.to_string()),
+            ("session_idle_minutes", self.session_idle_minutes.to_string()),
+            ("session_max_hours", self.session_max_hours.to_string()),
+        ]
+    }
+
+    // 店舗のタイムゾーン (範囲外の値なら日本時間)
+    fn timezone(&self) -> FixedOffset {
+        FixedOffset::east_opt(self.utc_offset_minutes * 60)
+            .unwrap_or_else(|| FixedOffset::east_opt(9 * 3600).expect("valid offset"))
+    }
+
+    // 店舗の現地時刻で表示用に整形する
+    fn format_local(&self, at: &DateTime<Utc>, fmt: &str) -> String {
+        at.with_timezone(&self.timezone()).format(fmt).to_string()
+    }
+
+    // フォームの datetime-local (店舗の現地時刻) を UTC に変換する
+    fn parse_local(&self, value: &str) -> Option<DateTime<Utc>> {
+        let naive = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M").ok()?;
+        self.timezone()
+            .from_local_datetime(&naive)
+            .single()
+            .map(|at| at.with_timezone(&Utc))
+    }
+
+    // 予約台帳のCSVの来店時刻。時刻だけなら今日の営業日とみなす
+    fn parse_expected(&self, value: &str) -> Option<DateTime<Utc>> {
+        let naive = match NaiveTime::parse_from_str(value, "%H:%M") {
+            Ok(time) => self.business_time(&Utc::now(), time),
+            Err(_) => ["%Y-%m-%d %H:%M", "%Y/%m/%d %H:%M", "%Y-%m-%dT%H:%M"]
+                .iter()
+                .find_map(|fmt| NaiveDateTime::parse_from_str(value, fmt).ok())?,
+        };
+        self.timezone()
+            .from_local_datetime(&naive)
+            .single()
+            .map(|at| at.with_timezone(&Utc))
+    }
+
+    // 今日の営業日の開店時刻 (未設定なら None)
+    fn opening_at(&self, now: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let time = NaiveTime::parse_from_str(&self.opening_time, "%H:%M").ok()?;
+        self.timezone()
+            .from_local_datetime(&self.business_time(now, time))
+            .single()
+            .map(|at| at.with_timezone(&Utc))
+    }
+
+    // 今日の閉店時刻 (未設定なら None)。営業日の切り替わりより前 (例: 5時切り替えで 02:00) なら翌日の深夜
+    fn closing_at(&self, now: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let time = NaiveTime::parse_from_str(&self.closing_time, "%H:%M").ok()?;
+        self.timezone()
+            .from_local_datetime(&self.business_time(now, time))
+            .single()
+            .map(|at| at.with_timezone(&Utc))
+    }
+
+    // 今日の営業日のうちの時刻 (店舗の現地時刻)。営業日の切り替わりより前の時刻は翌日の深夜
+    fn business_time(&self, now: &DateTime<Utc>, time: NaiveTime) -> NaiveDateTime {
+        let mut day = self.business_date(now);
+        if (time.hour() as i32) < self.day_start_hour {
+            day += chrono::Duration::days(1);
+        }
+        day.and_time(time)
+    }
+
+    // 営業日。店舗の現地時刻で、営業日の切り替わる時刻より前は前の日として扱う
+    // (深夜営業の店舗で、0時をまたいだ分の集計や整理番号が別の日に分かれないように)
+    fn business_date(&self, at: &DateTime<Utc>) -> NaiveDate {
+        (at.with_timezone(&self.timezone()) - chrono::Duration::hours(self.day_start_hour as i64)).date_naive()
+    }
+
+    // 営業日の始まり (その日の、営業日の切り替わる時刻) を UTC で
+    fn business_day_start(&self, date: NaiveDate) -> Option<DateTime<Utc>> {
+        let time = NaiveTime::from_hms_opt(self.day_start_hour as u32, 0, 0)?;
+        self.timezone()
+            .from_local_datetime(&date.and_time(time))
+            .single()
+            .map(|at| at.with_timezone(&Utc))
+    }
+
+    // 今日の営業日の開始時刻
+    fn today_start(&self, now: &DateTime<Utc>) -> DateTime<Utc> {
+        self.business_day_start(self.business_date(now)).unwrap_or(*now)
+    }
+
+    // SQLで営業日ごとにまとめる時に、UTCの時刻に足す分数 (時差から営業日の切り替わりの分を引いたもの)
+    fn business_offset_minutes(&self) -> i32 {
+        self.utc_offset_minutes - self.day_start_hour * 60
+    }
+
+    // 案内時間の見込み方の説明 (見通しの画面などに出す)
+    fn eta_description(&self) -> String {
+        match self.eta_algorithm.as_str() {
+            "group_size" => format!("過去{}日の人数ごとの案内時間", self.eta_window_days),
+            "percentile" => format!("過去{}日の案内時間の{}パーセンタイル (遅めの見込み)", self.eta_window_days, self.eta_percentile),
+            _ => format!("過去{}日の平均案内時間", self.eta_window_days),
+        }
+    }
+
+    // メンテナンス中なら画面に出す説明
+    fn maintenance_notice(&self) -> Option<String> {
+        self.maintenance_mode.then(|| {
+            if self.maintenance_message.is_empty() {
+                MAINTENANCE_DEFAULT_MESSAGE.to_string()
+            } else {
+                self.maintenance_message.clone()
+            }
+        })
+    }
+
+    fn blocks_overbooking(&self) -> bool {
+        self.overbooking_guard == "block"
+    }
+
+    // 1組の人数が設定の範囲内か (範囲外ならそのまま画面に出すメッセージ)
+    fn check_group_size(&self, group_size: i32) -> Result<i32, String> {
+        if (self.min_group_size..=self.max_group_size).contains(&group_size) {
+            Ok(group_size)
+        } else {
+            Err(format!("人数は{}〜{}名で入力してください。", self.min_group_size, self.max_group_size))
+        }
+    }
+
+    fn statuses(&self) -> Vec<CustomStatus> {
+        CustomStatus::parse_all(&self.custom_statuses)
+    }
+
+    fn fields(&self) -> Vec<CustomField> {
+        CustomField::parse_all(&self.custom_fields)
+    }
+
+    // 来場者画面の「前にいる組数」に足す、呼び出し済みでまだ案内中の組数
+    // (呼出中の本人には組数を表示しないので、待機中の整理券だけを対象にする)
+    fn called_ahead(&self, ticket: &Ticket, called_count: i64) -> i64 {
+        if self.count_called_ahead && ticket.status == "waiting" { called_count } else { 0 }
+    }
+
+    // チケットの現在の独自の状態 (定義が消された場合は None)
+    fn stage_of(&self, ticket: &Ticket) -> Option<CustomStatus> {
+        let key = ticket.stage.as_deref()?;
+        self.statuses().into_iter().find(|s| s.key == key)
+    }
+
+    // チケットを次に進められる独自の状態と "completed"
+    // 独自の状態に入っていないチケットは、どの独自の状態にも進められる
+    fn next_stages(&self, ticket: &Ticket) -> Vec<String> {
+        if !["waiting", "called"].contains(&ticket.status.as_str()) {
+            return vec![];
+        }
+        match self.stage_of(ticket) {
+            Some(stage) => stage.next,
+            None => self.statuses().into_iter().map(|s| s.key).collect(),
+        }
+    }
+
+    // 呼び出し管理画面のカードに出す項目 (知らないキーと重複は除く)
+    fn parse_call_columns(input: &str) -> Vec<String> {
+        let mut columns: Vec<String> = Vec::new();
+        for key in input.split(|c: char| c == ',' || c == '、' || c.is_whitespace()) {
+            let key = key.trim().to_lowercase();
+            if CALL_COLUMNS.iter().any(|(k, _)| *k == key) && !columns.contains(&key) {
+                columns.push(key);
+            }
+        }
+        columns
+    }
+
+    fn call_columns(&self) -> Vec<String> {
+        Settings::parse_call_columns(&self.call_columns)
+    }
+
+    fn shows_call_column(&self, key: &str) -> bool {
+        self.call_columns().iter().any(|c| c == key)
+    }
+
+    // 設定画面の入力欄の値 (見やすいように空白を入れる)
+    fn call_columns_value(&self) -> String {
+        self.call_columns().join(", ")
+    }
+
+    // スタッフの画面に出すお名前 (プライバシーモードでは先頭の1文字だけ)
+    fn staff_name(&self, name: &str) -> String {
+        if !self.privacy_mode {
+            return name.to_string();
+        }
+        match name.trim().chars().next() {
+            Some(c) => format!("{}.", c),
+            None => String::new(),
+        }
+    }
+
+    // 来店しなかった回数が基準に達しているか
+    fn is_repeat_no_show(&self, no_shows: i64) -> bool {
+        self.no_show_threshold > 0 && no_shows >= self.no_show_threshold
+    }
+
+    async fn save(&self, pool: &PgPool) {
+        for (key, value) in self.to_pairs() {
+            sqlx::query(
+                "INSERT INTO settings (key, value) VALUES ($1, $2) 
+                 ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value, updated_at = NOW()"
+            )
+            .bind(key)
+            .bind(value)
+            .execute(pool)
+            .await
+            .expect("Failed to save settings");
+        }
+    }
+}
+
+// --- テンプレート定義 ---
+
+#[derive(Template)]
+#[template(path = "admin_index.html")]
+struct AdminIndexTemplate {
+    stats: DashboardStats, // 初回表示用 (以降は admin_stats.html だけを更新する)
+    training_mode: bool,
+    theme: String, // 画面の配色 (STAFF_THEMES のキー)
+    polling: PollingSummary,      // 自動更新の通信量 (このサーバーが起動してから、またはリセットしてから)
+    polling_since: Option<String>, // 集計を始めた時刻 (店舗の現地時刻)
+    guest_poll_seconds: i64,
+    staff_poll_seconds: i64,
+    read_only: bool, // 見るだけ (状況と一部の画面へのリンクだけを出す)
+    store_name: String,
+    onboarded: bool, // false なら初期設定への案内を出す
+    maintenance: Option<String>, // メンテナンス中の説明
+}
+
+#[derive(Template)]
+#[template(path = "admin_stats.html")] // 部品用
+struct AdminStatsTemplate {
+    stats: DashboardStats,
+}
+
+#[derive(Template)]
+#[template(path = "admin_devices.html")]
+struct AdminDevicesTemplate {
+    devices: Vec<(Device, String)>, // 端末と表示用の最終応答時刻 (店舗の現地時刻)
+    kinds: [(&'static str, &'static str); 3],
+    languages: [(&'static str, &'static str); 4],
+    queues: Vec<Queue>,
+    created: Option<(String, String, String)>, // 追加・発行し直した直後だけ表示する (端末名, トークン, 署名用の鍵)
+    base_url: String,
+    flash: Option<Flash>,
+}
+
+#[derive(Template)]
+#[template(path = "admin_notifications.html")]
+struct AdminNotificationsTemplate {
+    items: Vec<(StaffNotification, String)>, // お知らせと表示用の時刻 (店舗の現地時刻)
+    unread: usize,
+}
+
+#[derive(Template)]
+#[template(path = "notification_bell.html")] // 部品用 (管理画面のヘッダーのベル)
+struct NotificationBellTemplate {
+    unread: i64,
+}
+
+// ゴミ箱の1行
+struct TrashItem {
+    ticket: Ticket,
+    issued_at: String,     // 発券時刻 (店舗の現地時刻)
+    deleted_at: String,    // 削除した時刻 (店舗の現地時刻)
+    queue: Option<String>, // 列の名前 (列が2つ以上ある時だけ)
+}
+
+#[derive(Template)]
+#[template(path = "admin_onboarding.html")]
+struct AdminOnboardingTemplate {
+    step: usize, // 1から
+    steps: [&'static str; 5],
+    settings: Settings,
+    queues: Vec<Queue>,
+    error: Option<String>,
+    finished: bool,                // 最後の段階を送った後の完了の画面
+    devices: Vec<OnboardedDevice>, // 最後の段階で登録した端末
+    base_url: String,
+}
+
+impl AdminOnboardingTemplate {
+    fn queue_lines(&self) -> String {
+        self.queues.iter().map(|q| q.name.as_str()).collect::<Vec<_>>().join("\n")
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin_usage.html")]
+struct AdminUsageTemplate {
+    months: Vec<UsageMonth>,
+    metrics: [(&'static str, &'static str); 3],
+    store_code: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin_templates.html")]
+struct AdminTemplatesTemplate {
+    slots: Vec<TemplateSlotView>,
+    flash: Option<Flash>,
+}
+
+#[derive(Template)]
+#[template(path = "admin_migrations.html")]
+struct AdminMigrationsTemplate {
+    schema_version: Option<i64>, // 適用済みの最新のマイグレーション
+    migrations: Vec<MigrationView>, // 新しい順
+    confirmed: Vec<i64>, // MIGRATIONS_CONFIRM で確認したバージョン
+}
+
+#[derive(Template)]
+#[template(path = "admin_trash.html")]
+struct AdminTrashTemplate {
+    tickets: Vec<TrashItem>,
+    flash: Option<Flash>,
+}
+
+#[derive(Template)]
+#[template(path = "admin_digests.html")]
+struct AdminDigestsTemplate {
+    digests: Vec<(String, String)>, // (集計した週, 本文)
+}
+
+#[derive(Template)]
+#[template(path = "admin_forecast.html")]
+struct AdminForecastTemplate {
+    forecast: Forecast,
+    closing_time: String, // 空なら閉店時刻は未設定
+    eta_description: String,
+    updated_at: String,
+}
+
+#[derive(FromRow)]
+struct QueueToday {
+    queue_id: i32,
+    issued: i64,
+    completed: i64,
+    called: i64,
+    wait_seconds: f64, // 今日呼び出した組の、発券から呼び出しまでの合計
+}
+
+// 列 (受付の種類) ごとの状況
+struct QueueStats {
+    name: String,
+    waiting: i64,
+    issued_today: i64,
+    completed_today: i64,
+    avg_wait_today: Option<i64>, // 分
+}
+
+impl QueueStats {
+    fn new(name: String, waiting: i64, rows: &[&QueueToday]) -> Self {
+        let called: i64 = rows.iter().map(|r| r.called).sum();
+        let wait_seconds: f64 = rows.iter().map(|r| r.wait_seconds).sum();
+        QueueStats {
+            name,
+            waiting,
+            issued_today: rows.iter().map(|r| r.issued).sum(),
+            completed_today: rows.iter().map(|r| r.completed).sum(),
+            avg_wait_today: (called > 0).then(|| (wait_seconds / called as f64 / 60.0).round() as i64),
+        }
+    }
+}
+
+// 管理メニューの上部に出す、今の状況
+struct DashboardStats {
+    queues: Vec<QueueStats>,    // 列ごとの内訳
+    waiting: i64,
+    called: Vec<Ticket>,        // 呼出中のチケット (呼び出した順)
+    issued_today: i64,          // 今日発券した組数
+    completed_today: i64,       // 今日案内を終えた組数
+    avg_wait_today: Option<i64>, // 今日の発券から呼び出しまでの平均 (分)
+    updated_at: String,         // 店舗の現地時刻 "HH:MM"
+}
+
+#[derive(Template)]
+#[template(path = "admin_audit.html")]
+struct AdminAuditTemplate {
+    entries: Vec<(AuditEntry, String)>, // (記録, 表示用の日時)
+    users: Vec<User>,
+    filter: AuditFilter,
+    limit: i64,
+}
+
+#[derive(Template)]
+#[template(path = "admin_privacy.html")]
+struct AdminPrivacyTemplate {
+    phone: String,               // 入力された電話番号 (正規化後)
+    summary: Option<GuestDataSummary>,
+    message: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "admin_history.html")]
+struct AdminHistoryTemplate {
+    items: Vec<HistoryItem>,
+    filter: HistoryFilter,
+    limit: i64,
+}
+
+#[derive(Template)]
+#[template(path = "admin_history_ticket.html")]
+struct AdminHistoryTicketTemplate {
+    item: HistoryItem,
+    events: Vec<(TimelineEvent, String)>, // (操作, 表示用の日時)
+}
+
+#[derive(Template)]
+#[template(path = "admin_maintenance.html")]
+struct AdminMaintenanceTemplate {
+    backup: Option<Arc<BackupConfig>>,
+    backups: Vec<(BackupRun, String)>, // (実行記録, 表示用の日時)
+    started: bool,
+    settings: Settings,
+    flash: Option<Flash>,
+}
+
+#[derive(Template)]
+#[template(path = "admin_reset.html")]
+struct AdminResetTemplate {
+    acting: Option<User>,
+    allowed: bool,  // 店長として操作しているか
+    phrase: String, // 確認のために入力してもらう文字列
+    issued_at: i64,
+    token: String,
+    archives: Vec<(ResetArchive, String)>, // (控え, 表示用の日時)
+    error: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "staff.html")]
+struct StaffTemplate {
+    users: Vec<User>,
+    acting: Option<User>,
+    error: Option<String>,
+    flash: Option<Flash>,
+    theme: String,         // 今の配色
+    station_theme: Option<String>, // この端末に設定した配色
+    user_theme: Option<String>,    // 担当者に設定した配色
+    themes: [(&'static str, &'static str); 3],
+    can_share: bool, // 見るだけの共有リンク・招待リンクを発行できるか (責任者のみ)
+    can_manage_staff: bool, // スタッフを登録・無効化できるか (責任者のみ。責任者がまだいなければ誰でも)
+    observer_links: Vec<(ObserverLink, String, String)>, // 有効な共有リンク (リンク, URL, 表示用の期限)
+    invites: Vec<(StaffInvite, Option<String>, String)>, // 未使用の招待リンク (招待, URL (責任者にだけ見せる), 表示用の期限)
+}
+
+impl StaffTemplate {
+    fn theme_label(&self, key: &str) -> &'static str {
+        STAFF_THEMES.iter().find(|(k, _)| *k == key).map(|(_, label)| *label).unwrap_or("")
+    }
+}
+
+#[derive(Template)]
+#[template(path = "display.html")]
+struct DisplayTemplate {
+    key: Option<String>, // 部品・SSEの取得にも同じ端末キーを付ける
+    device: Option<String>, // 死活監視のハートビートに使う端末のトークン
+    chime_url: Option<String>,
+    checkin_code: Option<String>, // リモート受付の到着確認コード (来場者に見せる)
+    poll_seconds: i64,            // リアルタイム通知が途切れた時の備えの再取得の間隔
+}
+
+#[derive(Template)]
+#[template(path = "display_content.html")]
+struct DisplayContentTemplate {
+    now_serving: Vec<(i32, String, i32)>, // 列ごとの「ただいまご案内中の番号」
+    called: Vec<Ticket>,
+    waiting: Vec<Ticket>,
+    highlight_since: DateTime<Utc>,
+    queues: Vec<Queue>, // 番号に列の色・アイコンを付ける
+}
+
+impl DisplayContentTemplate {
+    // 列の色・アイコン (列が2つ以上ある時だけ使う)
+    fn queue_of(&self, id: &i32) -> Option<&Queue> {
+        self.queues.iter().find(|q| q.id == *id).filter(|_| self.queues.len() > 1)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin_reservations.html")]
+struct AdminReservationsTemplate {
+    slots: Vec<SlotView>,
+    external: Vec<(Reservation, String)>, // 予約サービス・CSVから受け取った予約 (予約, 表示用の来店時刻)
+    error: Option<String>,
+    import_errors: Vec<String>, // CSVの読み込みで誤りのあった行
+    flash: Option<Flash>,
+}
+
+#[derive(Template)]
+#[template(path = "reserve.html")]
+struct ReserveTemplate {
+    slots: Vec<SlotView>,
+    error: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "reserve_status.html")]
+struct ReserveStatusTemplate {
+    reservation: Reservation,
+    slot_label: String,
+}
+
+#[derive(Template)]
+#[template(path = "front.html")]
+struct FrontTemplate {
+    last_ticket: Option<Ticket>,
+    qr_code: Option<String>,
+    guest_url: Option<String>, // QRコードと同じ署名つきのリンク (確認用)
+    queues: Vec<Queue>, // 2つ以上あれば発券時に選ばせる
+    projection: Option<Projection>, // 閉店までに案内できるかの見込み
+    error: Option<String>,
+    training_mode: bool, // 練習モード中 (発券する整理券は練習用になる)
+    theme: String,
+    maintenance: Option<String>, // メンテナンス中の説明
+    custom_fields: Vec<CustomField>, // 発券時に入力する追加の項目
+}
+
+// 呼び出し画面のメッセージ欄だけを書き換える時用
+#[derive(Template)]
+#[template(path = "flash.html")]
+struct FlashTemplate {
+    flash: Flash,
+}
+
+#[derive(Template)]
+#[template(path = "call.html")]
+struct CallTemplate {
+    filter: CallFilter, // 一覧そのものは call_list (CallListTemplate) で読み込む
+    chime_url: Option<String>,
+    acting: Option<User>, // 現在操作中のスタッフ
+    queues: Vec<Queue>,
+    counters: Vec<Counter>, // 窓口専用の画面へのリンク用
+    counter: Option<Counter>, // 窓口専用の画面なら、その窓口
+    flash: Option<Flash>,
+    training_mode: bool,
+    presets: Vec<SavedFilter>, // 操作中のスタッフが保存した絞り込み条件
+    tags_in_use: Vec<String>,  // 有効なチケットについているタグ (絞り込みの候補)
+    theme: String,
+    themes: [(&'static str, &'static str); 3],
+    poll_seconds: i64, // リアルタイム通知が途切れた時の備えの再取得の間隔
+    read_only: bool,   // 見るだけ (操作のボタンを隠す)
+    maintenance: Option<String>, // メンテナンス中の説明
+}
+
+#[derive(Template)]
+#[template(path = "call_items.html")] // 部品用
+struct CallListTemplate {
+    tickets: Vec<Ticket>,
+    next_cursor: Option<String>, // 次のページがある場合のカーソル
+    is_first_page: bool,
+    highlight_since: DateTime<Utc>, // これ以降に呼び出されたチケットを強調表示する
+    now: DateTime<Utc>,
+    settings: Settings,
+    overdue_numbers: Vec<i32>, // 時間を超過しているチケット (ページに関係なく全件)
+    free_tables: Vec<Table>,   // 呼び出し時に案内できる空席
+    suggestions: Vec<Suggestion>, // 空席ごとの、次に呼ぶおすすめ
+    queues: Vec<Queue>,        // 列の表示と、列の移動先の選択用
+    no_shows: HashMap<Uuid, i64>, // 基準に達したチケットごとの、直近30日に来店しなかった回数
+    counters: Vec<Counter>,    // 呼び出した窓口の表示用
+    counter: Option<Counter>,  // 窓口専用の画面なら、その窓口 (呼び出す時に記録する)
+    my_called: Vec<Ticket>,    // この窓口で呼出中のチケット (ページに関係なく全件)
+    resized: HashMap<Uuid, String>, // 直近にお客様が人数を変更したチケット (変更の内容)
+    names: HashMap<Uuid, String>,   // 予約から発券したチケットの予約者名 (カードに出す設定の場合のみ)
+    custom_fields: Vec<CustomField>, // 追加の項目の定義 (カードに値を出す)
+}
+
+impl CallListTemplate {
+    fn counter_name(&self, id: &Option<i32>) -> Option<&str> {
+        let id = (*id)?;
+        self.counters.iter().find(|c| c.id == id).map(|c| c.name.as_str())
+    }
+
+    fn stage_of(&self, ticket: &Ticket) -> Option<CustomStatus> {
+        self.settings.stage_of(ticket)
+    }
+
+    // 進める先のボタン (キー, 表示名)
+    fn stage_buttons(&self, ticket: &Ticket) -> Vec<(String, String)> {
+        let statuses = self.settings.statuses();
+        self.settings
+            .next_stages(ticket)
+            .into_iter()
+            .map(|key| {
+                let label = statuses.iter().find(|s| s.key == key).map_or_else(|| "完了".to_string(), |s| s.label.clone());
+                (key, label)
+            })
+            .collect()
+    }
+
+    fn resized(&self, id: &Uuid) -> Option<&str> {
+        self.resized.get(id).map(String::as_str)
+    }
+
+    fn reservation_name(&self, id: &Uuid) -> Option<&str> {
+        self.names.get(id).map(String::as_str)
+    }
+
+    fn no_show_count(&self, id: &Uuid) -> Option<i64> {
+        self.no_shows.get(id).copied()
+    }
+
+    // 列の色・アイコン (列が2つ以上ある時だけ使う)
+    fn queue_of(&self, id: &i32) -> Option<&Queue> {
+        self.queues.iter().find(|q| q.id == *id).filter(|_| self.queues.len() > 1)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin_credentials.html")]
+struct AdminCredentialsTemplate {
+    groups: Vec<CredentialGroup>,
+    allowed: bool, // 変更できるか (責任者のみ)
+    created: Option<(&'static str, String)>, // 発行した直後だけ表示する (種類の表示名, キー)
+    flash: Option<Flash>,
+}
+
+// 種類ごとの一覧
+struct CredentialGroup {
+    kind: &'static str,
+    label: &'static str,
+    entries: Vec<CredentialEntry>,
+}
+
+struct CredentialEntry {
+    id: Option<i32>,       // Secrets の値で、まだ期限をつけていなければ None
+    label: String,
+    from_config: bool,
+    created: Option<String>, // 表示用の追加した日時 (Secrets の値は None)
+    retire_at: Option<String>, // 表示用の期限 (店舗の現地時刻)
+    retired: bool,           // 期限を過ぎて、もう受け付けない
+}
+
+#[derive(Template)]
+#[template(path = "admin_sessions.html")]
+struct AdminSessionsTemplate {
+    sessions: Vec<(AdminSessionRow, String, String)>, // (セッション, 表示用のログイン時刻, 表示用の最終操作時刻)
+    current: Option<Uuid>, // この端末のセッション
+    idle_minutes: i64,
+    max_hours: i64,
+    flash: Option<Flash>,
+}
+
+#[derive(Template)]
+#[template(path = "admin_webhooks.html")]
+struct AdminWebhooksTemplate {
+    configured: bool, // TICKET_WEBHOOK_URL を設定しているか
+    deliveries: Vec<WebhookDeliveryView>,
+    problems_only: bool, // 届いていないものだけを表示
+    pending: i64,
+    failed: i64,
+    flash: Option<Flash>,
+}
+
+#[derive(Template)]
+#[template(path = "admin_counters.html")]
+struct AdminCountersTemplate {
+    counters: Vec<Counter>,
+    queues: Vec<Queue>,
+    error: Option<String>,
+    flash: Option<Flash>,
+}
+
+impl AdminCountersTemplate {
+    // 担当する列の表示 (空ならすべての列)
+    fn queue_names(&self, counter: &Counter) -> String {
+        if counter.queue_ids.is_empty() {
+            return "すべての列".to_string();
+        }
+        self.queues
+            .iter()
+            .filter(|q| counter.queue_ids.contains(&q.id))
+            .map(|q| q.name.as_str())
+            .collect::<Vec<_>>()
+            .join("・")
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin_queues.html")]
+struct AdminQueuesTemplate {
+    queues: Vec<QueueRow>,
+    error: Option<String>,
+    flash: Option<Flash>,
+}
+
+#[derive(Template)]
+#[template(path = "join_deposit.html")]
+struct JoinDepositTemplate {
+    status: String, // pending, paid, expired など
+    amount: i32,
+}
+
+#[derive(Template)]
+#[template(path = "ticket_qr.html")]
+struct TicketQrTemplate {
+    ticket: Ticket,
+    url: String,
+    qr_code: String,
+    expires_at: String, // リンクの有効期限 (店舗の現地時刻)
+    queue: Option<Queue>, // 並んでいる列 (名前と、色・アイコン)
+    issued_at: String,  // 発券時刻 (店舗の現地時刻)
+    lookup_url: String, // QRコードを読めないお客様向けに、番号で開けるページ
+    print: bool,        // 開いたらすぐ印刷ダイアログを出す (呼び出し画面の「再印刷」)
+    store_name: String, // 空なら出さない
+    custom_slip: Option<String>, // 店舗で差し替えた印刷レイアウト (/admin/templates)
+}
+
+#[derive(Template)]
+#[template(path = "admin_tables.html")]
+struct AdminTablesTemplate {
+    tables: Vec<TableRow>,
+    error: Option<String>,
+    flash: Option<Flash>,
+}
+
+#[derive(Template)]
+#[template(path = "admin_settings.html")]
+struct AdminSettingsTemplate {
+    settings: Settings,
+    saved: bool,
+}
+
+#[derive(Template)]
+#[template(path = "guest.html")]
+struct GuestTemplate {
+    ticket: Ticket,
+    stage: Option<CustomStatus>, // 店舗独自の状態 (あれば待機中・呼出中の代わりに表示する)
+    waiting_count: i64,
+    called_ahead: i64,          // 前にいる呼出中の組数 (設定で含める場合のみ。含めなければ0)
+    table_name: Option<String>, // 案内先のテーブル
+    counter_name: Option<String>, // 呼び出した窓口
+    estimate: Option<CallEstimate>, // 呼び出し時刻の見込み (待機中のみ)
+    now_serving: Option<i32>,     // 並んでいる列の、ただいまご案内中の番号
+    custom_body: Option<String>,  // 店舗で差し替えた本文 (/admin/templates)
+    called_message: Option<String>, // 店舗で差し替えた呼び出し時の案内文
+    sig: String,                  // この画面のリンクの署名 (自動更新・チェックインで引き継ぐ)
+    other_ticket: Option<Ticket>, // 同じ端末で既に持っている有効な整理券（警告用）
+    other_link: Option<String>,
+    self_checkin: bool,           // 到着確認コードによるセルフチェックインが使えるか
+    checkin_failed: bool,         // 直前のセルフチェックインが失敗した
+    resize_message: Option<(bool, String)>, // 直前の人数変更の結果 (エラーか, メッセージ)
+    handoff_error: Option<String>, // 直前の受け渡しが失敗した理由
+    can_rename: bool,             // 予約から発券した整理券なら、受け渡しの時に予約者名を変えられる
+    can_resize: bool,             // 運用設定で人数の変更を許しているか
+    can_handoff: bool,            // 運用設定で受け渡しを許しているか
+    maintenance: Option<String>,  // メンテナンス中の説明
+    min_group_size: i32,          // 人数変更の入力範囲
+    max_group_size: i32,
+    poll_seconds: i64,            // 自動更新の間隔
+}
+
+// 受け渡し用の新しいリンク (譲る人の画面に一度だけ出す)
+#[derive(Template)]
+#[template(path = "guest_handoff.html")]
+struct GuestHandoffTemplate {
+    ticket: Ticket,
+    url: String,
+    qr_code: String,
+    expires_at: String,
+}
+
+#[derive(Template)]
+#[template(path = "staff_invite.html")]
+struct StaffInviteTemplate {
+    invite: Option<StaffInvite>, // 使えない (期限切れ・使用済み・取り消し) リンクなら None
+    sig: String,
+    error: Option<String>,
+    done: bool, // 登録が済んだ
+}
+
+#[derive(Template)]
+#[template(path = "lookup.html")]
+struct LookupTemplate {
+    queues: Vec<Queue>, // 2つ以上なら列を選んでもらう
+    error: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "join.html")]
+struct JoinTemplate {
+    phone_required: bool,
+    error: Option<String>,
+    paused: Option<String>, // 待ち時間が長く、新しい受付を止めている時の説明
+}
+
+#[derive(Template)]
+#[template(path = "join_verify.html")]
+struct JoinVerifyTemplate {
+    id: Uuid,
+    error: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "guest_blocked.html")]
+struct GuestBlockedTemplate {
+    other_ticket: Ticket,
+    other_link: String,
+}
+
+#[derive(Template)]
+#[template(path = "guest_content.html")]
+struct GuestContentTemplate {
+    ticket: Ticket,
+    stage: Option<CustomStatus>,
+    waiting_count: i64,
+    called_ahead: i64,
+    table_name: Option<String>, // 案内先のテーブル
+    counter_name: Option<String>, // 呼び出した窓口
+    estimate: Option<CallEstimate>,
+    now_serving: Option<i32>,     // 並んでいる列の、ただいまご案内中の番号
+    custom_body: Option<String>,  // 店舗で差し替えた本文 (/admin/templates)
+    called_message: Option<String>, // 店舗で差し替えた呼び出し時の案内文
+}
+
+// --- ヘルパー ---
+struct HtmlTemplate<T>(T);
+impl<T: Template> IntoResponse for HtmlTemplate<T> {
+    fn into_response(self) -> Response {
+        match self.0.render() {
+            Ok(html) => Html(html).into_response(),
+            Err(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to render template: {}", err),
+            )
+                .into_response(),
+        }
+    }
+}
+
+// QRコードSVG変換関数
+fn to_svg_string(qr: &QrCode, border: i32) -> String {
+    let mut res = String::new();
+    let dim = qr.size();
+    let brd = border;
+    let width = dim + brd * 2;
+    use std::fmt::Write;
+    let _ = write!(res, "<svg xmlns=\"http://www.w3.org/2000/svg\" version=\"1.1\" viewBox=\"0 0 {0} {0}\" stroke=\"none\">", width);
+    res.push_str("<rect width=\"100%\" height=\"100%\" fill=\"#FFFFFF\"/>");
+    res.push_str("<path d=\"");
+    for y in 0..dim {
+        for x in 0..dim {
+            if qr.get_module(x, y) {
+                let _ = write!(res, "M{},{}h1v1h-1z ", x + brd, y + brd);
+            }
+        }
+    }
+    res.push_str("\" fill=\"#000000\"/></svg>");
+    res
+}
+
+// --- 署名付きCookie ---
+// 値の後ろに HMAC-SHA256 の署名を付けて改ざんを検出する ("値.署名")
+const DEVICE_COOKIE: &str = "qt_device";
+
+fn hmac_hex(key: &[u8], value: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(value.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn sign_cookie_value(key: &[u8], value: &str) -> String {
+    format!("{}.{}", value, hmac_hex(key, value))
+}
+
+fn verify_cookie_value(key: &[u8], signed: &str) -> Option<String> {
+    let (value, signature) = signed.rsplit_once('.')?;
+    let signature = hex::decode(signature).ok()?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(value.as_bytes());
+    // verify_slice は定数時間比較
+    mac.verify_slice(&signature).ok()?;
+    Some(value.to_string())
+}
+
+// 署名付きCookieの Set-Cookie ヘッダー値を作る (max_age = 0 で削除)
+fn signed_cookie(state: &AppState, name: &str, value: &str, max_age: i64, same_site: &str) -> String {
+    let secure = if state.base_url.starts_with("https://") { "; Secure" } else { "" };
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite={}{}",
+        name,
+        sign_cookie_value(&state.cookie_key, value),
+        max_age,
+        same_site,
+        secure
+    )
+}
+
+// Cookieヘッダーから指定した名前の値を取り出す
+fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+// --- フラッシュメッセージ ---
+// リダイレクトした先の画面に1回だけ出すメッセージ。署名付きCookieで渡し、表示したら消す
+const FLASH_COOKIE: &str = "qt_flash";
+
+#[derive(Clone)]
+struct Flash {
+    error: bool, // true ならエラー、false なら完了のお知らせ
+    message: String,
+}
+
+impl Flash {
+    fn notice(message: &str) -> Self {
+        Flash { error: false, message: message.to_string() }
+    }
+
+    fn error(message: &str) -> Self {
+        Flash { error: true, message: message.to_string() }
+    }
+}
+
+// メッセージをCookieに入れてリダイレクトする
+// HTMXからの操作では画面の一部しか書き換えないので、画面ごと読み直させてメッセージを出す
+fn flash_redirect(state: &AppState, headers: &HeaderMap, path: &str, flash: Flash) -> Response {
+    // Cookieの値に使えない文字があるので、本文は Base64 にする
+    let value = format!("{}:{}", if flash.error { "error" } else { "notice" }, BASE64_URL_SAFE_NO_PAD.encode(&flash.message));
+    let cookie = signed_cookie(state, FLASH_COOKIE, &value, 60, "Lax");
+    if headers.contains_key("HX-Request") {
+        return ([(SET_COOKIE, cookie)], [("HX-Refresh", "true")]).into_response();
+    }
+    ([(SET_COOKIE, cookie)], Redirect::to(&state.url(path))).into_response()
+}
+
+fn read_flash(state: &AppState, headers: &HeaderMap) -> Option<Flash> {
+    let value = get_cookie(headers, FLASH_COOKIE).and_then(|v| verify_cookie_value(&state.cookie_key, v))?;
+    let (kind, message) = value.split_once(':')?;
+    let message = String::from_utf8(BASE64_URL_SAFE_NO_PAD.decode(message).ok()?).ok()?;
+    Some(Flash { error: kind == "error", message })
+}
+
+// メッセージを出した画面を返す時に、Cookieを消す
+fn clear_flash(state: &AppState, flash: &Option<Flash>, page: impl IntoResponse) -> Response {
+    let mut res = page.into_response();
+    if flash.is_some() {
+        if let Ok(cookie) = axum::http::HeaderValue::from_str(&signed_cookie(state, FLASH_COOKIE, "", 0, "Lax")) {
+            res.headers_mut().append(SET_COOKIE, cookie);
+        }
+    }
+    res
+}
+
+// --- トレース (OpenTelemetry) ---
+// ログは標準出力へ。OTLP_ENDPOINT を設定すると、リクエストごとのトレースを
+// Tempo / Jaeger などへ送る。sqlx は実行したSQLと所要時間をイベントとして出すので、
+// トレース側では DEBUG まで拾い、どのクエリが遅いかをリクエストの中で見られるようにする
+fn init_tracing(otlp_endpoint: Option<&str>, metrics: &Metrics) {
+    // クエリの所要時間の集計は、ログの設定に関係なく常に行う
+    let metrics_layer = QueryMetricsLayer { metrics: metrics.clone() }
+        .with_filter(EnvFilter::new("sqlx::query=debug"));
+
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")));
+
+    let otel_layer = otlp_endpoint.map(|endpoint| {
+        use opentelemetry::trace::TracerProvider as _;
+        use opentelemetry_otlp::WithExportConfig;
+
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .expect("Failed to build OTLP exporter");
+        let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(opentelemetry_sdk::Resource::builder().with_service_name("queueticket").build())
+            .build();
+        let tracer = provider.tracer("queueticket");
+        opentelemetry::global::set_tracer_provider(provider);
+
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(EnvFilter::new("info,queueticket=debug,sqlx::query=debug"))
+    });
+
+    tracing_subscriber::registry().with(fmt_layer).with(otel_layer).with(metrics_layer).init();
+}
+
+// リクエストのスパン。クエリ文字列 (来場者リンクの署名など) は記録しない
+fn request_span(req: &axum::http::Request<axum::body::Body>) -> tracing::Span {
+    let route = req
+        .extensions()
+        .get::<axum::extract::MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        otel.name = %format!("{} {}", req.method(), route),
+        http.request.method = %req.method(),
+        http.route = %route,
+        url.path = %req.uri().path(),
+    )
+}
+
+// --- 組み込み用の設定とルーター ---
+// 単体で動かす時は src/main.rs が Secrets.toml から作る
+// 他の Axum アプリに組み込む時は、同じキーを環境変数などから from_settings で読み込み、返されたルーターを nest する
+pub struct Config {
+    base_path: String, // 組み込む時のパス (例: "/queue")。単体で動かす時は空
+    static_dir: PathBuf, // CSS・アイコンなどを置いたディレクトリ
+    slow_query_ms: u64,
+    otlp_endpoint: Option<String>,
+    metrics: Metrics,
+    base_url: String,
+    admin_password: String,
+    cookie_key: Vec<u8>,
+    device_guard: DeviceGuardMode,
+    page_size: i64,
+    chime_url: Option<String>,
+    highlight_seconds: i64,
+    display_key: Option<String>,
+    display_waiting_count: i64,
+    sms_webhook_url: Option<String>,
+    mqtt: Option<MqttConfig>,
+    webhook: Option<WebhookConfig>,
+    reservation_webhook_key: Option<String>,
+    calendar_feed_key: Option<String>,
+    reservation_sources: Result<HashMap<String, ReservationMapping>, String>, // 読めなかった時は、ログの準備ができてから記録する
+    sheets: Option<Result<SheetsConfig, String>>, // 鍵のJSONが読めなかった時は、ログの準備ができてから記録する
+    backup: Option<Arc<BackupConfig>>,
+    stripe: Option<StripeConfig>,
+    digest_mail: Option<Arc<DigestMailConfig>>,
+    guest_link_hours: i64,
+    metrics_token: Option<String>,
+    events_api_key: Option<String>,
+    widget_origins: Vec<axum::http::HeaderValue>,
+    pos_api_key: Option<String>,
+    security_headers: SecurityHeaders,
+    trusted_proxy_hops: usize,
+    route_policy: RoutePolicy,
+    hooks: Vec<Box<dyn TicketHook>>,
+    store_code: String,
+    migration_guard: MigrationGuard,
+}
+
+impl Config {
+    // Shuttle の Secrets.toml から作る (単体で動かす時)
+    pub fn from_secrets(secret_store: &SecretStore) -> Self {
+        Self::from_lookup(&|key| secret_store.get(key))
+    }
+
+    // Secrets.toml と同じキーと値の組から作る (Shuttle を使わずに組み込む時)
+    // 例: Config::from_settings(std::env::vars()) で環境変数から読む
+    pub fn from_settings<K: Into<String>, V: Into<String>>(settings: impl IntoIterator<Item = (K, V)>) -> Self {
+        let settings: HashMap<String, String> = settings.into_iter().map(|(k, v)| (k.into(), v.into())).collect();
+        Self::from_lookup(&|key| settings.get(key).cloned())
+    }
+
+    fn from_lookup(get: &dyn Fn(&str) -> Option<String>) -> Self {
+        // 遅いクエリとして警告する時間 (既定は500ミリ秒)
+        let slow_query_ms = get("SLOW_QUERY_MS")
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(500);
+        let metrics = Metrics { slow_query_seconds: slow_query_ms as f64 / 1000.0, ..Default::default() };
+
+        // 設定取得
+        let base_url = get("BASE_URL")
+            .unwrap_or_else(|| "http://localhost:8000".to_string());
+
+        let admin_password = get("ADMIN_PASSWORD")
+            .expect("ADMIN_PASSWORD must be set");
+
+        // Cookie署名用の鍵 (未設定なら管理者パスワードから流用)
+        let cookie_key = get("COOKIE_SECRET")
+            .unwrap_or_else(|| admin_password.clone())
+            .into_bytes();
+
+        // (任意) 完了した整理券を Google スプレッドシートに書き出す。IDと鍵の両方がそろった時だけ有効
+        let sheets = match (
+            get("GOOGLE_SHEETS_ID").filter(|v| !v.is_empty()),
+            get("GOOGLE_SERVICE_ACCOUNT_JSON").filter(|v| !v.is_empty()),
+        ) {
+            (Some(spreadsheet_id), Some(json)) => Some(
+                serde_json::from_str::<ServiceAccountKey>(&json)
+                    .map(|key| SheetsConfig {
+                        spreadsheet_id,
+                        range: get("GOOGLE_SHEETS_RANGE")
+                            .filter(|v| !v.is_empty())
+                            .unwrap_or_else(|| "Sheet1!A:H".to_string()),
+                        key,
+                    })
+                    .map_err(|e| e.to_string()),
+            ),
+            _ => None,
+        };
+
+        // (任意) 毎晩、S3互換ストレージへバックアップする。バケットと鍵がそろった時だけ有効
+        let backup = match (
+            get("BACKUP_BUCKET").filter(|v| !v.is_empty()),
+            get("BACKUP_ACCESS_KEY").filter(|v| !v.is_empty()),
+            get("BACKUP_SECRET_KEY").filter(|v| !v.is_empty()),
+        ) {
+            (Some(bucket), Some(access_key), Some(secret_key)) => Some(Arc::new(BackupConfig {
+                bucket,
+                endpoint: get("BACKUP_ENDPOINT").filter(|v| !v.is_empty()),
+                region: get("BACKUP_REGION")
+                    .filter(|v| !v.is_empty())
+                    .unwrap_or_else(|| "auto".to_string()),
+                access_key,
+                secret_key,
+                prefix: get("BACKUP_PREFIX").unwrap_or_else(|| "queueticket/".to_string()),
+                hour: get("BACKUP_HOUR").and_then(|v| v.parse().ok()).unwrap_or(4),
+            })),
+            _ => None,
+        };
+
+        // (任意) 大人数のリモート受付のデポジット決済。両方そろった時だけ有効
+        let stripe = match (
+            get("STRIPE_SECRET_KEY").filter(|v| !v.is_empty()),
+            get("STRIPE_WEBHOOK_SECRET").filter(|v| !v.is_empty()),
+        ) {
+            (Some(secret_key), Some(webhook_secret)) => Some(StripeConfig { secret_key, webhook_secret }),
+            _ => None,
+        };
+
+        // (任意) 週ごとの集計をメールで送るゲートウェイと宛先 (カンマ区切り)。両方そろった時だけ有効
+        let digest_mail = match (
+            get("DIGEST_MAIL_WEBHOOK_URL").filter(|v| !v.is_empty()),
+            get("DIGEST_MAIL_TO").map(|v| {
+                v.split(',').map(|to| to.trim().to_string()).filter(|to| !to.is_empty()).collect::<Vec<_>>()
+            }),
+        ) {
+            (Some(url), Some(to)) if !to.is_empty() => Some(Arc::new(DigestMailConfig { url, to })),
+            _ => None,
+        };
+
+        Config {
+            base_path: String::new(),
+            // CSS・アイコンなどのディレクトリ (既定は実行時のカレントディレクトリの static/)
+            static_dir: get("STATIC_DIR").filter(|v| !v.is_empty()).map(PathBuf::from).unwrap_or_else(|| PathBuf::from("static")),
+            slow_query_ms,
+            // (任意) トレースの送信先 (例: http://localhost:4318/v1/traces)
+            otlp_endpoint: get("OTLP_ENDPOINT").filter(|v| !v.is_empty()),
+            metrics,
+            base_url,
+            admin_password,
+            cookie_key,
+            // 1端末1枚ガード: "off" / "warn" / "block" (既定は warn)
+            device_guard: DeviceGuardMode::from_setting(get("DEVICE_GUARD_MODE")),
+            // 一覧画面の1ページあたりの件数 (既定は50件)
+            page_size: get("PAGE_SIZE")
+                .and_then(|v| v.parse::<i64>().ok())
+                .filter(|v| *v > 0)
+                .unwrap_or(50),
+            // 呼び出し時のチャイム音 (未設定ならブラウザで電子音を鳴らす)
+            chime_url: get("CHIME_URL").filter(|v| !v.is_empty()),
+            // 呼び出し直後のチケットを強調表示する秒数 (既定は30秒)
+            highlight_seconds: get("HIGHLIGHT_SECONDS")
+                .and_then(|v| v.parse::<i64>().ok())
+                .unwrap_or(30),
+            // 表示用モニターの端末キー (設定すると管理者パスワードなしで /display?key=... を開ける)
+            display_key: get("DISPLAY_KEY").filter(|v| !v.is_empty()),
+            // 表示用モニターに出す「次の待機中」の件数 (既定は5件)
+            display_waiting_count: get("DISPLAY_WAITING_COUNT")
+                .and_then(|v| v.parse::<i64>().ok())
+                .unwrap_or(5),
+            // (任意) SMS送信ゲートウェイ。{"to": 電話番号, "message": 本文} をPOSTする
+            sms_webhook_url: get("SMS_WEBHOOK_URL").filter(|v| !v.is_empty()),
+            // (任意) 予約サービスからの Webhook (/api/reservations/{サービス名}) のキーと、サービスごとの項目の場所
+            reservation_webhook_key: get("RESERVATION_WEBHOOK_KEY").filter(|v| !v.is_empty()),
+            reservation_sources: reservation_sources(get("RESERVATION_SOURCES")),
+            // (任意) 予約カレンダー (/calendar.ics?key=...) のキー。管理画面の「パスワード・APIキーの切り替え」からも追加できる
+            calendar_feed_key: get("CALENDAR_FEED_KEY").filter(|v| !v.is_empty()),
+            // (任意) 発券・呼び出し・完了・取り消しを外部システム (POSなど) へ送る Webhook
+            webhook: get("TICKET_WEBHOOK_URL").filter(|v| !v.is_empty()).map(|url| WebhookConfig {
+                url,
+                secret: get("TICKET_WEBHOOK_SECRET").filter(|v| !v.is_empty()),
+            }),
+            // (任意) 呼び出した番号を MQTT で配信する (ブローカーのホストを設定した時だけ有効)
+            mqtt: get("MQTT_HOST").filter(|v| !v.is_empty()).map(|host| MqttConfig {
+                host,
+                port: get("MQTT_PORT").and_then(|v| v.parse().ok()).unwrap_or(1883),
+                username: get("MQTT_USERNAME").filter(|v| !v.is_empty()),
+                password: get("MQTT_PASSWORD").filter(|v| !v.is_empty()),
+                topic: get("MQTT_TOPIC")
+                    .filter(|v| !v.is_empty())
+                    .unwrap_or_else(|| "queueticket/called".to_string()),
+                tls: get("MQTT_TLS").is_some_and(|v| v == "true"),
+            }),
+            sheets,
+            backup,
+            stripe,
+            digest_mail,
+            // 来場者画面のリンク (QRコード) の有効時間 (既定は24時間)
+            guest_link_hours: get("GUEST_LINK_HOURS")
+                .and_then(|v| v.parse::<i64>().ok())
+                .filter(|v| *v > 0)
+                .unwrap_or(24),
+            // (任意) 監視システムから /metrics を取得するためのトークン
+            metrics_token: get("METRICS_TOKEN").filter(|v| !v.is_empty()),
+            // (任意) 分析基盤が /api/v1/events からイベントを取り込むためのキー
+            events_api_key: get("EVENTS_API_KEY").filter(|v| !v.is_empty()),
+            // (任意) 待ち状況ウィジェット (/widget.json) を読み込んでよいサイト (カンマ区切り)
+            widget_origins: get("WIDGET_ALLOWED_ORIGINS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|v| v.trim())
+                .filter(|v| !v.is_empty())
+                .filter_map(|v| axum::http::HeaderValue::from_str(v).ok())
+                .collect(),
+            // (任意) POSからの着席・会計の連携に使うAPIキー
+            pos_api_key: get("POS_API_KEY").filter(|v| !v.is_empty()),
+            security_headers: SecurityHeaders::from_lookup(get),
+            // 手前にある信頼できるプロキシの段数 (Shuttle では1段。直接受ける時は0で、接続元のアドレスを使う)
+            trusted_proxy_hops: get("TRUSTED_PROXY_HOPS")
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(1),
+            // (任意) 画面・操作ごとに使える担当者の役割 (既定の決まりに追加・上書きする)
+            route_policy: RoutePolicy::from_lookup(get),
+            hooks: Vec::new(),
+            // (任意) フランチャイズの本部が店舗を見分けるコード。利用量のCSVに入る
+            store_code: get("STORE_CODE").map(|v| v.trim().to_string()).unwrap_or_default(),
+            migration_guard: MigrationGuard::from_lookup(get),
+        }
+        .with_base_path(&get("BASE_PATH").unwrap_or_default())
+    }
+
+    // 組み込む先のパス (例: "/queue")。画面内のリンクやリダイレクト先の先頭につく
+    pub fn with_base_path(mut self, path: &str) -> Self {
+        self.base_path = path.trim_end_matches('/').to_string();
+        self
+    }
+
+    // CSS・アイコンなどを置いたディレクトリ。組み込み先のカレントディレクトリに依らず配信できるよう、絶対パスで渡す
+    pub fn with_static_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.static_dir = dir.into();
+        self
+    }
+
+    // 整理券のフックを登録する (登録した順に呼ばれる)
+    pub fn with_hook(mut self, hook: impl TicketHook + 'static) -> Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    // ログとトレースの設定 (単体で動かす時用。組み込む側のアプリが自分で設定している場合は呼ばない)
+    pub fn init_tracing(&self) {
+        init_tracing(self.otlp_endpoint.as_deref(), &self.metrics);
+    }
+}
+
+pub struct QueueTicketApp {
+    // 画面・API。組み込む時は Config の base_path に nest する
+    pub router: Router,
+    // gRPC (番号表示器など)。パスが /queueticket.v1.Tickets/... で決まっているので、組み込み先のルートに merge する
+    pub grpc: Router,
+}
+
+impl QueueTicketApp {
+    // マイグレーションを適用し、定期処理を起動してからルーターを返す
+    pub async fn build(config: Config, pool: PgPool) -> Self {
+        // 遅いクエリは sqlx 自身に WARN で記録させる (以降に開く接続から有効)
+        let connect_options = (*pool.connect_options())
+            .clone()
+            .log_slow_statements(log::LevelFilter::Warn, Duration::from_millis(config.slow_query_ms));
+        pool.set_connect_options(connect_options);
+
+        // データを消す変更が未適用なら、確認されるまで起動しない (MIGRATIONS_CONFIRM)
+        let migrator = migrator();
+        config.migration_guard.check(&migrator, &pool).await;
+        migrator.run(&pool).await.expect("Migrations failed");
+
+        let sheets = match config.sheets {
+            Some(Ok(sheets)) => Some(sheets),
+            Some(Err(e)) => {
+                tracing::error!("GOOGLE_SERVICE_ACCOUNT_JSON is invalid: {}", e);
+                None
+            }
+            None => None,
+        };
+        let reservation_sources = match config.reservation_sources {
+            Ok(sources) => sources,
+            Err(e) => {
+                tracing::error!("RESERVATION_SOURCES is invalid: {}", e);
+                reservation_sources(None).unwrap_or_default()
+            }
+        };
+        for rule in &config.route_policy.invalid {
+            tracing::error!("ADMIN_ROUTE_POLICY has an invalid rule (ignored): {:?}", rule);
+        }
+
+        // 呼び出した番号の MQTT 配信も、フックの1つとして登録する
+        let mut hooks = config.hooks;
+        if let Some(mqtt) = config.mqtt {
+            hooks.push(Box::new(MqttHook::connect(mqtt)));
+        }
+        // 外部システムへの Webhook も、フックの1つとして登録する (送り直しは webhook_worker)
+        let http = reqwest::Client::new();
+        let webhook = config.webhook.map(Arc::new);
+        if let Some(webhook) = &webhook {
+            hooks.push(Box::new(WebhookHook { pool: pool.clone(), http: http.clone(), config: webhook.clone() }));
+        }
+
+        // リアルタイム通知用チャネル (live_event_hub が DB の通知を流し込み、受信側は各画面のSSE接続ごとに作る)
+        let (live, _) = broadcast::channel(100);
+
+        // Stateの初期化
+        let credentials = Credentials::new(
+            &config.cookie_key,
+            &[
+                ("admin", Some(config.admin_password.as_str())),
+                ("pos", config.pos_api_key.as_deref()),
+                ("metrics", config.metrics_token.as_deref()),
+                ("reservations", config.reservation_webhook_key.as_deref()),
+                ("calendar", config.calendar_feed_key.as_deref()),
+                ("events", config.events_api_key.as_deref()),
+            ],
+        );
+        credentials.reload(&pool).await;
+
+        let state = AppState {
+            pool,
+            base_url: config.base_url,
+            base_path: Arc::from(config.base_path.as_str()),
+            credentials,
+            cookie_key: config.cookie_key,
+            device_guard: config.device_guard,
+            page_size: config.page_size,
+            live,
+            chime_url: config.chime_url,
+            highlight_seconds: config.highlight_seconds,
+            display_key: config.display_key,
+            display_waiting_count: config.display_waiting_count,
+            http,
+            webhook,
+            reservation_sources: Arc::new(reservation_sources),
+            sms_webhook_url: config.sms_webhook_url,
+            stripe: config.stripe,
+            digest_mail: config.digest_mail,
+            guest_link_hours: config.guest_link_hours,
+            metrics: config.metrics.clone(),
+            snapshot: SnapshotCache::default(),
+            public_wait: PublicWaitCache::default(),
+            template_overrides: TemplateOverrideCache::default(),
+            security_headers: config.security_headers,
+            trusted_proxy_hops: config.trusted_proxy_hops,
+            route_policy: Arc::new(config.route_policy),
+            backup: config.backup,
+            hooks: hooks.into(),
+            store_code: config.store_code,
+            migrations_confirmed: config.migration_guard.confirmed,
+        };
+
+        // --- ルーティングの構築 ---
+
+        // 1. 公開エリア (ゲスト画面用) + ルートリダイレクト
+        let public_routes = Router::new()
+            .route("/", get(root_redirect))
+            .route("/guest/{id}", get(guest_page))
+            .route("/guest/{id}/content", get(guest_content))
+            .route("/guest/{id}/status", get(guest_status_json)) // 追加: 来場者の状態と位置 (JSON)
+            .route("/lookup", get(lookup_page).post(lookup_ticket)) // 追加: 整理番号から来場者画面を開く
+            .route("/reserve", get(reserve_page).post(reserve)) // 追加: 来場者による予約
+            .route("/reserve/{id}", get(reserve_status))
+            .route("/reserve/{id}/cancel", post(reserve_cancel))
+            .route("/join", get(join_page).post(join)) // 追加: 来店前のリモート受付
+            .route("/join/verify/{id}", get(verify_page).post(verify_phone))
+            .route("/join/deposit/{id}", get(deposit_status)) // 追加: デポジットの支払い確認
+            .route("/stripe/webhook", post(stripe_webhook))
+            .route("/calendar.ics", get(calendar_feed)) // 追加: 予約のカレンダー (キーはハンドラで確認する)
+            .route("/guest/{id}/checkin", post(guest_checkin))
+            .route("/guest/{id}/resize", post(guest_resize)) // 追加: お客様自身による人数の変更
+            .route("/guest/{id}/handoff", post(guest_handoff)) // 追加: お客様同士での整理券の受け渡し
+            .route("/observe/{id}", get(open_observer_link)) // 追加: 見るだけの共有リンク
+            .route("/invite/{id}", get(invite_page).post(accept_invite)); // 追加: スタッフの招待リンク
+
+        // 2. 管理者エリア (認証が必要)
+        let admin_routes = Router::new()
+            .route("/admin", get(admin_index))
+            .route("/admin/stats", get(admin_stats)) // 追加: 管理メニューの状況欄 (自動更新用)
+            .route("/admin/stats/polling/reset", post(reset_polling_stats)) // 追加: 自動更新の通信量の集計をやり直す
+            .route("/admin/forecast", get(forecast_page)) // 追加: 今日の残りの待ち組数・待ち時間の見通し
+            .route("/admin/digests", get(digests_page)) // 追加: 週ごとの集計
+            .route("/admin/notifications", get(notifications_page)) // 追加: スタッフ向けのお知らせ
+            .route("/admin/notifications/bell", get(notification_bell))
+            .route("/admin/notifications/read", post(mark_notifications_read))
+            .route("/admin/reset", get(reset_page).post(reset_db))
+            .route("/admin/reset/archives/{id}", get(download_reset_archive))
+            .route("/admin/audit", get(audit_page))
+            .route("/admin/maintenance", get(maintenance_page))
+            .route("/admin/maintenance/backup", post(start_backup))
+            .route("/admin/maintenance/mode", post(set_maintenance_mode)) // 追加: メンテナンスモードの切り替え
+            .route("/admin/maintenance/queue_state.json", get(export_queue_state)) // 追加: 列の状態の書き出し・取り込み (別の環境への移行)
+            .route("/admin/maintenance/queue_state", post(import_queue_state))
+            .route("/admin/maintenance/migrations", get(migrations_page)) // 追加: DBの構造の変更 (マイグレーション) の状況
+            .route("/admin/history", get(history_page))
+            .route("/admin/history/{id}", get(history_ticket))
+            .route("/admin/privacy", get(privacy_page))
+            .route("/admin/privacy/export", get(export_guest_data))
+            .route("/admin/privacy/delete", post(delete_guest_data))
+            .route("/admin/download_csv", get(download_csv)) // 追加: トラフィックダウンロード用
+            .route("/admin/front", get(front_page))
+            .route("/admin/front/tickets", post(create_ticket))
+            .route("/admin/call", get(call_page))
+            .route("/admin/call/list", get(call_list)) // 追加: 自動更新用エンドポイント
+            .route("/admin/call/{counter}", get(counter_call_page)) // 追加: 窓口専用の呼び出し画面
+            .route("/admin/call/update", post(update_status))
+            .route("/admin/call/stage", post(update_stage)) // 追加: 店舗独自の状態に進める
+            .route("/admin/call/complete_next", post(complete_and_call_next)) // 追加: 完了して次を呼ぶ
+            .route("/admin/call/checkin", post(staff_checkin)) // 追加: リモート受付の到着確認
+            .route("/admin/call/transfer", post(transfer_ticket)) // 追加: 列の移動
+            .route("/admin/call/tags", post(update_tags)) // 追加: 整理券のタグ
+            .route("/admin/call/note", post(update_note)) // 追加: 整理券のメモ
+            .route("/admin/call/filters", post(save_call_filter)) // 追加: スタッフごとの絞り込みの保存
+            .route("/admin/call/filters/{id}/delete", post(delete_call_filter))
+            .route("/admin/tickets/{id}/qr", get(ticket_qr)) // 追加: 整理券のQRコードの再表示
+            .route("/admin/tickets/{id}/rotate_link", post(rotate_guest_link))
+            .route("/admin/call/split", post(split_ticket)) // 追加: 1組を2組に分割
+            .route("/admin/call/merge", post(merge_tickets)) // 追加: 2組を1組に合流
+            .route("/admin/call/no_show", post(mark_no_show)) // 追加: 呼び出しても来なかったお客様
+            .route("/admin/call/delete", post(delete_ticket)) // 追加: 整理券の削除 (ゴミ箱へ)
+            .route("/admin/trash", get(trash_page)) // 追加: ゴミ箱
+            .route("/admin/onboarding", get(onboarding_page).post(save_onboarding)) // 追加: 新しい店舗の初期設定
+            .route("/admin/usage", get(usage_page)) // 追加: 月ごとの利用量 (フランチャイズ店への請求)
+            .route("/admin/usage.csv", get(download_usage_csv))
+            .route("/admin/templates", get(templates_page)) // 追加: 画面の差し替え (来場者画面・印刷レイアウト)
+            .route("/admin/templates/{name}", post(save_template_override))
+            .route("/admin/trash/{id}/restore", post(restore_ticket))
+            .route("/admin/call/events", get(live_events)) // 追加: リアルタイム通知 (SSE)
+            .route("/admin/settings", get(settings_page).post(update_settings)) // 追加: 運用設定
+            .route("/admin/queues", get(admin_queues).post(create_queue)) // 追加: 列の管理
+            .route("/admin/queues/{id}/rename", post(rename_queue))
+            .route("/admin/queues/{id}/numbering", post(update_queue_numbering)) // 追加: 整理番号の付け方
+            .route("/admin/queues/{id}/appearance", post(update_queue_appearance)) // 追加: 列の色とアイコン
+            .route("/admin/queues/{id}/delete", post(delete_queue))
+            .route("/admin/counters", get(admin_counters).post(create_counter)) // 追加: 受付窓口の管理
+            .route("/admin/counters/{id}/delete", post(delete_counter))
+            .route("/admin/devices", get(admin_devices).post(create_device)) // 追加: 店頭の端末の死活監視
+            .route("/admin/devices/{id}/config", post(update_device_config)) // 追加: 端末ごとの設定
+            .route("/admin/devices/{id}/delete", post(delete_device))
+            .route("/admin/devices/{id}/reissue", post(reissue_device_token))
+            .route("/admin/tables", get(admin_tables).post(create_table)) // 追加: テーブル管理
+            .route("/admin/tables/{id}/status", post(update_table_status))
+            .route("/admin/tables/{id}/delete", post(delete_table))
+            .route("/admin/reservations", get(admin_reservations)) // 追加: 予約枠の管理
+            .route("/admin/reservations/slots", post(create_slot))
+            .route("/admin/reservations/slots/{id}/delete", post(delete_slot))
+            .route("/admin/reservations/slots/{id}/book", post(admin_book))
+            .route("/admin/reservations/{id}/cancel", post(admin_cancel_reservation))
+            .route("/admin/reservations/{id}/queue", post(admin_queue_reservation))
+            .route("/admin/reservations/import", post(import_reservations)) // 追加: 予約台帳のCSVの読み込み
+            .route("/admin/staff", get(staff_page).post(create_staff))
+            .route("/admin/staff/switch", post(switch_staff)) // 追加: PINで担当者を切り替え
+            .route("/admin/staff/signout", post(signout_staff))
+            .route("/admin/staff/invites", post(create_invite)) // 追加: スタッフの招待リンク
+            .route("/admin/staff/invites/{id}/revoke", post(revoke_invite))
+            .route("/admin/theme", post(set_theme)) // 追加: スタッフ向け画面の配色
+            .route("/admin/observer_links", post(create_observer_link)) // 追加: 見るだけの共有リンク
+            .route("/admin/observer_links/{id}/revoke", post(revoke_observer_link))
+            .route("/admin/sessions", get(admin_sessions)) // 追加: ログイン中の端末の一覧
+            .route("/admin/credentials", get(admin_credentials).post(add_credential)) // 追加: パスワード・APIキーの切り替え
+            .route("/admin/credentials/retire", post(retire_credential))
+            .route("/admin/sessions/revoke_others", post(revoke_other_sessions))
+            .route("/admin/webhooks", get(admin_webhooks)) // 追加: Webhookの送信記録と送り直し
+            .route("/admin/webhooks/retry_failed", post(retry_failed_webhooks))
+            .route("/admin/webhooks/{id}/retry", post(retry_webhook))
+            .route("/admin/sessions/{id}/revoke", post(revoke_session))
+            .route("/admin/staff/{id}/deactivate", post(deactivate_staff))
+            // ここで認証ミドルウェアを適用
+            .route_layer(middleware::from_fn_with_state(state.clone(), auth));
+
+        let worker_state = state.clone();
+
+        // 3. 表示用モニター (端末キー または 管理者認証)
+        let display_routes = Router::new()
+            .route("/display", get(display_page))
+            .route("/display/content", get(display_content))
+            .route("/display/events", get(live_events))
+            .route_layer(middleware::from_fn_with_state(state.clone(), display_auth));
+
+        // 4. POS連携API (APIキーが必要)
+        let api_routes = Router::new()
+            .route("/api/pos/seated", post(pos_seated))
+            .route("/api/pos/completed", post(pos_completed))
+            .route_layer(middleware::from_fn_with_state(state.clone(), pos_auth));
+
+        // 4-2. 店頭の端末用API (端末ごとのトークンが必要。ハンドラで確認する)
+        let device_routes = Router::new()
+            .route("/api/devices/heartbeat", post(device_heartbeat))
+            .route("/api/devices/config", get(device_config))
+            .route("/api/devices/tickets", post(device_create_ticket)) // 追加: 発券機からの発券 (オフライン中の受付の送り直しを含む)
+            .route_layer(middleware::from_fn_with_state(state.clone(), device_signature_auth));
+
+        // 4.5 予約サービスからの Webhook (キーが必要)
+        let reservation_routes = Router::new()
+            .route("/api/reservations/{source}", post(receive_reservation))
+            .route_layer(middleware::from_fn_with_state(state.clone(), reservation_auth));
+
+        // 5. メトリクス (トークンまたは管理者認証が必要)
+        let metrics_routes = Router::new()
+            .route("/metrics", get(export_metrics))
+            .route_layer(middleware::from_fn_with_state(state.clone(), metrics_auth));
+
+        // 5-2. 分析基盤向けのイベント取得API (キーまたは管理者認証が必要)
+        let events_routes = Router::new()
+            .route("/api/v1/events", get(export_events))
+            .route_layer(middleware::from_fn_with_state(state.clone(), events_auth));
+
+        // 6. 待ち状況ウィジェット (他のサイトから読み込めるよう、許可したオリジンにCORSを返す)
+        let widget_routes = Router::new()
+            .route("/widget", get(widget_page))
+            .route("/widget.json", get(widget_json))
+            .layer(
+                CorsLayer::new()
+                    .allow_origin(AllowOrigin::list(config.widget_origins))
+                    .allow_methods([Method::GET]),
+            );
+
+        // 7. 公開の待ち時間API (認証なし。どのサイトからでも読み込める)
+        let public_api_routes = Router::new()
+            .route("/api/v1/wait", get(public_wait))
+            .route("/api/v1/client-config", get(client_config)) // 追加: 画面・アプリの自動更新の間隔
+            .layer(CorsLayer::new().allow_origin(Any).allow_methods([Method::GET]));
+
+        // 8. 静的ファイル (CSSなど)。URLに版をつけているので1年間キャッシュさせる
+        let static_files = SetResponseHeader::overriding(
+            ServeDir::new(&config.static_dir),
+            axum::http::header::CACHE_CONTROL,
+            axum::http::HeaderValue::from_static("public, max-age=31536000, immutable"),
+        );
+
+        // アイコンと robots.txt は決まったURLで読まれる (版をつけられない) ので、キャッシュは1日にする
+        let site_files = Router::new()
+            .route_service("/favicon.ico", site_file(config.static_dir.join("favicon.ico")))
+            .route_service("/apple-touch-icon.png", site_file(config.static_dir.join("apple-touch-icon.png")))
+            .route("/robots.txt", get(robots_txt));
+
+        // 9. 全体をマージ
+        let app = Router::new()
+            .merge(public_routes)
+            .merge(site_files)
+            .merge(admin_routes)
+            .merge(display_routes)
+            .merge(api_routes)
+            .merge(device_routes)
+            .merge(reservation_routes)
+            .merge(metrics_routes)
+            .merge(events_routes)
+            .merge(widget_routes)
+            .merge(public_api_routes)
+            .nest_service("/static", static_files)
+            .layer(middleware::from_fn_with_state(state.clone(), maintenance_guard))
+            .layer(middleware::from_fn_with_state(state.clone(), polling_metrics))
+            .layer(middleware::from_fn_with_state(state.clone(), security_headers))
+            .layer(middleware::from_fn_with_state(state.clone(), resolve_client_ip))
+            .layer(middleware::from_fn_with_state(state.clone(), scope_base_path))
+            .layer(CompressionLayer::new()) // 電波の悪い会場でも軽くなるよう、HTML等を圧縮して返す (SSEは対象外)
+            .layer(TraceLayer::new_for_http().make_span_with(request_span))
+            .with_state(state);
+
+        // 10. gRPC (番号表示器など。端末ごとのトークンが必要。サービスで確認する)
+        // パスが /queueticket.v1.Tickets/... なので、画面・API と同じポートで受けられる (機器からは HTTP/2 で接続する)
+        let grpc = tonic::service::Routes::new(TicketsServer::new(GrpcTickets { state: worker_state.clone() })).into_axum_router();
+
+        // 予約枠の時刻になった予約の整理券への変換や、デポジットの没収を定期的に行う
+        tokio::spawn(pool_metrics_worker(worker_state.pool.clone(), config.metrics));
+        tokio::spawn(live_event_hub(worker_state.clone()));
+        tokio::spawn(credentials_worker(worker_state.clone()));
+        if let Some(sheets) = sheets {
+            tokio::spawn(sheets_worker(worker_state.clone(), sheets));
+        }
+        if worker_state.backup.is_some() {
+            tokio::spawn(backup_worker(worker_state.clone()));
+        }
+        if let Some(webhook) = worker_state.webhook.clone() {
+            tokio::spawn(webhook_worker(worker_state.clone(), webhook));
+        }
+        tokio::spawn(background_worker(worker_state));
+
+        QueueTicketApp { router: app, grpc }
+    }
+
+    // 単体で動かす時用: 画面・API と gRPC を1つのルーターにまとめる
+    pub fn into_router(self) -> Router {
+        self.router.merge(self.grpc)
+    }
+}
+
+// --- 認証ミドルウェア (セキュリティ強化版) ---
+// Basic認証 ("Basic " + Base64("admin:password")) のパスワードを照合する
+fn is_admin(state: &AppState, headers: &HeaderMap) -> bool {
+    let password = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| BASE64_STANDARD.decode(v).ok())
+        .and_then(|v| String::from_utf8(v).ok());
+
+    match password.as_deref().and_then(|v| v.strip_prefix("admin:")) {
+        Some(password) => state.credentials.accepts("admin", password),
+        None => false,
+    }
+}
+
+// --- 見るだけのアクセス ---
+// 共有リンクで開いた端末と、見るだけの担当者が開ける画面 (GET のみ)
+const OBSERVER_PATHS: [&str; 7] = [
+    "/admin",
+    "/admin/stats",
+    "/admin/call",
+    "/admin/call/list",
+    "/admin/call/events",
+    "/admin/forecast",
+    "/admin/notifications/bell",
+];
+// 見るだけの担当者でも送信できるもの (自分の表示のための操作)
+const OBSERVER_POST_PATHS: [&str; 3] = ["/admin/staff/switch", "/admin/staff/signout", "/admin/theme"];
+// 見るだけの共有リンクで開いた端末に置くCookie (中身はリンクのID)
+const OBSERVER_COOKIE: &str = "qt_observer";
+// 共有リンクの有効期間の上限 (時間)
+const OBSERVER_LINK_MAX_HOURS: i64 = 168;
+
+fn is_observable(method: &Method, path: &str) -> bool {
+    if method != Method::GET && method != Method::HEAD {
+        return false;
+    }
+    // 窓口ごとの呼び出し画面 (/admin/call/{窓口のID})
+    let counter_page = path.strip_prefix("/admin/call/").is_some_and(|id| id.parse::<i32>().is_ok());
+    OBSERVER_PATHS.contains(&path) || counter_page
+}
+
+fn observer_forbidden() -> Response {
+    // 401 を返すとブラウザがBasic認証をやり直してしまうので 403 にする
+    (StatusCode::FORBIDDEN, "見るだけのアクセスでは、この画面は開けず、操作もできません。").into_response()
+}
+
+#[derive(Clone, FromRow)]
+struct ObserverLink {
+    id: Uuid,
+    label: String,
+    expires_at: DateTime<Utc>,
+}
+
+fn observer_sig(state: &AppState, id: Uuid) -> String {
+    hmac_hex(&state.cookie_key, &format!("observer:{}", id))[..32].to_string()
+}
+
+// 有効な (期限内で取り消していない) 共有リンクで開いた端末なら、そのリンク
+async fn observer_link(state: &AppState, headers: &HeaderMap) -> Option<ObserverLink> {
+    let id = get_cookie(headers, OBSERVER_COOKIE)
+        .and_then(|v| verify_cookie_value(&state.cookie_key, v))
+        .and_then(|v| Uuid::parse_str(&v).ok())?;
+    sqlx::query_as::<_, ObserverLink>(
+        "SELECT id, label, expires_at FROM observer_links WHERE id = $1 AND revoked_at IS NULL AND expires_at > NOW()"
+    )
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await
+    .unwrap_or(None)
+}
+
+// 画面の操作ボタンを隠すか (共有リンクで開いた端末か、見るだけの担当者に切り替えたことのあるセッション)
+async fn is_read_only(state: &AppState, headers: &HeaderMap, acting: Option<&User>) -> bool {
+    !is_admin(state, headers) || acting.is_some_and(|u| u.role == "observer") || session_read_only(state, headers).await
+}
+
+// 見るだけの担当者に切り替えたセッションか (担当者の選択を外しても見るだけのまま)
+async fn session_read_only(state: &AppState, headers: &HeaderMap) -> bool {
+    let Some(id) = admin_session_id(state, headers) else {
+        return false;
+    };
+    sqlx::query_scalar("SELECT read_only FROM admin_sessions WHERE id = $1")
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await
+        .unwrap_or(None)
+        .unwrap_or(false)
+}
+
+// --- 画面ごとの役割の制限 ---
+// 管理画面のどの画面・操作を、どの役割の担当者が使えるか。ADMIN_ROUTE_POLICY で店舗ごとに狭められる
+// 書式は "[メソッド ]パス=役割,役割; ..." (パスの末尾の * は前方一致)。当てはまる決まりがない画面は誰でも使える
+// 既定の決まりは各画面の中の「責任者だけ」のチェックと同じもの (画面の中のチェックは残るので、ここで広げることはできない)
+const DEFAULT_ROUTE_POLICY: &str =
+    "POST /admin/reset=manager; POST /admin/credentials*=manager; POST /admin/observer_links*=manager; POST /admin/staff/invites*=manager; \
+     /admin/maintenance/queue_state*=manager";
+const POLICY_ROLES: [(&str, &str); 3] = [("manager", "責任者"), ("staff", "スタッフ"), ("observer", "見るだけ")];
+
+#[derive(Clone)]
+struct PolicyRule {
+    method: Option<Method>, // None ならすべてのメソッド
+    path: String,
+    prefix: bool,
+    roles: Vec<String>,
+}
+
+impl PolicyRule {
+    fn parse(rule: &str) -> Option<Self> {
+        let (target, roles) = rule.split_once('=')?;
+        let target = target.trim();
+        let (method, path) = match target.split_once(char::is_whitespace) {
+            Some((method, path)) => (Some(Method::from_bytes(method.to_ascii_uppercase().as_bytes()).ok()?), path.trim()),
+            None => (None, target),
+        };
+        let (path, prefix) = match path.strip_suffix('*') {
+            Some(path) => (path, true),
+            None => (path, false),
+        };
+        if !path.starts_with("/admin") {
+            return None;
+        }
+        let roles: Vec<String> = roles.split(',').map(|r| r.trim().to_string()).filter(|r| !r.is_empty()).collect();
+        if roles.is_empty() || !roles.iter().all(|r| POLICY_ROLES.iter().any(|(key, _)| key == r)) {
+            return None;
+        }
+        Some(PolicyRule { method, path: path.to_string(), prefix, roles })
+    }
+
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        // GET の決まりは HEAD にも当てはめる
+        let method_matches = self.method.as_ref().is_none_or(|m| m == method || (*m == Method::GET && method == Method::HEAD));
+        method_matches && if self.prefix { path.starts_with(&self.path) } else { path == self.path }
+    }
+
+    // 1つのリクエストに複数の決まりが当たる時は、長いパス・完全一致・メソッドつきの順に優先する
+    fn specificity(&self) -> (usize, bool, bool) {
+        (self.path.len(), !self.prefix, self.method.is_some())
+    }
+}
+
+#[derive(Clone, Default)]
+struct RoutePolicy {
+    rules: Vec<PolicyRule>,
+    invalid: Vec<String>, // 読めなかった決まり (ログの準備ができてから記録する)
+}
+
+impl RoutePolicy {
+    fn from_lookup(get: &dyn Fn(&str) -> Option<String>) -> Self {
+        let mut policy = RoutePolicy::default();
+        policy.add(DEFAULT_ROUTE_POLICY);
+        if let Some(rules) = get("ADMIN_ROUTE_POLICY") {
+            policy.add(&rules);
+        }
+        policy
+    }
+
+    // 同じメソッド・パスの決まりは後から書いたもので置き換える
+    fn add(&mut self, rules: &str) {
+        for text in rules.split([';', '\n']).map(str::trim).filter(|r| !r.is_empty()) {
+            match PolicyRule::parse(text) {
+                Some(rule) => {
+                    self.rules.retain(|r| !(r.method == rule.method && r.path == rule.path && r.prefix == rule.prefix));
+                    self.rules.push(rule);
+                }
+                None => self.invalid.push(text.to_string()),
+            }
+        }
+    }
+
+    // 使える役割 (当てはまる決まりがなければ None で、誰でも使える)
+    fn roles_for(&self, method: &Method, path: &str) -> Option<&[String]> {
+        self.rules
+            .iter()
+            .filter(|r| r.matches(method, path))
+            .max_by_key(|r| r.specificity())
+            .map(|r| r.roles.as_slice())
+    }
+}
+
+fn policy_forbidden(roles: &[String], signed_in: bool) -> Response {
+    let labels: Vec<&str> = POLICY_ROLES.iter().filter(|(key, _)| roles.iter().any(|r| r == key)).map(|(_, label)| *label).collect();
+    let message = if signed_in {
+        format!("この画面・操作は{}の担当者だけが使えます。", labels.join("・"))
+    } else {
+        format!("この画面・操作は{}の担当者だけが使えます。担当者を選んでからやり直してください。", labels.join("・"))
+    };
+    // 401 を返すとブラウザがBasic認証をやり直してしまうので 403 にする
+    (StatusCode::FORBIDDEN, message).into_response()
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(axum::http::header::WWW_AUTHENTICATE, "Basic realm=\"Admin Area\"")],
+        "Unauthorized: Access Denied",
+    ).into_response()
+}
+
+#[derive(Deserialize)]
+struct DisplayKeyQuery {
+    key: Option<String>,
+    device: Option<String>, // 端末の管理に登録したトークン (あれば死活監視のハートビートを送る)
+}
+
+// 表示用モニターの認証: 端末キーが一致すれば通し、なければ管理者のBasic認証を求める
+// (壁掛けテレビなどで、管理者パスワードを入れずに無人で表示し続けられるようにする)
+async fn display_auth(
+    State(state): State<AppState>,
+    Query(query): Query<DisplayKeyQuery>,
+    req: Request,
+    next: Next,
+) -> impl IntoResponse {
+    let key_ok = match (&state.display_key, &query.key) {
+        (Some(expected), Some(given)) => constant_time_eq(expected.as_bytes(), given.as_bytes()),
+        _ => false,
+    };
+
+    if !key_ok && !is_admin(&state, req.headers()) {
+        return unauthorized();
+    }
+
+    next.run(req).await
+}
+// --- 来場者画面のリンク ---
+// UUIDだけでは、漏れたURLを後日でも使えてしまうので、
+// チケットごとのリンク発行時刻に対する署名をつけ、発行から一定時間で無効にする
+fn guest_sig(state: &AppState, ticket: &Ticket) -> String {
+    let value = format!("guest:{}:{}", ticket.id, ticket.link_issued_at.timestamp_micros());
+    // QRコードを細かくしすぎないよう、128bit分だけ使う
+    hmac_hex(&state.cookie_key, &value)[..32].to_string()
+}
+
+fn guest_path(state: &AppState, ticket: &Ticket) -> String {
+    state.url(&format!("/guest/{}?sig={}", ticket.id, guest_sig(state, ticket)))
+}
+
+fn guest_link_expires_at(state: &AppState, ticket: &Ticket) -> DateTime<Utc> {
+    ticket.link_issued_at + chrono::Duration::hours(state.guest_link_hours)
+}
+
+fn verify_guest_link(state: &AppState, ticket: &Ticket, sig: Option<&str>) -> bool {
+    let Some(sig) = sig else { return false };
+    Utc::now() < guest_link_expires_at(state, ticket)
+        && constant_time_eq(guest_sig(state, ticket).as_bytes(), sig.as_bytes())
+}
+
+// 署名が正しい場合だけチケットを返す (存在しないIDと区別できないようにする)
+#[tracing::instrument(skip(state, sig))]
+async fn fetch_guest_ticket(state: &AppState, id: Uuid, sig: Option<&str>) -> Option<Ticket> {
+    sqlx::query_as::<_, Ticket>("SELECT * FROM tickets WHERE id = $1")
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await
+        .unwrap_or(None)
+        .filter(|ticket| verify_guest_link(state, ticket, sig))
+}
+
+// 来場者画面の表示に必要なもの (チケット・前に待っている組数・案内先のテーブル名)
+#[derive(FromRow)]
+struct GuestView {
+    #[sqlx(flatten)]
+    ticket: Ticket,
+    waiting_count: i64,
+    called_count: i64, // 同じ列で呼出中 (案内中) の組数
+    table_name: Option<String>,
+    counter_name: Option<String>, // 呼び出した窓口
+}
+
+
+// 来場者画面に出す呼び出し時刻の見込み (表示のたびに計算し直す)
+#[derive(Serialize)]
+struct CallEstimate {
+    from: String, // 店舗の現地時刻 "HH:MM" (5分単位)
+    to: String,
+    minutes: i64, // 早い方の見込みまでの残り時間 (分)
+    slowing: bool, // 案内のペースが落ちていて、幅を広げている
+    closing: String, // 閉店時刻 "HH:MM" (未設定なら空)
+    after_closing: bool, // 早い方の見込みでも閉店時刻を過ぎる (時刻は出さず、今日のご案内が難しいかもしれないと伝える)
+    near_closing: bool,  // 遅い方の見込みだけが閉店時刻を過ぎる
+}
+
+impl CallEstimate {
+    fn new(settings: &Settings, snapshot: &QueueSnapshot, ticket: &Ticket, waiting_count: i64) -> Option<Self> {
+        // チェックイン前の組は呼ばれないので見込みを出さない
+        if ticket.status != "waiting" || ticket.needs_checkin() {
+            return None;
+        }
+        let now = Utc::now();
+        let (mut earliest, mut latest, slowing) = snapshot.call_window(ticket.queue_id, waiting_count, now)?;
+        // 開店前に受け付けた組は、開店してから呼び出しが始まる
+        if let Some(opening_at) = settings.opening_at(&now).filter(|at| *at > now) {
+            earliest += opening_at - now;
+            latest += opening_at - now;
+        }
+
+        // 細かすぎる時刻は約束のように見えるので5分単位に丸める (早い方は切り下げ、遅い方は切り上げ)
+        let step = 300;
+        let floor = DateTime::from_timestamp(earliest.timestamp() / step * step, 0)?;
+        let ceil = DateTime::from_timestamp((latest.timestamp() + step - 1) / step * step, 0)?;
+        // 閉店時刻を過ぎる見込みは、時刻を約束するより正直に伝える (発券画面の閉店までの見込みと同じ閉店時刻で判断する)
+        let closing_at = settings.closing_at(&now);
+        Some(CallEstimate {
+            from: settings.format_local(&floor, "%H:%M"),
+            to: settings.format_local(&ceil.max(floor), "%H:%M"),
+            minutes: (earliest - now).num_minutes().max(0),
+            slowing,
+            closing: closing_at.map(|at| settings.format_local(&at, "%H:%M")).unwrap_or_default(),
+            after_closing: closing_at.is_some_and(|at| earliest > at),
+            near_closing: closing_at.is_some_and(|at| earliest <= at && latest > at),
+        })
+    }
+}
+
+// 最も頻繁にポーリングされる画面なので、有効なチケットはスナップショットから返し、
+// 完了済みなどスナップショットにないものだけ1回のクエリでまとめて取得する
+// 優先度の高いチケット (予約からの発券) は番号に関係なく前に並ぶ
+#[tracing::instrument(skip(state, sig))]
+async fn fetch_guest_view(state: &AppState, id: Uuid, sig: Option<&str>) -> Option<GuestView> {
+    if let Some(view) = state.snapshot.get(&state.pool).await.guest_view(id) {
+        return Some(view).filter(|view| verify_guest_link(state, &view.ticket, sig));
+    }
+
+    sqlx::query_as::<_, GuestView>(
+        "SELECT t.*, w.waiting_count, cl.called_count, tb.name AS table_name, c.name AS counter_name 
+         FROM tickets t 
+         CROSS JOIN LATERAL (
+             SELECT COUNT(*) AS waiting_count FROM tickets o 
+             WHERE o.status = 'waiting' 
+               AND o.queue_id = t.queue_id -- 同じ列に並んでいる組だけを数える
+               AND o.is_test = t.is_test -- 練習用と本番は別に数える
+               AND (o.priority > t.priority 
+                    OR (o.priority = t.priority AND (o.created_at, o.id) < (t.created_at, t.id)))
+         ) w 
+         CROSS JOIN LATERAL (
+             SELECT COUNT(*) AS called_count FROM tickets o 
+             WHERE o.status = 'called' AND o.queue_id = t.queue_id AND o.is_test = t.is_test
+         ) cl 
+         LEFT JOIN tables tb ON tb.id = t.table_id 
+         LEFT JOIN counters c ON c.id = t.counter_id 
+         WHERE t.id = $1"
+    )
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await
+        .unwrap_or(None)
+        .filter(|view| verify_guest_link(state, &view.ticket, sig))
+}
+
+fn guest_link_invalid() -> Response {
+    AppError::TicketNotFound.html()
+}
+
+// --- エラー ---
+// JSON のAPIは RFC 7807 (application/problem+json) で返し、code で種類を見分けられるようにする
+// 画面 (ブラウザ) 向けには同じ種類を HTML で返す
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AppError {
+    Unauthorized,
+    InvalidRequest,    // JSON が読めない・項目が足りない
+    TicketNotFound,    // 整理券がない (来場者画面のリンクが無効な時も)
+    QueueNotFound,
+    InvalidTransition, // 整理券はあるが、今の状態からはできない操作 (既に案内済みなど)
+    QueueClosed,       // 受付を止めている (閉店に間に合わない見込みなど)
+    QueuePaused,       // 待ち時間の見込みが上限を超えて、新しい発券を自動で止めている
+    IdInUse,           // 発券機が決めたIDが、別の発券機の整理券で使われている
+    NotAKiosk,         // 発券機ではない端末からの発券
+    InvalidGroupSize,
+    InvalidQueue,      // この端末では発券できない列
+    RateLimited,
+    NotADisplay,        // 表示用モニター以外からの呼び出し・完了 (gRPC)
+    UnknownSource,      // 設定にない予約サービス
+    InvalidReservation, // 予約の来店時刻・人数が読めない
+    InvalidSignature,   // 端末のリクエストの署名が違う・時刻がずれている・送り直し
+    Maintenance,        // メンテナンス中で書き込みを受け付けていない
+}
+
+impl AppError {
+    fn status(self) -> StatusCode {
+        match self {
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::InvalidRequest => StatusCode::BAD_REQUEST,
+            AppError::TicketNotFound | AppError::QueueNotFound => StatusCode::NOT_FOUND,
+            AppError::InvalidTransition | AppError::QueueClosed | AppError::QueuePaused | AppError::IdInUse => StatusCode::CONFLICT,
+            AppError::NotAKiosk | AppError::NotADisplay | AppError::InvalidGroupSize | AppError::InvalidQueue => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            AppError::UnknownSource => StatusCode::NOT_FOUND,
+            AppError::InvalidReservation => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::InvalidSignature => StatusCode::UNAUTHORIZED,
+            AppError::Maintenance => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    // API利用者が分岐に使う値 (以前の {"error": ...} と同じ値)
+    fn code(self) -> &'static str {
+        match self {
+            AppError::Unauthorized => "unauthorized",
+            AppError::InvalidRequest => "invalid_request",
+            AppError::TicketNotFound => "ticket_not_found",
+            AppError::QueueNotFound => "queue_not_found",
+            AppError::InvalidTransition => "invalid_transition",
+            AppError::QueueClosed => "queue_closed",
+            AppError::QueuePaused => "queue_paused",
+            AppError::IdInUse => "id_in_use",
+            AppError::NotAKiosk => "not_a_kiosk",
+            AppError::InvalidGroupSize => "invalid_group_size",
+            AppError::InvalidQueue => "invalid_queue",
+            AppError::RateLimited => "rate_limited",
+            AppError::NotADisplay => "not_a_display",
+            AppError::UnknownSource => "unknown_source",
+            AppError::InvalidReservation => "invalid_reservation",
+            AppError::InvalidSignature => "invalid_signature",
+            AppError::Maintenance => "maintenance",
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            AppError::Unauthorized => "認証が必要です。",
+            AppError::InvalidRequest => "リクエストの形式が正しくありません。",
+            AppError::TicketNotFound => "整理券が見つからないか、リンクの有効期限が切れています。",
+            AppError::QueueNotFound => "列が見つかりません。",
+            AppError::InvalidTransition => "この整理券は既に案内済みか取り消されているため、操作できません。",
+            AppError::QueueClosed => "現在、受付を終了しています。",
+            AppError::QueuePaused => "待ち時間が長くなっているため、新しい受付を一時停止しています。",
+            AppError::IdInUse => "このIDは別の整理券で使われています。",
+            AppError::NotAKiosk => "この端末からは発券できません。",
+            AppError::InvalidGroupSize => "人数が設定の範囲外です。",
+            AppError::InvalidQueue => "この端末では、この列の整理券は発券できません。",
+            AppError::RateLimited => "リクエストの回数が多すぎます。しばらく時間をおいてください。",
+            AppError::NotADisplay => "呼び出し・完了は、表示用モニターとして登録した端末からのみ行えます。",
+            AppError::UnknownSource => "この予約サービスは設定されていません。",
+            AppError::InvalidReservation => "予約の来店時刻か人数が読み取れません。",
+            AppError::InvalidSignature => "リクエストの署名が正しくないか、時刻がずれているか、既に受け付けたリクエストです。",
+            AppError::Maintenance => "ただいまメンテナンス中のため、操作を受け付けていません。",
+        }
+    }
+
+    // 来場者向けの画面で、タイトルの下に出す案内
+    fn hint(self) -> &'static str {
+        match self {
+            AppError::Unauthorized => "ログインし直してください。",
+            AppError::Maintenance => "しばらく時間をおいてから、もう一度お試しください。",
+            _ => "お手数ですが、受付でお尋ねください。",
+        }
+    }
+
+    fn html(self) -> Response {
+        (self.status(), Html(format!("<h1>{}</h1><p>{}</p>", self.title(), self.hint()))).into_response()
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({
+            "type": format!("urn:queueticket:problem:{}", self.code()),
+            "title": self.title(),
+            "status": self.status().as_u16(),
+            "code": self.code(),
+            "error": self.code(), // 以前の形式の利用者向け
+        });
+        (self.status(), [(CONTENT_TYPE, "application/problem+json")], body.to_string()).into_response()
+    }
+}
+
+// JSON のAPIの本文が読めなかった時も、problem+json で返す
+impl From<axum::extract::rejection::JsonRejection> for AppError {
+    fn from(_: axum::extract::rejection::JsonRejection) -> Self {
+        AppError::InvalidRequest
+    }
+}
+
+// 予約やデポジットから発券されたチケットの画面へ (署名つきのリンクで) 移動する
+async fn redirect_to_guest(state: &AppState, ticket_id: Uuid) -> Response {
+    let ticket = sqlx::query_as::<_, Ticket>("SELECT * FROM tickets WHERE id = $1")
+        .bind(ticket_id)
+        .fetch_optional(&state.pool)
+        .await
+        .unwrap_or(None);
+
+    match ticket {
+        Some(ticket) => Redirect::to(&guest_path(state, &ticket)).into_response(),
+        None => guest_link_invalid(),
+    }
+}
+
+#[derive(Deserialize)]
+struct GuestLinkQuery {
+    sig: Option<String>,
+}
+
+// 整理券のQRコードとリンクを表示する (紛失時の再表示・再印刷用)
+#[derive(Deserialize)]
+struct TicketQrQuery {
+    print: Option<String>,
+}
+
+// 整理券をなくしたお客様向けに、同じ番号のままQRコードを再表示・再印刷する
+async fn ticket_qr(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    Query(query): Query<TicketQrQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let Some(ticket) = sqlx::query_as::<_, Ticket>("SELECT * FROM tickets WHERE id = $1")
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await
+        .unwrap_or(None)
+    else {
+        return (StatusCode::NOT_FOUND, "整理券が見つかりません。").into_response();
+    };
+
+    let print = query.print.is_some();
+    if print {
+        // 再印刷した記録を履歴に残す (同じ番号の控えが2枚出回ることになるため)
+        let acting = acting_user(&state, &headers).await;
+        record_event(&state.pool, id, "slip_reprinted", acting.as_ref()).await;
+    }
+
+    let settings = Settings::load(&state.pool).await;
+    let guest_url = format!("{}{}", state.base_url, guest_path(&state, &ticket));
+    let qr = QrCode::encode_text(&guest_url, QrCodeEcc::Medium).unwrap();
+    let expires_at = settings.format_local(&guest_link_expires_at(&state, &ticket), "%m/%d %H:%M");
+    let issued_at = settings.format_local(&ticket.created_at, "%m/%d %H:%M");
+    let queue = fetch_queues(&state.pool).await.into_iter().find(|q| q.id == ticket.queue_id);
+    let lookup_url = format!("{}{}", state.base_url, state.url("/lookup"));
+    let qr_code = to_svg_string(&qr, 4);
+    let custom_slip = state.template_overrides.get(&state.pool).await.render(
+        "slip",
+        &minijinja::context! {
+            number => ticket.label(),
+            queue_name => queue.as_ref().map(|q| q.name.as_str()),
+            queue_icon => queue.as_ref().map(|q| q.icon.as_str()),
+            group_size => ticket.group_size,
+            issued_at => issued_at.as_str(),
+            expires_at => expires_at.as_str(),
+            qr_code => minijinja::Value::from_safe_string(qr_code.clone()),
+            url => guest_url.as_str(),
+            lookup_url => lookup_url.as_str(),
+            store_name => settings.store_name.as_str(),
+        },
+    );
+
+    HtmlTemplate(TicketQrTemplate {
+        ticket,
+        url: guest_url,
+        qr_code,
+        expires_at,
+        queue,
+        issued_at,
+        lookup_url,
+        print,
+        store_name: settings.store_name.clone(),
+        custom_slip,
+    })
+    .into_response()
+}
+
+// リンクを発行し直す。以前のリンク・QRコードは使えなくなる
+async fn rotate_guest_link(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let rotated = sqlx::query("UPDATE tickets SET link_issued_at = NOW() WHERE id = $1")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to rotate guest link")
+        .rows_affected();
+
+    if rotated > 0 {
+        state.snapshot.invalidate();
+        let acting = acting_user(&state, &headers).await;
+        record_event(&state.pool, id, "link_rotated", acting.as_ref()).await;
+    }
+    Redirect::to(&url(&format!("/admin/tickets/{}/qr", id)))
+}
+
+// POS連携API用: Authorization: Bearer <POS_API_KEY>
+// Cookie を使わないので、管理画面のような Origin の確認はしない
+async fn pos_auth(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> impl IntoResponse {
+    if !bearer_matches(&state, req.headers(), "pos") {
+        return AppError::Unauthorized.into_response();
+    }
+
+    next.run(req).await
+}
+
+// メトリクス用: METRICS_TOKEN による Bearer 認証、または管理者の Basic 認証
+async fn metrics_auth(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> impl IntoResponse {
+    if !bearer_matches(&state, req.headers(), "metrics") && !is_admin(&state, req.headers()) {
+        return unauthorized();
+    }
+
+    next.run(req).await
+}
+
+// イベント取得API用: EVENTS_API_KEY による Bearer 認証、または管理者の Basic 認証
+async fn events_auth(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> impl IntoResponse {
+    if !bearer_matches(&state, req.headers(), "events") && !is_admin(&state, req.headers()) {
+        return unauthorized();
+    }
+
+    next.run(req).await
+}
+
+async fn export_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        state.metrics.render(&state.pool),
+    )
+}
+
+async fn auth(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> impl IntoResponse {
+    // 1. Basic認証チェック (タイミング攻撃対策済み)
+    if !is_admin(&state, req.headers()) {
+        // 見るだけの共有リンクで開いた端末は、閲覧できる画面だけを通す
+        if observer_link(&state, req.headers()).await.is_none() {
+            return unauthorized();
+        }
+        if !is_observable(req.method(), req.uri().path()) {
+            return observer_forbidden();
+        }
+        // 店舗の決まりで見るだけの人に閉じた画面
+        if let Some(roles) = state.route_policy.roles_for(req.method(), req.uri().path()) {
+            if !roles.iter().any(|r| r == "observer") {
+                return policy_forbidden(roles, true);
+            }
+        }
+        return next.run(req).await;
+    }
+
+    // 2. 端末ごとのセッション。取り消した端末・期限切れの端末にはログインをやり直させる
+    let new_session = match check_admin_session(&state, req.headers()).await {
+        AdminSession::Active => None,
+        AdminSession::Ended => return session_ended(&state),
+        AdminSession::Missing => Some(start_admin_session(&state, req.headers()).await),
+    };
+    let mut res = authorized(&state, req, next).await;
+    if let Some(cookie) = new_session.and_then(|c| axum::http::HeaderValue::from_str(&c).ok()) {
+        res.headers_mut().append(SET_COOKIE, cookie);
+    }
+    res
+}
+
+// ログイン済みの端末からのリクエストの残りのチェック
+async fn authorized(state: &AppState, req: Request, next: Next) -> Response {
+    // 3. CSRF対策 (簡易版: Origin/Refererチェック)
+    // データを書き換えるメソッド(POST, DELETE等)の場合、リクエスト元を確認する
+    if req.method() == Method::POST || req.method() == Method::PUT || req.method() == Method::DELETE {
+        let headers = req.headers();
+        
+        // OriginまたはRefererヘッダーを取得
+        let origin = headers.get("Origin")
+            .and_then(|v| v.to_str().ok())
+            .or_else(|| headers.get("Referer").and_then(|v| v.to_str().ok()));
+
+        // 環境変数の BASE_URL と前方一致するか確認
+        // 例: "https://my-app.shuttle.rs" からのリクエストか？
+        let is_valid_origin = match origin {
+            Some(o) => o.starts_with(&state.base_url),
+            None => false, // OriginもRefererもないPOSTリクエストは拒否
+        };
+
+        if !is_valid_origin {
+            return (
+                StatusCode::FORBIDDEN,
+                "Forbidden: CSRF Check Failed (Invalid Origin)",
+            ).into_response();
+        }
+    }
+
+    // 4. 店舗の決まりで役割を限った画面・操作 (担当者を選んでいない端末も通さない)
+    if let Some(roles) = state.route_policy.roles_for(req.method(), req.uri().path()) {
+        let acting = acting_user(state, req.headers()).await;
+        if !acting.as_ref().is_some_and(|u| roles.contains(&u.role)) {
+            return policy_forbidden(roles, acting.is_some());
+        }
+    }
+
+    // 5. 見るだけの担当者 (に切り替えたセッション) は、担当者の切り替えと配色のほかは書き換えられない
+    if req.method() != Method::GET && req.method() != Method::HEAD
+        && !OBSERVER_POST_PATHS.contains(&req.uri().path())
+        && (acting_user(state, req.headers()).await.is_some_and(|u| u.role == "observer")
+            || session_read_only(state, req.headers()).await)
+    {
+        return observer_forbidden();
+    }
+
+    // 6. 書き換え操作は監査ログに残す
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return run_audited(state, req, next).await;
+    }
+
+    // すべてのチェックを通過
+    next.run(req).await
+}
+
+// --- 管理画面のセッション ---
+// Basic認証はブラウザが覚えてしまい取り消せないので、端末ごとにCookieでセッションを持ち、
+// 一覧から取り消せるようにする。取り消し・期限切れの端末には 401 を返して、パスワードを入れ直させる
+const SESSION_COOKIE: &str = "qt_session";
+
+enum AdminSession {
+    Active,  // 有効
+    Ended,   // 取り消されたか期限切れ (または記録が残っていない)
+    Missing, // Cookieがない (この端末で初めてのログイン)
+}
+
+#[derive(FromRow)]
+struct AdminSessionRow {
+    id: Uuid,
+    user_agent: String,
+    ip: String,
+    created_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+}
+
+impl AdminSessionRow {
+    // User-Agent から「iPad・Safari」のような端末の見出しを作る
+    fn device_label(&self) -> String {
+        const OS: [(&str, &str); 7] = [
+            ("iPad", "iPad"),
+            ("iPhone", "iPhone"),
+            ("Android", "Android"),
+            ("CrOS", "Chromebook"),
+            ("Windows", "Windows"),
+            ("Mac OS X", "Mac"),
+            ("Linux", "Linux"),
+        ];
+        // Chrome の User-Agent にも Safari が入るので、先に他のブラウザを探す
+        const BROWSERS: [(&str, &str); 6] = [
+            ("Edg", "Edge"),
+            ("CriOS", "Chrome"),
+            ("Chrome/", "Chrome"),
+            ("FxiOS", "Firefox"),
+            ("Firefox/", "Firefox"),
+            ("Safari/", "Safari"),
+        ];
+        let find = |list: &[(&'static str, &'static str)]| {
+            list.iter().find(|(key, _)| self.user_agent.contains(key)).map(|(_, label)| *label)
+        };
+        match (find(&OS[..]), find(&BROWSERS[..])) {
+            (Some(os), Some(browser)) => format!("{}・{}", os, browser),
+            (Some(name), None) | (None, Some(name)) => name.to_string(),
+            (None, None) if self.user_agent.is_empty() => "不明な端末".to_string(),
+            (None, None) => self.user_agent.chars().take(40).collect(),
+        }
+    }
+}
+
+fn admin_session_id(state: &AppState, headers: &HeaderMap) -> Option<Uuid> {
+    get_cookie(headers, SESSION_COOKIE)
+        .and_then(|v| verify_cookie_value(&state.cookie_key, v))
+        .and_then(|v| Uuid::parse_str(&v).ok())
+}
+
+async fn check_admin_session(state: &AppState, headers: &HeaderMap) -> AdminSession {
+    let Some(id) = admin_session_id(state, headers) else {
+        return AdminSession::Missing;
+    };
+    let settings = Settings::load(&state.pool).await;
+    // (有効か, 最終操作時刻を更新する頃合いか)
+    let row: Option<(bool, bool)> = sqlx::query_as(
+        "SELECT revoked_at IS NULL 
+                AND ($2 = 0 OR last_seen_at > NOW() - make_interval(mins => $2)) 
+                AND ($3 = 0 OR created_at > NOW() - make_interval(hours => $3)), 
+                last_seen_at < NOW() - INTERVAL '1 minute' 
+         FROM admin_sessions WHERE id = $1"
+    )
+    .bind(id)
+    .bind(settings.session_idle_minutes as i32)
+    .bind(settings.session_max_hours as i32)
+    .fetch_optional(&state.pool)
+    .await
+    .unwrap_or(None);
+
+    match row {
+        Some((true, stale)) => {
+            // 呼び出し画面の自動更新のたびに書き込まないよう、1分に1回だけ更新する
+            if stale {
+                sqlx::query("UPDATE admin_sessions SET last_seen_at = NOW(), ip = $2 WHERE id = $1")
+                    .bind(id)
+                    .bind(client_ip(headers))
+                    .execute(&state.pool)
+                    .await
+                    .expect("Failed to touch admin session");
+            }
+            AdminSession::Active
+        }
+        _ => AdminSession::Ended,
+    }
+}
+
+// 新しいセッションを記録して、その Set-Cookie ヘッダー値を返す
+async fn start_admin_session(state: &AppState, headers: &HeaderMap) -> String {
+    let id = Uuid::new_v4();
+    let user_agent = headers
+        .get(axum::http::header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    sqlx::query("INSERT INTO admin_sessions (id, user_agent, ip) VALUES ($1, $2, $3)")
+        .bind(id)
+        .bind(user_agent.chars().take(300).collect::<String>())
+        .bind(client_ip(headers))
+        .execute(&state.pool)
+        .await
+        .expect("Failed to create admin session");
+    // 期限は運用設定で判定するので、Cookie自体は長めに持たせる
+    // (他のサイトのリンクから開いた時に別の端末と数えないよう Lax にする)
+    signed_cookie(state, SESSION_COOKIE, &id.to_string(), 31_536_000, "Lax")
+}
+
+// ログイン中の端末の一覧 (取り消したもの・期限切れのものは出さない)
+async fn admin_sessions(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    let sessions = sqlx::query_as::<_, AdminSessionRow>(
+        "SELECT id, user_agent, ip, created_at, last_seen_at FROM admin_sessions 
+         WHERE revoked_at IS NULL 
+           AND ($1 = 0 OR last_seen_at > NOW() - make_interval(mins => $1)) 
+           AND ($2 = 0 OR created_at > NOW() - make_interval(hours => $2)) 
+         ORDER BY last_seen_at DESC"
+    )
+    .bind(settings.session_idle_minutes as i32)
+    .bind(settings.session_max_hours as i32)
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![])
+    .into_iter()
+    .map(|session| {
+        let created = settings.format_local(&session.created_at, "%m/%d %H:%M");
+        let last_seen = settings.format_local(&session.last_seen_at, "%m/%d %H:%M");
+        (session, created, last_seen)
+    })
+    .collect();
+
+    let flash = read_flash(&state, &headers);
+    let page = AdminSessionsTemplate {
+        sessions,
+        current: admin_session_id(&state, &headers),
+        idle_minutes: settings.session_idle_minutes,
+        max_hours: settings.session_max_hours,
+        flash: flash.clone(),
+    };
+    clear_flash(&state, &flash, HtmlTemplate(page))
+}
+
+// --- Webhookの送信記録 ---
+#[derive(FromRow)]
+struct WebhookDelivery {
+    id: Uuid,
+    event: String,
+    payload: String,
+    status: String,
+    attempts: i32,
+    next_attempt_at: DateTime<Utc>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct WebhookAttempt {
+    delivery_id: Uuid,
+    attempted_at: DateTime<Utc>,
+    status_code: Option<i32>,
+    latency_ms: i32,
+    error: Option<String>,
+}
+
+struct WebhookDeliveryView {
+    delivery: WebhookDelivery,
+    created: String,
+    next_attempt: String,
+    attempts: Vec<(WebhookAttempt, String)>, // (試行, 表示用の時刻) 新しい順
+}
+
+impl WebhookDeliveryView {
+    fn status_label(&self) -> &'static str {
+        match self.delivery.status.as_str() {
+            "delivered" => "送信済み",
+            "failed" => "失敗 (送り直し終了)",
+            _ if self.delivery.attempts == 0 => "送信待ち",
+            _ => "送り直し待ち",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct WebhooksQuery {
+    problems: Option<String>,
+}
+
+const WEBHOOK_PAGE_SIZE: i64 = 100;
+
+async fn admin_webhooks(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<WebhooksQuery>,
+) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    let problems_only = query.problems.is_some();
+    let deliveries = sqlx::query_as::<_, WebhookDelivery>(
+        "SELECT id, event, payload, status, attempts, next_attempt_at, created_at FROM webhook_deliveries 
+         WHERE NOT $1 OR status != 'delivered' 
+         ORDER BY created_at DESC LIMIT $2"
+    )
+    .bind(problems_only)
+    .bind(WEBHOOK_PAGE_SIZE)
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![]);
+
+    let ids: Vec<Uuid> = deliveries.iter().map(|d| d.id).collect();
+    let mut attempts: HashMap<Uuid, Vec<(WebhookAttempt, String)>> = HashMap::new();
+    for attempt in sqlx::query_as::<_, WebhookAttempt>(
+        "SELECT delivery_id, attempted_at, status_code, latency_ms, error FROM webhook_attempts 
+         WHERE delivery_id = ANY($1) ORDER BY attempted_at DESC"
+    )
+    .bind(&ids)
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![])
+    {
+        let at = settings.format_local(&attempt.attempted_at, "%m/%d %H:%M:%S");
+        attempts.entry(attempt.delivery_id).or_default().push((attempt, at));
+    }
+
+    let deliveries = deliveries
+        .into_iter()
+        .map(|delivery| WebhookDeliveryView {
+            created: settings.format_local(&delivery.created_at, "%m/%d %H:%M:%S"),
+            next_attempt: settings.format_local(&delivery.next_attempt_at, "%H:%M:%S"),
+            attempts: attempts.remove(&delivery.id).unwrap_or_default(),
+            delivery,
+        })
+        .collect();
+
+    let (pending, failed): (i64, i64) = sqlx::query_as(
+        "SELECT COUNT(*) FILTER (WHERE status = 'pending'), COUNT(*) FILTER (WHERE status = 'failed') FROM webhook_deliveries"
+    )
+    .fetch_one(&state.pool)
+    .await
+    .unwrap_or((0, 0));
+
+    let flash = read_flash(&state, &headers);
+    let page = AdminWebhooksTemplate {
+        configured: state.webhook.is_some(),
+        deliveries,
+        problems_only,
+        pending,
+        failed,
+        flash: flash.clone(),
+    };
+    clear_flash(&state, &flash, HtmlTemplate(page))
+}
+
+// 送信待ちに戻してすぐに送る (届いたものも、受け取る側で取りこぼした時のために送り直せる)
+async fn retry_webhook(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    const BACK: &str = "/admin/webhooks";
+    let Some(config) = state.webhook.clone() else {
+        return flash_redirect(&state, &headers, BACK, Flash::error("TICKET_WEBHOOK_URL が設定されていないため送れません。"));
+    };
+    let updated = sqlx::query(
+        "UPDATE webhook_deliveries SET status = 'pending', attempts = 0, next_attempt_at = NOW(), delivered_at = NULL WHERE id = $1"
+    )
+    .bind(id)
+    .execute(&state.pool)
+    .await
+    .expect("Failed to retry webhook delivery")
+    .rows_affected();
+    if updated == 0 {
+        return flash_redirect(&state, &headers, BACK, Flash::error("送信記録が見つかりません。"));
+    }
+    deliver_webhook(&state.pool, &state.http, &config, id).await;
+
+    let delivered: bool = sqlx::query_scalar("SELECT status = 'delivered' FROM webhook_deliveries WHERE id = $1")
+        .bind(id)
+        .fetch_one(&state.pool)
+        .await
+        .unwrap_or(false);
+    if delivered {
+        flash_redirect(&state, &headers, BACK, Flash::notice("送り直しました。"))
+    } else {
+        flash_redirect(&state, &headers, BACK, Flash::error("送り直しましたが、届きませんでした。この後も自動で送り直します。"))
+    }
+}
+
+// 送り直しをあきらめたものを、すべて送信待ちに戻す (送るのは webhook_worker に任せる)
+async fn retry_failed_webhooks(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    const BACK: &str = "/admin/webhooks";
+    if state.webhook.is_none() {
+        return flash_redirect(&state, &headers, BACK, Flash::error("TICKET_WEBHOOK_URL が設定されていないため送れません。"));
+    }
+    let count = sqlx::query(
+        "UPDATE webhook_deliveries SET status = 'pending', attempts = 0, next_attempt_at = NOW() WHERE status = 'failed'"
+    )
+    .execute(&state.pool)
+    .await
+    .expect("Failed to retry webhook deliveries")
+    .rows_affected();
+
+    flash_redirect(&state, &headers, BACK, Flash::notice(&format!("{}件を送信待ちに戻しました。発生した順に送り直します。", count)))
+}
+
+async fn revoke_session(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    sqlx::query("UPDATE admin_sessions SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to revoke admin session");
+
+    // 自分の端末を取り消した場合は、次の画面でログインし直すことになる
+    flash_redirect(&state, &headers, "/admin/sessions", Flash::notice("端末のログインを取り消しました。その端末は次の操作からログインし直しになります。"))
+}
+
+// なくした端末がどれか分からない時に、この端末以外をまとめて取り消す
+async fn revoke_other_sessions(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let revoked = sqlx::query("UPDATE admin_sessions SET revoked_at = NOW() WHERE revoked_at IS NULL AND id IS DISTINCT FROM $1")
+        .bind(admin_session_id(&state, &headers))
+        .execute(&state.pool)
+        .await
+        .expect("Failed to revoke admin sessions")
+        .rows_affected();
+
+    let message = format!("この端末以外の {} 台のログインを取り消しました。", revoked);
+    flash_redirect(&state, &headers, "/admin/sessions", Flash::notice(&message))
+}
+
+// --- 認証情報の切り替え (管理画面) ---
+async fn render_credentials_page(
+    state: &AppState,
+    headers: &HeaderMap,
+    created: Option<(&'static str, String)>,
+    flash: Option<Flash>,
+) -> AdminCredentialsTemplate {
+    let settings = Settings::load(&state.pool).await;
+    let now = Utc::now();
+    let rows = state.credentials.snapshot();
+    let groups = CREDENTIAL_KINDS
+        .iter()
+        .map(|(kind, label)| {
+            let mut entries = Vec::new();
+            // Secrets の値 (期限をつけていれば、その行の期限を出す)
+            if let Some(hash) = state.credentials.config_hash(kind) {
+                let row = rows.iter().find(|c| c.secret_hash == hash);
+                entries.push(CredentialEntry {
+                    id: row.map(|c| c.id),
+                    label: "Secrets.toml の値".to_string(),
+                    from_config: true,
+                    created: None,
+                    retire_at: row.and_then(|c| c.retire_at).map(|at| settings.format_local(&at, "%m/%d %H:%M")),
+                    retired: row.is_some_and(|c| !c.is_active(now)),
+                });
+            }
+            // 管理画面から追加した値 (期限を過ぎたものは出さない)
+            for c in rows.iter().filter(|c| c.kind == *kind && c.is_active(now)) {
+                if Some(c.secret_hash.as_str()) == state.credentials.config_hash(kind) {
+                    continue;
+                }
+                entries.push(CredentialEntry {
+                    id: Some(c.id),
+                    label: c.label.clone(),
+                    from_config: false,
+                    created: Some(settings.format_local(&c.created_at, "%Y/%m/%d %H:%M")),
+                    retire_at: c.retire_at.map(|at| settings.format_local(&at, "%m/%d %H:%M")),
+                    retired: false,
+                });
+            }
+            CredentialGroup { kind, label, entries }
+        })
+        .collect();
+
+    AdminCredentialsTemplate {
+        groups,
+        allowed: acting_user(state, headers).await.is_some_and(|u| u.role == "manager"),
+        created,
+        flash,
+    }
+}
+
+async fn admin_credentials(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let flash = read_flash(&state, &headers);
+    let page = render_credentials_page(&state, &headers, None, flash.clone()).await;
+    clear_flash(&state, &flash, HtmlTemplate(page))
+}
+
+#[derive(Deserialize)]
+struct AddCredentialForm {
+    kind: String,
+    label: String,
+    password: Option<String>, // 管理者パスワードの時だけ (APIキーはこちらで作る)
+    overlap_hours: String,    // 今の値を受け付け続ける時間
+}
+
+// 新しい値を追加し、同じ種類の今の値に期限をつける (短くするだけで、延ばしはしない)
+async fn add_credential(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<AddCredentialForm>,
+) -> impl IntoResponse {
+    const BACK: &str = "/admin/credentials";
+    if acting_user(&state, &headers).await.filter(|u| u.role == "manager").is_none() {
+        return flash_redirect(&state, &headers, BACK, Flash::error("認証情報を変更できるのは責任者だけです。"));
+    }
+    let Some((kind, kind_label)) = CREDENTIAL_KINDS.iter().find(|(k, _)| *k == form.kind).copied() else {
+        return flash_redirect(&state, &headers, BACK, Flash::error("種類を選んでください。"));
+    };
+    let label = form.label.trim();
+    let overlap_hours = form.overlap_hours.trim().parse::<i64>().unwrap_or(-1);
+    if label.is_empty() || !(0..=CREDENTIAL_OVERLAP_MAX_HOURS).contains(&overlap_hours) {
+        let message = format!("名前と、0〜{}時間の切り替え期間を入力してください。", CREDENTIAL_OVERLAP_MAX_HOURS);
+        return flash_redirect(&state, &headers, BACK, Flash::error(&message));
+    }
+    let secret = if kind == "admin" {
+        let password = form.password.unwrap_or_default();
+        if password.chars().count() < MIN_ADMIN_PASSWORD_CHARS || password.contains(char::is_control) {
+            let message = format!("新しいパスワードは{}文字以上にしてください。", MIN_ADMIN_PASSWORD_CHARS);
+            return flash_redirect(&state, &headers, BACK, Flash::error(&message));
+        }
+        password
+    } else {
+        Uuid::new_v4().simple().to_string()
+    };
+    let hash = credential_hash(&state.credentials.key, kind, &secret);
+    if state.credentials.accepts(kind, &secret) || state.credentials.snapshot().iter().any(|c| c.secret_hash == hash) {
+        return flash_redirect(&state, &headers, BACK, Flash::error("今までと同じ値は使えません。"));
+    }
+
+    let mut tx = state.pool.begin().await.expect("Failed to begin transaction");
+    // Secrets の値にも期限をつけられるよう、その値の行を用意しておく
+    if let Some(config_hash) = state.credentials.config_hash(kind) {
+        sqlx::query("INSERT INTO credentials (kind, label, secret_hash) VALUES ($1, 'Secrets.toml の値', $2) ON CONFLICT (secret_hash) DO NOTHING")
+            .bind(kind)
+            .bind(config_hash)
+            .execute(&mut *tx)
+            .await
+            .expect("Failed to record config credential");
+    }
+    let id: i32 = sqlx::query_scalar("INSERT INTO credentials (kind, label, secret_hash) VALUES ($1, $2, $3) RETURNING id")
+        .bind(kind)
+        .bind(label)
+        .bind(&hash)
+        .fetch_one(&mut *tx)
+        .await
+        .expect("Failed to add credential");
+    sqlx::query(
+        "UPDATE credentials SET retire_at = NOW() + make_interval(hours => $3) 
+         WHERE kind = $1 AND id <> $2 
+           AND (retire_at IS NULL OR retire_at > NOW() + make_interval(hours => $3))"
+    )
+    .bind(kind)
+    .bind(id)
+    .bind(overlap_hours as i32)
+    .execute(&mut *tx)
+    .await
+    .expect("Failed to schedule credential retirement");
+    tx.commit().await.expect("Failed to commit transaction");
+    state.credentials.reload(&state.pool).await;
+
+    if kind == "admin" {
+        let message = if overlap_hours == 0 {
+            "新しい管理者パスワードを追加し、今までのパスワードを無効にしました。次の操作で新しいパスワードを入力してください。".to_string()
+        } else {
+            format!("新しい管理者パスワードを追加しました。今までのパスワードも{}時間は使えます。", overlap_hours)
+        };
+        return flash_redirect(&state, &headers, BACK, Flash::notice(&message));
+    }
+    // APIキーはここで一度だけ表示する
+    HtmlTemplate(render_credentials_page(&state, &headers, Some((kind_label, secret)), None).await).into_response()
+}
+
+#[derive(Deserialize)]
+struct RetireCredentialForm {
+    kind: String,
+    id: Option<i32>, // なければ Secrets の値
+    hours: String,   // 今から何時間後に無効にするか (0ですぐ。空なら期限を外す)
+}
+
+// 期限をつける・変える・外す
+async fn retire_credential(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<RetireCredentialForm>,
+) -> impl IntoResponse {
+    const BACK: &str = "/admin/credentials";
+    if acting_user(&state, &headers).await.filter(|u| u.role == "manager").is_none() {
+        return flash_redirect(&state, &headers, BACK, Flash::error("認証情報を変更できるのは責任者だけです。"));
+    }
+    let Some(kind) = CREDENTIAL_KINDS.iter().map(|(k, _)| *k).find(|k| *k == form.kind) else {
+        return flash_redirect(&state, &headers, BACK, Flash::error("種類を選んでください。"));
+    };
+    let hours = match form.hours.trim() {
+        "" => None,
+        value => match value.parse::<i64>() {
+            Ok(h) if (0..=CREDENTIAL_OVERLAP_MAX_HOURS).contains(&h) => Some(h),
+            _ => {
+                let message = format!("0〜{}時間で入力してください。", CREDENTIAL_OVERLAP_MAX_HOURS);
+                return flash_redirect(&state, &headers, BACK, Flash::error(&message));
+            }
+        },
+    };
+
+    let rows = state.credentials.snapshot();
+    let target_hash = match form.id {
+        Some(id) => rows.iter().find(|c| c.id == id && c.kind == kind).map(|c| c.secret_hash.clone()),
+        None => state.credentials.config_hash(kind).map(str::to_string),
+    };
+    let Some(target_hash) = target_hash else {
+        return flash_redirect(&state, &headers, BACK, Flash::error("対象が見つかりません。"));
+    };
+
+    // すぐに無効にすると管理者パスワードが1つも残らない場合は止める
+    if kind == "admin" && hours == Some(0) {
+        let now = Utc::now();
+        let others_active = rows
+            .iter()
+            .any(|c| c.kind == kind && c.secret_hash != target_hash && c.is_active(now))
+            || state.credentials.config_hash(kind).is_some_and(|h| {
+                h != target_hash && !rows.iter().any(|c| c.secret_hash == h && !c.is_active(now))
+            });
+        if !others_active {
+            return flash_redirect(&state, &headers, BACK, Flash::error("使える管理者パスワードがなくなるので、無効にできません。先に新しいパスワードを追加してください。"));
+        }
+    }
+
+    sqlx::query(
+        "INSERT INTO credentials (kind, label, secret_hash, retire_at) 
+         VALUES ($1, 'Secrets.toml の値', $2, NOW() + make_interval(hours => $3)) 
+         ON CONFLICT (secret_hash) DO UPDATE SET retire_at = EXCLUDED.retire_at"
+    )
+    .bind(kind)
+    .bind(&target_hash)
+    .bind(hours.map(|h| h as i32))
+    .execute(&state.pool)
+    .await
+    .expect("Failed to update credential retirement");
+    state.credentials.reload(&state.pool).await;
+
+    let message = match hours {
+        None => "期限を外しました。".to_string(),
+        Some(0) => "無効にしました。".to_string(),
+        Some(h) => format!("{}時間後に無効になるよう設定しました。", h),
+    };
+    flash_redirect(&state, &headers, BACK, Flash::notice(&message))
+}
+
+fn session_ended(state: &AppState) -> Response {
+    let cookie = signed_cookie(state, SESSION_COOKIE, "", 0, "Lax");
+    (
+        StatusCode::UNAUTHORIZED,
+        [
+            (axum::http::header::WWW_AUTHENTICATE, "Basic realm=\"Admin Area\"".to_string()),
+            (SET_COOKIE, cookie),
+        ],
+        "この端末のログインは取り消されたか、期限が切れました。もう一度ログインしてください。",
+    )
+        .into_response()
+}
+
+// --- 監査ログ ---
+// 管理画面のフォームは小さいので、これを超える送信は受け付けない
+const AUDIT_BODY_LIMIT: usize = 1024 * 1024;
+const AUDIT_DETAIL_MAX_CHARS: usize = 500;
+
+// 名前にこれらを含む項目は値を記録しない
+// (電話番号は、個人データの削除依頼に応じた後も監査ログに残ってしまわないよう伏せる)
+const AUDIT_REDACTED_FIELDS: [&str; 6] = ["pin", "token", "password", "secret", "confirm", "phone"];
+
+// フォームの内容を「項目=値」の並びにする (フォーム以外の送信は記録しない)
+fn audit_detail(headers: &HeaderMap, body: &[u8]) -> Option<String> {
+    let is_form = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+    if !is_form || body.is_empty() {
+        return None;
+    }
+
+    let fields: Vec<(String, String)> = serde_urlencoded::from_bytes(body).ok()?;
+    let detail = fields
+        .into_iter()
+        .map(|(key, value)| {
+            let lower = key.to_lowercase();
+            if AUDIT_REDACTED_FIELDS.iter().any(|f| lower.contains(f)) {
+                format!("{}=***", key)
+            } else {
+                format!("{}={}", key, value)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    Some(detail.chars().take(AUDIT_DETAIL_MAX_CHARS).collect())
+}
+
+async fn run_audited(state: &AppState, req: Request, next: Next) -> Response {
+    let (parts, body) = req.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, AUDIT_BODY_LIMIT).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+
+    let acting = acting_user(state, &parts.headers).await;
+    let detail = audit_detail(&parts.headers, &bytes);
+    let ip = client_ip(&parts.headers);
+    let method = parts.method.to_string();
+    let path = parts.uri.path().to_string();
+
+    let res = next.run(Request::from_parts(parts, axum::body::Body::from(bytes))).await;
+
+    // 記録に失敗しても操作自体は終わっているので、ログに残すだけにする
+    let recorded = sqlx::query(
+        "INSERT INTO audit_log (user_id, method, path, detail, status, ip) VALUES ($1, $2, $3, $4, $5, $6)"
+    )
+    .bind(acting.map(|u| u.id))
+    .bind(&method)
+    .bind(&path)
+    .bind(detail)
+    .bind(res.status().as_u16() as i32)
+    .bind(ip)
+    .execute(&state.pool)
+    .await;
+    if let Err(e) = recorded {
+        tracing::error!("Failed to record audit log for {} {}: {}", method, path, e);
+    }
+
+    res
+}
+
+#[derive(FromRow)]
+struct AuditEntry {
+    user_name: Option<String>,
+    method: String,
+    path: String,
+    detail: Option<String>,
+    status: i32,
+    ip: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct AuditFilter {
+    user: Option<String>, // スタッフのID
+    q: Option<String>,    // パスやフォームの内容の部分一致
+    from: Option<String>, // 店舗の現地時刻 (datetime-local)
+    to: Option<String>,
+}
+
+impl AuditFilter {
+    fn user(&self) -> Option<Uuid> {
+        self.user.as_deref().and_then(|v| Uuid::parse_str(v.trim()).ok())
+    }
+
+    fn q(&self) -> &str {
+        self.q.as_deref().map(str::trim).unwrap_or("")
+    }
+
+    // テンプレートでの入力値の復元用
+    fn start_input(&self) -> &str {
+        self.from.as_deref().unwrap_or("")
+    }
+
+    fn end_input(&self) -> &str {
+        self.to.as_deref().unwrap_or("")
+    }
+
+    fn is_user(&self, id: &Uuid) -> bool {
+        self.user() == Some(*id)
+    }
+}
+
+const AUDIT_PAGE_SIZE: i64 = 200;
+
+async fn audit_page(State(state): State<AppState>, Query(filter): Query<AuditFilter>) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    let q = filter.q();
+
+    let entries = sqlx::query_as::<_, AuditEntry>(
+        "SELECT u.name AS user_name, a.method, a.path, a.detail, a.status, a.ip, a.created_at 
+         FROM audit_log a LEFT JOIN users u ON u.id = a.user_id 
+         WHERE ($1::uuid IS NULL OR a.user_id = $1) 
+           AND ($2 = '' OR a.path ILIKE '%' || $2 || '%' OR a.detail ILIKE '%' || $2 || '%') 
+           AND ($3::timestamptz IS NULL OR a.created_at >= $3) 
+           AND ($4::timestamptz IS NULL OR a.created_at < $4) 
+         ORDER BY a.id DESC LIMIT $5"
+    )
+    .bind(filter.user())
+    .bind(q)
+    .bind(filter.from.as_deref().and_then(|v| settings.parse_local(v)))
+    .bind(filter.to.as_deref().and_then(|v| settings.parse_local(v)))
+    .bind(AUDIT_PAGE_SIZE)
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![])
+    .into_iter()
+    .map(|entry| {
+        let label = settings.format_local(&entry.created_at, "%m/%d %H:%M:%S");
+        (entry, label)
+    })
+    .collect();
+
+    let users = sqlx::query_as::<_, User>("SELECT id, name, role, active FROM users ORDER BY active DESC, name ASC")
+        .fetch_all(&state.pool)
+        .await
+        .unwrap_or(vec![]);
+
+    HtmlTemplate(AdminAuditTemplate { entries, users, filter, limit: AUDIT_PAGE_SIZE })
+}
+
+// --- ハンドラ ---
+async fn root_redirect() -> impl IntoResponse {
+    Redirect::to(&url("/admin"))
+}
+
+// --- データの全消去 (リセット) ---
+// 店長だけが、画面に出た確認の文字列を入力して実行できる。消す前に整理券の控えを自動で取る
+const RESET_PHRASE_MINUTES: i64 = 10;
+
+#[derive(FromRow)]
+struct ResetArchive {
+    id: Uuid,
+    user_name: Option<String>,
+    ticket_count: i64,
+    created_at: DateTime<Utc>,
+}
+
+// 確認の文字列は保存せず、発行時刻とあわせて署名したものをフォームに埋め込む
+fn reset_token(state: &AppState, phrase: &str, issued_at: i64) -> String {
+    hmac_hex(&state.cookie_key, &format!("reset:{}:{}", phrase, issued_at))
+}
+
+async fn render_reset_page(state: &AppState, headers: &HeaderMap, error: Option<String>) -> AdminResetTemplate {
+    let acting = acting_user(state, headers).await;
+    let settings = Settings::load(&state.pool).await;
+    let phrase = format!("RESET {:04}", Uuid::new_v4().as_u128() % 10_000);
+    let issued_at = Utc::now().timestamp();
+
+    let archives = sqlx::query_as::<_, ResetArchive>(
+        "SELECT a.id, u.name AS user_name, a.ticket_count, a.created_at 
+         FROM reset_archives a LEFT JOIN users u ON u.id = a.user_id 
+         ORDER BY a.created_at DESC LIMIT 20"
+    )
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![])
+    .into_iter()
+    .map(|archive| {
+        let label = settings.format_local(&archive.created_at, "%Y/%m/%d %H:%M");
+        (archive, label)
+    })
+    .collect();
+
+    AdminResetTemplate {
+        allowed: acting.as_ref().is_some_and(|u| u.role == "manager"),
+        acting,
+        token: reset_token(state, &phrase, issued_at),
+        phrase,
+        issued_at,
+        archives,
+        error,
+    }
+}
+
+async fn reset_page(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    HtmlTemplate(render_reset_page(&state, &headers, None).await)
+}
+
+#[derive(Deserialize)]
+struct ResetForm {
+    phrase: String,
+    issued_at: i64,
+    token: String,
+    confirm: String, // 管理者が入力した確認の文字列
+}
+
+async fn reset_db(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<ResetForm>,
+) -> impl IntoResponse {
+    let Some(acting) = acting_user(&state, &headers).await.filter(|u| u.role == "manager") else {
+        let page = render_reset_page(&state, &headers, Some("リセットできるのは店長だけです。".to_string())).await;
+        return (StatusCode::FORBIDDEN, HtmlTemplate(page)).into_response();
+    };
+
+    let fresh = Utc::now().timestamp() - form.issued_at <= RESET_PHRASE_MINUTES * 60;
+    let signed = constant_time_eq(
+        reset_token(&state, &form.phrase, form.issued_at).as_bytes(),
+        form.token.as_bytes(),
+    );
+    if !fresh || !signed || form.confirm.trim() != form.phrase {
+        let message = "確認の文字列が一致しないか、時間が経ちすぎています。もう一度入力してください。".to_string();
+        let page = render_reset_page(&state, &headers, Some(message)).await;
+        return (StatusCode::BAD_REQUEST, HtmlTemplate(page)).into_response();
+    }
+
+    let mut tx = state.pool.begin().await.expect("Failed to begin transaction");
+
+    // 消す前の控え (CSVダウンロードと同じ内容)
+    let tickets = sqlx::query_as::<_, Ticket>("SELECT * FROM tickets ORDER BY number ASC")
+        .fetch_all(&mut *tx)
+        .await
+        .expect("Failed to load tickets for archive");
+    sqlx::query("INSERT INTO reset_archives (user_id, ticket_count, tickets_csv) VALUES ($1, $2, $3)")
+        .bind(acting.id)
+        .bind(tickets.len() as i64)
+        .bind(tickets_csv(&tickets, &Settings::load(&state.pool).await))
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to archive tickets");
+
+    // 操作履歴 (ticket_events) も外部キーでつながっているので一緒に消す
+    sqlx::query("TRUNCATE TABLE tickets CASCADE")
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to reset table");
+    // 整理番号は各列の最初の番号からやり直す (採番の記録もここでだけ消す)
+    sqlx::query("DELETE FROM number_sequences")
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to reset ticket numbers");
+    // 案内中のお客様もいなくなるので、テーブルはすべて空席に戻す
+    sqlx::query("UPDATE tables SET status = 'free', updated_at = NOW()")
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to reset tables");
+
+    tx.commit().await.expect("Failed to commit transaction");
+
+    tracing::warn!(user = %acting.name, tickets = tickets.len(), "all ticket data was reset");
+    state.snapshot.invalidate();
+    for hook in state.hooks.iter() {
+        hook.on_reset();
+    }
+    Redirect::to(&url("/admin/reset")).into_response()
+}
+
+async fn download_reset_archive(Path(id): Path<Uuid>, State(state): State<AppState>) -> impl IntoResponse {
+    let archive: Option<(String, DateTime<Utc>)> =
+        sqlx::query_as("SELECT tickets_csv, created_at FROM reset_archives WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&state.pool)
+            .await
+            .unwrap_or(None);
+
+    let Some((csv_data, created_at)) = archive else {
+        return (StatusCode::NOT_FOUND, "控えが見つかりません。").into_response();
+    };
+
+    let disposition = format!("attachment; filename=\"tickets_before_reset_{}.csv\"", created_at.format("%Y%m%d%H%M%S"));
+    (
+        [
+            (CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+            (axum::http::header::CONTENT_DISPOSITION, disposition),
+        ],
+        csv_data,
+    )
+        .into_response()
+}
+
+// 待ち状況はキャッシュ (QueueSnapshot) から、今日の実績はDBから集計する
+async fn dashboard_stats(state: &AppState) -> DashboardStats {
+    let settings = Settings::load(&state.pool).await;
+    let snapshot = state.snapshot.get(&state.pool).await;
+    let now = Utc::now();
+
+    // 列 (受付の種類) ごとの今日の実績
+    let today: Vec<QueueToday> = sqlx::query_as::<_, QueueToday>(
+        "SELECT queue_id, 
+                COUNT(*) FILTER (WHERE created_at >= $1) AS issued, 
+                COUNT(*) FILTER (WHERE status = 'completed' AND completed_at >= $1) AS completed, 
+                COUNT(*) FILTER (WHERE called_at >= $1) AS called, 
+                COALESCE(SUM(EXTRACT(EPOCH FROM (called_at - created_at))) FILTER (WHERE called_at >= $1), 0)::float8 AS wait_seconds 
+         FROM tickets 
+         WHERE (created_at >= $1 OR called_at >= $1 OR completed_at >= $1) AND NOT is_test AND deleted_at IS NULL 
+         GROUP BY queue_id"
+    )
+    .bind(settings.today_start(&now))
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![]);
+
+    let queues = fetch_queues(&state.pool)
+        .await
+        .into_iter()
+        .map(|q| {
+            let waiting = snapshot.real_tickets().filter(|t| t.queue_id == q.id && t.status == "waiting").count() as i64;
+            let rows: Vec<&QueueToday> = today.iter().filter(|row| row.queue_id == q.id).collect();
+            QueueStats::new(q.name, waiting, &rows)
+        })
+        .collect();
+    let waiting = snapshot.real_tickets().filter(|t| t.status == "waiting").count() as i64;
+    let mut called: Vec<Ticket> = snapshot.real_tickets().filter(|t| t.status == "called").cloned().collect();
+    called.sort_by_key(|t| t.called_at);
+    let total = QueueStats::new(String::new(), waiting, &today.iter().collect::<Vec<_>>());
+
+    DashboardStats {
+        queues,
+        waiting,
+        called,
+        issued_today: total.issued_today,
+        completed_today: total.completed_today,
+        avg_wait_today: total.avg_wait_today,
+        updated_at: settings.format_local(&now, "%H:%M"),
+    }
+}
+
+async fn admin_index(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    let acting = acting_user(&state, &headers).await;
+    let theme = staff_theme(&state, &headers, acting.as_ref()).await;
+    let polling = state.metrics.polling_summary();
+    HtmlTemplate(AdminIndexTemplate {
+        stats: dashboard_stats(&state).await,
+        training_mode: settings.training_mode,
+        theme,
+        polling_since: polling.since.map(|at| settings.format_local(&at, "%m/%d %H:%M")),
+        polling,
+        guest_poll_seconds: settings.guest_poll_seconds,
+        staff_poll_seconds: settings.staff_poll_seconds,
+        read_only: is_read_only(&state, &headers, acting.as_ref()).await,
+        store_name: settings.store_name.clone(),
+        onboarded: settings.onboarded,
+        maintenance: settings.maintenance_notice(),
+    })
+}
+
+// 自動更新の通信量の集計をやり直す (間隔を変えた前後で比べる時に)
+async fn reset_polling_stats(State(state): State<AppState>) -> impl IntoResponse {
+    state.metrics.reset_polling();
+    Redirect::to(&url("/admin#polling"))
+}
+
+// 管理メニューの状況欄だけを返す (リアルタイム通知を受けた時に再取得する)
+async fn admin_stats(State(state): State<AppState>) -> impl IntoResponse {
+    HtmlTemplate(AdminStatsTemplate { stats: dashboard_stats(&state).await })
+}
+
+// --- 今日の残りの見通し ---
+// 過去4週間の同じ曜日の時間帯ごとの発券数を、今日これまでの発券の多さで補正して、
+// 閉店 (未設定なら24時) までの1時間ごとの待ち組数と待ち時間を見積もる
+struct ForecastHour {
+    label: String,          // 時間帯 (店舗の現地時刻 "19時")
+    arrivals: i64,          // 見込みの発券数
+    queue_length: i64,      // その時間帯の終わりに待っている組数の見込み
+    wait_minutes: Option<i64>, // その時間帯の終わりに発券した場合の待ち時間 (実績がなければ None)
+    wait_with_extra: Option<i64>, // 窓口・テーブルを1つ増やした場合の待ち時間
+    past_closing: bool,     // この時間帯に発券すると閉店までに案内できない見込み
+}
+
+struct Forecast {
+    hours: Vec<ForecastHour>,
+    arrival_ratio: f64,     // 今日の発券の多さ (過去の同じ曜日の同じ時刻までとの比)
+    history_days: usize,    // 参考にした過去の日数
+    servers: i64,           // 同時に案内できる組数
+    cap_after: Option<String>, // この時間帯から受付を止めることを検討する (閉店に間に合わなくなる最初の時間帯)
+}
+
+impl Forecast {
+    fn arrival_percent(&self) -> i64 {
+        (self.arrival_ratio * 100.0).round() as i64
+    }
+}
+
+// 店舗名の長さの上限 (整理券の幅に収まるように)
+const MAX_STORE_NAME_CHARS: usize = 30;
+
+// 営業日の切り替わりに設定できる時刻の上限 (これより遅いと、昼の営業が前の日に入ってしまう)
+const MAX_DAY_START_HOUR: i32 = 12;
+
+// 過去の日数が少ないうちは偏りが大きいので、今日の発券の多さによる補正の幅を抑える
+const FORECAST_RATIO_RANGE: (f64, f64) = (0.5, 2.0);
+
+async fn forecast_rest_of_day(state: &AppState, settings: &Settings) -> Forecast {
+    let now = Utc::now();
+    let today_start = settings.today_start(&now);
+    let local_now = now.with_timezone(&settings.timezone());
+    let current_hour = local_now.hour() as i32;
+
+    // 過去4週間の同じ曜日の、日ごと・時間帯ごとの発券数
+    let rows = sqlx::query_as::<_, (NaiveDate, i32, i64)>(
+        "SELECT (created_at + make_interval(mins => $4))::date AS day, 
+                EXTRACT(HOUR FROM created_at + make_interval(mins => $1))::int AS hour, 
+                COUNT(*) 
+         FROM tickets 
+         WHERE created_at >= $2 - INTERVAL '28 days' AND created_at < $2 AND NOT is_test AND deleted_at IS NULL 
+           AND EXTRACT(DOW FROM created_at + make_interval(mins => $4)) = $3 
+         GROUP BY day, hour"
+    )
+    .bind(settings.utc_offset_minutes)
+    .bind(today_start)
+    .bind(settings.business_date(&now).weekday().num_days_from_sunday() as i32)
+    .bind(settings.business_offset_minutes())
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![]);
+
+    let history_days = rows.iter().map(|(day, _, _)| *day).collect::<HashSet<_>>().len();
+    let mut per_hour = [0.0_f64; 24];
+    for (_, hour, count) in &rows {
+        per_hour[*hour as usize % 24] += *count as f64 / history_days.max(1) as f64;
+    }
+
+    // 今日これまでの発券数と、過去の同じ時刻までの平均を比べる
+    let issued_today: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM tickets WHERE created_at >= $1 AND NOT is_test AND deleted_at IS NULL")
+        .bind(today_start)
+        .fetch_one(&state.pool)
+        .await
+        .unwrap_or(0);
+    let minute_fraction = local_now.minute() as f64 / 60.0;
+    let expected_so_far = per_hour[..current_hour as usize].iter().sum::<f64>()
+        + per_hour[current_hour as usize] * minute_fraction;
+    let arrival_ratio = if expected_so_far >= 5.0 {
+        (issued_today as f64 / expected_so_far).clamp(FORECAST_RATIO_RANGE.0, FORECAST_RATIO_RANGE.1)
+    } else {
+        1.0
+    };
+
+    let snapshot = state.snapshot.get(&state.pool).await;
+    let servers = snapshot.servers(None);
+    // 1時間に案内できる組数 (過去の案内時間の実績がなければ待ち時間は出さない)
+    let capacity = |servers: i64| snapshot.service_minutes.map(|m| servers as f64 * 60.0 / m);
+
+    let closing_at = settings.closing_at(&now);
+    let last_hour = closing_at
+        .filter(|at| *at > now)
+        .map_or(23, |at| {
+            let local = at.with_timezone(&settings.timezone());
+            // ちょうど閉店の時刻から始まる時間帯は含めない
+            local.hour() as i32 - i32::from(local.minute() == 0)
+        });
+
+    let mut queue = snapshot.real_tickets().count() as f64;
+    let mut queue_extra = queue;
+    // 開店前に開いた時は、開店の時間帯から見込む
+    let first_hour = NaiveTime::parse_from_str(&settings.opening_time, "%H:%M")
+        .ok()
+        .map(|at| at.hour() as i32)
+        .filter(|hour| *hour > current_hour)
+        .unwrap_or(current_hour);
+    let mut hours = Vec::new();
+    for hour in first_hour..=last_hour.max(first_hour) {
+        // 今の時間帯は残りの分だけを見込む
+        let fraction = if hour == current_hour { 1.0 - minute_fraction } else { 1.0 };
+        let arrivals = per_hour[hour as usize] * arrival_ratio * fraction;
+
+        let step = |queue: &mut f64, capacity: Option<f64>| {
+            let served = capacity.map_or(0.0, |c| c * fraction);
+            *queue = (*queue + arrivals - served).max(0.0);
+            capacity.map(|c| (*queue / c * 60.0).ceil() as i64)
+        };
+        let wait_minutes = step(&mut queue, capacity(servers));
+        let wait_with_extra = step(&mut queue_extra, capacity(servers + 1));
+
+        let hour_end = today_start + chrono::Duration::hours(hour as i64 + 1);
+        let past_closing = match (closing_at, wait_minutes) {
+            (Some(closing), Some(wait)) => hour_end + chrono::Duration::minutes(wait) > closing,
+            _ => false,
+        };
+        hours.push(ForecastHour {
+            label: format!("{}時", hour),
+            arrivals: arrivals.round() as i64,
+            queue_length: queue.round() as i64,
+            wait_minutes,
+            wait_with_extra,
+            past_closing,
+        });
+    }
+
+    let cap_after = hours.iter().find(|h| h.past_closing).map(|h| h.label.clone());
+    Forecast { hours, arrival_ratio, history_days, servers, cap_after }
+}
+
+async fn forecast_page(State(state): State<AppState>) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    HtmlTemplate(AdminForecastTemplate {
+        forecast: forecast_rest_of_day(&state, &settings).await,
+        closing_time: settings.closing_time.clone(),
+        eta_description: settings.eta_description(),
+        updated_at: settings.format_local(&Utc::now(), "%H:%M"),
+    })
+}
+
+// --- 週ごとの集計 ---
+// 月曜の朝に前の週 (月〜日) の集計を作り、前の週と比べた本文をベルでお知らせする
+// DIGEST_MAIL_WEBHOOK_URL があれば、メール送信ゲートウェイへ本文を渡して DIGEST_MAIL_TO の宛先に送る
+const DIGEST_HOUR: u32 = 6; // 店舗の現地時刻でこの時以降に作る
+const DIGEST_KEEP: i64 = 12; // 画面に出す週の数
+
+struct DigestMailConfig {
+    url: String,     // {"to": [宛先], "subject": 件名, "body": 本文} をPOSTする
+    to: Vec<String>, // 店長などの宛先
+}
+
+struct WeekStats {
+    tickets: i64,
+    called: i64,
+    avg_wait_minutes: Option<f64>, // 発券から呼び出しまで (呼び出した組がなければ None)
+    no_shows: i64,
+    busiest_hour: Option<(i32, i64)>, // (時, 発券数)
+}
+
+impl WeekStats {
+    // 呼び出した組のうち、来なかった組の割合 (%)
+    fn no_show_rate(&self) -> Option<f64> {
+        (self.called > 0).then(|| self.no_shows as f64 * 100.0 / self.called as f64)
+    }
+}
+
+async fn week_stats(pool: &PgPool, settings: &Settings, from: DateTime<Utc>) -> WeekStats {
+    let until = from + chrono::Duration::days(7);
+    let (tickets, called, avg_wait_minutes): (i64, i64, Option<f64>) = sqlx::query_as(
+        "SELECT COUNT(*), 
+                COUNT(*) FILTER (WHERE called_at IS NOT NULL), 
+                (AVG(EXTRACT(EPOCH FROM called_at - created_at)) / 60)::float8 
+         FROM tickets 
+         WHERE created_at >= $1 AND created_at < $2 AND NOT is_test AND deleted_at IS NULL"
+    )
+    .bind(from)
+    .bind(until)
+    .fetch_one(pool)
+    .await
+    .unwrap_or((0, 0, None));
+    let no_shows: i64 = sqlx::query_scalar(
+        "SELECT COUNT(DISTINCT e.ticket_id) FROM ticket_events e JOIN tickets t ON t.id = e.ticket_id 
+         WHERE e.event_type = 'no_show' AND t.created_at >= $1 AND t.created_at < $2 AND NOT t.is_test AND t.deleted_at IS NULL"
+    )
+    .bind(from)
+    .bind(until)
+    .fetch_one(pool)
+    .await
+    .unwrap_or(0);
+    let busiest_hour = sqlx::query_as::<_, (i32, i64)>(
+        "SELECT EXTRACT(HOUR FROM created_at + make_interval(mins => $3))::int AS hour, COUNT(*) 
+         FROM tickets 
+         WHERE created_at >= $1 AND created_at < $2 AND NOT is_test AND deleted_at IS NULL 
+         GROUP BY hour 
+         ORDER BY COUNT(*) DESC, hour ASC 
+         LIMIT 1"
+    )
+    .bind(from)
+    .bind(until)
+    .bind(settings.utc_offset_minutes)
+    .fetch_optional(pool)
+    .await
+    .unwrap_or(None);
+
+    WeekStats { tickets, called, avg_wait_minutes, no_shows, busiest_hour }
+}
+
+// 本文 (1行目はベルのお知らせにも使う)
+fn digest_body(label: &str, this: &WeekStats, last: &WeekStats) -> String {
+    let change = |now: i64, before: i64| match before {
+        0 => String::new(),
+        _ => format!(" (前週 {}組、{:+.0}%)", before, (now - before) as f64 * 100.0 / before as f64),
+    };
+    let mut lines = vec![format!("{} の集計: 発券 {}組{}", label, this.tickets, change(this.tickets, last.tickets))];
+    lines.push(match (this.avg_wait_minutes, last.avg_wait_minutes) {
+        (Some(now), Some(before)) => format!("平均の待ち時間: {:.0}分 (前週 {:.0}分、{:+.0}分)", now, before, now - before),
+        (Some(now), None) => format!("平均の待ち時間: {:.0}分", now),
+        (None, _) => "平均の待ち時間: 呼び出した組がありません".to_string(),
+    });
+    if let Some((hour, count)) = this.busiest_hour {
+        lines.push(format!("最も混んだ時間帯: {}時台 (発券 {}組)", hour, count));
+    }
+    lines.push(match (this.no_show_rate(), last.no_show_rate()) {
+        (Some(now), Some(before)) => format!("来店なし: {}組 (呼び出した組の{:.1}%、前週 {:.1}%)", this.no_shows, now, before),
+        (Some(now), None) => format!("来店なし: {}組 (呼び出した組の{:.1}%)", this.no_shows, now),
+        (None, _) => format!("来店なし: {}組", this.no_shows),
+    });
+    lines.join("\n")
+}
+
+// 1分おきの定期処理から呼ぶ。その週の分を作り終えていれば何もしない
+async fn create_weekly_digest(state: &AppState, settings: &Settings) {
+    let pool = &state.pool;
+    let now = Utc::now();
+    if now.with_timezone(&settings.timezone()).hour() < DIGEST_HOUR {
+        return;
+    }
+    let today = settings.business_date(&now);
+    let this_monday = today - chrono::Duration::days(today.weekday().num_days_from_monday() as i64);
+    let week_start = this_monday - chrono::Duration::days(7);
+    let done: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM weekly_digests WHERE week_start = $1)")
+        .bind(week_start)
+        .fetch_one(pool)
+        .await
+        .unwrap_or(true);
+    if done {
+        return;
+    }
+    let Some(from) = settings.business_day_start(week_start) else {
+        return;
+    };
+
+    let this = week_stats(pool, settings, from).await;
+    let last = week_stats(pool, settings, from - chrono::Duration::days(7)).await;
+    let label = format!("{}〜{}", week_start.format("%m/%d"), (week_start + chrono::Duration::days(6)).format("%m/%d"));
+    let body = digest_body(&label, &this, &last);
+    let created = sqlx::query("INSERT INTO weekly_digests (week_start, body) VALUES ($1, $2) ON CONFLICT (week_start) DO NOTHING")
+        .bind(week_start)
+        .bind(&body)
+        .execute(pool)
+        .await
+        .expect("Failed to save weekly digest")
+        .rows_affected();
+    if created > 0 {
+        let headline = body.lines().next().unwrap_or_default();
+        notify_staff(pool, "weekly_digest", &format!("📊 {} (詳しくは「週ごとの集計」)", headline), None).await;
+        if let Err(err) = send_digest_mail(state, &label, &body).await {
+            tracing::error!("Failed to send weekly digest mail: {}", err);
+            notify_staff(pool, "digest_mail_failed", "週ごとの集計をメールで送信できませんでした。メール送信ゲートウェイの状態を確認してください。", None).await;
+        }
+    }
+}
+
+// 宛先が設定されていなければ送らない (画面とベルだけで読む)
+async fn send_digest_mail(state: &AppState, label: &str, body: &str) -> Result<(), String> {
+    let Some(mail) = &state.digest_mail else {
+        return Ok(());
+    };
+
+    state
+        .http
+        .post(&mail.url)
+        .json(&serde_json::json!({ "to": mail.to, "subject": format!("週ごとの集計 ({})", label), "body": body }))
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+async fn digests_page(State(state): State<AppState>) -> impl IntoResponse {
+    let digests = sqlx::query_as::<_, (NaiveDate, String)>(
+        "SELECT week_start, body FROM weekly_digests ORDER BY week_start DESC LIMIT $1"
+    )
+    .bind(DIGEST_KEEP)
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![])
+    .into_iter()
+    .map(|(week_start, body)| (format!("{}〜", week_start.format("%Y/%m/%d")), body))
+    .collect();
+
+    HtmlTemplate(AdminDigestsTemplate { digests })
+}
+
+async fn front_page(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    let acting = acting_user(&state, &headers).await;
+    HtmlTemplate(FrontTemplate {
+        last_ticket: None,
+        qr_code: None,
+        guest_url: None,
+        queues: fetch_queues(&state.pool).await,
+        projection: project_wait(&state, &settings).await,
+        error: None,
+        training_mode: settings.training_mode,
+        theme: staff_theme(&state, &headers, acting.as_ref()).await,
+        maintenance: settings.maintenance_notice(),
+        custom_fields: settings.fields(),
+    })
+}
+
+// --- 待ち時間の見込み (受けすぎの防止) ---
+// 過去の案内時間 (運用設定の見込み方で計算) と、いま待っている・案内中の組から、
+// これから発券するお客様を案内できる時刻を見積もる
+struct Projection {
+    groups: i64,            // 待機中 + 案内中の組数
+    service_minutes: f64,   // 1組あたりの案内時間の見込み (呼び出し〜完了)
+    servers: i64,           // 同時に案内できる組数 (テーブル数か開いている窓口数。どちらもなければ1)
+    wait_minutes: i64,      // 今から発券した場合の待ち時間の見込み
+    expected_at: DateTime<Utc>,
+    closing_at: DateTime<Utc>,
+    local_expected: String, // 表示用 (店舗の現地時刻)
+    local_closing: String,
+}
+
+impl Projection {
+    fn exceeds_closing(&self) -> bool {
+        self.expected_at > self.closing_at
+    }
+
+    fn service_minutes_rounded(&self) -> i64 {
+        self.service_minutes.round() as i64
+    }
+}
+
+// 閉店時刻が未設定、または過去の実績がない場合は見積もらない
+#[tracing::instrument(skip_all)]
+async fn project_wait(state: &AppState, settings: &Settings) -> Option<Projection> {
+    let now = Utc::now();
+    let closing_at = settings.closing_at(&now)?;
+
+    let snapshot = state.snapshot.get(&state.pool).await;
+    let service_minutes = snapshot.service_minutes?;
+    let groups = snapshot.real_tickets().count() as i64;
+    let servers = snapshot.servers(None);
+
+    // 前の組がすべて案内を終えるまで + 自分自身の案内時間
+    let wait_minutes = snapshot.wait_minutes(snapshot.real_tickets(), None)?;
+    let expected_at = now + chrono::Duration::minutes(wait_minutes + service_minutes.ceil() as i64);
+
+    Some(Projection {
+        groups,
+        service_minutes,
+        servers,
+        wait_minutes,
+        expected_at,
+        closing_at,
+        local_expected: settings.format_local(&expected_at, "%H:%M"),
+        local_closing: settings.format_local(&closing_at, "%H:%M"),
+    })
+}
+
+// 待ち時間の見込みが運用設定の上限を超えていれば、その見込み (分) を返す
+// この間は、閉店時刻の設定に関係なく新しい発券を止める (厨房が受けきれない約束をしないため)
+async fn wait_pause(state: &AppState, settings: &Settings) -> Option<i64> {
+    if settings.max_wait_minutes <= 0 {
+        return None;
+    }
+    let snapshot = state.snapshot.get(&state.pool).await;
+    let wait_minutes = snapshot.wait_minutes(snapshot.real_tickets(), None)?;
+    (wait_minutes > settings.max_wait_minutes).then_some(wait_minutes)
+}
+
+// 来場者・発券機の画面に出す説明
+fn wait_pause_message(wait_minutes: i64) -> String {
+    format!("ただいま待ち時間が約{}分と長くなっているため、新しい受付を一時停止しています。順番が進みしだい再開しますので、しばらくお待ちください。", wait_minutes)
+}
+
+// 1分おきの定期処理から呼ぶ。止めた時と再開した時に1回ずつスタッフへお知らせする
+async fn track_wait_pause(state: &AppState, settings: &Settings) {
+    let paused = wait_pause(state, settings).await;
+    let open: Option<i64> = sqlx::query_scalar("SELECT id FROM queue_pauses WHERE ended_at IS NULL ORDER BY id DESC LIMIT 1")
+        .fetch_optional(&state.pool)
+        .await
+        .unwrap_or(None);
+    match (paused, open) {
+        (Some(wait_minutes), None) => {
+            sqlx::query("INSERT INTO queue_pauses (wait_minutes) VALUES ($1)")
+                .bind(wait_minutes)
+                .execute(&state.pool)
+                .await
+                .expect("Failed to record queue pause");
+            let message = format!(
+                "待ち時間の見込みが約{}分になり、上限の{}分を超えたため、新しい発券を自動で止めました。",
+                wait_minutes, settings.max_wait_minutes
+            );
+            notify_staff(&state.pool, "queue_paused", &message, None).await;
+        }
+        (None, Some(id)) => {
+            sqlx::query("UPDATE queue_pauses SET ended_at = NOW() WHERE id = $1")
+                .bind(id)
+                .execute(&state.pool)
+                .await
+                .expect("Failed to record queue resume");
+            notify_staff(&state.pool, "queue_resumed", "待ち時間の見込みが上限を下回ったため、発券を再開しました。", None).await;
+        }
+        _ => {}
+    }
+}
+
+// 発券するチケットの内容
+#[derive(Default)]
+struct NewTicket {
+    group_size: i32,
+    priority: i32,
+    remote: bool,          // リモート受付 (到着確認が必要)
+    queue_id: Option<i32>, // 並ぶ列 (未指定なら最初の列)
+    phone: Option<String>, // 確認済みの電話番号
+    needs: Vec<String>,    // 配慮が必要なこと (NEEDS のキー)
+    id: Option<Uuid>,      // 発券機が決めたID (オフライン中の受付の送り直しで二重に発券しないため)
+    created_at: Option<DateTime<Utc>>, // オフライン中に受け付けた時刻 (この時刻の順に並ぶ)
+    device_id: Option<i32>, // 発券した発券機
+    tags: Vec<String>,      // スタッフがつけたタグ
+    custom_fields: serde_json::Map<String, serde_json::Value>, // 追加の項目の値 (collect_custom_fields で集めたもの)
+}
+
+// 整理番号を採番して発券し、履歴の記録と画面への通知まで行う
+// (受付での発券、予約からの発券、リモート受付で共通)
+// 列ごとの整理番号の付け方
+#[derive(FromRow)]
+struct NumberScheme {
+    number_prefix: String,
+    number_start: i32,
+    number_step: i32,
+    number_max: i32,
+}
+
+impl NumberScheme {
+    // 一周で使う番号の数
+    fn cycle_len(&self) -> i32 {
+        (self.number_max - self.number_start) / self.number_step + 1
+    }
+}
+
+// 列の今日の行を1つ進める (上限を超えたら最初に戻る)。行のロックはトランザクションの終わりまで持つ
+async fn advance_number_sequence(conn: &mut sqlx::PgConnection, queue_id: i32, business_date: NaiveDate) -> Option<i32> {
+    sqlx::query_scalar(
+        "UPDATE number_sequences s SET 
+             last_number = CASE 
+                 WHEN s.last_number IS NULL OR s.last_number < q.number_start OR s.last_number > q.number_max - q.number_step 
+                 THEN q.number_start 
+                 ELSE s.last_number + q.number_step 
+             END, 
+             updated_at = NOW() 
+         FROM queues q 
+         WHERE q.id = s.queue_id AND s.queue_id = $1 AND s.business_date = $2 
+         RETURNING s.last_number"
+    )
+    .bind(queue_id)
+    .bind(business_date)
+    .fetch_optional(&mut *conn)
+    .await
+    .expect("Failed to advance ticket number")
+}
+
+// 次に発券する番号と頭文字 (列の設定の上限を超えたら最初に戻る)
+// 一周して戻った時に、まだ待機中・呼出中のチケットと同じ番号 (同じ頭文字のどの列でも) は飛ばす
+// 同時に発券されても重ならないよう、トランザクションの中で呼び出す
+async fn next_ticket_number(conn: &mut sqlx::PgConnection, queue_id: i32, business_date: NaiveDate) -> (String, i32) {
+    let scheme = sqlx::query_as::<_, NumberScheme>(
+        "SELECT number_prefix, number_start, number_step, number_max FROM queues WHERE id = $1"
+    )
+    .bind(queue_id)
+    .fetch_optional(&mut *conn)
+    .await
+    .expect("Failed to load numbering scheme")
+    .unwrap_or(NumberScheme { number_prefix: String::new(), number_start: 1, number_step: 1, number_max: 999 });
+    // 同じ頭文字の列どうしは番号が重なりうるので、頭文字ごとに順番にする
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext('ticket_number:' || $1))")
+        .bind(&scheme.number_prefix)
+        .execute(&mut *conn)
+        .await
+        .expect("Failed to lock ticket numbering");
+
+    // 営業日の最初の発券で今日の行を作る (毎日やり直さない列は、前の日の最後の番号を引き継ぐ)
+    sqlx::query(
+        "INSERT INTO number_sequences (queue_id, business_date, last_number) 
+         SELECT q.id, $2, CASE WHEN q.number_reset_daily THEN NULL ELSE ( 
+             SELECT s.last_number FROM number_sequences s 
+             WHERE s.queue_id = q.id AND s.business_date < $2 
+             ORDER BY s.business_date DESC LIMIT 1 
+         ) END 
+         FROM queues q WHERE q.id = $1 
+         ON CONFLICT (queue_id, business_date) DO NOTHING"
+    )
+    .bind(queue_id)
+    .bind(business_date)
+    .execute(&mut *conn)
+    .await
+    .expect("Failed to start ticket numbering for the day");
+
+    let active: Vec<i32> = sqlx::query_scalar(
+        "SELECT number FROM tickets WHERE status IN ('waiting', 'called') AND number_prefix = $1"
+    )
+    .bind(&scheme.number_prefix)
+    .fetch_all(&mut *conn)
+    .await
+    .unwrap_or(vec![]);
+
+    let Some(first) = advance_number_sequence(conn, queue_id, business_date).await else {
+        return (scheme.number_prefix, scheme.number_start); // 列が見つからない
+    };
+    let mut number = first;
+    for _ in 1..scheme.cycle_len() {
+        if !active.contains(&number) {
+            break;
+        }
+        number = advance_number_sequence(conn, queue_id, business_date).await.unwrap_or(first);
+    }
+    if active.contains(&number) {
+        // すべての番号が使われている場合は重なってでも発券する (受付は止めない)。一周進めて最初の候補に戻す
+        tracing::warn!(queue_id, "every ticket number of the queue is in use");
+        number = advance_number_sequence(conn, queue_id, business_date).await.unwrap_or(first);
+    }
+    (scheme.number_prefix, number)
+}
+
+#[tracing::instrument(skip_all, fields(group_size = new.group_size))]
+async fn issue_ticket(state: &AppState, new: NewTicket, user: Option<&User>) -> Ticket {
+    try_issue_ticket(state, new, user).await.expect("Ticket id already exists")
+}
+
+// 同じIDの整理券が既にあれば、番号を使わずに None を返す
+async fn try_issue_ticket(state: &AppState, new: NewTicket, user: Option<&User>) -> Option<Ticket> {
+    let queue_id = new.queue_id.unwrap_or(DEFAULT_QUEUE_ID);
+    // 練習モード中に発券した整理券は練習用として印をつける
+    let settings = Settings::load(&state.pool).await;
+    let is_test = settings.training_mode;
+    let mut tx = state.pool.begin().await.expect("Failed to begin transaction");
+    let (prefix, number) = next_ticket_number(&mut tx, queue_id, settings.business_date(&Utc::now())).await;
+
+    // 修正: RETURNING * に変更して、すべての列（日時含む）を取得する
+    let ticket = sqlx::query_as::<_, Ticket>(
+        "INSERT INTO tickets (number, group_size, status, priority, remote, phone, queue_id, needs, id, created_at, device_id, is_test, tags, number_prefix, custom_fields) 
+         VALUES ($1, $2, 'waiting', $3, $4, $5, $6, $7, $8, COALESCE($9, NOW()), $10, $11, $12, $13, $14) 
+         ON CONFLICT (id) DO NOTHING 
+         RETURNING *" 
+    )
+    .bind(number)
+    .bind(new.group_size)
+    .bind(new.priority)
+    .bind(new.remote)
+    .bind(new.phone)
+    .bind(queue_id)
+    .bind(&new.needs)
+    .bind(new.id.unwrap_or_else(Uuid::new_v4))
+    .bind(new.created_at)
+    .bind(new.device_id)
+    .bind(is_test)
+    .bind(&new.tags)
+    .bind(&prefix)
+    .bind(sqlx::types::Json(&new.custom_fields))
+    .fetch_optional(&mut *tx)
+    .await
+    .expect("Failed to create ticket")?; // 取り消し (ロールバック) で番号も戻る
+
+    record_event(&mut *tx, ticket.id, "created", user).await;
+    if !is_test {
+        record_usage(&mut *tx, &settings, "tickets", 1).await;
+    }
+    tx.commit().await.expect("Failed to commit transaction");
+    state.snapshot.invalidate();
+    state.run_hooks(TicketLifecycle::Created, &ticket);
+    Some(ticket)
+}
+
+// 人数は空欄や数字以外でも送信されるので、文字列で受けてから解釈する
+#[derive(Deserialize)]
+struct CreateTicketForm {
+    group_size: String,
+    queue_id: Option<i32>,
+    force: Option<String>, // 閉店までに案内できない見込みでも発券する (お客様の了承を得た場合)
+    // 配慮が必要なこと (チェックボックスは未チェックだと送信されない)
+    need_wheelchair: Option<String>,
+    need_stroller: Option<String>,
+    need_elderly: Option<String>,
+    need_interpreter: Option<String>,
+    tags: Option<String>, // タグ (空白・カンマ区切り)
+}
+
+impl CreateTicketForm {
+    fn needs(&self) -> Vec<String> {
+        let checked = [&self.need_wheelchair, &self.need_stroller, &self.need_elderly, &self.need_interpreter];
+        NEEDS.iter().zip(checked).filter(|(_, v)| v.is_some()).map(|((key, ..), _)| key.to_string()).collect()
+    }
+}
+
+// 発券画面に戻してエラーを出す (入力し直してもらう)
+async fn front_error(state: &AppState, headers: &HeaderMap, settings: &Settings, status: StatusCode, error: &str) -> Response {
+    let acting = acting_user(state, headers).await;
+    (
+        status,
+        HtmlTemplate(FrontTemplate {
+            last_ticket: None,
+            qr_code: None,
+            guest_url: None,
+            queues: fetch_queues(&state.pool).await,
+            projection: project_wait(state, settings).await,
+            error: Some(error.to_string()),
+            training_mode: settings.training_mode,
+            theme: staff_theme(state, headers, acting.as_ref()).await,
+            maintenance: settings.maintenance_notice(),
+            custom_fields: settings.fields(),
+        }),
+    )
+        .into_response()
+}
+
+async fn create_ticket(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    // 追加の項目 (field_キー) は店舗ごとに変わるので、決まった項目とは別に並びのまま読む
+    let Ok(form) = serde_urlencoded::from_bytes::<CreateTicketForm>(&body) else {
+        return front_error(&state, &headers, &settings, StatusCode::UNPROCESSABLE_ENTITY, "入力を読み取れませんでした。画面を読み込み直してください。").await;
+    };
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_bytes(&body).unwrap_or_default();
+
+    let group_size = form
+        .group_size
+        .trim()
+        .parse::<i32>()
+        .map_err(|_| "人数を数字で入力してください。".to_string())
+        .and_then(|n| settings.check_group_size(n));
+    let group_size = match group_size {
+        Ok(n) => n,
+        Err(error) => return front_error(&state, &headers, &settings, StatusCode::UNPROCESSABLE_ENTITY, &error).await,
+    };
+    if let Some(queue_id) = form.queue_id {
+        if !fetch_queues(&state.pool).await.iter().any(|q| q.id == queue_id) {
+            return front_error(&state, &headers, &settings, StatusCode::UNPROCESSABLE_ENTITY, "選んだ列が見つかりません。画面を読み込み直してください。").await;
+        }
+    }
+
+    // 「発券しない」設定では、閉店に間に合わない見込みの発券を止める
+    let exceeds = project_wait(&state, &settings).await.is_some_and(|p| p.exceeds_closing());
+    if exceeds && settings.blocks_overbooking() && form.force.is_none() {
+        let error = "閉店時刻までにご案内できない見込みのため、発券を止めています。お客様の了承を得た場合のみ発券してください。";
+        return front_error(&state, &headers, &settings, StatusCode::CONFLICT, error).await;
+    }
+    if let Some(wait_minutes) = wait_pause(&state, &settings).await.filter(|_| form.force.is_none()) {
+        let error = format!(
+            "待ち時間の見込み (約{}分) が上限の{}分を超えたため、新しい発券を自動で止めています。お客様の了承を得た場合のみ発券してください。",
+            wait_minutes, settings.max_wait_minutes
+        );
+        return front_error(&state, &headers, &settings, StatusCode::CONFLICT, &error).await;
+    }
+
+    let acting = acting_user(&state, &headers).await;
+    // 設定で有効なら、車椅子・ご高齢のお客様は予約と同じく優先して案内する
+    let needs = form.needs();
+    let prioritized = settings.prioritize_needs
+        && NEEDS.iter().any(|(key, _, _, priority)| *priority && needs.iter().any(|n| n == key));
+    let tags = parse_tags(form.tags.as_deref().unwrap_or(""));
+    let custom_fields = collect_custom_fields(&settings.fields(), |key| {
+        pairs.iter().find(|(k, _)| k.strip_prefix("field_") == Some(key)).map(|(_, v)| v.as_str())
+    });
+    let new = NewTicket {
+        group_size,
+        queue_id: form.queue_id,
+        needs,
+        tags,
+        priority: i32::from(prioritized),
+        custom_fields,
+        ..Default::default()
+    };
+    let ticket = issue_ticket(&state, new, acting.as_ref()).await;
+
+    let url = format!("{}{}", state.base_url, guest_path(&state, &ticket));
+    let qr = QrCode::encode_text(&url, QrCodeEcc::Medium).unwrap();
+    let svg = to_svg_string(&qr, 4);
+
+    HtmlTemplate(FrontTemplate {
+        last_ticket: Some(ticket),
+        qr_code: Some(svg),
+        guest_url: Some(url),
+        queues: fetch_queues(&state.pool).await,
+        projection: project_wait(&state, &settings).await,
+        error: None,
+        training_mode: settings.training_mode,
+        theme: staff_theme(&state, &headers, acting.as_ref()).await,
+        maintenance: settings.maintenance_notice(),
+        custom_fields: settings.fields(),
+    })
+    .into_response()
+}
+
+// 呼び出し管理画面の絞り込み条件 (クエリ文字列から受け取る)
+// 空欄のまま送信されると "" になるので、数値も一旦文字列で受けてから解釈する
+#[derive(Deserialize, Default)]
+struct CallFilter {
+    q: Option<String>,        // 整理番号の検索 (前方一致)
+    status: Option<String>,   // "waiting" / "called" / 未指定(全て)
+    min_size: Option<String>, // 人数の下限
+    max_size: Option<String>, // 人数の上限
+    queue: Option<String>,    // 列 (未指定なら全ての列)
+    counter: Option<String>,  // 窓口専用の画面 (担当する列の待機中と、この窓口で呼び出したものだけ)
+    tag: Option<String>,      // タグ (このタグのついたチケットだけ)
+    after: Option<String>,    // ページ送り用カーソル (直前のページの最後のチケット)
+}
+
+impl CallFilter {
+    fn q(&self) -> &str {
+        self.q.as_deref().map(str::trim).unwrap_or("")
+    }
+
+    // ホワイトリストにあるステータスのみ受け付ける
+    fn status(&self) -> Option<&str> {
+        match self.status.as_deref() {
+            Some(s @ ("waiting" | "called")) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn min_size(&self) -> Option<i32> {
+        self.min_size.as_deref().and_then(|v| v.trim().parse().ok())
+    }
+
+    fn max_size(&self) -> Option<i32> {
+        self.max_size.as_deref().and_then(|v| v.trim().parse().ok())
+    }
+
+    fn queue(&self) -> Option<i32> {
+        self.queue.as_deref().and_then(|v| v.trim().parse().ok())
+    }
+
+    fn counter(&self) -> Option<i32> {
+        self.counter.as_deref().and_then(|v| v.trim().parse().ok())
+    }
+
+    fn tag(&self) -> Option<String> {
+        self.tag.as_deref().and_then(|v| parse_tags(v).into_iter().next())
+    }
+
+    fn tag_value(&self) -> String {
+        self.tag().unwrap_or_default()
+    }
+
+    // 保存する絞り込み条件 (ページ送りと窓口は含めない)
+    fn preset_query(&self) -> String {
+        let pairs = [
+            ("q", self.q().to_string()),
+            ("status", self.status().unwrap_or("").to_string()),
+            ("min_size", self.min_size_value()),
+            ("max_size", self.max_size_value()),
+            ("queue", self.queue().map(|v| v.to_string()).unwrap_or_default()),
+            ("tag", self.tag_value()),
+        ];
+        let pairs: Vec<(&str, String)> = pairs.into_iter().filter(|(_, v)| !v.is_empty()).collect();
+        serde_urlencoded::to_string(pairs).unwrap_or_default()
+    }
+
+    // テンプレートでの入力値の復元用
+    fn min_size_value(&self) -> String {
+        self.min_size().map(|v| v.to_string()).unwrap_or_default()
+    }
+
+    fn max_size_value(&self) -> String {
+        self.max_size().map(|v| v.to_string()).unwrap_or_default()
+    }
+
+    fn after(&self) -> Option<(i32, DateTime<Utc>, Uuid)> {
+        self.after.as_deref().and_then(parse_cursor)
+    }
+
+    fn after_value(&self) -> &str {
+        self.after.as_deref().unwrap_or("")
+    }
+}
+
+// スタッフごとに保存した絞り込み条件 (呼び出し管理画面のボタンから1回で適用する)
+#[derive(FromRow)]
+struct SavedFilter {
+    id: i32,
+    name: String,
+    query: String, // CallFilter::preset_query の形式
+}
+
+#[derive(Deserialize)]
+struct SaveFilterForm {
+    name: String,
+    #[serde(flatten)]
+    filter: CallFilter, // 画面で選んでいる絞り込み条件
+}
+
+// 今の絞り込み条件を、操作中のスタッフの名前つきの条件として保存する (同じ名前なら上書き)
+async fn save_call_filter(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<SaveFilterForm>,
+) -> impl IntoResponse {
+    let back = match form.filter.counter() {
+        Some(id) => format!("/admin/call/{}", id),
+        None => "/admin/call".to_string(),
+    };
+    let Some(acting) = acting_user(&state, &headers).await else {
+        return flash_redirect(&state, &headers, &back, Flash::error("絞り込みを保存するには、先に担当者を選んでください。"));
+    };
+    let name: String = form.name.trim().chars().take(30).collect();
+    if name.is_empty() {
+        return flash_redirect(&state, &headers, &back, Flash::error("保存する名前を入力してください。"));
+    }
+
+    let query = form.filter.preset_query();
+    sqlx::query(
+        "INSERT INTO saved_filters (user_id, name, query) VALUES ($1, $2, $3) 
+         ON CONFLICT (user_id, name) DO UPDATE SET query = EXCLUDED.query"
+    )
+    .bind(acting.id)
+    .bind(&name)
+    .bind(&query)
+    .execute(&state.pool)
+    .await
+    .expect("Failed to save filter");
+
+    let path = if query.is_empty() { back } else { format!("{}?{}", back, query) };
+    flash_redirect(&state, &headers, &path, Flash::notice(&format!("絞り込み「{}」を保存しました。", name)))
+}
+
+// 保存した絞り込み条件を消す (本人の分だけ)
+async fn delete_call_filter(
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Some(acting) = acting_user(&state, &headers).await {
+        sqlx::query("DELETE FROM saved_filters WHERE id = $1 AND user_id = $2")
+            .bind(id)
+            .bind(acting.id)
+            .execute(&state.pool)
+            .await
+            .expect("Failed to delete filter");
+    }
+    flash_redirect(&state, &headers, "/admin/call", Flash::notice("保存した絞り込みを削除しました。"))
+}
+
+// --- キーセット・ページネーション ---
+// OFFSET ではなく「直前のページの最後の (priority, created_at, id)」より後ろを取得する。
+// カーソルは "優先度_発券時刻(マイクロ秒)_ID" 形式の文字列としてURLに載せる。
+fn cursor_of(ticket: &Ticket) -> String {
+    format!("{}_{}_{}", ticket.priority, ticket.created_at.timestamp_micros(), ticket.id)
+}
+
+fn parse_cursor(cursor: &str) -> Option<(i32, DateTime<Utc>, Uuid)> {
+    let mut parts = cursor.splitn(3, '_');
+    let priority = parts.next()?.parse().ok()?;
+    let created_at = DateTime::from_timestamp_micros(parts.next()?.parse().ok()?)?;
+    let id = Uuid::parse_str(parts.next()?).ok()?;
+    Some((priority, created_at, id))
+}
+
+// page_size + 1 件取得した結果から、1ページ分と次ページのカーソルを切り出す
+fn split_page(mut tickets: Vec<Ticket>, page_size: i64) -> (Vec<Ticket>, Option<String>) {
+    let page_size = page_size as usize;
+    if tickets.len() > page_size {
+        tickets.truncate(page_size);
+        let next = tickets.last().map(cursor_of);
+        (tickets, next)
+    } else {
+        (tickets, None)
+    }
+}
+
+// 未完了のチケットを絞り込み条件付きで取得する (次ページ判定のため limit 件まで)
+// 予約からの発券など優先度の高いチケットを先に、同じ優先度では発券順に並べる
+// (番号は分割したチケットや上限からの折り返しで発券順と一致しないため、並び順には使わない)
+async fn fetch_active_tickets(pool: &PgPool, filter: &CallFilter, limit: i64) -> Vec<Ticket> {
+    // 数字以外が入力された場合は検索条件として扱わない
+    let q = Some(filter.q()).filter(|q| !q.is_empty() && q.chars().all(|c| c.is_ascii_digit()));
+    let after = filter.after();
+
+    sqlx::query_as::<_, Ticket>(
+        "SELECT * FROM tickets 
+         WHERE status IN ('waiting', 'called') 
+           AND ($1::text IS NULL OR number::text LIKE $1 || '%')
+           AND ($2::text IS NULL OR status = $2)
+           AND ($3::int IS NULL OR group_size >= $3)
+           AND ($4::int IS NULL OR group_size <= $4)
+           AND ($5::int IS NULL OR (-priority, created_at, id) > (-$5, $6, $7))
+           AND ($9::int IS NULL OR queue_id = $9)
+           AND ($11::text IS NULL OR $11 = ANY(tags))
+           AND ($10::int IS NULL OR counter_id = $10 OR (status = 'waiting' AND EXISTS (
+                SELECT 1 FROM counters c WHERE c.id = $10 AND (c.queue_ids = '{}' OR tickets.queue_id = ANY(c.queue_ids))
+           )))
+         ORDER BY priority DESC, created_at ASC, id ASC
+         LIMIT $8"
+    )
+    .bind(q)
+    .bind(filter.status())
+    .bind(filter.min_size())
+    .bind(filter.max_size())
+    .bind(after.map(|(priority, _, _)| priority))
+    .bind(after.map(|(_, created_at, _)| created_at))
+    .bind(after.map(|(_, _, id)| id))
+    .bind(limit)
+    .bind(filter.queue())
+    .bind(filter.counter())
+    .bind(filter.tag())
+    .fetch_all(pool)
+    .await
+    .unwrap_or(vec![])
+}
+
+async fn call_page(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(filter): Query<CallFilter>,
+) -> impl IntoResponse {
+    let flash = read_flash(&state, &headers);
+    let page = render_call_page(&state, &headers, filter, flash.clone()).await;
+    clear_flash(&state, &flash, HtmlTemplate(page))
+}
+
+// 窓口専用の呼び出し画面 (全体の画面の絞り込みに窓口を加えたもの)
+async fn counter_call_page(
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(mut filter): Query<CallFilter>,
+) -> impl IntoResponse {
+    if !fetch_counters(&state.pool).await.iter().any(|c| c.id == id) {
+        return (StatusCode::NOT_FOUND, "窓口が見つかりません。").into_response();
+    }
+    filter.counter = Some(id.to_string());
+    let flash = read_flash(&state, &headers);
+    let page = render_call_page(&state, &headers, filter, flash.clone()).await;
+    clear_flash(&state, &flash, HtmlTemplate(page)).into_response()
+}
+
+async fn render_call_page(state: &AppState, headers: &HeaderMap, filter: CallFilter, flash: Option<Flash>) -> CallTemplate {
+    let counters = fetch_counters(&state.pool).await;
+    let counter = filter.counter().and_then(|id| counters.iter().find(|c| c.id == id).cloned());
+    let acting = acting_user(state, headers).await;
+    let presets = match &acting {
+        Some(user) => sqlx::query_as::<_, SavedFilter>(
+            "SELECT id, name, query FROM saved_filters WHERE user_id = $1 ORDER BY name ASC"
+        )
+        .bind(user.id)
+        .fetch_all(&state.pool)
+        .await
+        .unwrap_or(vec![]),
+        None => vec![],
+    };
+    let tags_in_use: Vec<String> = sqlx::query_scalar(
+        "SELECT DISTINCT tag FROM tickets, UNNEST(tags) AS tag WHERE status IN ('waiting', 'called') ORDER BY tag"
+    )
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![]);
+    let settings = Settings::load(&state.pool).await;
+    CallTemplate {
+        filter,
+        chime_url: state.chime_url.clone(),
+        queues: fetch_queues(&state.pool).await,
+        counters,
+        counter,
+        flash,
+        training_mode: settings.training_mode,
+        poll_seconds: settings.staff_poll_seconds,
+        presets,
+        tags_in_use,
+        theme: staff_theme(state, headers, acting.as_ref()).await,
+        themes: STAFF_THEMES,
+        read_only: is_read_only(state, headers, acting.as_ref()).await,
+        maintenance: settings.maintenance_notice(),
+        acting,
+    }
+}
+
+// 呼び出し画面からの操作を受け付けられなかった時のエラー
+// HTMXからならメッセージの欄だけを書き換え、そうでなければ画面ごと返す
+async fn call_form_error(state: &AppState, headers: &HeaderMap, status: StatusCode, message: &str) -> Response {
+    if headers.contains_key("HX-Request") {
+        let body = HtmlTemplate(FlashTemplate { flash: Flash::error(message) });
+        return (status, [("HX-Retarget", "#flash-area"), ("HX-Reswap", "innerHTML")], body).into_response();
+    }
+    let page = render_call_page(state, headers, CallFilter::default(), Some(Flash::error(message))).await;
+    (status, HtmlTemplate(page)).into_response()
+}
+
+// 追加: リストの中身だけを返すハンドラ
+async fn call_list(
+    State(state): State<AppState>,
+    Query(filter): Query<CallFilter>,
+) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    (poll_header(settings.staff_poll_seconds), HtmlTemplate(render_call_list(&state, &filter).await))
+}
+
+// 一覧部品の描画データを作る (自動更新と「完了して次を呼ぶ」で共通)
+#[tracing::instrument(skip_all)]
+async fn render_call_list(state: &AppState, filter: &CallFilter) -> CallListTemplate {
+    let tickets = fetch_active_tickets(&state.pool, filter, state.page_size + 1).await;
+    let (tickets, next_cursor) = split_page(tickets, state.page_size);
+    let settings = Settings::load(&state.pool).await;
+    let counters = fetch_counters(&state.pool).await;
+    let counter = filter.counter().and_then(|id| counters.iter().find(|c| c.id == id).cloned());
+
+    // 窓口専用の画面では、この窓口で呼出中のお客様を一覧の上部にまとめて出す
+    let my_called = match &counter {
+        Some(counter) => sqlx::query_as::<_, Ticket>(
+            "SELECT * FROM tickets WHERE status = 'called' AND counter_id = $1 ORDER BY called_at ASC"
+        )
+        .bind(counter.id)
+        .fetch_all(&state.pool)
+        .await
+        .unwrap_or(vec![]),
+        None => vec![],
+    };
+
+    // 警告はページ送りや絞り込みに関係なく、超過している全チケットを対象にする
+    let overdue_numbers = if settings.overdue_alert {
+        sqlx::query_scalar::<_, i32>(
+            "SELECT number FROM tickets 
+             WHERE ($1 > 0 AND status = 'called' AND called_at <= NOW() - make_interval(mins => $1::int)) 
+                OR ($2 > 0 AND status = 'waiting' AND created_at <= NOW() - make_interval(mins => $2::int)) 
+             ORDER BY number ASC"
+        )
+        .bind(settings.called_overdue_minutes)
+        .bind(settings.waiting_overdue_minutes)
+        .fetch_all(&state.pool)
+        .await
+        .unwrap_or(vec![])
+    } else {
+        vec![]
+    };
+
+    let free_tables = sqlx::query_as::<_, Table>(
+        "SELECT id, name, capacity FROM tables WHERE status = 'free' ORDER BY capacity ASC, name ASC"
+    )
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![]);
+
+    // おすすめは絞り込みに関係なく、呼び出せる待機チケット全体から選ぶ
+    let suggestions = if free_tables.is_empty() {
+        vec![]
+    } else {
+        let waiting = sqlx::query_as::<_, Ticket>(
+            "SELECT * FROM tickets 
+             WHERE status = 'waiting' AND (NOT remote OR checked_in_at IS NOT NULL) 
+             ORDER BY priority DESC, created_at ASC"
+        )
+        .fetch_all(&state.pool)
+        .await
+        .unwrap_or(vec![]);
+        // 窓口専用の画面では、担当する列のお客様だけをすすめる
+        let waiting: Vec<Ticket> = waiting
+            .into_iter()
+            .filter(|t| counter.as_ref().is_none_or(|c| c.serves(t.queue_id)))
+            .collect();
+        suggest_for_tables(&free_tables, &waiting, &Utc::now())
+    };
+
+    // 来店しなかった回数が多い電話番号のチケットに、さりげなく印をつける
+    let no_shows = if settings.no_show_threshold > 0 {
+        let ids: Vec<Uuid> = tickets.iter().map(|t| t.id).collect();
+        sqlx::query_as::<_, (Uuid, i64)>(
+            "SELECT t.id, COUNT(*) FROM tickets t 
+             JOIN no_shows n ON n.phone = t.phone AND n.created_at > NOW() - make_interval(days => $2) 
+             WHERE t.id = ANY($1) 
+             GROUP BY t.id HAVING COUNT(*) >= $3"
+        )
+        .bind(&ids)
+        .bind(NO_SHOW_WINDOW_DAYS)
+        .bind(settings.no_show_threshold)
+        .fetch_all(&state.pool)
+        .await
+        .unwrap_or(vec![])
+        .into_iter()
+        .collect()
+    } else {
+        HashMap::new()
+    };
+
+    // お客様が人数を変えたことを、しばらくカードに出してスタッフに知らせる
+    let resized = sqlx::query_as::<_, (Uuid, String)>(
+        "SELECT DISTINCT ON (ticket_id) ticket_id, detail FROM ticket_events 
+         WHERE event_type = 'resized' AND ticket_id = ANY($1) AND created_at > NOW() - INTERVAL '15 minutes' 
+         ORDER BY ticket_id, created_at DESC"
+    )
+    .bind(tickets.iter().map(|t| t.id).collect::<Vec<_>>())
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![])
+    .into_iter()
+    .collect();
+
+    // 予約者名はカードに出す設定の時だけ読む (プライバシーモードならイニシャルにする)
+    let names = if settings.shows_call_column("name") {
+        sqlx::query_as::<_, (Uuid, String)>("SELECT ticket_id, name FROM reservations WHERE ticket_id = ANY($1)")
+            .bind(tickets.iter().map(|t| t.id).collect::<Vec<_>>())
+            .fetch_all(&state.pool)
+            .await
+            .unwrap_or(vec![])
+            .into_iter()
+            .map(|(id, name)| (id, settings.staff_name(&name)))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+
+    CallListTemplate {
+        tickets,
+        next_cursor,
+        is_first_page: filter.after().is_none(),
+        highlight_since: Utc::now() - chrono::Duration::seconds(state.highlight_seconds),
+        now: Utc::now(),
+        custom_fields: settings.fields(),
+        settings,
+        overdue_numbers,
+        free_tables,
+        suggestions,
+        queues: fetch_queues(&state.pool).await,
+        no_shows,
+        counters,
+        counter,
+        my_called,
+        resized,
+        names,
+    }
+}
+
+// 空いた席1つあたり、待ち時間何分ぶんの不利とみなすか
+// (例: 6人席に2名を通すと4席空くので、20分長く待っている組と同じ扱いになる)
+const SEAT_WASTE_MINUTES: i64 = 5;
+
+// 空席に案内するおすすめ
+struct Suggestion {
+    table: Table,
+    ticket: Ticket,
+}
+
+// 空席ごとに、次に呼ぶのにふさわしいチケットを選ぶ
+// 単純な先着順だと、先頭の大人数が入れない小さな席が空いたままになるので、
+// 「席に収まること」を条件に、待ち時間と席の無駄のバランスで選ぶ。予約 (priority) は常に優先する
+fn suggest_for_tables(tables: &[Table], waiting: &[Ticket], now: &DateTime<Utc>) -> Vec<Suggestion> {
+    let mut taken: Vec<Uuid> = vec![];
+    let mut suggestions = vec![];
+
+    // 小さい席から順に埋めることで、大きい席を大人数のために残す
+    let mut tables: Vec<&Table> = tables.iter().collect();
+    tables.sort_by_key(|table| table.capacity);
+
+    for table in tables {
+        let best = waiting
+            .iter()
+            .filter(|ticket| ticket.group_size <= table.capacity && !taken.contains(&ticket.id))
+            .max_by_key(|ticket| {
+                let waste = i64::from(table.capacity - ticket.group_size);
+                (ticket.priority, ticket.minutes_in_status(now) - waste * SEAT_WASTE_MINUTES, std::cmp::Reverse(ticket.created_at))
+            });
+        if let Some(ticket) = best {
+            taken.push(ticket.id);
+            suggestions.push(Suggestion { table: table.clone(), ticket: ticket.clone() });
+        }
+    }
+
+    suggestions
+}
+
+#[derive(Deserialize)]
+struct CompleteNextForm {
+    id: Uuid,
+    #[serde(flatten)]
+    filter: CallFilter, // 返却する一覧に、画面の絞り込み条件をそのまま適用する
+}
+
+// 「完了して次を呼ぶ」: 選択中のチケットの完了と、次の待機チケットの呼び出しを
+// 1つのトランザクションで行い、更新後の一覧部品を返す
+async fn complete_and_call_next(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<CompleteNextForm>,
+) -> impl IntoResponse {
+    let acting = acting_user(&state, &headers).await;
+    let settings = Settings::load(&state.pool).await;
+    let mut tx = state.pool.begin().await.expect("Failed to begin transaction");
+
+    let completed = sqlx::query_as::<_, Ticket>(
+        "UPDATE tickets SET status = 'completed', completed_at = NOW() 
+         WHERE id = $1 AND status = 'called' 
+         RETURNING *"
+    )
+    .bind(form.id)
+    .fetch_optional(&mut *tx)
+    .await
+    .expect("Failed to complete ticket");
+
+    // 完了できた場合のみ次を呼ぶ (二重送信で2組呼んでしまわないように)
+    // 並行して押された場合に同じチケットを取り合わないよう SKIP LOCKED で確保する
+    // 優先案内の割合を設定している場合、同じ範囲で今日最後に呼んだ $3 組に優先のお客様がいれば、
+    // 待っている通常のお客様を先に呼ぶ (通常のお客様がいなければ優先のお客様を呼ぶ)
+    let called = match &completed {
+        Some(_) => sqlx::query_as::<_, Ticket>(
+            "UPDATE tickets SET status = 'called', called_at = NOW(), counter_id = $2 
+             WHERE id = (
+                 SELECT id FROM tickets w 
+                 WHERE status = 'waiting' 
+                   -- 窓口専用の画面なら担当する列から、そうでなければ完了したチケットと同じ列から呼ぶ
+                   AND CASE WHEN $2::int IS NULL THEN queue_id = $1 ELSE EXISTS (
+                       SELECT 1 FROM counters c WHERE c.id = $2 AND (c.queue_ids = '{}' OR w.queue_id = ANY(c.queue_ids))
+                   ) END 
+                   AND (NOT remote OR checked_in_at IS NOT NULL) -- 未到着のリモート受付は飛ばす
+                 ORDER BY CASE WHEN $3 = 0 OR NOT EXISTS (
+                              SELECT 1 FROM (
+                                  SELECT r.priority FROM tickets r 
+                                  WHERE r.called_at >= $4 
+                                    AND CASE WHEN $2::int IS NULL THEN r.queue_id = $1 ELSE EXISTS (
+                                        SELECT 1 FROM counters c WHERE c.id = $2 AND (c.queue_ids = '{}' OR r.queue_id = ANY(c.queue_ids))
+                                    ) END 
+                                  ORDER BY r.called_at DESC 
+                                  LIMIT $3
+                              ) recent WHERE recent.priority > 0
+                          ) THEN priority ELSE (priority = 0)::int END DESC, 
+                          priority DESC, created_at ASC, number ASC 
+                 LIMIT 1 
+                 FOR UPDATE SKIP LOCKED
+             ) 
+             RETURNING *"
+        )
+        .bind(completed.as_ref().map(|t| t.queue_id))
+        .bind(form.filter.counter())
+        .bind(settings.priority_fairness as i32)
+        .bind(settings.today_start(&Utc::now()))
+        .fetch_optional(&mut *tx)
+        .await
+        .expect("Failed to call next ticket"),
+        None => None,
+    };
+
+    if let Some(ticket) = &completed {
+        record_event(&mut *tx, ticket.id, "completed", acting.as_ref()).await;
+    }
+    if let Some(ticket) = &called {
+        record_event(&mut *tx, ticket.id, "called", acting.as_ref()).await;
+    }
+
+    tx.commit().await.expect("Failed to commit transaction");
+
+    if completed.is_some() || called.is_some() {
+        state.snapshot.invalidate();
+    }
+    if let Some(ticket) = &completed {
+        state.run_hooks(TicketLifecycle::Completed, ticket);
+    }
+    if let Some(ticket) = &called {
+        state.run_hooks(TicketLifecycle::Called, ticket);
+    }
+
+    if !headers.contains_key("HX-Request") {
+        return Redirect::to(&url("/admin/call")).into_response();
+    }
+
+    HtmlTemplate(render_call_list(&state, &form.filter).await).into_response()
+}
+
+#[derive(Deserialize)]
+struct UpdateStatusForm {
+    id: Uuid,
+    status: String,
+    undo: Option<String>, // 追加: 直前の操作の取り消し (値は何でもよい)
+    table_id: Option<String>, // 追加: 呼び出し時に案内するテーブル (空なら指定なし)
+    counter_id: Option<i32>,  // 追加: 窓口専用の画面から呼び出した場合の窓口
+}
+
+// update_status 関数を書き換え
+async fn update_status(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<UpdateStatusForm>,
+) -> impl IntoResponse {
+    // 画面のボタンから送られる組み合わせ以外は受け付けない
+    let allowed = if form.undo.is_some() { ["waiting", "called"] } else { ["called", "completed"] };
+    if !allowed.contains(&form.status.as_str()) {
+        return call_form_error(&state, &headers, StatusCode::UNPROCESSABLE_ENTITY, "操作の内容が正しくありません。").await;
+    }
+    // 案内するテーブルは、空欄か登録済みのテーブルのみ
+    let table_id = match form.table_id.as_deref().map(str::trim).filter(|v| !v.is_empty()) {
+        Some(v) => {
+            let known = match Uuid::parse_str(v) {
+                Ok(id) => sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM tables WHERE id = $1)")
+                    .bind(id)
+                    .fetch_one(&state.pool)
+                    .await
+                    .unwrap_or(false)
+                    .then_some(id),
+                Err(_) => None,
+            };
+            let Some(id) = known else {
+                return call_form_error(&state, &headers, StatusCode::UNPROCESSABLE_ENTITY, "選んだテーブルが見つかりません。").await;
+            };
+            Some(id)
+        }
+        None => None,
+    };
+    if let Some(id) = form.counter_id {
+        if !fetch_counters(&state.pool).await.iter().any(|c| c.id == id) {
+            return call_form_error(&state, &headers, StatusCode::UNPROCESSABLE_ENTITY, "窓口が見つかりません。画面を読み込み直してください。").await;
+        }
+    }
+
+    // ステータスに応じて、更新するカラムを変える
+    let updated = if form.undo.is_some() {
+        // 取り消し: 呼び出し → 待機、完了 → 呼び出し に戻し、記録した時刻も消す
+        match form.status.as_str() {
+            "waiting" => {
+                // 案内したテーブルも空席に戻す。待機に戻せた時だけ戻すよう、同じトランザクションで行う
+                let mut tx = state.pool.begin().await.expect("Failed to begin transaction");
+                let table_id = called_table_of(&mut tx, form.id).await;
+                let ticket = sqlx::query_as::<_, Ticket>(
+                    "UPDATE tickets SET status = 'waiting', called_at = NULL, table_id = NULL, counter_id = NULL WHERE id = $1 AND status = 'called' RETURNING *"
+                )
+                .bind(form.id)
+                .fetch_optional(&mut *tx)
+                .await
+                .expect("Failed to undo status");
+                if let (Some(_), Some(table_id)) = (&ticket, table_id) {
+                    free_table(&mut *tx, table_id).await;
+                }
+                tx.commit().await.expect("Failed to commit transaction");
+                ticket
+            }
+            "called" => sqlx::query_as::<_, Ticket>("UPDATE tickets SET status = 'called', completed_at = NULL WHERE id = $1 AND status = 'completed' RETURNING *")
+                .bind(form.id)
+                .fetch_optional(&state.pool)
+                .await
+                .expect("Failed to undo status"),
+            _ => None,
+        }
+    } else if form.status == "called" {
+        // 呼び出し: status を変えつつ、called_at に現在時刻を入れる
+        // テーブルを指定された場合は、チケットに記録してテーブルを使用中にする
+        let ticket = sqlx::query_as::<_, Ticket>(
+            "UPDATE tickets SET status = 'called', called_at = NOW(), table_id = COALESCE($2, table_id), counter_id = COALESCE($3, counter_id) 
+             WHERE id = $1 AND status IN ('waiting', 'called') RETURNING *"
+        )
+            .bind(form.id)
+            .bind(table_id)
+            .bind(form.counter_id)
+            .fetch_optional(&state.pool)
+            .await
+            .expect("Failed to update status to called");
+        if let (Some(_), Some(table_id)) = (&ticket, table_id) {
+            set_table_status(&state, table_id, "occupied").await;
+        }
+        ticket
+    } else if form.status == "completed" {
+        // 完了: status を変えつつ、completed_at に現在時刻を入れる
+        sqlx::query_as::<_, Ticket>(
+            "UPDATE tickets SET status = 'completed', completed_at = NOW() WHERE id = $1 AND status IN ('waiting', 'called') RETURNING *"
+        )
+            .bind(form.id)
+            .fetch_optional(&state.pool)
+            .await
+            .expect("Failed to update status to completed")
+    } else {
+        None
+    };
+
+    // 他の端末で先に操作された場合など
+    let Some(ticket) = &updated else {
+        return call_form_error(&state, &headers, StatusCode::CONFLICT, "この整理券は、すでに状態が変わっています。").await;
+    };
+    let kind = if form.undo.is_some() {
+        "reverted" // 取り消しではチャイムを鳴らさない
+    } else if ticket.status == "called" {
+        "called"
+    } else {
+        "completed"
+    };
+    let acting = acting_user(&state, &headers).await;
+    record_event(&state.pool, ticket.id, kind, acting.as_ref()).await;
+    state.snapshot.invalidate();
+    match kind {
+        "called" => state.run_hooks(TicketLifecycle::Called, ticket),
+        "completed" => state.run_hooks(TicketLifecycle::Completed, ticket),
+        _ => {}
+    }
+
+    // HTMXからのリクエストなら画面遷移せず、一覧の再読み込みだけを指示する
+    if headers.contains_key("HX-Request") {
+        return ([("HX-Trigger", "live-update")], "").into_response();
+    }
+
+    Redirect::to(&url("/admin/call")).into_response()
+}
+
+#[derive(Deserialize)]
+struct UpdateStageForm {
+    id: Uuid,
+    stage: String, // 独自の状態のキーか "completed"
+}
+
+// 店舗独自の状態に進める
+// 呼出中に属する状態に待機中のチケットを進めた場合は、呼び出しと同じ扱い (called_at・チャイム・フック) にする
+async fn update_stage(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<UpdateStageForm>,
+) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    let current = sqlx::query_as::<_, Ticket>("SELECT * FROM tickets WHERE id = $1 AND status IN ('waiting', 'called')")
+        .bind(form.id)
+        .fetch_optional(&state.pool)
+        .await
+        .expect("Failed to load ticket");
+    let Some(current) = current else {
+        return call_form_error(&state, &headers, StatusCode::CONFLICT, "この整理券は、すでに状態が変わっています。").await;
+    };
+    if !settings.next_stages(&current).contains(&form.stage) {
+        return call_form_error(&state, &headers, StatusCode::UNPROCESSABLE_ENTITY, "この状態には進められません。").await;
+    }
+
+    let (status, stage, label) = if form.stage == "completed" {
+        ("completed", None, "完了".to_string())
+    } else {
+        let def = settings.statuses().into_iter().find(|s| s.key == form.stage).expect("stage checked above");
+        // 呼出中のチケットは待機中に戻さない
+        let status = if def.base == "called" || current.status == "called" { "called" } else { "waiting" };
+        (status, Some(def.key), def.label)
+    };
+
+    // 他の端末で先に操作されていないか、読み込んだ時の状態を条件にする
+    let updated = sqlx::query_as::<_, Ticket>(
+        "UPDATE tickets SET status = $2, stage = $3, 
+                called_at = CASE WHEN $2 = 'called' AND status = 'waiting' THEN NOW() ELSE called_at END, 
+                completed_at = CASE WHEN $2 = 'completed' THEN NOW() ELSE completed_at END 
+         WHERE id = $1 AND status = $4 AND stage IS NOT DISTINCT FROM $5 RETURNING *"
+    )
+    .bind(form.id)
+    .bind(status)
+    .bind(&stage)
+    .bind(&current.status)
+    .bind(&current.stage)
+    .fetch_optional(&state.pool)
+    .await
+    .expect("Failed to update stage");
+    let Some(ticket) = updated else {
+        return call_form_error(&state, &headers, StatusCode::CONFLICT, "この整理券は、すでに状態が変わっています。").await;
+    };
+
+    let acting = acting_user(&state, &headers).await;
+    record_event_detail(&state.pool, ticket.id, "stage_changed", acting.as_ref(), Some(&label)).await;
+    state.snapshot.invalidate();
+    if ticket.status != current.status {
+        match ticket.status.as_str() {
+            "called" => state.run_hooks(TicketLifecycle::Called, &ticket),
+            "completed" => state.run_hooks(TicketLifecycle::Completed, &ticket),
+            _ => {}
+        }
+    }
+
+    if headers.contains_key("HX-Request") {
+        return ([("HX-Trigger", "live-update")], "").into_response();
+    }
+
+    Redirect::to(&url("/admin/call")).into_response()
+}
+
+#[derive(Deserialize)]
+struct UpdateNoteForm {
+    id: Uuid,
+    note: String, // 空ならメモを消す
+}
+
+// 整理券のメモを書き換える (待機中・呼出中のみ)
+async fn update_note(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<UpdateNoteForm>,
+) -> impl IntoResponse {
+    let note: String = form.note.trim().chars().take(MAX_NOTE_CHARS).collect();
+    let note = Some(note).filter(|n| !n.is_empty());
+    let updated = sqlx::query_as::<_, Ticket>(
+        "UPDATE tickets SET note = $2 WHERE id = $1 AND status IN ('waiting', 'called') AND note IS DISTINCT FROM $2 RETURNING *"
+    )
+    .bind(form.id)
+    .bind(&note)
+    .fetch_optional(&state.pool)
+    .await
+    .expect("Failed to update note");
+
+    if let Some(ticket) = &updated {
+        let acting = acting_user(&state, &headers).await;
+        record_event_detail(&state.pool, ticket.id, "note_changed", acting.as_ref(), Some(note.as_deref().unwrap_or("(なし)"))).await;
+        state.snapshot.invalidate();
+    }
+
+    if headers.contains_key("HX-Request") {
+        return ([("HX-Trigger", "live-update")], "").into_response();
+    }
+    Redirect::to(&url("/admin/call")).into_response()
+}
+
+#[derive(Deserialize)]
+struct UpdateTagsForm {
+    id: Uuid,
+    tags: String, // 空白・カンマ区切り (空ならタグをすべて外す)
+}
+
+// 整理券のタグを書き換える (待機中・呼出中のみ)
+async fn update_tags(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<UpdateTagsForm>,
+) -> impl IntoResponse {
+    let tags = parse_tags(&form.tags);
+    let updated = sqlx::query_as::<_, Ticket>(
+        "UPDATE tickets SET tags = $2 WHERE id = $1 AND status IN ('waiting', 'called') AND tags IS DISTINCT FROM $2 RETURNING *"
+    )
+    .bind(form.id)
+    .bind(&tags)
+    .fetch_optional(&state.pool)
+    .await
+    .expect("Failed to update tags");
+
+    if let Some(ticket) = &updated {
+        let acting = acting_user(&state, &headers).await;
+        let detail = if tags.is_empty() { "(なし)".to_string() } else { tags.join(" ") };
+        record_event_detail(&state.pool, ticket.id, "tagged", acting.as_ref(), Some(&detail)).await;
+        state.snapshot.invalidate();
+    }
+
+    if headers.contains_key("HX-Request") {
+        return ([("HX-Trigger", "live-update")], "").into_response();
+    }
+    Redirect::to(&url("/admin/call")).into_response()
+}
+
+// リアルタイム通知 (Server-Sent Events)
+// 接続ごとに broadcast の受信側を作り、届いたイベントをそのまま流す
+async fn live_events(State(state): State<AppState>) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let stream = BroadcastStream::new(state.live.subscribe())
+        .filter_map(|msg| msg.ok()) // 受信が追いつかず取りこぼした分は捨てる
+        .map(|ev| Event::default().event(&ev.kind).json_data(&ev));
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+// --- 運用設定 ---
+#[derive(Deserialize)]
+struct SettingsQuery {
+    saved: Option<String>,
+}
+
+async fn settings_page(
+    State(state): State<AppState>,
+    Query(query): Query<SettingsQuery>,
+) -> impl IntoResponse {
+    HtmlTemplate(AdminSettingsTemplate {
+        settings: Settings::load(&state.pool).await,
+        saved: query.saved.is_some(),
+    })
+}
+
+// チェックボックスは未チェックだと送信されないので Option で受ける
+#[derive(Deserialize)]
+struct SettingsForm {
+    called_overdue_minutes: String,
+    waiting_overdue_minutes: String,
+    overdue_alert: Option<String>,
+    utc_offset_minutes: String,
+    day_start_hour: String,
+    public_reservations: Option<String>,
+    reservation_lead_minutes: String,
+    remote_join: Option<String>,
+    remote_join_phone_required: Option<String>,
+    remote_join_limit_per_hour: String,
+    checkin_code: String,
+    deposit_min_group_size: String,
+    deposit_amount: String,
+    store_name: String,
+    opening_time: String,
+    closing_time: String,
+    overbooking_guard: String,
+    max_wait_minutes: String,
+    eta_algorithm: String,
+    eta_window_days: String,
+    eta_percentile: String,
+    no_show_threshold: String,
+    no_show_policy: String,
+    min_group_size: String,
+    max_group_size: String,
+    custom_statuses: String,
+    custom_fields: String,
+    privacy_mode: Option<String>,
+    privacy_retention_days: String,
+    prioritize_needs: Option<String>,
+    priority_fairness: String,
+    count_called_ahead: Option<String>,
+    guest_resize_enabled: Option<String>,
+    guest_handoff_enabled: Option<String>,
+    alert_wait_minutes: String,
+    training_mode: Option<String>,
+    call_columns: String,
+    guest_poll_seconds: String,
+    staff_poll_seconds: String,
+    session_idle_minutes: String,
+    session_max_hours: String,
+}
+
+// 時刻として読めない入力は「未設定」(空) として扱う
+fn parse_hhmm(value: &str) -> String {
+    let value = value.trim();
+    if NaiveTime::parse_from_str(value, "%H:%M").is_ok() {
+        value.to_string()
+    } else {
+        String::new()
+    }
+}
+
+async fn update_settings(
+    State(state): State<AppState>,
+    Form(form): Form<SettingsForm>,
+) -> impl IntoResponse {
+    let mut settings = Settings::load(&state.pool).await;
+    settings.called_overdue_minutes = form.called_overdue_minutes.trim().parse::<i64>().unwrap_or(0).max(0);
+    settings.waiting_overdue_minutes = form.waiting_overdue_minutes.trim().parse::<i64>().unwrap_or(0).max(0);
+    settings.overdue_alert = form.overdue_alert.is_some();
+    if let Ok(v) = form.utc_offset_minutes.trim().parse::<i32>() {
+        settings.utc_offset_minutes = v.clamp(-12 * 60, 14 * 60);
+    }
+    settings.day_start_hour = form.day_start_hour.trim().parse::<i32>().unwrap_or(0).clamp(0, MAX_DAY_START_HOUR);
+    settings.public_reservations = form.public_reservations.is_some();
+    settings.reservation_lead_minutes = form.reservation_lead_minutes.trim().parse::<i64>().unwrap_or(0).max(0);
+    settings.remote_join = form.remote_join.is_some();
+    settings.remote_join_phone_required = form.remote_join_phone_required.is_some();
+    settings.remote_join_limit_per_hour = form.remote_join_limit_per_hour.trim().parse::<i64>().unwrap_or(3).max(1);
+    settings.checkin_code = form.checkin_code.trim().to_string();
+    settings.deposit_min_group_size = form.deposit_min_group_size.trim().parse::<i32>().unwrap_or(0).max(0);
+    // Stripe の日本円の最低決済額は50円
+    settings.deposit_amount = form.deposit_amount.trim().parse::<i32>().unwrap_or(1000).max(50);
+    settings.store_name = form.store_name.trim().chars().take(MAX_STORE_NAME_CHARS).collect();
+    settings.opening_time = parse_hhmm(&form.opening_time);
+    settings.closing_time = parse_hhmm(&form.closing_time);
+    settings.overbooking_guard = if form.overbooking_guard == "block" { "block" } else { "warn" }.to_string();
+    settings.max_wait_minutes = form.max_wait_minutes.trim().parse::<i64>().unwrap_or(0).clamp(0, 1440);
+    settings.eta_algorithm = match form.eta_algorithm.as_str() {
+        "group_size" | "percentile" => form.eta_algorithm.clone(),
+        _ => "average".to_string(),
+    };
+    settings.eta_window_days = form.eta_window_days.trim().parse::<i64>().unwrap_or(28).clamp(ETA_WINDOW_RANGE.0, ETA_WINDOW_RANGE.1);
+    settings.eta_percentile = form.eta_percentile.trim().parse::<i64>().unwrap_or(80).clamp(ETA_PERCENTILE_RANGE.0, ETA_PERCENTILE_RANGE.1);
+    settings.no_show_threshold = form.no_show_threshold.trim().parse::<i64>().unwrap_or(0).max(0);
+    settings.no_show_policy = if form.no_show_policy == "deposit" { "deposit" } else { "warn" }.to_string();
+    // 上限が下限を下回らないようにする (整理番号は3桁なので上限は999名まで)
+    settings.min_group_size = form.min_group_size.trim().parse::<i32>().unwrap_or(1).clamp(1, 999);
+    settings.max_group_size = form.max_group_size.trim().parse::<i32>().unwrap_or(50).clamp(settings.min_group_size, 999);
+    // 読めなかった行は保存せず、読めた定義だけを整えて残す
+    settings.custom_statuses = CustomStatus::parse_all(&form.custom_statuses)
+        .iter()
+        .map(CustomStatus::to_line)
+        .collect::<Vec<_>>()
+        .join("\n");
+    settings.custom_fields = CustomField::parse_all(&form.custom_fields)
+        .iter()
+        .map(CustomField::to_line)
+        .collect::<Vec<_>>()
+        .join("\n");
+    settings.privacy_mode = form.privacy_mode.is_some();
+    settings.prioritize_needs = form.prioritize_needs.is_some();
+    settings.priority_fairness = form.priority_fairness.trim().parse::<i64>().unwrap_or(0).clamp(0, 20);
+    settings.count_called_ahead = form.count_called_ahead.is_some();
+    settings.guest_resize_enabled = form.guest_resize_enabled.is_some();
+    settings.guest_handoff_enabled = form.guest_handoff_enabled.is_some();
+    settings.alert_wait_minutes = form.alert_wait_minutes.trim().parse::<i64>().unwrap_or(0).clamp(0, 600);
+    settings.training_mode = form.training_mode.is_some();
+    settings.call_columns = Settings::parse_call_columns(&form.call_columns).join(",");
+    settings.guest_poll_seconds = form.guest_poll_seconds.trim().parse::<i64>().unwrap_or(5).clamp(GUEST_POLL_RANGE.0, GUEST_POLL_RANGE.1);
+    settings.staff_poll_seconds = form.staff_poll_seconds.trim().parse::<i64>().unwrap_or(30).clamp(STAFF_POLL_RANGE.0, STAFF_POLL_RANGE.1);
+    settings.session_idle_minutes = form.session_idle_minutes.trim().parse::<i64>().unwrap_or(0).clamp(0, 10080);
+    settings.session_max_hours = form.session_max_hours.trim().parse::<i64>().unwrap_or(0).clamp(0, 8760);
+    settings.privacy_retention_days = form.privacy_retention_days.trim().parse::<i64>().unwrap_or(1).clamp(1, 365);
+    settings.save(&state.pool).await;
+    // 案内時間の見込み方はスナップショットに持っているので、すぐに反映させる
+    state.snapshot.invalidate();
+
+    Redirect::to(&url("/admin/settings?saved=1"))
+}
+
+// --- 初期設定 ---
+// 新しい店舗で、店舗名・タイムゾーン・営業時間・列・端末を順に設定する
+// 各段階の内容は運用設定・列の管理・端末の管理と同じ場所に保存するので、後からそれぞれの画面で変えられる
+const ONBOARDING_STEPS: [&str; 5] = ["店舗", "タイムゾーン", "営業時間", "列", "端末"];
+
+#[derive(Deserialize)]
+struct OnboardingQuery {
+    step: Option<usize>,
+}
+
+struct OnboardedDevice {
+    kind: &'static str,
+    kind_label: &'static str,
+    name: String,
+    token: String,
+    signing_key: String,
+}
+
+async fn render_onboarding(state: &AppState, step: usize, error: Option<String>) -> AdminOnboardingTemplate {
+    AdminOnboardingTemplate {
+        step: step.clamp(1, ONBOARDING_STEPS.len()),
+        steps: ONBOARDING_STEPS,
+        settings: Settings::load(&state.pool).await,
+        queues: fetch_queues(&state.pool).await,
+        error,
+        finished: false,
+        devices: Vec::new(),
+        base_url: state.base_url.clone(),
+    }
+}
+
+async fn onboarding_page(State(state): State<AppState>, Query(query): Query<OnboardingQuery>) -> impl IntoResponse {
+    HtmlTemplate(render_onboarding(&state, query.step.unwrap_or(1), None).await)
+}
+
+// 入力に誤りがあれば、同じ段階をメッセージつきで出し直す
+async fn onboarding_error(state: &AppState, step: usize, message: &str) -> Response {
+    (StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(render_onboarding(state, step, Some(message.to_string())).await)).into_response()
+}
+
+// 段階ごとのフォームは項目が違うので、キーと値の組で受ける
+async fn save_onboarding(State(state): State<AppState>, Form(fields): Form<Vec<(String, String)>>) -> impl IntoResponse {
+    let field = |name: &str| fields.iter().find(|(k, _)| k == name).map(|(_, v)| v.trim()).unwrap_or("");
+    let step: usize = field("step").parse().unwrap_or(1);
+    let mut settings = Settings::load(&state.pool).await;
+
+    match step {
+        1 => {
+            let name: String = field("store_name").chars().take(MAX_STORE_NAME_CHARS).collect();
+            if name.is_empty() {
+                return onboarding_error(&state, step, "店舗名を入力してください。").await;
+            }
+            settings.store_name = name;
+        }
+        2 => {
+            let Ok(offset) = field("utc_offset_minutes").parse::<i32>() else {
+                return onboarding_error(&state, step, "UTCとの時差を分で入力してください (日本は540)。").await;
+            };
+            settings.utc_offset_minutes = offset.clamp(-12 * 60, 14 * 60);
+            settings.day_start_hour = field("day_start_hour").parse::<i32>().unwrap_or(0).clamp(0, MAX_DAY_START_HOUR);
+        }
+        3 => {
+            settings.opening_time = parse_hhmm(field("opening_time"));
+            settings.closing_time = parse_hhmm(field("closing_time"));
+        }
+        4 => {
+            // 1行目は最初からある列の名前にし、2行目以降は同じ名前の列がなければ追加する
+            let names: Vec<&str> = field("queues").lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+            let Some((first, rest)) = names.split_first() else {
+                return onboarding_error(&state, step, "列の名前を1つ以上入力してください。").await;
+            };
+            sqlx::query("UPDATE queues SET name = $2 WHERE id = $1")
+                .bind(DEFAULT_QUEUE_ID)
+                .bind(first)
+                .execute(&state.pool)
+                .await
+                .expect("Failed to rename queue");
+            for name in rest {
+                sqlx::query("INSERT INTO queues (name) SELECT $1 WHERE NOT EXISTS (SELECT 1 FROM queues WHERE name = $1)")
+                    .bind(name)
+                    .execute(&state.pool)
+                    .await
+                    .expect("Failed to create queue");
+            }
+            state.snapshot.invalidate();
+        }
+        _ => {
+            // 最後の段階: 名前を入れた端末を登録し、トークンと署名用の鍵をこの画面で一度だけ表示する
+            let auto_print = !field("auto_print").is_empty();
+            let mut devices = Vec::new();
+            for (kind, kind_label) in DEVICE_KINDS {
+                let name = field(&format!("{}_name", kind));
+                if name.is_empty() {
+                    continue;
+                }
+                let (token, signing_key) = register_device(&state, name, kind, kind == "kiosk" && auto_print).await;
+                devices.push(OnboardedDevice { kind, kind_label, name: name.to_string(), token, signing_key });
+            }
+            settings.onboarded = true;
+            settings.save(&state.pool).await;
+
+            let mut page = render_onboarding(&state, ONBOARDING_STEPS.len(), None).await;
+            page.finished = true;
+            page.devices = devices;
+            return HtmlTemplate(page).into_response();
+        }
+    }
+
+    settings.save(&state.pool).await;
+    Redirect::to(&url(&format!("/admin/onboarding?step={}", step + 1))).into_response()
+}
+
+// --- 予約 ---
+// 予約枠ごとの予約済み組数 (キャンセル以外) を数えながら取得する
+async fn fetch_slots(state: &AppState, settings: &Settings, upcoming_only: bool) -> Vec<SlotView> {
+    let rows = sqlx::query_as::<_, SlotRow>(
+        "SELECT s.id, s.starts_at, s.capacity, 
+                COUNT(r.id) FILTER (WHERE r.status != 'cancelled') AS booked 
+         FROM reservation_slots s 
+         LEFT JOIN reservations r ON r.slot_id = s.id 
+         WHERE s.starts_at >= CASE WHEN $1 THEN NOW() ELSE NOW() - INTERVAL '1 day' END 
+         GROUP BY s.id 
+         ORDER BY s.starts_at ASC"
+    )
+    .bind(upcoming_only)
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![]);
+
+    let mut slots = Vec::with_capacity(rows.len());
+    for row in rows {
+        // 来場者向けの画面では他の予約者の名前を出さない
+        let reservations = if upcoming_only {
+            vec![]
+        } else {
+            sqlx::query_as::<_, Reservation>("SELECT * FROM reservations WHERE slot_id = $1 ORDER BY created_at ASC")
+                .bind(row.id)
+                .fetch_all(&state.pool)
+                .await
+                .unwrap_or(vec![])
+                .into_iter()
+                .map(|r| Reservation { name: settings.staff_name(&r.name), ..r })
+                .collect()
+        };
+        slots.push(SlotView {
+            id: row.id,
+            starts_at: row.starts_at,
+            label: settings.format_local(&row.starts_at, "%m/%d %H:%M"),
+            capacity: row.capacity,
+            booked: row.booked,
+            reservations,
+        });
+    }
+    slots
+}
+
+fn validate_reservation(settings: &Settings, name: &str, group_size: i32) -> Result<(), String> {
+    if name.trim().is_empty() || name.chars().count() > 50 {
+        return Err("お名前を50文字以内で入力してください。".to_string());
+    }
+    settings.check_group_size(group_size)?;
+    Ok(())
+}
+
+// 予約枠の行をロックしてから数えることで、同時に予約されても定員を超えないようにする
+async fn book_slot(
+    state: &AppState,
+    slot_id: Uuid,
+    name: &str,
+    group_size: i32,
+    upcoming_only: bool,
+) -> Result<Reservation, String> {
+    validate_reservation(&Settings::load(&state.pool).await, name, group_size)?;
+
+    let mut tx = state.pool.begin().await.expect("Failed to begin transaction");
+
+    let capacity: Option<i32> = sqlx::query_scalar(
+        "SELECT capacity FROM reservation_slots WHERE id = $1 AND ($2 = FALSE OR starts_at > NOW()) FOR UPDATE"
+    )
+    .bind(slot_id)
+    .bind(upcoming_only)
+    .fetch_optional(&mut *tx)
+    .await
+    .expect("Failed to lock reservation slot");
+    let Some(capacity) = capacity else {
+        return Err("この予約枠は受付を終了しました。".to_string());
+    };
+
+    let booked: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM reservations WHERE slot_id = $1 AND status != 'cancelled'")
+        .bind(slot_id)
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap_or(0);
+    if booked >= capacity as i64 {
+        return Err("この予約枠は満席です。別の時間をお選びください。".to_string());
+    }
+
+    let reservation = sqlx::query_as::<_, Reservation>(
+        "INSERT INTO reservations (slot_id, name, group_size) VALUES ($1, $2, $3) RETURNING *"
+    )
+    .bind(slot_id)
+    .bind(name.trim())
+    .bind(group_size)
+    .fetch_one(&mut *tx)
+    .await
+    .expect("Failed to create reservation");
+
+    tx.commit().await.expect("Failed to commit transaction");
+    Ok(reservation)
+}
+
+// 予約を整理券に変換して待機列に入れる (優先度1で、当日受付の列より先に案内する)
+// status を booked → queued に変えられた場合だけ発券するので、二重に発券されない
+async fn queue_reservation(state: &AppState, reservation_id: Uuid, user: Option<&User>) -> Option<Ticket> {
+    let reservation = sqlx::query_as::<_, Reservation>(
+        "UPDATE reservations SET status = 'queued' WHERE id = $1 AND status = 'booked' RETURNING *"
+    )
+    .bind(reservation_id)
+    .fetch_optional(&state.pool)
+    .await
+    .expect("Failed to claim reservation")?;
+
+    let new = NewTicket { group_size: reservation.group_size, priority: 1, ..Default::default() };
+    let ticket = issue_ticket(state, new, user).await;
+
+    sqlx::query("UPDATE reservations SET ticket_id = $1 WHERE id = $2")
+        .bind(ticket.id)
+        .bind(reservation.id)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to link reservation to ticket");
+
+    Some(ticket)
+}
+
+// 1分ごとに、開始時刻 (の設定分前) を過ぎた予約を整理券に変換する
+// あわせて、来店しなかったお客様のデポジットを没収扱いにする
+async fn background_worker(state: AppState) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        // 複数台で動かしている場合は、同じ予約を二重に発券しないよう1台だけで実行する
+        let Some(_lock) = try_job_lock(&state.pool, "background_worker").await else {
+            continue;
+        };
+
+        let settings = Settings::load(&state.pool).await;
+        // メンテナンス中は整理券を書き換える処理を止め、再開してからまとめて行う
+        if settings.maintenance_mode {
+            continue;
+        }
+        let due: Vec<Uuid> = sqlx::query_scalar(
+            "SELECT r.id FROM reservations r 
+             LEFT JOIN reservation_slots s ON s.id = r.slot_id 
+             WHERE r.status = 'booked' 
+               AND COALESCE(r.starts_at, s.starts_at) <= NOW() + make_interval(mins => $1::int) 
+             ORDER BY COALESCE(r.starts_at, s.starts_at) ASC, r.created_at ASC"
+        )
+        .bind(settings.reservation_lead_minutes)
+        .fetch_all(&state.pool)
+        .await
+        .unwrap_or(vec![]);
+
+        for id in due {
+            queue_reservation(&state, id, None).await;
+        }
+
+        record_remote_no_shows(&state).await;
+        if state.stripe.is_some() {
+            forfeit_no_show_deposits(&state).await;
+        }
+
+        if settings.privacy_mode {
+            purge_personal_data(&state.pool, settings.privacy_retention_days).await;
+        }
+
+        if settings.alert_wait_minutes > 0 {
+            alert_long_waits(&state.pool, settings.alert_wait_minutes).await;
+        }
+        alert_silent_devices(&state.pool).await;
+        track_wait_pause(&state, &settings).await;
+        purge_test_tickets(&state, &settings).await;
+        record_storage_usage(&state.pool, &settings).await;
+        create_weekly_digest(&state, &settings).await;
+
+        // 終わった管理画面のセッションを掃除する (一覧に出さなくなってから30日残す)
+        sqlx::query(
+            "DELETE FROM admin_sessions WHERE COALESCE(revoked_at, last_seen_at) < NOW() - INTERVAL '30 days'"
+        )
+        .execute(&state.pool)
+        .await
+        .expect("Failed to clean up admin sessions");
+
+        // 届いた Webhook の記録は30日で消す (失敗したものは送り直すか確認するまで残す)
+        sqlx::query("DELETE FROM webhook_deliveries WHERE status = 'delivered' AND delivered_at < NOW() - INTERVAL '30 days'")
+            .execute(&state.pool)
+            .await
+            .expect("Failed to clean up webhook deliveries");
+
+        // レート制限の古い記録を掃除する (制限の期間は最長1時間)
+        sqlx::query("DELETE FROM rate_limit_hits WHERE hit_at < NOW() - INTERVAL '1 day'")
+            .execute(&state.pool)
+            .await
+            .expect("Failed to clean up rate limit hits");
+
+        // 時刻のずれを許す幅を過ぎた署名は、届いても時刻で断るので覚えておかなくてよい
+        sqlx::query("DELETE FROM device_request_signatures WHERE seen_at < NOW() - make_interval(secs => $1)")
+            .bind((DEVICE_SIGNATURE_WINDOW_SECONDS * 2) as i32)
+            .execute(&state.pool)
+            .await
+            .expect("Failed to clean up device request signatures");
+    }
+}
+
+// 練習用の整理券は、翌日 (店舗の日付が変わった後) に削除する
+// 操作履歴と出力済みの記録は外部キーで一緒に消える
+async fn purge_test_tickets(state: &AppState, settings: &Settings) {
+    let purged = sqlx::query("DELETE FROM tickets WHERE is_test AND created_at < $1")
+        .bind(settings.today_start(&Utc::now()))
+        .execute(&state.pool)
+        .await
+        .expect("Failed to purge test tickets")
+        .rows_affected();
+    if purged > 0 {
+        tracing::info!(purged, "purged test tickets");
+        state.snapshot.invalidate();
+    }
+}
+
+// --- 店頭の端末 ---
+// 端末は Authorization: Bearer <端末のトークン> で認証する
+fn device_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+async fn authenticated_device(state: &AppState, headers: &HeaderMap) -> Option<Device> {
+    let token = device_token(headers)?;
+    sqlx::query_as::<_, Device>(
+        "SELECT * FROM devices WHERE token_hash = $1"
+    )
+    .bind(hmac_hex(&state.cookie_key, &format!("device:{}", token)))
+    .fetch_optional(&state.pool)
+    .await
+    .unwrap_or(None)
+}
+
+fn device_unauthorized() -> Response {
+    AppError::Unauthorized.into_response()
+}
+
+// --- 端末のリクエストの署名 ---
+// 発券機・プリンターは、リクエストごとに次のヘッダーをつける
+//   X-Device-Timestamp: UNIX時刻 (秒)
+//   X-Device-Signature: HMAC-SHA256(署名用の鍵, "時刻\nメソッド\nパス\n本文") の16進数
+// 署名用の鍵はトークンとサーバーの鍵から作り、通信には載せない。リクエストを盗み見られても、
+// トークンだけでは別のリクエストに署名できず、同じリクエストは一度しか受け付けないので送り直しもできない
+const DEVICE_SIGNATURE_WINDOW_SECONDS: i64 = 60; // 端末とサーバーの時計のずれを許す幅
+const DEVICE_BODY_LIMIT: usize = 64 * 1024;
+
+fn device_signing_key(state: &AppState, token: &str) -> String {
+    hmac_hex(&state.cookie_key, &format!("device-signing:{}", token))
+}
+
+async fn verify_device_request(
+    state: &AppState,
+    device: &Device,
+    headers: &HeaderMap,
+    method: &str,
+    path: &str,
+    body: &[u8],
+    grpc: bool,
+) -> Result<(), AppError> {
+    if !device.signs_requests(grpc) {
+        return Ok(());
+    }
+    let Some(token) = device_token(headers) else {
+        return Err(AppError::InvalidSignature);
+    };
+    let request = SignedRequest { headers, method, path, body };
+    // 時刻のずれを許す間に同じ署名が届いたら送り直しとみなす
+    verify_signed_request(&device_signing_key(state, token), &request, Utc::now(), |signature| async move {
+        let first = sqlx::query("INSERT INTO device_request_signatures (device_id, signature) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+            .bind(device.id)
+            .bind(signature)
+            .execute(&state.pool)
+            .await
+            .expect("Failed to record device request signature")
+            .rows_affected()
+            == 1;
+        if !first {
+            tracing::warn!(device = device.id, "replayed device request");
+        }
+        first
+    })
+    .await
+}
+
+// 署名を確かめる端末のリクエスト
+struct SignedRequest<'a> {
+    headers: &'a HeaderMap,
+    method: &'a str,
+    path: &'a str,
+    body: &'a [u8],
+}
+
+// 署名が正しければ first_use に (小文字にした) 署名を渡し、初めて使われた署名なら true を返してもらう
+async fn verify_signed_request<F: std::future::Future<Output = bool>>(
+    signing_key: &str,
+    request: &SignedRequest<'_>,
+    now: DateTime<Utc>,
+    first_use: impl FnOnce(String) -> F,
+) -> Result<(), AppError> {
+    let header = |name: &str| request.headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim);
+    let (Some(timestamp), Some(signature)) = (header("x-device-timestamp"), header("x-device-signature")) else {
+        return Err(AppError::InvalidSignature);
+    };
+    let Ok(sent_at) = timestamp.parse::<i64>() else {
+        return Err(AppError::InvalidSignature);
+    };
+    if (now.timestamp() - sent_at).abs() > DEVICE_SIGNATURE_WINDOW_SECONDS {
+        return Err(AppError::InvalidSignature);
+    }
+    let Ok(expected) = hex::decode(signature) else {
+        return Err(AppError::InvalidSignature);
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}\n{}\n{}\n", timestamp, request.method, request.path).as_bytes());
+    mac.update(request.body);
+    if mac.verify_slice(&expected).is_err() {
+        return Err(AppError::InvalidSignature);
+    }
+    if !first_use(signature.to_ascii_lowercase()).await {
+        return Err(AppError::InvalidSignature);
+    }
+    Ok(())
+}
+
+// 署名を確かめるには本文が必要なので、一度読み込んでからハンドラへ渡し直す
+// トークンが違う場合は、これまでどおり各ハンドラで断る
+async fn device_signature_auth(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let (parts, body) = req.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, DEVICE_BODY_LIMIT).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    if let Some(device) = authenticated_device(&state, &parts.headers).await {
+        let method = parts.method.to_string();
+        if let Err(e) = verify_device_request(&state, &device, &parts.headers, &method, parts.uri.path(), &bytes, false).await {
+            return e.into_response();
+        }
+    }
+
+    next.run(Request::from_parts(parts, axum::body::Body::from(bytes))).await
+}
+
+#[derive(Deserialize, Default)]
+struct HeartbeatRequest {
+    status: Option<String>, // 端末の状態 ("ok" 以外ならスタッフにお知らせする。例: "paper_out")
+}
+
+// 端末からの生存報告。1分おきに送ってもらう
+async fn device_heartbeat(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Option<Json<HeartbeatRequest>>,
+) -> impl IntoResponse {
+    let Some(device) = authenticated_device(&state, &headers).await else {
+        return device_unauthorized();
+    };
+    let status = body
+        .and_then(|Json(req)| req.status)
+        .map(|s| s.trim().chars().take(100).collect::<String>())
+        .filter(|s| !s.is_empty() && s != "ok");
+
+    sqlx::query("UPDATE devices SET last_seen_at = NOW(), last_status = $2, alerted_at = NULL WHERE id = $1")
+        .bind(device.id)
+        .bind(&status)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to record heartbeat");
+
+    // 状態が変わった時だけお知らせする (同じ内容は notify_staff でまとめられる)
+    if let Some(status) = status.as_ref().filter(|s| device.last_status.as_ref() != Some(*s)) {
+        let message = format!("{}「{}」から異常の報告がありました: {}", device.kind_label(), device.name, status);
+        notify_staff(&state.pool, "device_status", &message, None).await;
+    }
+
+    Json(serde_json::json!({ "id": device.id, "name": device.name })).into_response()
+}
+
+// 端末ごとの設定を返す。発券機は起動時と、ハートビートのついでに定期的に取得し直す
+async fn device_config(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let Some(device) = authenticated_device(&state, &headers).await else {
+        return device_unauthorized();
+    };
+    let settings = Settings::load(&state.pool).await;
+    let queues: Vec<serde_json::Value> = fetch_queues(&state.pool)
+        .await
+        .into_iter()
+        .filter(|q| device.serves(q.id))
+        .map(|q| serde_json::json!({ "id": q.id, "name": q.name }))
+        .collect();
+
+    Json(serde_json::json!({
+        "id": device.id,
+        "name": device.name,
+        "kind": device.kind,
+        "queues": queues,
+        "group_sizes": device.group_size_buttons(&settings),
+        "min_group_size": settings.min_group_size,
+        "max_group_size": settings.max_group_size,
+        "language": device.language,
+        "auto_print": device.auto_print,
+        "store_name": settings.store_name,
+        "poll_seconds": settings.staff_poll_seconds,
+        "custom_fields": settings.fields(),
+        // 待ち時間の見込みが上限を超えて発券を止めている間は、発券の画面の代わりに説明を出す
+        "pause_message": wait_pause(&state, &settings).await.map(wait_pause_message),
+    }))
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct DeviceTicketRequest {
+    id: Uuid, // 発券機が決めるID。同じIDを何度送っても1枚しか発券しない
+    group_size: i32,
+    queue_id: Option<i32>,
+    #[serde(default)]
+    needs: Vec<String>,
+    #[serde(default)]
+    custom_fields: HashMap<String, serde_json::Value>, // 追加の項目 (キー: 文字列。チェックボックスは true)
+    accepted_at: Option<DateTime<Utc>>, // オフライン中に受け付けた時刻 (つながっている時は省略)
+    provisional: Option<String>,        // オフライン中にお客様へ伝えた仮の番号 (整理番号との対応を履歴に残す)
+}
+
+// オフライン中の受付の時刻は、端末の時計のずれや古すぎる送り直しを考えてこの範囲だけ信用する
+const OFFLINE_ACCEPT_MAX_MINUTES: i64 = 120;
+
+// 発券機からの発券。店舗のWi-Fiが切れている間は発券機に溜めておき、つながったら送ってもらう
+// 整理番号はサーバーに届いた時に採番し、列には受け付けた時刻の順に入る
+async fn device_create_ticket(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Result<Json<DeviceTicketRequest>, axum::extract::rejection::JsonRejection>,
+) -> impl IntoResponse {
+    let Some(device) = authenticated_device(&state, &headers).await else {
+        return device_unauthorized();
+    };
+    let Json(req) = match body {
+        Ok(body) => body,
+        Err(e) => return AppError::from(e).into_response(),
+    };
+    let respond = |ticket: &Ticket, duplicate: bool| {
+        Json(serde_json::json!({
+            "id": ticket.id,
+            "number": ticket.number,
+            "queue_id": ticket.queue_id,
+            "group_size": ticket.group_size,
+            "guest_url": format!("{}{}", state.base_url, guest_path(&state, ticket)),
+            "duplicate": duplicate, // 既に受け取っていた送り直し
+        }))
+        .into_response()
+    };
+
+    // 送り直し: 同じ発券機からのものなら、最初に発券した整理券をそのまま返す
+    let existing = sqlx::query_as::<_, Ticket>("SELECT * FROM tickets WHERE id = $1")
+        .bind(req.id)
+        .fetch_optional(&state.pool)
+        .await
+        .unwrap_or(None);
+    if let Some(ticket) = existing {
+        if ticket.device_id != Some(device.id) {
+            return AppError::IdInUse.into_response();
+        }
+        return respond(&ticket, true);
+    }
+
+    let settings = Settings::load(&state.pool).await;
+    let queue_id = req.queue_id.unwrap_or(DEFAULT_QUEUE_ID);
+    if let Err(e) = check_kiosk_ticket(&state, &device, &settings, req.group_size, queue_id).await {
+        return e.into_response();
+    }
+
+    let needs: Vec<String> = req.needs.into_iter().filter(|n| NEEDS.iter().any(|(key, ..)| key == n)).collect();
+    let prioritized = settings.prioritize_needs
+        && NEEDS.iter().any(|(key, _, _, priority)| *priority && needs.iter().any(|n| n == key));
+    let now = Utc::now();
+    let accepted_at = req
+        .accepted_at
+        .filter(|at| *at < now && *at > now - chrono::Duration::minutes(OFFLINE_ACCEPT_MAX_MINUTES));
+
+    // 「発券しない」設定では、閉店に間に合わない見込みの発券を止める
+    // ただしオフライン中に受け付けた分は、既にお客様に番号を伝えているので発券する
+    if req.accepted_at.is_none() && settings.blocks_overbooking() && project_wait(&state, &settings).await.is_some_and(|p| p.exceeds_closing()) {
+        return AppError::QueueClosed.into_response();
+    }
+    if req.accepted_at.is_none() && wait_pause(&state, &settings).await.is_some() {
+        return AppError::QueuePaused.into_response();
+    }
+    let new = NewTicket {
+        group_size: req.group_size,
+        queue_id: Some(queue_id),
+        needs,
+        priority: i32::from(prioritized),
+        id: Some(req.id),
+        created_at: accepted_at,
+        device_id: Some(device.id),
+        custom_fields: collect_custom_fields(&settings.fields(), |key| match req.custom_fields.get(key) {
+            Some(serde_json::Value::Bool(true)) => Some("1"),
+            Some(serde_json::Value::String(v)) => Some(v.as_str()),
+            _ => None,
+        }),
+        ..Default::default()
+    };
+    let Some(ticket) = try_issue_ticket(&state, new, None).await else {
+        // 同時に届いた送り直しに先を越された
+        return match sqlx::query_as::<_, Ticket>("SELECT * FROM tickets WHERE id = $1 AND device_id = $2")
+            .bind(req.id)
+            .bind(device.id)
+            .fetch_optional(&state.pool)
+            .await
+            .unwrap_or(None)
+        {
+            Some(ticket) => respond(&ticket, true),
+            None => AppError::IdInUse.into_response(),
+        };
+    };
+
+    if let Some(at) = accepted_at {
+        let provisional = req.provisional.as_deref().map(str::trim).filter(|p| !p.is_empty());
+        let detail = format!(
+            "{}「{}」でオフライン中に受付 ({}){}",
+            device.kind_label(),
+            device.name,
+            settings.format_local(&at, "%H:%M"),
+            provisional.map_or(String::new(), |p| format!(" 仮番号 {} → {}", p.chars().take(20).collect::<String>(), ticket.number)),
+        );
+        record_event_detail(&state.pool, ticket.id, "offline_synced", None, Some(&detail)).await;
+    }
+    respond(&ticket, false)
+}
+
+// 発券機からの発券として受け付けられるか (HTTP の API と gRPC で共通)
+async fn check_kiosk_ticket(state: &AppState, device: &Device, settings: &Settings, group_size: i32, queue_id: i32) -> Result<(), AppError> {
+    if device.kind != "kiosk" {
+        return Err(AppError::NotAKiosk);
+    }
+    if settings.check_group_size(group_size).is_err() {
+        return Err(AppError::InvalidGroupSize);
+    }
+    if !device.serves(queue_id) || !fetch_queues(&state.pool).await.iter().any(|q| q.id == queue_id) {
+        return Err(AppError::InvalidQueue);
+    }
+    Ok(())
+}
+
+// --- gRPC (HTTP+HTML を話せない店頭の機器向け) ---
+// 番号表示器のコントローラーなどが使う。定義は proto/queueticket.proto (build.rs で生成)
+// 認証は HTTP の端末用APIと同じく、端末ごとのトークン (metadata の authorization: Bearer <トークン>)
+mod pb {
+    tonic::include_proto!("queueticket.v1");
+}
+
+use pb::tickets_server::{Tickets, TicketsServer};
+
+struct GrpcTickets {
+    state: AppState,
+}
+
+impl From<AppError> for tonic::Status {
+    fn from(e: AppError) -> Self {
+        let code = match e {
+            AppError::Unauthorized | AppError::InvalidSignature => tonic::Code::Unauthenticated,
+            AppError::TicketNotFound | AppError::QueueNotFound | AppError::UnknownSource => tonic::Code::NotFound,
+            AppError::InvalidTransition | AppError::QueueClosed | AppError::QueuePaused | AppError::IdInUse => tonic::Code::FailedPrecondition,
+            AppError::NotAKiosk | AppError::NotADisplay => tonic::Code::PermissionDenied,
+            AppError::RateLimited => tonic::Code::ResourceExhausted,
+            AppError::Maintenance => tonic::Code::Unavailable,
+            AppError::InvalidRequest | AppError::InvalidGroupSize | AppError::InvalidQueue | AppError::InvalidReservation => {
+                tonic::Code::InvalidArgument
+            }
+        };
+        // 機器の側で見分けやすいよう、メッセージは JSON の API の code と同じにする
+        tonic::Status::new(code, e.code())
+    }
+}
+
+impl GrpcTickets {
+    // 署名の本文はメッセージを protobuf でエンコードしたもの、パスは "/queueticket.v1.Tickets/IssueTicket" のような gRPC のパス
+    async fn device<T: prost::Message>(&self, request: &tonic::Request<T>, method: &str) -> Result<Device, tonic::Status> {
+        let headers = request.metadata().clone().into_headers();
+        let device = authenticated_device(&self.state, &headers).await.ok_or(AppError::Unauthorized)?;
+        let path = format!("/queueticket.v1.Tickets/{}", method);
+        verify_device_request(&self.state, &device, &headers, "POST", &path, &request.get_ref().encode_to_vec(), true).await?;
+        Ok(device)
+    }
+
+    // gRPC は HTTP のミドルウェアを通らないので、書き込みのメソッドでメンテナンス中かを確かめる
+    async fn writable(&self) -> Result<(), tonic::Status> {
+        if Settings::load(&self.state.pool).await.maintenance_mode {
+            return Err(AppError::Maintenance.into());
+        }
+        Ok(())
+    }
+
+    fn ticket(&self, ticket: &Ticket) -> pb::Ticket {
+        pb::Ticket {
+            id: ticket.id.to_string(),
+            number: ticket.number,
+            queue_id: ticket.queue_id,
+            group_size: ticket.group_size,
+            status: ticket.status.clone(),
+            guest_url: format!("{}{}", self.state.base_url, guest_path(&self.state, ticket)),
+            number_prefix: ticket.number_prefix.clone(),
+        }
+    }
+
+    // 呼び出し・完了は、番号表示器 (表示用モニターとして登録した端末) の担当する列の整理券だけ
+    // 列ごとに頭文字があると番号だけでは重なるので、頭文字と番号の組で探す
+    async fn update_by_number(&self, device: &Device, req: &pb::TicketNumberRequest, sql: &str) -> Result<Ticket, tonic::Status> {
+        if device.kind != "display" {
+            return Err(AppError::NotADisplay.into());
+        }
+        let (number, prefix) = (req.number, req.number_prefix.trim());
+        // 番号は一周して使い回すので、未完了のうち最も古いものを対象にする
+        let ticket = sqlx::query_as::<_, Ticket>(sql)
+            .bind(number)
+            .bind(&device.queue_ids)
+            .bind(prefix)
+            .fetch_optional(&self.state.pool)
+            .await
+            .expect("Failed to update ticket by number");
+        match ticket {
+            Some(ticket) => Ok(ticket),
+            None => {
+                let settings = Settings::load(&self.state.pool).await;
+                let exists: bool = sqlx::query_scalar(
+                    "SELECT EXISTS (SELECT 1 FROM tickets WHERE number = $1 AND number_prefix = $3 AND created_at >= $2)"
+                )
+                .bind(number)
+                .bind(settings.today_start(&Utc::now()))
+                .bind(prefix)
+                .fetch_one(&self.state.pool)
+                .await
+                .unwrap_or(false);
+                Err(if exists { AppError::InvalidTransition } else { AppError::TicketNotFound }.into())
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Tickets for GrpcTickets {
+    async fn issue_ticket(&self, request: tonic::Request<pb::IssueTicketRequest>) -> Result<tonic::Response<pb::Ticket>, tonic::Status> {
+        let device = self.device(&request, "IssueTicket").await?;
+        self.writable().await?;
+        let req = request.into_inner();
+        let settings = Settings::load(&self.state.pool).await;
+        let queue_id = req.queue_id.unwrap_or(DEFAULT_QUEUE_ID);
+        check_kiosk_ticket(&self.state, &device, &settings, req.group_size, queue_id).await?;
+        if settings.blocks_overbooking() && project_wait(&self.state, &settings).await.is_some_and(|p| p.exceeds_closing()) {
+            return Err(AppError::QueueClosed.into());
+        }
+        if wait_pause(&self.state, &settings).await.is_some() {
+            return Err(AppError::QueuePaused.into());
+        }
+
+        let new = NewTicket {
+            group_size: req.group_size,
+            queue_id: Some(queue_id),
+            device_id: Some(device.id),
+            ..Default::default()
+        };
+        let ticket = issue_ticket(&self.state, new, None).await;
+        Ok(tonic::Response::new(self.ticket(&ticket)))
+    }
+
+    async fn call_ticket(&self, request: tonic::Request<pb::TicketNumberRequest>) -> Result<tonic::Response<pb::Ticket>, tonic::Status> {
+        let device = self.device(&request, "CallTicket").await?;
+        self.writable().await?;
+        let ticket = self
+            .update_by_number(
+                &device,
+                &request.into_inner(),
+                "UPDATE tickets SET status = 'called', called_at = NOW() 
+                 WHERE id = (
+                     SELECT id FROM tickets 
+                     WHERE number = $1 AND number_prefix = $3 AND status IN ('waiting', 'called') AND ($2 = '{}' OR queue_id = ANY($2)) 
+                     ORDER BY created_at ASC LIMIT 1
+                 ) 
+                 RETURNING *",
+            )
+            .await?;
+        let detail = format!("{}「{}」", device.kind_label(), device.name);
+        record_event_detail(&self.state.pool, ticket.id, "called", None, Some(&detail)).await;
+        self.state.snapshot.invalidate();
+        self.state.run_hooks(TicketLifecycle::Called, &ticket);
+        Ok(tonic::Response::new(self.ticket(&ticket)))
+    }
+
+    async fn complete_ticket(&self, request: tonic::Request<pb::TicketNumberRequest>) -> Result<tonic::Response<pb::Ticket>, tonic::Status> {
+        let device = self.device(&request, "CompleteTicket").await?;
+        self.writable().await?;
+        let ticket = self
+            .update_by_number(
+                &device,
+                &request.into_inner(),
+                "UPDATE tickets SET status = 'completed', completed_at = NOW() 
+                 WHERE id = (
+                     SELECT id FROM tickets 
+                     WHERE number = $1 AND number_prefix = $3 AND status IN ('waiting', 'called') AND ($2 = '{}' OR queue_id = ANY($2)) 
+                     ORDER BY created_at ASC LIMIT 1
+                 ) 
+                 RETURNING *",
+            )
+            .await?;
+        let detail = format!("{}「{}」", device.kind_label(), device.name);
+        record_event_detail(&self.state.pool, ticket.id, "completed", None, Some(&detail)).await;
+        self.state.snapshot.invalidate();
+        self.state.run_hooks(TicketLifecycle::Completed, &ticket);
+        Ok(tonic::Response::new(self.ticket(&ticket)))
+    }
+
+    async fn get_wait(&self, request: tonic::Request<pb::GetWaitRequest>) -> Result<tonic::Response<pb::Wait>, tonic::Status> {
+        self.device(&request, "GetWait").await?;
+        let queue_id = request.into_inner().queue_id;
+        let wait = match self.state.public_wait.get(queue_id) {
+            Some(wait) => wait,
+            None => {
+                let wait = load_public_wait(&self.state, queue_id).await.ok_or(AppError::QueueNotFound)?;
+                self.state.public_wait.put(queue_id, wait.clone());
+                wait
+            }
+        };
+        Ok(tonic::Response::new(pb::Wait {
+            waiting_groups: wait.waiting_groups,
+            estimated_minutes: wait.estimated_minutes,
+            queue_open: wait.queue_open,
+        }))
+    }
+
+    type WatchCalledStream = ReceiverStream<Result<pb::CalledTicket, tonic::Status>>;
+
+    async fn watch_called(&self, request: tonic::Request<pb::WatchCalledRequest>) -> Result<tonic::Response<Self::WatchCalledStream>, tonic::Status> {
+        let device = self.device(&request, "WatchCalled").await?;
+        let mut queue_ids = request.into_inner().queue_ids;
+        if queue_ids.is_empty() {
+            queue_ids = device.queue_ids;
+        }
+        let state = self.state.clone();
+        let mut live = state.live.subscribe();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+
+        // 機器との接続が切れる (送れなくなる) まで、呼び出しを配る
+        tokio::spawn(async move {
+            let called = |ticket: Ticket| pb::CalledTicket {
+                id: ticket.id.to_string(),
+                number: ticket.number,
+                queue_id: ticket.queue_id,
+                cleared: false,
+                number_prefix: ticket.number_prefix,
+            };
+            let load = |id: Option<Uuid>| {
+                let pool = state.pool.clone();
+                let queue_ids = queue_ids.clone();
+                async move {
+                    sqlx::query_as::<_, Ticket>(
+                        "SELECT * FROM tickets WHERE ($1::uuid IS NULL OR id = $1) AND status = 'called' AND ($2 = '{}' OR queue_id = ANY($2)) 
+                         ORDER BY called_at DESC LIMIT 1"
+                    )
+                    .bind(id)
+                    .bind(&queue_ids)
+                    .fetch_optional(&pool)
+                    .await
+                    .unwrap_or(None)
+                }
+            };
+
+            // MQTT の retain と同じく、後から接続した機器にも今の番号を表示させる
+            if let Some(ticket) = load(None).await {
+                if tx.send(Ok(called(ticket))).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                let event = match live.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue, // 取りこぼした分は捨てる
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let message = match event.kind.as_str() {
+                    "called" => match load(event.id).await {
+                        Some(ticket) => called(ticket),
+                        None => continue, // 担当しない列
+                    },
+                    "reset" => pb::CalledTicket { cleared: true, ..Default::default() },
+                    _ => continue,
+                };
+                if tx.send(Ok(message)).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(tonic::Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+// ハートビートが途絶えた端末を、途絶えるたびに1回だけお知らせする
+// (一度も応答していない端末は、まだ設置していないものとして対象にしない)
+async fn alert_silent_devices(pool: &PgPool) {
+    let silent = sqlx::query_as::<_, Device>(
+        "UPDATE devices SET alerted_at = NOW() 
+         WHERE alerted_at IS NULL AND last_seen_at < NOW() - make_interval(mins => $1::int) 
+         RETURNING *"
+    )
+    .bind(DEVICE_SILENT_MINUTES)
+    .fetch_all(pool)
+    .await
+    .unwrap_or(vec![]);
+
+    for device in silent {
+        let message = format!("{}「{}」から{}分以上応答がありません。電源や接続を確認してください。", device.kind_label(), device.name, DEVICE_SILENT_MINUTES);
+        notify_staff(pool, "device_silent", &message, None).await;
+    }
+}
+
+async fn render_admin_devices(state: &AppState, created: Option<(String, String, String)>, flash: Option<Flash>) -> AdminDevicesTemplate {
+    let settings = Settings::load(&state.pool).await;
+    let devices = sqlx::query_as::<_, Device>(
+        "SELECT * FROM devices ORDER BY kind, id"
+    )
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![]);
+
+    AdminDevicesTemplate {
+        devices: devices
+            .into_iter()
+            .map(|d| {
+                let seen = d.last_seen_at.map_or("未接続".to_string(), |at| settings.format_local(&at, "%m/%d %H:%M"));
+                (d, seen)
+            })
+            .collect(),
+        kinds: DEVICE_KINDS,
+        languages: DEVICE_LANGUAGES,
+        queues: fetch_queues(&state.pool).await,
+        created,
+        base_url: state.base_url.clone(),
+        flash,
+    }
+}
+
+async fn admin_devices(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let flash = read_flash(&state, &headers);
+    let page = render_admin_devices(&state, None, flash.clone()).await;
+    clear_flash(&state, &flash, HtmlTemplate(page))
+}
+
+#[derive(Deserialize)]
+struct DeviceForm {
+    name: String,
+    kind: String,
+}
+
+// トークンと署名用の鍵はここで一度だけ表示し、DBにはトークンのHMACだけを残す
+async fn create_device(State(state): State<AppState>, headers: HeaderMap, Form(form): Form<DeviceForm>) -> impl IntoResponse {
+    let name = form.name.trim();
+    if name.is_empty() || !DEVICE_KINDS.iter().any(|(k, _)| *k == form.kind) {
+        return flash_redirect(&state, &headers, "/admin/devices", Flash::error("端末の名前と種類を入力してください。"));
+    }
+
+    let (token, signing_key) = register_device(&state, name, &form.kind, false).await;
+    HtmlTemplate(render_admin_devices(&state, Some((name.to_string(), token, signing_key)), None).await).into_response()
+}
+
+// 端末を登録し、(トークン, 署名用の鍵) を返す
+async fn register_device(state: &AppState, name: &str, kind: &str, auto_print: bool) -> (String, String) {
+    let token = Uuid::new_v4().simple().to_string();
+    sqlx::query("INSERT INTO devices (name, kind, token_hash, auto_print) VALUES ($1, $2, $3, $4)")
+        .bind(name)
+        .bind(kind)
+        .bind(hmac_hex(&state.cookie_key, &format!("device:{}", token)))
+        .bind(auto_print)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to create device");
+
+    let signing_key = device_signing_key(state, &token);
+    (token, signing_key)
+}
+
+// トークンを発行し直す (署名の仕組みより前に登録した端末を署名に切り替える時や、トークンが漏れた時)
+// 古いトークンはすぐに使えなくなる
+async fn reissue_device_token(Path(id): Path<i32>, State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let token = Uuid::new_v4().simple().to_string();
+    let name = sqlx::query_scalar::<_, String>(
+        "UPDATE devices SET token_hash = $2, allow_unsigned = FALSE WHERE id = $1 RETURNING name"
+    )
+    .bind(id)
+    .bind(hmac_hex(&state.cookie_key, &format!("device:{}", token)))
+    .fetch_optional(&state.pool)
+    .await
+    .expect("Failed to reissue device token");
+    let Some(name) = name else {
+        return flash_redirect(&state, &headers, "/admin/devices", Flash::error("端末が見つかりません。"));
+    };
+
+    let signing_key = device_signing_key(&state, &token);
+    HtmlTemplate(render_admin_devices(&state, Some((name, token, signing_key)), None).await).into_response()
+}
+
+// 担当する列はチェックボックスで複数送られてくるので、キーと値の組で受ける
+async fn update_device_config(
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(fields): Form<Vec<(String, String)>>,
+) -> impl IntoResponse {
+    let field = |name: &str| fields.iter().find(|(k, _)| k == name).map(|(_, v)| v.trim()).unwrap_or("");
+    let settings = Settings::load(&state.pool).await;
+
+    let known: Vec<i32> = fetch_queues(&state.pool).await.iter().map(|q| q.id).collect();
+    let queue_ids: Vec<i32> = fields
+        .iter()
+        .filter(|(k, _)| k == "queue_id")
+        .filter_map(|(_, v)| v.parse().ok())
+        .filter(|id| known.contains(id))
+        .collect();
+
+    // 「1, 2, 4」のようにカンマ区切りで入力する。人数の範囲外は無視する
+    let mut group_sizes: Vec<i32> = field("group_sizes")
+        .split([',', '、', ' '])
+        .filter_map(|v| v.trim().parse().ok())
+        .filter(|n| settings.check_group_size(*n).is_ok())
+        .collect();
+    group_sizes.sort_unstable();
+    group_sizes.dedup();
+
+    let language = Some(field("language"))
+        .filter(|l| DEVICE_LANGUAGES.iter().any(|(k, _)| k == l))
+        .unwrap_or("ja");
+
+    sqlx::query("UPDATE devices SET queue_ids = $2, group_sizes = $3, language = $4, auto_print = $5 WHERE id = $1")
+        .bind(id)
+        .bind(&queue_ids)
+        .bind(&group_sizes)
+        .bind(language)
+        .bind(!field("auto_print").is_empty())
+        .execute(&state.pool)
+        .await
+        .expect("Failed to update device config");
+
+    flash_redirect(&state, &headers, "/admin/devices", Flash::notice("端末の設定を保存しました。"))
+}
+
+async fn delete_device(Path(id): Path<i32>, State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    sqlx::query("DELETE FROM devices WHERE id = $1")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to delete device");
+
+    flash_redirect(&state, &headers, "/admin/devices", Flash::notice("端末を削除しました。"))
+}
+
+// --- スタッフ向けのお知らせ ---
+#[derive(FromRow)]
+struct StaffNotification {
+    kind: String,
+    message: String,
+    created_at: DateTime<Utc>,
+    read_at: Option<DateTime<Utc>>,
+}
+
+impl StaffNotification {
+    fn icon(&self) -> &'static str {
+        match self.kind.as_str() {
+            "wait_exceeded" => "⏰",
+            "guest_resized" => "👥",
+            "device_silent" | "device_status" => "📴",
+            _ => "⚠️", // 外部連携の失敗
+        }
+    }
+}
+
+// 管理画面のベルに出すお知らせを追加する
+// 失敗し続ける連携で一覧が埋まらないよう、同じ内容の未読のお知らせが直近30分にあれば追加しない
+// (失敗を知らせるための処理なので、ここで失敗しても元の処理は止めない)
+async fn notify_staff(pool: &PgPool, kind: &str, message: &str, ticket_id: Option<Uuid>) {
+    let result = sqlx::query(
+        "INSERT INTO staff_notifications (kind, message, ticket_id) 
+         SELECT $1, $2, $3 
+         WHERE NOT EXISTS (
+             SELECT 1 FROM staff_notifications 
+             WHERE kind = $1 AND message = $2 AND read_at IS NULL AND created_at > NOW() - INTERVAL '30 minutes'
+         )"
+    )
+    .bind(kind)
+    .bind(message)
+    .bind(ticket_id)
+    .execute(pool)
+    .await;
+    if let Err(e) = result {
+        tracing::warn!("Failed to add staff notification: {}", e);
+    }
+}
+
+// 設定した時間より長くお待ちのお客様を、1枚につき1回だけお知らせする
+// (チェックイン前のリモート受付は会場にいないので対象にしない)
+async fn alert_long_waits(pool: &PgPool, minutes: i64) {
+    sqlx::query(
+        "INSERT INTO staff_notifications (kind, message, ticket_id) 
+         SELECT 'wait_exceeded', '整理番号 ' || t.number || ' のお客様が' || $1 || '分以上お待ちです', t.id 
+         FROM tickets t 
+         WHERE t.status = 'waiting' AND t.created_at < NOW() - make_interval(mins => $1::int) 
+           AND (NOT t.remote OR t.checked_in_at IS NOT NULL) 
+           AND NOT EXISTS (SELECT 1 FROM staff_notifications n WHERE n.kind = 'wait_exceeded' AND n.ticket_id = t.id)"
+    )
+    .bind(minutes)
+    .execute(pool)
+    .await
+    .expect("Failed to alert long waits");
+}
+
+async fn unread_notification_count(pool: &PgPool) -> i64 {
+    sqlx::query_scalar("SELECT COUNT(*) FROM staff_notifications WHERE read_at IS NULL")
+        .fetch_one(pool)
+        .await
+        .unwrap_or(0)
+}
+
+async fn notifications_page(State(state): State<AppState>) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    let notifications = sqlx::query_as::<_, StaffNotification>(
+        "SELECT kind, message, created_at, read_at FROM staff_notifications ORDER BY created_at DESC, id DESC LIMIT 100"
+    )
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![]);
+
+    HtmlTemplate(AdminNotificationsTemplate {
+        unread: notifications.iter().filter(|n| n.read_at.is_none()).count(),
+        items: notifications
+            .into_iter()
+            .map(|n| {
+                let at = settings.format_local(&n.created_at, "%m/%d %H:%M");
+                (n, at)
+            })
+            .collect(),
+    })
+}
+
+async fn notification_bell(State(state): State<AppState>) -> impl IntoResponse {
+    HtmlTemplate(NotificationBellTemplate { unread: unread_notification_count(&state.pool).await })
+}
+
+async fn mark_notifications_read(State(state): State<AppState>) -> impl IntoResponse {
+    sqlx::query("UPDATE staff_notifications SET read_at = NOW() WHERE read_at IS NULL")
+        .execute(&state.pool)
+        .await
+        .expect("Failed to mark notifications as read");
+    Redirect::to(&url("/admin/notifications"))
+}
+
+// プライバシーモードの保存期間を過ぎた個人データを消す
+// 整理券そのもの (番号・時刻) は集計に使うので残し、電話番号と予約者名だけを外す
+// 案内中のチケットと返金前のデポジットは対象にしない
+async fn purge_personal_data(pool: &PgPool, days: i64) {
+    let statements = [
+        "UPDATE tickets SET phone = NULL 
+         WHERE phone IS NOT NULL AND status NOT IN ('waiting', 'called') AND created_at < NOW() - make_interval(days => $1::int)",
+        "UPDATE reservations SET name = '' 
+         WHERE name <> '' AND status <> 'booked' AND created_at < NOW() - make_interval(days => $1::int)",
+        "UPDATE deposits SET phone = NULL 
+         WHERE phone IS NOT NULL AND status <> 'paid' AND created_at < NOW() - make_interval(days => $1::int)",
+        "DELETE FROM phone_verifications WHERE created_at < NOW() - make_interval(days => $1::int)",
+        "DELETE FROM no_shows WHERE created_at < NOW() - make_interval(days => $1::int)",
+    ];
+    for sql in statements {
+        sqlx::query(sql)
+            .bind(days)
+            .execute(pool)
+            .await
+            .expect("Failed to purge personal data");
+    }
+}
+
+async fn render_admin_reservations(state: &AppState, error: Option<String>) -> AdminReservationsTemplate {
+    let settings = Settings::load(&state.pool).await;
+    let external = sqlx::query_as::<_, Reservation>(
+        "SELECT * FROM reservations WHERE source IS NOT NULL AND starts_at >= NOW() - INTERVAL '1 day' ORDER BY starts_at ASC"
+    )
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![])
+    .into_iter()
+    .map(|r| {
+        let label = r.starts_at.map(|at| settings.format_local(&at, "%m/%d %H:%M")).unwrap_or_default();
+        (Reservation { name: settings.staff_name(&r.name), ..r }, label)
+    })
+    .collect();
+    AdminReservationsTemplate {
+        slots: fetch_slots(state, &settings, false).await,
+        external,
+        error,
+        import_errors: vec![],
+        flash: None,
+    }
+}
+
+async fn admin_reservations(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let flash = read_flash(&state, &headers);
+    let mut page = render_admin_reservations(&state, None).await;
+    page.flash = flash.clone();
+    clear_flash(&state, &flash, HtmlTemplate(page))
+}
+
+// --- 予約台帳のCSVの読み込み ---
+// 紙の予約台帳から移行する店舗向けに、その日の予約をまとめて登録する
+// 1行が1組で「名前,人数,来店時刻」。来店時刻は 18:30 (今日) か 2026-10-16 18:30
+// 予約サービスの予約と同じく、来店時刻の少し前に整理券に変換される
+const RESERVATION_IMPORT_SOURCE: &str = "csv";
+const RESERVATION_IMPORT_MAX_ROWS: usize = 500;
+
+struct ImportedReservation {
+    name: String,
+    group_size: i32,
+    starts_at: DateTime<Utc>,
+}
+
+// 1行でも誤りがあれば、誤りのある行をすべて返す (途中まで読み込むと、直して読み込み直した時に重複するため)
+fn parse_reservation_csv(settings: &Settings, text: &str, now: DateTime<Utc>) -> Result<Vec<ImportedReservation>, Vec<String>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(text.as_bytes());
+    let mut rows = vec![];
+    let mut errors = vec![];
+    for (i, record) in reader.records().enumerate() {
+        let line = record.as_ref().ok().and_then(|r| r.position()).map_or(i as u64 + 1, |p| p.line());
+        let record = match record {
+            Ok(record) => record,
+            Err(_) => {
+                errors.push(format!("{}行目: CSVの形式が正しくありません。", line));
+                continue;
+            }
+        };
+        let (name, size, time) = (record.get(0).unwrap_or(""), record.get(1).unwrap_or(""), record.get(2).unwrap_or(""));
+        // 1行目が見出し (名前,人数,時刻 など) なら飛ばす
+        if i == 0 && size.parse::<i32>().is_err() {
+            continue;
+        }
+        let mut problems = vec![];
+        if name.is_empty() {
+            problems.push("名前が空です");
+        }
+        let group_size = size.parse::<i32>().ok().filter(|n| *n >= 1);
+        if group_size.is_none() {
+            problems.push("人数は1以上の数字で入力してください");
+        }
+        let starts_at = settings.parse_expected(time);
+        match starts_at {
+            None => problems.push("来店時刻は 18:30 か 2026-10-16 18:30 の形で入力してください"),
+            Some(at) if at < now => problems.push("来店時刻が過ぎています"),
+            Some(_) => {}
+        }
+        match (group_size, starts_at) {
+            (Some(group_size), Some(starts_at)) if problems.is_empty() => rows.push(ImportedReservation {
+                name: name.chars().take(50).collect(),
+                group_size,
+                starts_at,
+            }),
+            _ => errors.push(format!("{}行目: {}。", line, problems.join("、"))),
+        }
+    }
+    if rows.len() > RESERVATION_IMPORT_MAX_ROWS {
+        errors.push(format!("一度に読み込めるのは{}組までです。ファイルを分けてください。", RESERVATION_IMPORT_MAX_ROWS));
+    }
+
+    if errors.is_empty() { Ok(rows) } else { Err(errors) }
+}
+
+async fn import_reservations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> impl IntoResponse {
+    let mut data = None;
+    while let Ok(Some(field)) = multipart.next_field().await {
+        if field.name() == Some("file") {
+            data = field.bytes().await.ok();
+            break;
+        }
+    }
+    // Excel で「CSV UTF-8」として保存したファイルを想定する (先頭の BOM は読み飛ばす)
+    let Some(text) = data.and_then(|d| String::from_utf8(d.to_vec()).ok()) else {
+        let error = Some("ファイルを読み込めませんでした。UTF-8 のCSVファイルを選んでください。".to_string());
+        return (StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(render_admin_reservations(&state, error).await)).into_response();
+    };
+
+    let settings = Settings::load(&state.pool).await;
+    let rows = match parse_reservation_csv(&settings, text.trim_start_matches('\u{feff}'), Utc::now()) {
+        Ok(rows) if rows.is_empty() => {
+            let error = Some("読み込む予約がありません。".to_string());
+            return (StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(render_admin_reservations(&state, error).await)).into_response();
+        }
+        Ok(rows) => rows,
+        Err(errors) => {
+            let error = Some("誤りのある行があったため、読み込みませんでした。直してからもう一度読み込んでください。".to_string());
+            let mut page = render_admin_reservations(&state, error).await;
+            page.import_errors = errors;
+            return (StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(page)).into_response();
+        }
+    };
+
+    // 同じファイルを2回読み込んでも重複しないよう、同じ名前・人数・来店時刻の予約は飛ばす
+    let mut tx = state.pool.begin().await.expect("Failed to begin transaction");
+    let mut created = 0;
+    for row in &rows {
+        let inserted = sqlx::query(
+            "INSERT INTO reservations (source, starts_at, name, group_size) 
+             SELECT $1, $2, $3, $4 
+             WHERE NOT EXISTS (
+                 SELECT 1 FROM reservations 
+                 WHERE source = $1 AND starts_at = $2 AND name = $3 AND group_size = $4 AND status != 'cancelled'
+             )"
+        )
+        .bind(RESERVATION_IMPORT_SOURCE)
+        .bind(row.starts_at)
+        .bind(&row.name)
+        .bind(row.group_size)
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to import reservation")
+        .rows_affected();
+        created += inserted;
+    }
+    tx.commit().await.expect("Failed to commit transaction");
+
+    let skipped = rows.len() as u64 - created;
+    let message = if skipped > 0 {
+        format!("{}組の予約を読み込みました (読み込み済みの{}組は飛ばしました)。", created, skipped)
+    } else {
+        format!("{}組の予約を読み込みました。", created)
+    };
+    flash_redirect(&state, &headers, "/admin/reservations", Flash::notice(&message))
+}
+
+#[derive(Deserialize)]
+struct CreateSlotForm {
+    starts_at: String, // datetime-local (店舗の現地時刻)
+    capacity: i32,
+    count: Option<i32>,        // 同じ間隔で続けて作る枠の数
+    interval_minutes: Option<i32>,
+}
+
+async fn create_slot(
+    State(state): State<AppState>,
+    Form(form): Form<CreateSlotForm>,
+) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    let Some(starts_at) = settings.parse_local(&form.starts_at) else {
+        let error = Some("開始時刻を正しく入力してください。".to_string());
+        return (StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(render_admin_reservations(&state, error).await)).into_response();
+    };
+    if form.capacity < 1 {
+        let error = Some("受け入れ組数は1以上にしてください。".to_string());
+        return (StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(render_admin_reservations(&state, error).await)).into_response();
+    }
+
+    // 例: 18:00から30分おきに4枠、のようにまとめて作れるようにする
+    let count = form.count.unwrap_or(1).clamp(1, 48);
+    let interval = form.interval_minutes.unwrap_or(30).max(1);
+    for i in 0..count {
+        sqlx::query("INSERT INTO reservation_slots (starts_at, capacity) VALUES ($1, $2)")
+            .bind(starts_at + chrono::Duration::minutes((i * interval) as i64))
+            .bind(form.capacity)
+            .execute(&state.pool)
+            .await
+            .expect("Failed to create reservation slot");
+    }
+
+    Redirect::to(&url("/admin/reservations")).into_response()
+}
+
+async fn delete_slot(Path(id): Path<Uuid>, State(state): State<AppState>) -> impl IntoResponse {
+    sqlx::query("DELETE FROM reservation_slots WHERE id = $1")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to delete reservation slot");
+    Redirect::to(&url("/admin/reservations"))
+}
+
+#[derive(Deserialize)]
+struct BookForm {
+    name: String,
+    group_size: i32,
+}
+
+// スタッフが電話などで受けた予約を代理で登録する (開始済みの枠にも登録できる)
+async fn admin_book(
+    Path(slot_id): Path<Uuid>,
+    State(state): State<AppState>,
+    Form(form): Form<BookForm>,
+) -> impl IntoResponse {
+    match book_slot(&state, slot_id, &form.name, form.group_size, false).await {
+        Ok(_) => Redirect::to(&url("/admin/reservations")).into_response(),
+        Err(error) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            HtmlTemplate(render_admin_reservations(&state, Some(error)).await),
+        )
+            .into_response(),
+    }
+}
+
+async fn admin_cancel_reservation(Path(id): Path<Uuid>, State(state): State<AppState>) -> impl IntoResponse {
+    sqlx::query("UPDATE reservations SET status = 'cancelled' WHERE id = $1 AND status = 'booked'")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to cancel reservation");
+    Redirect::to(&url("/admin/reservations"))
+}
+
+// 予約者が早めに来店した場合などに、枠の時刻を待たずに発券する
+async fn admin_queue_reservation(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let acting = acting_user(&state, &headers).await;
+    queue_reservation(&state, id, acting.as_ref()).await;
+    Redirect::to(&url("/admin/reservations"))
+}
+
+// 来場者向けの予約画面 (設定で有効にした場合のみ)
+async fn reserve_page(State(state): State<AppState>) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    if !settings.public_reservations {
+        return (StatusCode::NOT_FOUND, "現在、予約は受け付けていません。").into_response();
+    }
+
+    HtmlTemplate(ReserveTemplate {
+        slots: fetch_slots(&state, &settings, true).await,
+        error: None,
+    })
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct ReserveForm {
+    slot_id: Uuid,
+    name: String,
+    group_size: i32,
+}
+
+async fn reserve(State(state): State<AppState>, Form(form): Form<ReserveForm>) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    if !settings.public_reservations {
+        return (StatusCode::NOT_FOUND, "現在、予約は受け付けていません。").into_response();
+    }
+
+    match book_slot(&state, form.slot_id, &form.name, form.group_size, true).await {
+        Ok(reservation) => Redirect::to(&url(&format!("/reserve/{}", reservation.id))).into_response(),
+        Err(error) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            HtmlTemplate(ReserveTemplate {
+                slots: fetch_slots(&state, &settings, true).await,
+                error: Some(error),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+// 予約の確認画面。整理券に変換済みなら整理券の画面へ案内する
+async fn reserve_status(Path(id): Path<Uuid>, State(state): State<AppState>) -> impl IntoResponse {
+    let Some(reservation) = sqlx::query_as::<_, Reservation>("SELECT * FROM reservations WHERE id = $1")
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await
+        .unwrap_or(None)
+    else {
+        return (StatusCode::NOT_FOUND, "予約が見つかりません。").into_response();
+    };
+
+    if let Some(ticket_id) = reservation.ticket_id {
+        return redirect_to_guest(&state, ticket_id).await;
+    }
+
+    let settings = Settings::load(&state.pool).await;
+    let starts_at: Option<DateTime<Utc>> = match reservation.starts_at {
+        Some(at) => Some(at),
+        None => sqlx::query_scalar("SELECT starts_at FROM reservation_slots WHERE id = $1")
+            .bind(reservation.slot_id)
+            .fetch_optional(&state.pool)
+            .await
+            .unwrap_or(None),
+    };
+
+    HtmlTemplate(ReserveStatusTemplate {
+        slot_label: starts_at
+            .map(|at| settings.format_local(&at, "%m/%d %H:%M"))
+            .unwrap_or_default(),
+        reservation,
+    })
+    .into_response()
+}
+
+async fn reserve_cancel(Path(id): Path<Uuid>, State(state): State<AppState>) -> impl IntoResponse {
+    sqlx::query("UPDATE reservations SET status = 'cancelled' WHERE id = $1 AND status = 'booked'")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to cancel reservation");
+    Redirect::to(&url(&format!("/reserve/{}", id)))
+}
+
+// --- 予約のカレンダー (iCal) ---
+// 責任者が普段のカレンダーアプリで、今夜の予約を確認できるようにする
+// カレンダーアプリはヘッダーをつけられないので、キーは URL に入れる (/calendar.ics?key=...)
+const CALENDAR_DAYS: i64 = 30;
+// 予約枠には長さがないので、カレンダー上はこの長さで表示する
+const CALENDAR_EVENT_MINUTES: i64 = 30;
+
+#[derive(Deserialize)]
+struct CalendarQuery {
+    key: Option<String>,
+}
+
+// iCal の TEXT の値のエスケープ (RFC 5545 3.3.11)
+fn ical_text(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+        .replace('\r', "")
+}
+
+// 1行75バイトを超える行は、空白で始まる次の行に折り返す (文字の途中では切らない)
+fn ical_line(out: &mut String, line: &str) {
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+fn ical_time(at: &DateTime<Utc>) -> String {
+    at.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+fn ical_event(out: &mut String, uid: &str, starts_at: &DateTime<Utc>, summary: &str, description: &str) {
+    let ends_at = *starts_at + chrono::Duration::minutes(CALENDAR_EVENT_MINUTES);
+    ical_line(out, "BEGIN:VEVENT");
+    ical_line(out, &format!("UID:{}@queueticket", uid));
+    ical_line(out, &format!("DTSTAMP:{}", ical_time(&Utc::now())));
+    ical_line(out, &format!("DTSTART:{}", ical_time(starts_at)));
+    ical_line(out, &format!("DTEND:{}", ical_time(&ends_at)));
+    ical_line(out, &format!("SUMMARY:{}", ical_text(summary)));
+    if !description.is_empty() {
+        ical_line(out, &format!("DESCRIPTION:{}", ical_text(description)));
+    }
+    ical_line(out, "END:VEVENT");
+}
+
+// 今日から CALENDAR_DAYS 日先までの予約枠と、予約サービス・予約台帳から受け取った予約
+async fn calendar_feed(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<CalendarQuery>,
+) -> impl IntoResponse {
+    let allowed = bearer_matches(&state, &headers, "calendar")
+        || query.key.is_some_and(|key| state.credentials.accepts("calendar", &key));
+    if !allowed {
+        return (StatusCode::UNAUTHORIZED, "キーが正しくありません。").into_response();
+    }
+
+    let settings = Settings::load(&state.pool).await;
+    let from = settings.today_start(&Utc::now());
+    let until = from + chrono::Duration::days(CALENDAR_DAYS);
+    let mut out = String::new();
+    ical_line(&mut out, "BEGIN:VCALENDAR");
+    ical_line(&mut out, "VERSION:2.0");
+    ical_line(&mut out, "PRODID:-//QueueTicket//Reservations//JA");
+    ical_line(&mut out, "CALSCALE:GREGORIAN");
+    ical_line(&mut out, "X-WR-CALNAME:予約");
+
+    // 予約枠は、予約した組の名前と人数を説明に並べる
+    let slots = fetch_slots(&state, &settings, false)
+        .await
+        .into_iter()
+        .filter(|slot| slot.starts_at >= from && slot.starts_at < until);
+    for slot in slots {
+        let parties: Vec<String> = slot
+            .reservations
+            .iter()
+            .filter(|r| r.status != "cancelled")
+            .map(|r| format!("{} {}名", r.name, r.group_size))
+            .collect();
+        let summary = format!("予約枠 {}/{}組", slot.booked, slot.capacity);
+        ical_event(&mut out, &format!("slot-{}", slot.id), &slot.starts_at, &summary, &parties.join("\n"));
+    }
+
+    let external = sqlx::query_as::<_, Reservation>(
+        "SELECT * FROM reservations 
+         WHERE source IS NOT NULL AND status != 'cancelled' AND starts_at >= $1 AND starts_at < $2 
+         ORDER BY starts_at ASC"
+    )
+    .bind(from)
+    .bind(until)
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![]);
+    for r in external {
+        let Some(starts_at) = r.starts_at else { continue };
+        let summary = format!("{} {}名", settings.staff_name(&r.name), r.group_size);
+        let source = if r.source.as_deref() == Some(RESERVATION_IMPORT_SOURCE) { "予約台帳 (CSV)".to_string() } else { r.source.unwrap_or_default() };
+        let description = if r.status == "queued" { format!("{}から (発券済み)", source) } else { format!("{}から", source) };
+        ical_event(&mut out, &format!("reservation-{}", r.id), &starts_at, &summary, &description);
+    }
+    ical_line(&mut out, "END:VCALENDAR");
+
+    (
+        [
+            (CONTENT_TYPE, "text/calendar; charset=utf-8"),
+            (axum::http::header::CACHE_CONTROL, "private, max-age=300"),
+        ],
+        out,
+    )
+        .into_response()
+}
+
+// --- 予約サービスからの予約 ---
+// TableCheck・トレタなどの予約サービスから Webhook で予約の作成・変更・取り消しを受け取り、
+// 予約枠の予約と同じく、来店時刻の少し前に整理券に変換して待機列に入れる
+// 送られてくる JSON の形はサービスごとに違うので、項目の場所 (JSON Pointer) を RESERVATION_SOURCES で決める
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+struct ReservationMapping {
+    id: String,                 // 予約サービス側の予約ID
+    starts_at: String,          // 来店時刻 (RFC 3339 の文字列か、UNIX時刻の秒)
+    name: String,               // 予約者名 (なくてもよい)
+    group_size: String,         // 人数 (数値か数字の文字列)
+    event: String,              // 作成・変更と取り消しを見分ける項目
+    cancel_values: Vec<String>, // event がこのどれかなら取り消し (大文字・小文字は区別しない)
+}
+
+impl Default for ReservationMapping {
+    fn default() -> Self {
+        ReservationMapping {
+            id: "/id".to_string(),
+            starts_at: "/starts_at".to_string(),
+            name: "/name".to_string(),
+            group_size: "/group_size".to_string(),
+            event: "/event".to_string(),
+            cancel_values: vec!["cancelled".to_string(), "canceled".to_string()],
+        }
+    }
+}
+
+// 受け取った予約 (項目の場所に従って取り出したもの)
+struct ExternalReservation {
+    external_id: String,
+    starts_at: Option<DateTime<Utc>>,
+    name: String,
+    group_size: Option<i32>,
+    cancelled: bool,
+}
+
+impl ReservationMapping {
+    // 予約IDが読めなければ None
+    fn extract(&self, body: &serde_json::Value) -> Option<ExternalReservation> {
+        let text = |pointer: &str| match body.pointer(pointer)? {
+            serde_json::Value::String(v) => Some(v.trim().to_string()),
+            serde_json::Value::Number(v) => Some(v.to_string()),
+            _ => None,
+        };
+        let external_id = text(&self.id).filter(|v| !v.is_empty())?;
+        let starts_at = match body.pointer(&self.starts_at) {
+            Some(serde_json::Value::String(v)) => DateTime::parse_from_rfc3339(v.trim()).ok().map(|at| at.with_timezone(&Utc)),
+            Some(serde_json::Value::Number(v)) => v.as_i64().and_then(|secs| DateTime::from_timestamp(secs, 0)),
+            _ => None,
+        };
+        let cancelled = !self.event.is_empty()
+            && text(&self.event).is_some_and(|event| self.cancel_values.iter().any(|c| c.eq_ignore_ascii_case(&event)));
+        Some(ExternalReservation {
+            external_id,
+            starts_at,
+            name: text(&self.name).unwrap_or_default().chars().take(50).collect(),
+            group_size: text(&self.group_size).and_then(|v| v.parse().ok()),
+            cancelled,
+        })
+    }
+}
+
+// 既定の "generic" (上の Default の形) に、RESERVATION_SOURCES の JSON で追加・上書きする
+// 例: {"tablecheck": {"id": "/reservation/id", "starts_at": "/reservation/start_at", ...}}
+fn reservation_sources(json: Option<String>) -> Result<HashMap<String, ReservationMapping>, String> {
+    let mut sources = HashMap::from([("generic".to_string(), ReservationMapping::default())]);
+    if let Some(json) = json.filter(|v| !v.trim().is_empty()) {
+        let configured: HashMap<String, ReservationMapping> = serde_json::from_str(&json).map_err(|e| e.to_string())?;
+        sources.extend(configured);
+    }
+    Ok(sources)
+}
+
+#[derive(Deserialize)]
+struct ReservationKeyQuery {
+    key: Option<String>,
+}
+
+// Authorization: Bearer <RESERVATION_WEBHOOK_KEY>。ヘッダーを決められないサービス向けに ?key= でも受け付ける
+async fn reservation_auth(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> impl IntoResponse {
+    let query_key = Query::<ReservationKeyQuery>::try_from_uri(req.uri()).ok().and_then(|q| q.0.key);
+    let allowed = bearer_matches(&state, req.headers(), "reservations")
+        || query_key.is_some_and(|key| state.credentials.accepts("reservations", &key));
+    if !allowed {
+        return AppError::Unauthorized.into_response();
+    }
+
+    next.run(req).await
+}
+
+async fn receive_reservation(
+    Path(source): Path<String>,
+    State(state): State<AppState>,
+    body: Result<Json<serde_json::Value>, axum::extract::rejection::JsonRejection>,
+) -> impl IntoResponse {
+    let Some(mapping) = state.reservation_sources.get(&source) else {
+        return AppError::UnknownSource.into_response();
+    };
+    let Json(body) = match body {
+        Ok(body) => body,
+        Err(e) => return AppError::from(e).into_response(),
+    };
+    let Some(external) = mapping.extract(&body) else {
+        return AppError::InvalidRequest.into_response();
+    };
+
+    if external.cancelled {
+        return cancel_external_reservation(&state, &source, &external.external_id).await;
+    }
+
+    let (Some(starts_at), Some(group_size)) = (external.starts_at, external.group_size.filter(|n| *n > 0)) else {
+        return AppError::InvalidReservation.into_response();
+    };
+    // 予約サービスで確定した予約なので、人数が店舗の設定の範囲外でも受け取る
+    let name = if external.name.is_empty() { format!("{}の予約", source) } else { external.name };
+
+    // 同じ予約の変更は上書きする。取り消した後に戻された予約は、まだ発券していなければ予約中に戻す
+    let reservation = sqlx::query_as::<_, Reservation>(
+        "INSERT INTO reservations (source, external_id, starts_at, name, group_size) 
+         VALUES ($1, $2, $3, $4, $5) 
+         ON CONFLICT (source, external_id) WHERE source IS NOT NULL DO UPDATE SET 
+             starts_at = EXCLUDED.starts_at, 
+             name = EXCLUDED.name, 
+             group_size = EXCLUDED.group_size, 
+             status = CASE WHEN reservations.status = 'cancelled' AND reservations.ticket_id IS NULL 
+                           THEN 'booked' ELSE reservations.status END 
+         RETURNING *"
+    )
+    .bind(&source)
+    .bind(&external.external_id)
+    .bind(starts_at)
+    .bind(&name)
+    .bind(group_size)
+    .fetch_one(&state.pool)
+    .await
+    .expect("Failed to save external reservation");
+
+    // 発券済みで待機中なら、人数の変更を整理券にも反映する
+    if let (Some(ticket_id), "queued") = (reservation.ticket_id, reservation.status.as_str()) {
+        let updated = sqlx::query("UPDATE tickets SET group_size = $2 WHERE id = $1 AND status = 'waiting' AND group_size != $2")
+            .bind(ticket_id)
+            .bind(group_size)
+            .execute(&state.pool)
+            .await
+            .expect("Failed to update ticket group size")
+            .rows_affected();
+        if updated > 0 {
+            state.snapshot.invalidate();
+        }
+    }
+
+    Json(serde_json::json!({
+        "reservation_id": reservation.id,
+        "status": reservation.status,
+        "ticket_id": reservation.ticket_id,
+    }))
+    .into_response()
+}
+
+// 取り消し。発券済みで、まだ呼び出していない整理券は列から外す
+async fn cancel_external_reservation(state: &AppState, source: &str, external_id: &str) -> Response {
+    let reservation = sqlx::query_as::<_, Reservation>(
+        "UPDATE reservations SET status = 'cancelled' 
+         WHERE source = $1 AND external_id = $2 AND status != 'cancelled' 
+         RETURNING *"
+    )
+    .bind(source)
+    .bind(external_id)
+    .fetch_optional(&state.pool)
+    .await
+    .expect("Failed to cancel external reservation");
+    // 知らない予約・取り消し済みの予約の取り消しは、何もせずに受け取る (再送されても同じ結果にする)
+    let Some(reservation) = reservation else {
+        return Json(serde_json::json!({ "reservation_id": null, "status": "cancelled", "ticket_id": null })).into_response();
+    };
+
+    if let Some(ticket_id) = reservation.ticket_id {
+        // 予約サービス側の誤操作でも戻せるよう、ゴミ箱に入れる
+        let ticket = sqlx::query_as::<_, Ticket>(
+            "UPDATE tickets SET status = 'deleted', deleted_at = NOW() WHERE id = $1 AND status = 'waiting' RETURNING *"
+        )
+        .bind(ticket_id)
+        .fetch_optional(&state.pool)
+        .await
+        .expect("Failed to cancel ticket");
+        if let Some(ticket) = &ticket {
+            let detail = format!("{} の予約 {}", source, external_id);
+            record_event_detail(&state.pool, ticket.id, "reservation_cancelled", None, Some(&detail)).await;
+            state.snapshot.invalidate();
+            state.run_hooks(TicketLifecycle::Cancelled, ticket);
+        }
+    }
+
+    Json(serde_json::json!({
+        "reservation_id": reservation.id,
+        "status": reservation.status,
+        "ticket_id": reservation.ticket_id,
+    }))
+    .into_response()
+}
+
+// --- 表示用モニター ---
+async fn display_page(
+    State(state): State<AppState>,
+    Query(query): Query<DisplayKeyQuery>,
+) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    HtmlTemplate(DisplayTemplate {
+        key: query.key,
+        device: query.device,
+        chime_url: state.chime_url.clone(),
+        checkin_code: Some(settings.checkin_code).filter(|c| settings.remote_join && !c.is_empty()),
+        poll_seconds: settings.staff_poll_seconds,
+    })
+}
+
+async fn display_content(State(state): State<AppState>) -> impl IntoResponse {
+    let snapshot = state.snapshot.get(&state.pool).await;
+    let settings = Settings::load(&state.pool).await;
+
+    // 呼び出し中 (新しく呼ばれた順)
+    let mut called: Vec<Ticket> = snapshot.tickets.iter().filter(|t| t.status == "called").cloned().collect();
+    called.sort_by(|a, b| b.called_at.cmp(&a.called_at).then(a.number.cmp(&b.number)));
+
+    // 次に呼ばれる予定の待機中 (発券順に数件)
+    let mut waiting: Vec<Ticket> = snapshot.tickets.iter().filter(|t| t.status == "waiting").cloned().collect();
+    waiting.sort_by(|a, b| {
+        b.priority
+            .cmp(&a.priority)
+            .then(a.created_at.cmp(&b.created_at))
+            .then(a.number.cmp(&b.number))
+    });
+    waiting.truncate(state.display_waiting_count.max(0) as usize);
+
+    let page = HtmlTemplate(DisplayContentTemplate {
+        now_serving: snapshot.now_serving.clone(),
+        called,
+        waiting,
+        highlight_since: Utc::now() - chrono::Duration::seconds(state.highlight_seconds),
+        queues: fetch_queues(&state.pool).await,
+    });
+    (poll_header(settings.staff_poll_seconds), page)
+}
+
+fn poll_header(seconds: i64) -> [(&'static str, String); 1] {
+    [(POLL_SECONDS_HEADER, seconds.to_string())]
+}
+
+// お店のアプリや独自の画面向けに、自動更新の間隔を返す (運用設定の値。変えたらすぐ反映されるようキャッシュは短く)
+async fn client_config(State(state): State<AppState>) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    (
+        [(axum::http::header::CACHE_CONTROL, "public, max-age=30")],
+        Json(serde_json::json!({
+            "guest_poll_seconds": settings.guest_poll_seconds,
+            "staff_poll_seconds": settings.staff_poll_seconds,
+        })),
+    )
+}
+
+// --- 待ち状況ウィジェット ---
+// 店舗のWebサイトなどに、現在の待ち時間と待ち組数を埋め込んで表示する
+#[derive(Deserialize)]
+struct WidgetQuery {
+    queue: Option<i32>, // 列を指定するとその列だけを数える
+}
+
+#[derive(Serialize)]
+struct WidgetStatus {
+    queue: Option<String>,
+    waiting_groups: i64,       // 待機中の組数
+    wait_minutes: Option<i64>, // 今から並んだ場合の待ち時間の見込み (実績がなければ null)
+    now_serving: Option<i32>,  // ただいまご案内中の番号 (今日まだ呼び出していなければ null)
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(Template)]
+#[template(path = "widget.html")]
+struct WidgetTemplate {
+    status: WidgetStatus,
+}
+
+// 存在しない列を指定された場合は None
+async fn widget_status(state: &AppState, queue_id: Option<i32>) -> Option<WidgetStatus> {
+    let queue = match queue_id {
+        Some(id) => Some(
+            sqlx::query_scalar::<_, String>("SELECT name FROM queues WHERE id = $1")
+                .bind(id)
+                .fetch_optional(&state.pool)
+                .await
+                .unwrap_or(None)?,
+        ),
+        None => None,
+    };
+
+    // 外部のサイトから頻繁に読まれるので、スナップショットから数える
+    let snapshot = state.snapshot.get(&state.pool).await;
+    let in_queue: Vec<&Ticket> = snapshot
+        .real_tickets()
+        .filter(|t| queue_id.is_none_or(|id| t.queue_id == id))
+        .collect();
+    let waiting_groups = in_queue.iter().filter(|t| t.status == "waiting").count() as i64;
+
+    Some(WidgetStatus {
+        queue,
+        waiting_groups,
+        wait_minutes: snapshot.wait_minutes(in_queue.iter().copied(), queue_id),
+        now_serving: snapshot.now_serving(queue_id),
+        updated_at: Utc::now(),
+    })
+}
+
+async fn widget_page(State(state): State<AppState>, Query(query): Query<WidgetQuery>) -> impl IntoResponse {
+    match widget_status(&state, query.queue).await {
+        Some(status) => HtmlTemplate(WidgetTemplate { status }).into_response(),
+        None => AppError::QueueNotFound.html(),
+    }
+}
+
+async fn widget_json(State(state): State<AppState>, Query(query): Query<WidgetQuery>) -> impl IntoResponse {
+    match widget_status(&state, query.queue).await {
+        Some(status) => Json(status).into_response(),
+        None => AppError::QueueNotFound.into_response(),
+    }
+}
+
+// --- 公開の待ち時間API ---
+// 認証なしで、待ち組数・待ち時間の見込み・受付中かだけを返す (まとめサイトや店舗のWebサイト向け)
+// 整理券のAPIとは切り離し、内容は少しの間使い回して、送信元ごとに回数を制限する
+const PUBLIC_WAIT_CACHE_SECONDS: u64 = 30;
+const PUBLIC_WAIT_LIMIT_PER_MINUTE: usize = 60;
+
+#[derive(Clone, Serialize)]
+struct PublicWait {
+    waiting_groups: i64,
+    estimated_minutes: Option<i64>, // 実績がなければ null
+    queue_open: bool,               // 閉店時刻を過ぎた・閉店に間に合わない見込みで発券を止めている時は false
+}
+
+// 列ごと (None は全体) の、作った時刻と直近の応答
+type PublicWaitEntries = HashMap<Option<i32>, (Instant, PublicWait)>;
+
+// 存在する列だけを入れるので、大きくはならない
+#[derive(Clone, Default)]
+struct PublicWaitCache {
+    entries: Arc<Mutex<PublicWaitEntries>>,
+}
+
+impl PublicWaitCache {
+    fn get(&self, queue_id: Option<i32>) -> Option<PublicWait> {
+        let entries = self.entries.lock().expect("public wait cache lock poisoned");
+        entries
+            .get(&queue_id)
+            .filter(|(at, _)| at.elapsed() < Duration::from_secs(PUBLIC_WAIT_CACHE_SECONDS))
+            .map(|(_, wait)| wait.clone())
+    }
+
+    fn put(&self, queue_id: Option<i32>, wait: PublicWait) {
+        let mut entries = self.entries.lock().expect("public wait cache lock poisoned");
+        entries.insert(queue_id, (Instant::now(), wait));
+    }
+}
+
+// 存在しない列を指定された場合は None
+async fn load_public_wait(state: &AppState, queue_id: Option<i32>) -> Option<PublicWait> {
+    let status = widget_status(state, queue_id).await?;
+    let settings = Settings::load(&state.pool).await;
+    let now = Utc::now();
+    let past_closing = settings.closing_at(&now).is_some_and(|at| now >= at);
+    let blocked = (settings.blocks_overbooking() && project_wait(state, &settings).await.is_some_and(|p| p.exceeds_closing()))
+        || wait_pause(state, &settings).await.is_some();
+    Some(PublicWait {
+        waiting_groups: status.waiting_groups,
+        estimated_minutes: status.wait_minutes,
+        queue_open: !past_closing && !blocked,
+    })
+}
+
+async fn public_wait(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<WidgetQuery>,
+) -> impl IntoResponse {
+    let key = format!("wait:{}", client_ip(&headers));
+    if !check_rate_limit(&state.pool, &key, PUBLIC_WAIT_LIMIT_PER_MINUTE, Duration::from_secs(60)).await {
+        return AppError::RateLimited.into_response();
+    }
+    let wait = match state.public_wait.get(query.queue) {
+        Some(wait) => wait,
+        None => {
+            let Some(wait) = load_public_wait(&state, query.queue).await else {
+                return AppError::QueueNotFound.into_response();
+            };
+            state.public_wait.put(query.queue, wait.clone());
+            wait
+        }
+    };
+    let cache_control = format!("public, max-age={}", PUBLIC_WAIT_CACHE_SECONDS);
+    ([(axum::http::header::CACHE_CONTROL, cache_control)], Json(wait)).into_response()
+}
+
+// --- イベント取得API ---
+// 分析基盤がチケットの操作の記録 (ticket_events) を差分だけ取り込めるよう、挿入順 (id順) に返す
+// 前回の最後の id を after に渡して続きを読む。全件の書き出しをしなくても同期できる
+const EVENTS_PAGE_DEFAULT: i64 = 500;
+const EVENTS_PAGE_MAX: i64 = 1000;
+// id は挿入時に振られるが、書き込みの確定は前後しうる。確定し終えたものだけを返し、読み飛ばしを防ぐ (秒)
+const EVENTS_SETTLE_SECONDS: i32 = 10;
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    after: Option<i64>, // 前回受け取った最後のイベントの id (省略すると最初から)
+    limit: Option<i64>,
+}
+
+#[derive(Serialize, FromRow)]
+struct ApiEvent {
+    id: i64,
+    ticket_id: Uuid,
+    event_type: String,
+    detail: Option<String>,
+    staff: Option<String>, // 操作したスタッフの名前 (未選択・削除済みなら null)
+    queue_id: i32,
+    number_prefix: String,
+    number: i32,
+    group_size: i32,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct EventsPage {
+    events: Vec<ApiEvent>,
+    next_cursor: i64, // 次の after に渡す値 (新しいイベントがなければ after のまま)
+    has_more: bool,   // true ならすぐに続きを読める
+}
+
+async fn export_events(State(state): State<AppState>, Query(query): Query<EventsQuery>) -> impl IntoResponse {
+    let after = query.after.unwrap_or(0).max(0);
+    let limit = query.limit.unwrap_or(EVENTS_PAGE_DEFAULT).clamp(1, EVENTS_PAGE_MAX);
+
+    // 練習用の整理券は集計に含めないので、分析基盤にも渡さない
+    // 1件多く読んで、続きがあるかを判断する
+    let mut events = sqlx::query_as::<_, ApiEvent>(
+        "SELECT e.id, e.ticket_id, e.event_type, e.detail, u.name AS staff, 
+                t.queue_id, t.number_prefix, t.number, t.group_size, e.created_at 
+         FROM ticket_events e 
+         JOIN tickets t ON t.id = e.ticket_id 
+         LEFT JOIN users u ON u.id = e.user_id 
+         WHERE e.id > $1 AND e.created_at < NOW() - make_interval(secs => $2) AND NOT t.is_test 
+         ORDER BY e.id 
+         LIMIT $3"
+    )
+    .bind(after)
+    .bind(EVENTS_SETTLE_SECONDS)
+    .bind(limit + 1)
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![]);
+
+    let has_more = events.len() as i64 > limit;
+    events.truncate(limit as usize);
+    let next_cursor = events.last().map_or(after, |e| e.id);
+    Json(EventsPage { events, next_cursor, has_more })
+}
+
+// --- リモート受付 ---
+// 来店前にスマホから整理券を取り、到着後のチェックインで順番を確定する
+const VERIFY_MAX_ATTEMPTS: i32 = 5;
+
+async fn join_page(State(state): State<AppState>) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    if !settings.remote_join {
+        return (StatusCode::NOT_FOUND, "現在、リモート受付は行っていません。").into_response();
+    }
+
+    HtmlTemplate(JoinTemplate {
+        phone_required: settings.remote_join_phone_required,
+        error: None,
+        paused: wait_pause(&state, &settings).await.map(wait_pause_message),
+    })
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct JoinForm {
+    group_size: i32,
+    phone: Option<String>,
+}
+
+// 電話番号は数字 (と先頭の+) だけにそろえる
+fn normalize_phone(phone: &str) -> Option<String> {
+    let trimmed = phone.trim();
+    let digits: String = trimmed.chars().filter(|c| c.is_ascii_digit()).collect();
+    if !(10..=15).contains(&digits.len()) {
+        return None;
+    }
+    Some(if trimmed.starts_with('+') { format!("+{}", digits) } else { digits })
+}
+
+async fn send_sms(state: &AppState, to: &str, message: &str) -> Result<(), String> {
+    let Some(url) = &state.sms_webhook_url else {
+        return Err("SMS_WEBHOOK_URL is not configured".to_string());
+    };
+
+    state
+        .http
+        .post(url)
+        .json(&serde_json::json!({ "to": to, "message": message }))
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?;
+
+    let settings = Settings::load(&state.pool).await;
+    record_usage(&state.pool, &settings, "notifications", 1).await;
+    Ok(())
+}
+
+async fn join(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<JoinForm>,
+) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    if !settings.remote_join {
+        return (StatusCode::NOT_FOUND, "現在、リモート受付は行っていません。").into_response();
+    }
+
+    let phone_required = settings.remote_join_phone_required;
+    let error_page = |status: StatusCode, message: &str| {
+        (
+            status,
+            HtmlTemplate(JoinTemplate { phone_required, error: Some(message.to_string()), paused: None }),
+        )
+            .into_response()
+    };
+
+    // 同じ送信元から短時間に何枚も取られないようにする
+    let limit = settings.remote_join_limit_per_hour.max(1) as usize;
+    if !check_rate_limit(&state.pool, &format!("join:{}", client_ip(&headers)), limit, Duration::from_secs(3600)).await {
+        return error_page(StatusCode::TOO_MANY_REQUESTS, "受付の回数が多すぎます。しばらく時間をおいてからお試しください。");
+    }
+
+    if let Err(error) = settings.check_group_size(form.group_size) {
+        return error_page(StatusCode::UNPROCESSABLE_ENTITY, &error);
+    }
+
+    if settings.blocks_overbooking() && project_wait(&state, &settings).await.is_some_and(|p| p.exceeds_closing()) {
+        return error_page(StatusCode::CONFLICT, "本日は閉店時刻までにご案内できない見込みのため、受付を終了しました。");
+    }
+    if let Some(wait_minutes) = wait_pause(&state, &settings).await {
+        return error_page(StatusCode::CONFLICT, &wait_pause_message(wait_minutes));
+    }
+    if let Some(message) = device_guard_refusal(&state, &headers).await {
+        return error_page(StatusCode::CONFLICT, &message);
+    }
+
+    if !phone_required {
+        let new = NewTicket { group_size: form.group_size, remote: true, ..Default::default() };
+        return issue_remote_ticket(&state, &settings, &headers, new).await;
+    }
+
+    // 電話番号の確認: SMSで6桁のコードを送り、入力してもらってから発券する
+    let Some(phone) = form.phone.as_deref().and_then(normalize_phone) else {
+        return error_page(StatusCode::UNPROCESSABLE_ENTITY, "電話番号を正しく入力してください。");
+    };
+
+    let id = Uuid::new_v4();
+    let code = format!("{:06}", Uuid::new_v4().as_u128() % 1_000_000);
+    sqlx::query(
+        "INSERT INTO phone_verifications (id, phone, code_hash, group_size, expires_at) 
+         VALUES ($1, $2, $3, $4, NOW() + INTERVAL '10 minutes')"
+    )
+    .bind(id)
+    .bind(&phone)
+    .bind(hmac_hex(&state.cookie_key, &format!("{}:{}", id, code)))
+    .bind(form.group_size)
+    .execute(&state.pool)
+    .await
+    .expect("Failed to create phone verification");
+
+    let message = format!("整理券の確認コード: {} (10分間有効)", code);
+    if let Err(err) = send_sms(&state, &phone, &message).await {
+        tracing::error!("Failed to send SMS: {}", err);
+        notify_staff(&state.pool, "sms_failed", "SMSの確認コードを送信できませんでした。SMS送信ゲートウェイの状態を確認してください。", None).await;
+        return error_page(StatusCode::SERVICE_UNAVAILABLE, "確認コードを送信できませんでした。受付で整理券をお受け取りください。");
+    }
+
+    Redirect::to(&url(&format!("/join/verify/{}", id))).into_response()
+}
+
+async fn verify_page(Path(id): Path<Uuid>) -> impl IntoResponse {
+    HtmlTemplate(JoinVerifyTemplate { id, error: None })
+}
+
+#[derive(Deserialize)]
+struct VerifyForm {
+    code: String,
+}
+
+#[derive(FromRow)]
+struct PhoneVerification {
+    phone: String,
+    code_hash: String,
+    group_size: i32,
+    attempts: i32,
+}
+
+async fn verify_phone(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<VerifyForm>,
+) -> impl IntoResponse {
+    let error_page = |status: StatusCode, message: &str| {
+        (status, HtmlTemplate(JoinVerifyTemplate { id, error: Some(message.to_string()) })).into_response()
+    };
+
+    let Some(verification) = sqlx::query_as::<_, PhoneVerification>(
+        "SELECT phone, code_hash, group_size, attempts FROM phone_verifications 
+         WHERE id = $1 AND verified_at IS NULL AND expires_at > NOW()"
+    )
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await
+    .unwrap_or(None)
+    else {
+        return error_page(StatusCode::GONE, "確認コードの有効期限が切れました。最初からやり直してください。");
+    };
+
+    // 総当たりを防ぐため、一定回数間違えたら無効にする
+    if verification.attempts >= VERIFY_MAX_ATTEMPTS {
+        return error_page(StatusCode::TOO_MANY_REQUESTS, "入力の回数が上限に達しました。最初からやり直してください。");
+    }
+
+    let expected = hmac_hex(&state.cookie_key, &format!("{}:{}", id, form.code.trim()));
+    if !constant_time_eq(expected.as_bytes(), verification.code_hash.as_bytes()) {
+        sqlx::query("UPDATE phone_verifications SET attempts = attempts + 1 WHERE id = $1")
+            .bind(id)
+            .execute(&state.pool)
+            .await
+            .expect("Failed to count verification attempt");
+        return error_page(StatusCode::UNPROCESSABLE_ENTITY, "確認コードが正しくありません。");
+    }
+
+    // 確認コードを待つ間に、同じ端末で別の整理券を取っていないか
+    if let Some(message) = device_guard_refusal(&state, &headers).await {
+        return error_page(StatusCode::CONFLICT, &message);
+    }
+
+    // 二重送信で2枚発券しないよう、確認済みにできた場合だけ発券する
+    let claimed = sqlx::query("UPDATE phone_verifications SET verified_at = NOW() WHERE id = $1 AND verified_at IS NULL")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to mark verification as used")
+        .rows_affected();
+    if claimed == 0 {
+        return error_page(StatusCode::GONE, "この確認コードは既に使用されています。");
+    }
+
+    let new = NewTicket {
+        group_size: verification.group_size,
+        remote: true,
+        phone: Some(verification.phone),
+        ..Default::default()
+    };
+    let settings = Settings::load(&state.pool).await;
+    issue_remote_ticket(&state, &settings, &headers, new).await
+}
+
+// 到着確認 (スタッフ・来場者のどちらからでも)
+async fn check_in_ticket(state: &AppState, id: Uuid, user: Option<&User>) -> Option<Ticket> {
+    let ticket = sqlx::query_as::<_, Ticket>(
+        "UPDATE tickets SET checked_in_at = NOW() 
+         WHERE id = $1 AND remote AND checked_in_at IS NULL 
+         RETURNING *"
+    )
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await
+    .expect("Failed to check in ticket")?;
+
+    record_event(&state.pool, ticket.id, "checked_in", user).await;
+    state.snapshot.invalidate();
+    if state.stripe.is_some() {
+        refund_deposit(state, ticket.id).await;
+    }
+    Some(ticket)
+}
+
+#[derive(Deserialize)]
+struct CheckinForm {
+    code: String,
+}
+
+// 来場者によるセルフチェックイン (会場に掲示した到着確認コードを入力する)
+async fn guest_checkin(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    Query(query): Query<GuestLinkQuery>,
+    headers: HeaderMap,
+    Form(form): Form<CheckinForm>,
+) -> impl IntoResponse {
+    let Some(ticket) = fetch_guest_ticket(&state, id, query.sig.as_deref()).await else {
+        return guest_link_invalid();
+    };
+    let link = guest_path(&state, &ticket);
+
+    let settings = Settings::load(&state.pool).await;
+    let allowed = check_rate_limit(&state.pool, &format!("checkin:{}", client_ip(&headers)), 10, Duration::from_secs(600)).await;
+
+    let is_valid = allowed
+        && !settings.checkin_code.is_empty()
+        && constant_time_eq(form.code.trim().as_bytes(), settings.checkin_code.as_bytes());
+
+    if !is_valid {
+        return Redirect::to(&format!("{}&checkin=failed", link)).into_response();
+    }
+
+    check_in_ticket(&state, id, None).await;
+    Redirect::to(&link).into_response()
+}
+
+#[derive(Deserialize)]
+struct ResizeForm {
+    group_size: String,
+}
+
+// お客様自身による人数の変更 (待機中のみ)
+// 結果は来場者画面のクエリ文字列で伝え、スタッフには操作履歴と呼び出し管理画面の表示で伝える
+async fn guest_resize(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    Query(query): Query<GuestLinkQuery>,
+    Form(form): Form<ResizeForm>,
+) -> impl IntoResponse {
+    let Some(ticket) = fetch_guest_ticket(&state, id, query.sig.as_deref()).await else {
+        return guest_link_invalid();
+    };
+    let link = guest_path(&state, &ticket);
+    let back = |result: &str| Redirect::to(&format!("{}&resize={}", link, result)).into_response();
+
+    let settings = Settings::load(&state.pool).await;
+    // ボタンを隠すだけでなく、ここでも断る (古い画面やフォームの直接の送信に備える)
+    if !settings.guest_resize_enabled {
+        return back("disabled");
+    }
+    let Some(group_size) = form.group_size.trim().parse::<i32>().ok().filter(|n| settings.check_group_size(*n).is_ok()) else {
+        return back("invalid");
+    };
+    if group_size == ticket.group_size {
+        return back("done");
+    }
+    if !check_rate_limit(&state.pool, &format!("resize:{}", id), 5, Duration::from_secs(3600)).await {
+        return back("limited");
+    }
+
+    let updated = sqlx::query_as::<_, Ticket>(
+        "UPDATE tickets SET group_size = $2 WHERE id = $1 AND status = 'waiting' RETURNING *"
+    )
+    .bind(id)
+    .bind(group_size)
+    .fetch_optional(&state.pool)
+    .await
+    .expect("Failed to update group size");
+    let Some(updated) = updated else {
+        return back("closed");
+    };
+
+    let detail = format!("{}名 → {}名", ticket.group_size, updated.group_size);
+    record_event_detail(&state.pool, id, "resized", None, Some(&detail)).await;
+    let message = format!("整理番号 {} のお客様が人数を変更しました ({})", updated.number, detail);
+    notify_staff(&state.pool, "guest_resized", &message, Some(id)).await;
+    state.snapshot.invalidate();
+    back("done")
+}
+
+#[derive(Deserialize)]
+struct HandoffForm {
+    name: Option<String>, // 受け取る人の名前 (予約から発券した整理券のみ。空欄なら変えない)
+}
+
+// お客様が整理券を別の人に譲る (「友達が代わりに並んでいてくれる」など。スタッフの操作は要らない)
+// 新しいリンクを発行して以前のリンクを使えなくし、渡すためのリンクとQRコードを表示する
+async fn guest_handoff(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    Query(query): Query<GuestLinkQuery>,
+    headers: HeaderMap,
+    Form(form): Form<HandoffForm>,
+) -> impl IntoResponse {
+    let Some(ticket) = fetch_guest_ticket(&state, id, query.sig.as_deref()).await else {
+        return guest_link_invalid();
+    };
+    let back = |result: &str| Redirect::to(&format!("{}&handoff={}", guest_path(&state, &ticket), result)).into_response();
+
+    let settings = Settings::load(&state.pool).await;
+    if !settings.guest_handoff_enabled {
+        return back("disabled");
+    }
+    if !check_rate_limit(&state.pool, &format!("handoff:{}", id), 3, Duration::from_secs(3600)).await {
+        return back("limited");
+    }
+
+    let mut tx = state.pool.begin().await.expect("Failed to begin transaction");
+    let updated = sqlx::query_as::<_, Ticket>(
+        "UPDATE tickets SET link_issued_at = NOW() WHERE id = $1 AND status IN ('waiting', 'called') RETURNING *"
+    )
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await
+    .expect("Failed to rotate guest link");
+    let Some(updated) = updated else {
+        return back("closed");
+    };
+
+    // 予約者名は予約から発券した整理券にしかないので、それ以外では変えない
+    let name: Option<String> = form.name.as_deref().map(str::trim).filter(|n| !n.is_empty()).map(|n| n.chars().take(50).collect());
+    let mut renamed = false;
+    if let Some(name) = &name {
+        renamed = sqlx::query("UPDATE reservations SET name = $2 WHERE ticket_id = $1")
+            .bind(id)
+            .bind(name)
+            .execute(&mut *tx)
+            .await
+            .expect("Failed to rename reservation")
+            .rows_affected()
+            > 0;
+    }
+    // 名前そのものは履歴に残さない (プライバシーモードの削除対象にならないため)
+    record_event_detail(&mut *tx, id, "handed_off", None, renamed.then_some("予約者名を変更")).await;
+    tx.commit().await.expect("Failed to commit transaction");
+    state.snapshot.invalidate();
+
+    let url = format!("{}{}", state.base_url, guest_path(&state, &updated));
+    let qr = QrCode::encode_text(&url, QrCodeEcc::Medium).unwrap();
+    let page = HtmlTemplate(GuestHandoffTemplate {
+        expires_at: settings.format_local(&guest_link_expires_at(&state, &updated), "%m/%d %H:%M"),
+        ticket: updated,
+        url,
+        qr_code: to_svg_string(&qr, 4),
+    });
+
+    // 譲った端末が、1端末1枚ガードでこの整理券を持ったままにならないようにする
+    let holds_ticket = get_cookie(&headers, DEVICE_COOKIE)
+        .and_then(|v| verify_cookie_value(&state.cookie_key, v))
+        .is_some_and(|v| v == id.to_string());
+    if holds_ticket {
+        let cookie = signed_cookie(&state, DEVICE_COOKIE, "", 0, "Lax");
+        return ([(SET_COOKIE, cookie)], page).into_response();
+    }
+    page.into_response()
+}
+
+#[derive(Deserialize)]
+struct StaffCheckinForm {
+    id: Uuid,
+}
+
+async fn staff_checkin(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<StaffCheckinForm>,
+) -> impl IntoResponse {
+    let acting = acting_user(&state, &headers).await;
+    check_in_ticket(&state, form.id, acting.as_ref()).await;
+
+    if headers.contains_key("HX-Request") {
+        return ([("HX-Trigger", "live-update")], "").into_response();
+    }
+    Redirect::to(&url("/admin/call")).into_response()
+}
+
+// --- 列 (キュー) ---
+#[derive(FromRow)]
+struct QueueRow {
+    id: i32,
+    name: String,
+    color: String,
+    icon: String,
+    number_prefix: String, // 整理番号の付け方
+    number_start: i32,
+    number_step: i32,
+    number_max: i32,
+    number_reset_daily: bool,
+    active_count: i64, // 未完了のチケット数
+}
+
+async fn render_admin_queues(state: &AppState, error: Option<String>) -> AdminQueuesTemplate {
+    let queues = sqlx::query_as::<_, QueueRow>(
+        "SELECT q.id, q.name, q.color, q.icon, q.number_prefix, q.number_start, q.number_step, q.number_max, q.number_reset_daily, 
+                (SELECT COUNT(*) FROM tickets t WHERE t.queue_id = q.id AND t.status IN ('waiting', 'called')) AS active_count 
+         FROM queues q 
+         ORDER BY q.id ASC"
+    )
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![]);
+
+    AdminQueuesTemplate { queues, error, flash: None }
+}
+
+async fn admin_queues(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let flash = read_flash(&state, &headers);
+    let page = AdminQueuesTemplate { flash: flash.clone(), ..render_admin_queues(&state, None).await };
+    clear_flash(&state, &flash, HtmlTemplate(page))
+}
+
+#[derive(Deserialize)]
+struct QueueForm {
+    name: String,
+}
+
+async fn create_queue(State(state): State<AppState>, headers: HeaderMap, Form(form): Form<QueueForm>) -> impl IntoResponse {
+    let name = form.name.trim();
+    if name.is_empty() {
+        let error = Some("列の名前を入力してください。".to_string());
+        return (StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(render_admin_queues(&state, error).await)).into_response();
+    }
+
+    sqlx::query("INSERT INTO queues (name) VALUES ($1)")
+        .bind(name)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to create queue");
+
+    flash_redirect(&state, &headers, "/admin/queues", Flash::notice(&format!("「{}」を追加しました。", name)))
+}
+
+async fn rename_queue(
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<QueueForm>,
+) -> impl IntoResponse {
+    let name = form.name.trim();
+    if name.is_empty() {
+        let error = Some("列の名前を入力してください。".to_string());
+        return (StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(render_admin_queues(&state, error).await)).into_response();
+    }
+
+    sqlx::query("UPDATE queues SET name = $2 WHERE id = $1")
+        .bind(id)
+        .bind(name)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to rename queue");
+    flash_redirect(&state, &headers, "/admin/queues", Flash::notice(&format!("名前を「{}」に変更しました。", name)))
+}
+
+// 入力は空欄や数字以外でも送信されるので、文字列で受けてから解釈する
+#[derive(Deserialize)]
+struct NumberingForm {
+    number_prefix: String,
+    number_start: String,
+    number_step: String,
+    number_max: String,
+    number_reset_daily: Option<String>, // チェックボックスは未チェックだと送信されない
+}
+
+// 番号の頭文字の長さ (文字数) の上限
+const MAX_NUMBER_PREFIX_CHARS: usize = 3;
+
+// 整理番号の付け方を変える (次の発券から反映。4桁までの番号にする)
+async fn update_queue_numbering(
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<NumberingForm>,
+) -> impl IntoResponse {
+    let parse = |v: &str| v.trim().parse::<i32>().ok().filter(|n| (1..=9999).contains(n));
+    let (Some(start), Some(step), Some(max)) = (parse(&form.number_start), parse(&form.number_step), parse(&form.number_max)) else {
+        let error = Some("整理番号は1〜9999の数字で入力してください。".to_string());
+        return (StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(render_admin_queues(&state, error).await)).into_response();
+    };
+    if max < start {
+        let error = Some("最後の番号は最初の番号以上にしてください。".to_string());
+        return (StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(render_admin_queues(&state, error).await)).into_response();
+    }
+    // 頭文字は英数字だけ (読み上げ・番号での検索で紛れないように大文字にそろえる)
+    let prefix = form.number_prefix.trim().to_ascii_uppercase();
+    if prefix.chars().count() > MAX_NUMBER_PREFIX_CHARS || !prefix.chars().all(|c| c.is_ascii_alphanumeric()) {
+        let error = Some(format!("頭文字は英数字{}文字までにしてください。", MAX_NUMBER_PREFIX_CHARS));
+        return (StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(render_admin_queues(&state, error).await)).into_response();
+    }
+
+    let settings = Settings::load(&state.pool).await;
+    let mut tx = state.pool.begin().await.expect("Failed to begin transaction");
+    let updated = sqlx::query("UPDATE queues SET number_prefix = $2, number_start = $3, number_step = $4, number_max = $5, number_reset_daily = $6 WHERE id = $1")
+        .bind(id)
+        .bind(&prefix)
+        .bind(start)
+        .bind(step)
+        .bind(max)
+        .bind(form.number_reset_daily.is_some())
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to update queue numbering")
+        .rows_affected();
+    if updated == 0 {
+        return flash_redirect(&state, &headers, "/admin/queues", Flash::error("列が見つかりません。"));
+    }
+    // 付け方が変わったら、次は最初の番号から発券する
+    sqlx::query(
+        "INSERT INTO number_sequences (queue_id, business_date, last_number) VALUES ($1, $2, NULL) 
+         ON CONFLICT (queue_id, business_date) DO UPDATE SET last_number = NULL, updated_at = NOW()"
+    )
+    .bind(id)
+    .bind(settings.business_date(&Utc::now()))
+    .execute(&mut *tx)
+    .await
+    .expect("Failed to restart ticket numbering");
+    tx.commit().await.expect("Failed to commit transaction");
+    let message = format!("次の発券から No.{}{} 〜 No.{}{} で番号を付けます。", prefix, start, prefix, max);
+    flash_redirect(&state, &headers, "/admin/queues", Flash::notice(&message))
+}
+
+#[derive(Deserialize)]
+struct QueueAppearanceForm {
+    color: String,
+    no_color: Option<String>, // チェックすると色を付けない
+    icon: String,
+}
+
+// 列の色とアイコンを変える (呼び出し管理画面・表示用モニター・整理券に出す)
+async fn update_queue_appearance(
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<QueueAppearanceForm>,
+) -> impl IntoResponse {
+    let color = if form.no_color.is_some() { Some(String::new()) } else { parse_hex_color(form.color.trim()) };
+    let icon = form.icon.trim();
+    let (Some(color), true) = (color, icon.chars().count() <= MAX_QUEUE_ICON_CHARS) else {
+        let error = Some(format!("色は一覧から選び、アイコンは{}文字までにしてください。", MAX_QUEUE_ICON_CHARS));
+        return (StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(render_admin_queues(&state, error).await)).into_response();
+    };
+
+    sqlx::query("UPDATE queues SET color = $2, icon = $3 WHERE id = $1")
+        .bind(id)
+        .bind(&color)
+        .bind(icon)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to update queue appearance");
+    flash_redirect(&state, &headers, "/admin/queues", Flash::notice("列の色とアイコンを変更しました。"))
+}
+
+// チケットの記録が残っている列は、履歴が壊れないよう削除できない
+async fn delete_queue(Path(id): Path<i32>, State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let in_use: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM tickets WHERE queue_id = $1)")
+        .bind(id)
+        .fetch_one(&state.pool)
+        .await
+        .unwrap_or(true);
+
+    if id == DEFAULT_QUEUE_ID || in_use {
+        let error = Some("最初の列と、チケットの記録が残っている列は削除できません。".to_string());
+        return (StatusCode::CONFLICT, HtmlTemplate(render_admin_queues(&state, error).await)).into_response();
+    }
+
+    sqlx::query("DELETE FROM queues WHERE id = $1")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to delete queue");
+
+    flash_redirect(&state, &headers, "/admin/queues", Flash::notice("列を削除しました。"))
+}
+
+// --- 受付窓口 ---
+async fn render_admin_counters(state: &AppState, error: Option<String>) -> AdminCountersTemplate {
+    AdminCountersTemplate {
+        counters: fetch_counters(&state.pool).await,
+        queues: fetch_queues(&state.pool).await,
+        error,
+        flash: None,
+    }
+}
+
+async fn admin_counters(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let flash = read_flash(&state, &headers);
+    let page = AdminCountersTemplate { flash: flash.clone(), ..render_admin_counters(&state, None).await };
+    clear_flash(&state, &flash, HtmlTemplate(page))
+}
+
+// 担当する列はチェックボックスで複数送られてくるので、キーと値の組で受ける
+async fn create_counter(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(fields): Form<Vec<(String, String)>>,
+) -> impl IntoResponse {
+    let name = fields.iter().find(|(k, _)| k == "name").map(|(_, v)| v.trim()).unwrap_or("");
+    if name.is_empty() {
+        let error = Some("窓口の名前を入力してください。".to_string());
+        return (StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(render_admin_counters(&state, error).await)).into_response();
+    }
+    let known: Vec<i32> = fetch_queues(&state.pool).await.iter().map(|q| q.id).collect();
+    let queue_ids: Vec<i32> = fields
+        .iter()
+        .filter(|(k, _)| k == "queue_id")
+        .filter_map(|(_, v)| v.parse().ok())
+        .filter(|id| known.contains(id))
+        .collect();
+
+    sqlx::query("INSERT INTO counters (name, queue_ids) VALUES ($1, $2)")
+        .bind(name)
+        .bind(&queue_ids)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to create counter");
+
+    flash_redirect(&state, &headers, "/admin/counters", Flash::notice(&format!("「{}」を追加しました。", name)))
+}
+
+// 呼び出した記録は残し、チケットの窓口だけを外す (外部キーの ON DELETE SET NULL)
+async fn delete_counter(Path(id): Path<i32>, State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    sqlx::query("DELETE FROM counters WHERE id = $1")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to delete counter");
+    state.snapshot.invalidate();
+
+    flash_redirect(&state, &headers, "/admin/counters", Flash::notice("窓口を削除しました。"))
+}
+
+#[derive(Deserialize)]
+struct TransferForm {
+    id: Uuid,
+    queue_id: i32,
+}
+
+// 待機中のチケットを別の列へ移す
+// 番号と発券時刻はそのままなので、移動先でも発券順どおりの位置に入る
+async fn transfer_ticket(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<TransferForm>,
+) -> impl IntoResponse {
+    let acting = acting_user(&state, &headers).await;
+    let mut tx = state.pool.begin().await.expect("Failed to begin transaction");
+
+    let from: Option<String> = sqlx::query_scalar(
+        "SELECT q.name FROM tickets t JOIN queues q ON q.id = t.queue_id 
+         WHERE t.id = $1 AND t.status = 'waiting' AND t.queue_id <> $2 
+         FOR UPDATE OF t"
+    )
+    .bind(form.id)
+    .bind(form.queue_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .expect("Failed to load ticket queue");
+    let to: Option<String> = sqlx::query_scalar("SELECT name FROM queues WHERE id = $1")
+        .bind(form.queue_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .expect("Failed to load queue");
+
+    let transferred = match (from, to) {
+        (Some(from), Some(to)) => {
+            let ticket = sqlx::query_as::<_, Ticket>("UPDATE tickets SET queue_id = $2 WHERE id = $1 RETURNING *")
+                .bind(form.id)
+                .bind(form.queue_id)
+                .fetch_one(&mut *tx)
+                .await
+                .expect("Failed to transfer ticket");
+            let detail = format!("{} → {}", from, to);
+            record_event_detail(&mut *tx, ticket.id, "transferred", acting.as_ref(), Some(&detail)).await;
+            Some(ticket)
+        }
+        _ => None,
+    };
+
+    tx.commit().await.expect("Failed to commit transaction");
+
+    if transferred.is_none() {
+        return flash_redirect(&state, &headers, "/admin/call", Flash::error("移動できませんでした。待機中の整理券だけを、別の列へ移せます。"));
+    }
+    state.snapshot.invalidate();
+
+    if headers.contains_key("HX-Request") {
+        return ([("HX-Trigger", "live-update")], "").into_response();
+    }
+    Redirect::to(&url("/admin/call")).into_response()
+}
+
+// --- POS連携 ---
+// POSで着席・会計を登録した時に、整理番号を指定して順番待ちを片付ける
+#[derive(Deserialize)]
+struct PosSeatedRequest {
+    number: i32,
+    #[serde(default)]
+    number_prefix: String, // 番号の頭文字 (例: T12 なら "T")。頭文字のない列では省略する
+    table: Option<String>, // POS上のテーブル名 (テーブル管理の名前と一致すれば使用中にする)
+}
+
+#[derive(Deserialize)]
+struct PosCompletedRequest {
+    number: i32,
+    #[serde(default)]
+    number_prefix: String,
+}
+
+fn pos_ticket_json(ticket: &Ticket) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "id": ticket.id,
+        "number": ticket.number,
+        "number_prefix": ticket.number_prefix,
+        "status": ticket.status,
+        "table_id": ticket.table_id,
+    }))
+}
+
+// 番号の整理券が今日のどこかにあれば「今の状態からはできない」、なければ「見つからない」
+async fn pos_not_found(state: &AppState, number: i32, prefix: &str) -> Response {
+    let settings = Settings::load(&state.pool).await;
+    let exists: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM tickets WHERE number = $1 AND number_prefix = $3 AND created_at >= $2)"
+    )
+    .bind(number)
+    .bind(settings.today_start(&Utc::now()))
+    .bind(prefix)
+    .fetch_one(&state.pool)
+    .await
+    .unwrap_or(false);
+    if exists { AppError::InvalidTransition } else { AppError::TicketNotFound }.into_response()
+}
+
+// 着席: 待機中・呼出中のチケットを完了にし、テーブルがわかれば使用中にする
+async fn pos_seated(
+    State(state): State<AppState>,
+    body: Result<Json<PosSeatedRequest>, axum::extract::rejection::JsonRejection>,
+) -> impl IntoResponse {
+    let Json(req) = match body {
+        Ok(body) => body,
+        Err(e) => return AppError::from(e).into_response(),
+    };
+    let table_id: Option<Uuid> = match req.table.as_deref().map(str::trim).filter(|t| !t.is_empty()) {
+        Some(name) => sqlx::query_scalar("SELECT id FROM tables WHERE name = $1")
+            .bind(name)
+            .fetch_optional(&state.pool)
+            .await
+            .unwrap_or(None),
+        None => None,
+    };
+
+    // 番号は一周して使い回すので、未完了のうち最も古いものを対象にする
+    let Some(ticket) = sqlx::query_as::<_, Ticket>(
+        "UPDATE tickets SET status = 'completed', called_at = COALESCE(called_at, NOW()), completed_at = NOW(), 
+                table_id = COALESCE($2, table_id) 
+         WHERE id = (
+             SELECT id FROM tickets WHERE number = $1 AND number_prefix = $3 AND status IN ('waiting', 'called') 
+             ORDER BY created_at ASC LIMIT 1
+         ) 
+         RETURNING *"
+    )
+    .bind(req.number)
+    .bind(table_id)
+    .bind(req.number_prefix.trim())
+    .fetch_optional(&state.pool)
+    .await
+    .expect("Failed to mark ticket as seated")
+    else {
+        return pos_not_found(&state, req.number, req.number_prefix.trim()).await;
+    };
+
+    if let Some(table_id) = ticket.table_id {
+        set_table_status(&state, table_id, "occupied").await;
+    }
+    record_event_detail(&state.pool, ticket.id, "seated", None, Some("POS")).await;
+    state.snapshot.invalidate();
+    state.run_hooks(TicketLifecycle::Completed, &ticket);
+
+    pos_ticket_json(&ticket).into_response()
+}
+
+// 会計: まだ順番待ちに残っていれば完了にし、案内したテーブルを空席に戻す
+async fn pos_completed(
+    State(state): State<AppState>,
+    body: Result<Json<PosCompletedRequest>, axum::extract::rejection::JsonRejection>,
+) -> impl IntoResponse {
+    let Json(req) = match body {
+        Ok(body) => body,
+        Err(e) => return AppError::from(e).into_response(),
+    };
+    // 未完了のものを優先し、なければ直近に完了したものを対象にする
+    let Some(ticket) = sqlx::query_as::<_, Ticket>(
+        "SELECT * FROM tickets WHERE number = $1 AND number_prefix = $2 AND status IN ('waiting', 'called', 'completed') 
+         ORDER BY (status = 'completed') ASC, created_at DESC 
+         LIMIT 1"
+    )
+    .bind(req.number)
+    .bind(req.number_prefix.trim())
+    .fetch_optional(&state.pool)
+    .await
+    .unwrap_or(None)
+    else {
+        return pos_not_found(&state, req.number, req.number_prefix.trim()).await;
+    };
+
+    let ticket = if ticket.status == "completed" {
+        ticket
+    } else {
+        let ticket = sqlx::query_as::<_, Ticket>(
+            "UPDATE tickets SET status = 'completed', completed_at = NOW() WHERE id = $1 RETURNING *"
+        )
+        .bind(ticket.id)
+        .fetch_one(&state.pool)
+        .await
+        .expect("Failed to complete ticket");
+        record_event_detail(&state.pool, ticket.id, "completed", None, Some("POS")).await;
+        state.snapshot.invalidate();
+        state.run_hooks(TicketLifecycle::Completed, &ticket);
+        ticket
+    };
+
+    if let Some(table_id) = ticket.table_id {
+        set_table_status(&state, table_id, "free").await;
+    }
+
+    pos_ticket_json(&ticket).into_response()
+}
+
+// --- 分割・合流 ---
+#[derive(Deserialize)]
+struct SplitForm {
+    id: Uuid,
+    group_size: i32, // 別のチケットに分ける人数
+}
+
+// 1組を2組に分ける (別々の席に座りたい場合など)
+// 分けた側には新しい番号を出すが、発券時刻・優先度・列は元のチケットと同じなので順番は変わらない
+async fn split_ticket(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<SplitForm>,
+) -> impl IntoResponse {
+    let acting = acting_user(&state, &headers).await;
+    let settings = Settings::load(&state.pool).await;
+    let mut tx = state.pool.begin().await.expect("Failed to begin transaction");
+
+    let original = sqlx::query_as::<_, Ticket>(
+        "UPDATE tickets SET group_size = group_size - $2 
+         WHERE id = $1 AND status IN ('waiting', 'called') AND $2 >= 1 AND group_size > $2 
+         RETURNING *"
+    )
+    .bind(form.id)
+    .bind(form.group_size)
+    .fetch_optional(&mut *tx)
+    .await
+    .expect("Failed to split ticket");
+
+    let split = match &original {
+        Some(original) => {
+            // 同時に発券されても番号が重ならないよう、発券と同じく元の列の付け方で採番する
+            let (prefix, number) = next_ticket_number(&mut tx, original.queue_id, settings.business_date(&Utc::now())).await;
+            // テスト用の印・タグ・メモ・発券した端末・ステージも元の整理券から引き継ぐ
+            let ticket = sqlx::query_as::<_, Ticket>(
+                "INSERT INTO tickets (number, group_size, status, created_at, priority, remote, phone, checked_in_at, queue_id, needs, number_prefix, custom_fields, is_test, tags, note, device_id, stage) 
+                 SELECT $2, $3, 'waiting', created_at, priority, remote, phone, checked_in_at, queue_id, needs, $4, custom_fields, is_test, tags, note, device_id, stage 
+                 FROM tickets WHERE id = $1 
+                 RETURNING *"
+            )
+            .bind(original.id)
+            .bind(number)
+            .bind(form.group_size)
+            .bind(&prefix)
+            .fetch_one(&mut *tx)
+            .await
+            .expect("Failed to create split ticket");
+
+            let detail = format!("No.{} ({}名) と No.{} ({}名) に分割", original.number, original.group_size, ticket.number, ticket.group_size);
+            record_event_detail(&mut *tx, original.id, "split", acting.as_ref(), Some(&detail)).await;
+            record_event_detail(&mut *tx, ticket.id, "split", acting.as_ref(), Some(&detail)).await;
+            Some(ticket)
+        }
+        None => None,
+    };
+
+    tx.commit().await.expect("Failed to commit transaction");
+
+    let Some(ticket) = &split else {
+        return flash_redirect(&state, &headers, "/admin/call", Flash::error("分割できませんでした。分ける人数は1名以上、元の人数より少なくしてください。"));
+    };
+    state.snapshot.invalidate();
+    state.run_hooks(TicketLifecycle::Created, ticket);
+
+    if headers.contains_key("HX-Request") {
+        return ([("HX-Trigger", "live-update")], "").into_response();
+    }
+    Redirect::to(&url("/admin/call")).into_response()
+}
+
+#[derive(Deserialize)]
+struct MergeForm {
+    id: Uuid,
+    number: i32, // 合流させる相手の整理番号
+}
+
+// 2組を1組にまとめる。先に並んでいた方のチケットに人数を足し、もう一方は合流済みにする
+async fn merge_tickets(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<MergeForm>,
+) -> impl IntoResponse {
+    let acting = acting_user(&state, &headers).await;
+    let settings = Settings::load(&state.pool).await;
+    let mut tx = state.pool.begin().await.expect("Failed to begin transaction");
+
+    // 相手は同じ列の未完了のチケットから番号で探す
+    let pair = sqlx::query_as::<_, Ticket>(
+        "SELECT t.* FROM tickets t 
+         JOIN tickets base ON base.id = $1 AND base.status IN ('waiting', 'called') 
+         WHERE t.status IN ('waiting', 'called') 
+           AND t.queue_id = base.queue_id 
+           AND (t.id = $1 OR t.number = $2) 
+         ORDER BY t.priority DESC, t.created_at ASC, t.id ASC 
+         FOR UPDATE OF t"
+    )
+    .bind(form.id)
+    .bind(form.number)
+    .fetch_all(&mut *tx)
+    .await
+    .expect("Failed to load tickets to merge");
+
+    // まとめた人数が1組の上限を超える時は合流させない
+    let merged = match pair.as_slice() {
+        [kept, absorbed] if kept.group_size + absorbed.group_size > settings.max_group_size => {
+            return flash_redirect(&state, &headers, "/admin/call", Flash::error(&format!("合流すると{}名になり、1組の上限 ({}名) を超えるため合流できません。", kept.group_size + absorbed.group_size, settings.max_group_size)));
+        }
+        [kept, absorbed] => {
+            sqlx::query(
+                "UPDATE tickets SET group_size = group_size + $2, checked_in_at = COALESCE(checked_in_at, $3) 
+                 WHERE id = $1"
+            )
+            .bind(kept.id)
+            .bind(absorbed.group_size)
+            .bind(absorbed.checked_in_at)
+            .execute(&mut *tx)
+            .await
+            .expect("Failed to merge ticket");
+
+            // 合流された側に案内していたテーブルは空席に戻す (呼出中の間に戻すので、合流済みにする前に行う)
+            release_table_of(&mut *tx, absorbed.id).await;
+            sqlx::query("UPDATE tickets SET status = 'merged', completed_at = NOW() WHERE id = $1")
+                .bind(absorbed.id)
+                .execute(&mut *tx)
+                .await
+                .expect("Failed to close merged ticket");
+            // デポジットは合流先でまとめて返金・没収する
+            sqlx::query("UPDATE deposits SET ticket_id = $2, updated_at = NOW() WHERE ticket_id = $1")
+                .bind(absorbed.id)
+                .bind(kept.id)
+                .execute(&mut *tx)
+                .await
+                .expect("Failed to move deposits");
+
+            let detail = format!("No.{} に No.{} ({}名) が合流", kept.number, absorbed.number, absorbed.group_size);
+            record_event_detail(&mut *tx, kept.id, "merged", acting.as_ref(), Some(&detail)).await;
+            record_event_detail(&mut *tx, absorbed.id, "merged", acting.as_ref(), Some(&detail)).await;
+            Some(absorbed.clone())
+        }
+        _ => None,
+    };
+
+    tx.commit().await.expect("Failed to commit transaction");
+
+    // 合流された側の整理券はここで終わる
+    let Some(absorbed) = &merged else {
+        return flash_redirect(&state, &headers, "/admin/call", Flash::error("合流できませんでした。同じ列の、未完了の整理券どうしを選んでください。"));
+    };
+    state.snapshot.invalidate();
+    state.run_hooks(TicketLifecycle::Cancelled, absorbed);
+
+    if headers.contains_key("HX-Request") {
+        return ([("HX-Trigger", "live-update")], "").into_response();
+    }
+    Redirect::to(&url("/admin/call")).into_response()
+}
+
+// --- デポジット (Stripe Checkout) ---
+// 大人数の無断キャンセルを防ぐため、リモート受付の時点で少額を預かる
+// 到着確認で返金し、来店しなかった場合は没収する
+#[derive(Clone)]
+struct StripeConfig {
+    secret_key: String,
+    webhook_secret: String,
+}
+
+const STRIPE_API: &str = "https://api.stripe.com/v1";
+
+// --- S3互換ストレージへのバックアップ ---
+// tickets と ticket_events を CSV (gzip圧縮) にして、毎晩アップロードする
+const BACKUP_TABLES: [&str; 2] = ["tickets", "ticket_events"];
+
+struct BackupConfig {
+    bucket: String,
+    endpoint: Option<String>, // 未設定なら AWS S3 (R2 や MinIO などはURLを指定する)
+    region: String,
+    access_key: String,
+    secret_key: String,
+    prefix: String, // オブジェクトのキーの先頭 (例: "queueticket/")
+    hour: u32,      // 店舗の現地時刻で何時以降に実行するか
+}
+
+impl BackupConfig {
+    fn bucket(&self) -> Result<Box<s3::Bucket>, String> {
+        let region = match &self.endpoint {
+            Some(endpoint) => s3::Region::Custom { region: self.region.clone(), endpoint: endpoint.clone() },
+            None => self.region.parse().map_err(|e| format!("invalid region: {}", e))?,
+        };
+        let credentials = s3::creds::Credentials::new(Some(&self.access_key), Some(&self.secret_key), None, None, None)
+            .map_err(|e| e.to_string())?;
+        let mut bucket = s3::Bucket::new(&self.bucket, region, credentials).map_err(|e| e.to_string())?;
+        // S3互換ストレージの多くはパス形式のURLにしか対応していない
+        if self.endpoint.is_some() {
+            bucket.set_path_style();
+        }
+        Ok(bucket)
+    }
+}
+
+#[derive(FromRow)]
+struct BackupRun {
+    status: String,
+    objects: Option<String>,
+    bytes: Option<i64>,
+    error: Option<String>,
+    started_at: DateTime<Utc>,
+}
+
+// COPY でテーブルをそのまま CSV にし、gzip で圧縮する
+async fn export_table_gzip(pool: &PgPool, table: &str) -> Result<Vec<u8>, String> {
+    use std::io::Write;
+
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+    let mut stream = conn
+        .copy_out_raw(&format!("COPY {} TO STDOUT WITH (FORMAT csv, HEADER)", table))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        encoder.write_all(&chunk).map_err(|e| e.to_string())?;
+    }
+    encoder.finish().map_err(|e| e.to_string())
+}
+
+// 1回分のバックアップ。結果は backups テーブルに残す
+async fn run_backup(state: &AppState, config: &BackupConfig) {
+    // 手動の実行や他の台の実行と重ならないようにする
+    let Some(_lock) = try_job_lock(&state.pool, "backup").await else {
+        return;
+    };
+
+    let id: Uuid = sqlx::query_scalar("INSERT INTO backups DEFAULT VALUES RETURNING id")
+        .fetch_one(&state.pool)
+        .await
+        .expect("Failed to record backup");
+
+    let result: Result<(Vec<String>, i64), String> = async {
+        let bucket = config.bucket()?;
+        let settings = Settings::load(&state.pool).await;
+        let folder = settings.format_local(&Utc::now(), "%Y-%m-%d/%H%M%S");
+
+        let mut objects = vec![];
+        let mut bytes = 0;
+        for table in BACKUP_TABLES {
+            let data = export_table_gzip(&state.pool, table).await?;
+            let key = format!("{}{}/{}.csv.gz", config.prefix, folder, table);
+            let res = bucket
+                .put_object_with_content_type(&key, &data, "application/gzip")
+                .await
+                .map_err(|e| e.to_string())?;
+            if !(200..300).contains(&res.status_code()) {
+                return Err(format!("upload of {} failed with status {}", key, res.status_code()));
+            }
+            bytes += data.len() as i64;
+            objects.push(key);
+        }
+        Ok((objects, bytes))
+    }
+    .await;
+
+    let (status, objects, bytes, error) = match result {
+        Ok((objects, bytes)) => ("succeeded", Some(objects.join("\n")), Some(bytes), None),
+        Err(e) => {
+            tracing::error!("Backup failed: {}", e);
+            notify_staff(&state.pool, "backup_failed", "バックアップに失敗しました。メンテナンス画面で内容を確認してください。", None).await;
+            ("failed", None, None, Some(e))
+        }
+    };
+    sqlx::query("UPDATE backups SET status = $2, objects = $3, bytes = $4, error = $5, finished_at = NOW() WHERE id = $1")
+        .bind(id)
+        .bind(status)
+        .bind(objects)
+        .bind(bytes)
+        .bind(error)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to record backup result");
+}
+
+// 10分おきに確認し、今日 (店舗の現地日付) まだ成功していなければ指定の時刻以降に実行する
+async fn backup_worker(state: AppState) {
+    let Some(config) = state.backup.clone() else { return };
+    let mut interval = tokio::time::interval(Duration::from_secs(600));
+    loop {
+        interval.tick().await;
+
+        let settings = Settings::load(&state.pool).await;
+        let now = Utc::now().with_timezone(&settings.timezone());
+        if now.hour() < config.hour {
+            continue;
+        }
+        let Some(today_start) = settings.parse_local(&format!("{}T00:00", now.format("%Y-%m-%d"))) else {
+            continue;
+        };
+
+        // 失敗した場合は、次の確認で再実行する
+        let done: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM backups WHERE status IN ('succeeded', 'running') AND started_at >= $1)"
+        )
+        .bind(today_start)
+        .fetch_one(&state.pool)
+        .await
+        .unwrap_or(true);
+        if !done {
+            run_backup(&state, &config).await;
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MaintenanceQuery {
+    started: Option<String>,
+}
+
+async fn maintenance_page(State(state): State<AppState>, headers: HeaderMap, Query(query): Query<MaintenanceQuery>) -> impl IntoResponse {
+    let flash = read_flash(&state, &headers);
+    let settings = Settings::load(&state.pool).await;
+    let backups = sqlx::query_as::<_, BackupRun>(
+        "SELECT status, objects, bytes, error, started_at FROM backups ORDER BY started_at DESC LIMIT 30"
+    )
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![])
+    .into_iter()
+    .map(|run| {
+        let label = settings.format_local(&run.started_at, "%Y/%m/%d %H:%M");
+        (run, label)
+    })
+    .collect();
+
+    let page = AdminMaintenanceTemplate {
+        backup: state.backup.clone(),
+        backups,
+        started: query.started.is_some(),
+        settings,
+        flash: flash.clone(),
+    };
+    clear_flash(&state, &flash, HtmlTemplate(page))
+}
+
+// --- メンテナンスモード ---
+// データの移行や機器の入れ替えの間、画面は見られるまま書き込みだけを断る
+// 断っている間も、この切り替えと、ログイン中の端末・担当者の切り替え・バックアップ・端末の生存報告は受け付ける
+const MAINTENANCE_DEFAULT_MESSAGE: &str = "ただいまメンテナンス中です。整理券の発行・変更は、終わるまでお待ちください。";
+const MAINTENANCE_ALLOWED_WRITES: [&str; 5] = [
+    "/admin/maintenance",
+    "/admin/sessions",
+    "/admin/staff/switch",
+    "/admin/theme",
+    "/api/devices/heartbeat",
+];
+const MAX_MAINTENANCE_MESSAGE_CHARS: usize = 200;
+
+async fn maintenance_guard(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let writes = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let path = req.uri().path();
+    if !writes || MAINTENANCE_ALLOWED_WRITES.iter().any(|allowed| path.starts_with(allowed)) {
+        return next.run(req).await;
+    }
+    let settings = Settings::load(&state.pool).await;
+    let Some(message) = settings.maintenance_notice() else {
+        return next.run(req).await;
+    };
+
+    // APIは他のエラーと同じ形式で、画面からの操作は説明の画面 (HTMXならメッセージの欄) で断る
+    if path.starts_with("/api/") {
+        return AppError::Maintenance.into_response();
+    }
+    if req.headers().contains_key("HX-Request") {
+        let body = HtmlTemplate(FlashTemplate { flash: Flash::error(&message) });
+        return (StatusCode::SERVICE_UNAVAILABLE, [("HX-Retarget", "#flash-area"), ("HX-Reswap", "innerHTML")], body).into_response();
+    }
+    AppError::Maintenance.html()
+}
+
+#[derive(Deserialize)]
+struct MaintenanceModeForm {
+    enabled: Option<String>,
+    message: String,
+}
+
+async fn set_maintenance_mode(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<MaintenanceModeForm>,
+) -> impl IntoResponse {
+    let mut settings = Settings::load(&state.pool).await;
+    settings.maintenance_mode = form.enabled.is_some();
+    settings.maintenance_message = form.message.trim().chars().take(MAX_MAINTENANCE_MESSAGE_CHARS).collect();
+    settings.save(&state.pool).await;
+
+    let acting = acting_user(&state, &headers).await;
+    let message = if settings.maintenance_mode {
+        format!("{}がメンテナンスモードを開始しました。", acting.as_ref().map_or("管理者", |u| u.name.as_str()))
+    } else {
+        format!("{}がメンテナンスモードを終了しました。", acting.as_ref().map_or("管理者", |u| u.name.as_str()))
+    };
+    notify_staff(&state.pool, "maintenance", &message, None).await;
+
+    let notice = if settings.maintenance_mode {
+        "メンテナンスモードにしました。書き込みを断っています。"
+    } else {
+        "メンテナンスモードを終了しました。"
+    };
+    flash_redirect(&state, &headers, "/admin/maintenance", Flash::notice(notice))
+}
+
+// 今すぐバックアップする (完了を待たずに画面へ戻る)
+async fn start_backup(State(state): State<AppState>) -> impl IntoResponse {
+    if let Some(config) = state.backup.clone() {
+        tokio::spawn(async move { run_backup(&state, &config).await });
+    }
+    Redirect::to(&url("/admin/maintenance?started=1"))
+}
+
+// --- 列の状態の書き出し・取り込み (営業中に別の環境へ移る) ---
+// Shuttle と店内のサーバーの間などで、今日の整理券と番号の続きをそのまま移す
+// 移行元をメンテナンスモードにしてから書き出し、移行先で取り込む (どちらも /admin/maintenance から。メンテナンス中も使える)
+const QUEUE_STATE_FORMAT: &str = "queueticket.queue_state.v1";
+// 取り込める整理券の状態 (ゴミ箱に入れたものは書き出さない)
+const QUEUE_STATE_TICKET_STATUSES: [&str; 4] = ["waiting", "called", "completed", "merged"];
+
+#[derive(Serialize, Deserialize, FromRow)]
+struct QueueStateQueue {
+    id: i32,
+    name: String,
+    color: String,
+    icon: String,
+    number_prefix: String,
+    number_start: i32,
+    number_step: i32,
+    number_max: i32,
+    number_reset_daily: bool,
+    now_serving: Option<i32>,
+    now_serving_at: Option<DateTime<Utc>>,
+    last_number: Option<i32>, // 最後に発券した番号 (取り込んだ先の今日の採番はこの続きから)
+}
+
+#[derive(Serialize, Deserialize, FromRow)]
+struct QueueStateTicket {
+    id: Uuid,
+    number: i32,
+    number_prefix: String,
+    queue_id: i32,
+    group_size: i32,
+    status: String,
+    priority: i32,
+    remote: bool,
+    phone: Option<String>,
+    needs: Vec<String>,
+    tags: Vec<String>,
+    note: Option<String>,
+    stage: Option<String>,
+    is_test: bool,
+    table_id: Option<Uuid>,
+    counter_id: Option<i32>,
+    device_id: Option<i32>,
+    created_at: DateTime<Utc>,
+    called_at: Option<DateTime<Utc>>,
+    completed_at: Option<DateTime<Utc>>,
+    checked_in_at: Option<DateTime<Utc>>,
+    link_issued_at: DateTime<Utc>,
+    #[serde(default)] // 追加の項目がなかった頃に書き出したファイルも読めるように
+    custom_fields: sqlx::types::Json<serde_json::Map<String, serde_json::Value>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct QueueState {
+    format: String,
+    exported_at: DateTime<Utc>,
+    store_name: String,
+    business_date: NaiveDate,
+    queues: Vec<QueueStateQueue>,
+    tickets: Vec<QueueStateTicket>, // 待機中・呼出中と、今日の営業日に発券したもの (ゴミ箱に入れたものは除く)
+}
+
+async fn export_queue_state(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if acting_user(&state, &headers).await.filter(|u| u.role == "manager").is_none() {
+        return flash_redirect(&state, &headers, "/admin/maintenance", Flash::error("列の状態を書き出せるのは責任者だけです。"));
+    }
+    let settings = Settings::load(&state.pool).await;
+    let now = Utc::now();
+    let business_date = settings.business_date(&now);
+
+    // 毎日やり直す列は今日の番号だけ、そうでない列は最後の番号を引き継ぐ
+    let queues = sqlx::query_as::<_, QueueStateQueue>(
+        "SELECT q.id, q.name, q.color, q.icon, q.number_prefix, q.number_start, q.number_step, q.number_max, 
+                q.number_reset_daily, q.now_serving, q.now_serving_at, 
+                (SELECT s.last_number FROM number_sequences s 
+                 WHERE s.queue_id = q.id AND (NOT q.number_reset_daily OR s.business_date = $1) 
+                 ORDER BY s.business_date DESC LIMIT 1) AS last_number 
+         FROM queues q ORDER BY q.id"
+    )
+    .bind(business_date)
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![]);
+    let tickets = sqlx::query_as::<_, QueueStateTicket>(
+        "SELECT id, number, number_prefix, queue_id, group_size, status, priority, remote, phone, needs, tags, note, stage, 
+                is_test, table_id, counter_id, device_id, created_at, called_at, completed_at, checked_in_at, link_issued_at, custom_fields 
+         FROM tickets 
+         WHERE status IN ('waiting', 'called') OR (created_at >= $1 AND status != 'deleted') 
+         ORDER BY created_at ASC"
+    )
+    .bind(settings.today_start(&now))
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![]);
+
+    let export = QueueState {
+        format: QUEUE_STATE_FORMAT.to_string(),
+        exported_at: now,
+        store_name: settings.store_name.clone(),
+        business_date,
+        queues,
+        tickets,
+    };
+    let body = serde_json::to_string_pretty(&export).expect("Failed to serialize queue state");
+    let disposition = format!("attachment; filename=\"queue_state_{}.json\"", settings.format_local(&now, "%Y%m%d%H%M%S"));
+    (
+        [
+            (CONTENT_TYPE, "application/json; charset=utf-8".to_string()),
+            (axum::http::header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+// 取り込むファイルの中身を、DBに書く前に確かめる (制約に反する値で途中まで書いて止まらないように)
+fn validate_queue_state(imported: &QueueState, existing_queue_ids: &[i32]) -> Result<(), String> {
+    for queue in &imported.queues {
+        let in_range = |n: i32| (1..=9999).contains(&n);
+        if !in_range(queue.number_start) || !in_range(queue.number_step) || !in_range(queue.number_max) || queue.number_max < queue.number_start {
+            return Err(format!("列「{}」の番号の付け方が正しくありません (番号は1〜9999、最後の番号は最初の番号以上)。", queue.name));
+        }
+        if queue.number_prefix.chars().count() > MAX_NUMBER_PREFIX_CHARS || !queue.number_prefix.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(format!("列「{}」の頭文字が正しくありません (英数字{}文字まで)。", queue.name, MAX_NUMBER_PREFIX_CHARS));
+        }
+    }
+    let queue_ids: HashSet<i32> = imported.queues.iter().map(|q| q.id).chain(existing_queue_ids.iter().copied()).collect();
+    for ticket in &imported.tickets {
+        if !QUEUE_STATE_TICKET_STATUSES.contains(&ticket.status.as_str()) {
+            return Err(format!("整理券 {}{} の状態「{}」は取り込めません。", ticket.number_prefix, ticket.number, ticket.status));
+        }
+        if !queue_ids.contains(&ticket.queue_id) {
+            return Err(format!("整理券 {}{} の列 (ID {}) が、ファイルにもこの環境にもありません。", ticket.number_prefix, ticket.number, ticket.queue_id));
+        }
+        if ticket.group_size < 1 {
+            return Err(format!("整理券 {}{} の人数が正しくありません。", ticket.number_prefix, ticket.number));
+        }
+    }
+    Ok(())
+}
+
+// 取り込んだ整理券の枚数を返す。失敗した時は呼び出し元でトランザクションごと取り消す
+async fn write_queue_state(
+    conn: &mut sqlx::PgConnection,
+    imported: &QueueState,
+    acting: &User,
+    settings: &Settings,
+) -> Result<usize, sqlx::Error> {
+    // 列は番号の決め方ごと移行元に合わせる (同じIDの列は上書きする)
+    let business_date = settings.business_date(&Utc::now());
+    for queue in &imported.queues {
+        sqlx::query(
+            "INSERT INTO queues (id, name, color, icon, number_prefix, number_start, number_step, number_max, number_reset_daily, now_serving, now_serving_at) 
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) 
+             ON CONFLICT (id) DO UPDATE SET name = EXCLUDED.name, color = EXCLUDED.color, icon = EXCLUDED.icon, 
+                 number_prefix = EXCLUDED.number_prefix, number_start = EXCLUDED.number_start, number_step = EXCLUDED.number_step, 
+                 number_max = EXCLUDED.number_max, number_reset_daily = EXCLUDED.number_reset_daily, 
+                 now_serving = EXCLUDED.now_serving, now_serving_at = EXCLUDED.now_serving_at"
+        )
+        .bind(queue.id)
+        .bind(&queue.name)
+        .bind(&queue.color)
+        .bind(&queue.icon)
+        .bind(&queue.number_prefix)
+        .bind(queue.number_start)
+        .bind(queue.number_step)
+        .bind(queue.number_max)
+        .bind(queue.number_reset_daily)
+        .bind(queue.now_serving)
+        .bind(queue.now_serving_at)
+        .execute(&mut *conn)
+        .await?;
+
+        // 次の発券は移行元の最後の番号の続きから
+        if let Some(last_number) = queue.last_number {
+            sqlx::query(
+                "INSERT INTO number_sequences (queue_id, business_date, last_number) VALUES ($1, $2, $3) 
+                 ON CONFLICT (queue_id, business_date) DO UPDATE 
+                 SET last_number = GREATEST(COALESCE(number_sequences.last_number, 0), EXCLUDED.last_number), updated_at = NOW()"
+            )
+            .bind(queue.id)
+            .bind(business_date)
+            .bind(last_number)
+            .execute(&mut *conn)
+            .await?;
+        }
+    }
+    sqlx::query("SELECT setval(pg_get_serial_sequence('queues', 'id'), (SELECT MAX(id) FROM queues))")
+        .execute(&mut *conn)
+        .await?;
+
+    // 整理券はIDごと移すので、COOKIE_SECRET が同じならお客様の画面のリンクもそのまま使える
+    // テーブル・窓口・発券機は、この環境にない場合は外す
+    let detail = if imported.store_name.is_empty() {
+        format!("{} に書き出したもの", settings.format_local(&imported.exported_at, "%m/%d %H:%M"))
+    } else {
+        format!("{} ({} に書き出したもの)", imported.store_name, settings.format_local(&imported.exported_at, "%m/%d %H:%M"))
+    };
+    let mut created = 0;
+    for ticket in &imported.tickets {
+        let inserted = sqlx::query(
+            "INSERT INTO tickets (id, number, number_prefix, queue_id, group_size, status, priority, remote, phone, needs, tags, note, stage, 
+                                  is_test, table_id, counter_id, device_id, created_at, called_at, completed_at, checked_in_at, link_issued_at, custom_fields) 
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, 
+                     (SELECT id FROM tables WHERE id = $15), (SELECT id FROM counters WHERE id = $16), (SELECT id FROM devices WHERE id = $17), 
+                     $18, $19, $20, $21, $22, $23) 
+             ON CONFLICT (id) DO NOTHING"
+        )
+        .bind(ticket.id)
+        .bind(ticket.number)
+        .bind(&ticket.number_prefix)
+        .bind(ticket.queue_id)
+        .bind(ticket.group_size)
+        .bind(&ticket.status)
+        .bind(ticket.priority)
+        .bind(ticket.remote)
+        .bind(&ticket.phone)
+        .bind(&ticket.needs)
+        .bind(&ticket.tags)
+        .bind(&ticket.note)
+        .bind(&ticket.stage)
+        .bind(ticket.is_test)
+        .bind(ticket.table_id)
+        .bind(ticket.counter_id)
+        .bind(ticket.device_id)
+        .bind(ticket.created_at)
+        .bind(ticket.called_at)
+        .bind(ticket.completed_at)
+        .bind(ticket.checked_in_at)
+        .bind(ticket.link_issued_at)
+        .bind(&ticket.custom_fields)
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+        if inserted > 0 {
+            record_event_detail(&mut *conn, ticket.id, "imported", Some(acting), Some(&detail)).await;
+            created += 1;
+        }
+    }
+    Ok(created)
+}
+
+async fn import_queue_state(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> impl IntoResponse {
+    const BACK: &str = "/admin/maintenance";
+    let Some(acting) = acting_user(&state, &headers).await.filter(|u| u.role == "manager") else {
+        return flash_redirect(&state, &headers, BACK, Flash::error("列の状態を取り込めるのは責任者だけです。"));
+    };
+    let mut data = None;
+    while let Ok(Some(field)) = multipart.next_field().await {
+        if field.name() == Some("file") {
+            data = field.bytes().await.ok();
+            break;
+        }
+    }
+    let imported = data.and_then(|d| serde_json::from_slice::<QueueState>(&d).ok()).filter(|s| s.format == QUEUE_STATE_FORMAT);
+    let Some(imported) = imported else {
+        return flash_redirect(&state, &headers, BACK, Flash::error("ファイルを読み込めませんでした。「列の状態を書き出す」で保存したファイルを選んでください。"));
+    };
+    let existing_queue_ids: Vec<i32> = fetch_queues(&state.pool).await.iter().map(|q| q.id).collect();
+    if let Err(message) = validate_queue_state(&imported, &existing_queue_ids) {
+        return flash_redirect(&state, &headers, BACK, Flash::error(&message));
+    }
+
+    // 取り込む先に案内中の整理券があると番号が重なるので、空の (またはリセットした) 環境にだけ取り込む
+    let mut tx = state.pool.begin().await.expect("Failed to begin transaction");
+    let active: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM tickets WHERE status IN ('waiting', 'called')")
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap_or(0);
+    if active > 0 {
+        let message = format!("この環境には待機中・呼出中の整理券が{}枚あるため取り込めません。案内を終えるかリセットしてから取り込んでください。", active);
+        return flash_redirect(&state, &headers, BACK, Flash::error(&message));
+    }
+
+    let settings = Settings::load(&state.pool).await;
+    let created = match write_queue_state(&mut tx, &imported, &acting, &settings).await {
+        Ok(created) => created,
+        Err(err) => {
+            // tx を捨てるので、途中まで書いたものも残らない
+            tracing::error!("Failed to import queue state: {}", err);
+            return flash_redirect(&state, &headers, BACK, Flash::error("取り込みに失敗したため、何も変更していません。ファイルの中身を確認してください。"));
+        }
+    };
+    tx.commit().await.expect("Failed to commit transaction");
+
+    tracing::warn!(user = %acting.name, tickets = created, "queue state was imported");
+    state.snapshot.invalidate();
+
+    let skipped = imported.tickets.len() - created;
+    let message = if skipped > 0 {
+        format!("{}列と{}枚の整理券を取り込みました (取り込み済みの{}枚は飛ばしました)。", imported.queues.len(), created, skipped)
+    } else {
+        format!("{}列と{}枚の整理券を取り込みました。", imported.queues.len(), created)
+    };
+    flash_redirect(&state, &headers, BACK, Flash::notice(&message))
+}
+
+// --- マイグレーション (DBの構造の変更) ---
+// 起動時に自動で適用するが、営業中の店舗で既存のデータを消す・変える変更が知らないうちに当たらないよう、
+// そのような変更が未適用なら MIGRATIONS_CONFIRM でバージョンを確認するまで起動しない
+// MIGRATIONS_MODE=dry_run なら、未適用の変更を1つのトランザクションで試して取り消し、結果をログに出して止まる
+
+// 組み込む先のアプリも sqlx のマイグレーションを使っている場合に、
+// こちらの知らない適用済みのマイグレーションがあっても止まらないようにする
+fn migrator() -> sqlx::migrate::Migrator {
+    let mut migrator = sqlx::migrate!();
+    migrator.set_ignore_missing(true);
+    migrator
+}
+
+// 文の先頭 (ALTER TABLE は中身) で判断する。関数・トリガーの中の 'TRUNCATE' などは適用時に動かないので数えないが、
+// DO $$ ... $$ はその場で動くので中の文も見る
+fn is_destructive_migration(sql: &str) -> bool {
+    sql_statements(sql).iter().any(|statement| {
+        let statement = statement.to_ascii_uppercase();
+        if statement.starts_with("DO ") {
+            // PL/pgSQL の中は IF ... THEN UPDATE ... のように文の途中から始まるので、語の区切りから探す
+            let body = format!(" {} ", statement.replace(['$', '\'', '(', ')'], " "));
+            return DESTRUCTIVE_STATEMENTS.iter().any(|p| body.contains(&format!(" {p} ")))
+                || (body.contains(" ALTER TABLE ") && DESTRUCTIVE_ALTERS.iter().any(|p| body.contains(p)));
+        }
+        DESTRUCTIVE_STATEMENTS.iter().any(|p| statement.starts_with(&format!("{p} ")))
+            || (statement.starts_with("ALTER TABLE ") && DESTRUCTIVE_ALTERS.iter().any(|p| statement.contains(p)))
+            || (statement.starts_with("WITH ") && [" UPDATE ", " DELETE FROM "].iter().any(|p| statement.contains(p)))
+            || (statement.starts_with("INSERT ") && statement.contains(" DO UPDATE "))
+    })
+}
+
+// 既存の行や索引を消す・書き換える文の始まり
+const DESTRUCTIVE_STATEMENTS: [&str; 10] = [
+    "DROP TABLE",
+    "DROP SCHEMA",
+    "DROP INDEX",
+    "DROP VIEW",
+    "DROP MATERIALIZED VIEW",
+    "DROP TYPE",
+    "DROP SEQUENCE",
+    "TRUNCATE",
+    "DELETE FROM",
+    "UPDATE",
+];
+const DESTRUCTIVE_ALTERS: [&str; 3] = [" DROP COLUMN ", " RENAME ", " TYPE "];
+
+// コメントを除いて文に分け、空白を詰める。文字列とドル記号の引用 ($$ ... $$) の中の ; では分けない
+fn sql_statements(sql: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut rest = sql;
+    while let Some(c) = rest.chars().next() {
+        let quoted_len = if c == '\'' {
+            Some(rest[1..].find('\'').map_or(rest.len(), |i| i + 2))
+        } else {
+            dollar_quote_tag(rest).map(|tag| rest[tag.len()..].find(tag).map_or(rest.len(), |i| i + tag.len() * 2))
+        };
+        if let Some(len) = quoted_len {
+            current.push_str(&rest[..len]);
+            rest = &rest[len..];
+        } else if rest.starts_with("--") {
+            rest = rest.find('\n').map_or("", |i| &rest[i..]);
+        } else if rest.starts_with("/*") {
+            rest = rest[2..].find("*/").map_or("", |i| &rest[i + 4..]);
+            current.push(' ');
+        } else if c == ';' {
+            statements.push(std::mem::take(&mut current));
+            rest = &rest[1..];
+        } else {
+            current.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    statements.push(current);
+    statements
+        .iter()
+        .map(|statement| statement.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|statement| !statement.is_empty())
+        .collect()
+}
+
+// "$$" や "$body$" のようなドル記号の引用の始まり ($1 などの引数は除く)
+fn dollar_quote_tag(s: &str) -> Option<&str> {
+    let inner = s.strip_prefix('$')?;
+    let len = inner.find('$')?;
+    let tag = &inner[..len];
+    (!tag.starts_with(|c: char| c.is_ascii_digit()) && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+        .then(|| &s[..len + 2])
+}
+
+struct MigrationGuard {
+    dry_run: bool,
+    confirmed: Vec<i64>, // 適用してよいと確認した、データを消す・変えるマイグレーションのバージョン
+}
+
+impl MigrationGuard {
+    fn from_lookup(get: &dyn Fn(&str) -> Option<String>) -> Self {
+        MigrationGuard {
+            dry_run: get("MIGRATIONS_MODE").is_some_and(|v| v.trim() == "dry_run"),
+            confirmed: get("MIGRATIONS_CONFIRM")
+                .map(|v| v.split(',').filter_map(|v| v.trim().parse().ok()).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    async fn check(&self, migrator: &sqlx::migrate::Migrator, pool: &PgPool) {
+        // まだ何も適用していないDB (新しい店舗) には、守るデータがないのでそのまま適用する
+        let applied = applied_migrations(pool).await;
+        if applied.is_empty() {
+            return;
+        }
+        let applied: HashSet<i64> = applied.iter().filter(|m| m.success).map(|m| m.version).collect();
+        let pending: Vec<&sqlx::migrate::Migration> = migrator
+            .iter()
+            .filter(|m| !m.migration_type.is_down_migration() && !applied.contains(&m.version))
+            .collect();
+
+        if self.dry_run {
+            dry_run_migrations(pool, &pending).await;
+            panic!("MIGRATIONS_MODE is dry_run: {} pending migrations were tried and rolled back; not starting", pending.len());
+        }
+
+        let unconfirmed: Vec<i64> = pending
+            .iter()
+            .filter(|m| is_destructive_migration(&m.sql) && !self.confirmed.contains(&m.version))
+            .map(|m| {
+                tracing::error!(version = m.version, description = %m.description, "pending migration drops or rewrites existing data");
+                m.version
+            })
+            .collect();
+        if !unconfirmed.is_empty() {
+            panic!(
+                "Refusing to start: migrations {:?} drop or rewrite existing data. Back up the database, then add the versions to MIGRATIONS_CONFIRM",
+                unconfirmed
+            );
+        }
+    }
+}
+
+// 後のマイグレーションは前のものに頼るので、順に同じトランザクションで試す (最初に失敗したところで止める)
+async fn dry_run_migrations(pool: &PgPool, pending: &[&sqlx::migrate::Migration]) {
+    let mut tx = pool.begin().await.expect("Failed to begin transaction");
+    for migration in pending {
+        let destructive = is_destructive_migration(&migration.sql);
+        // 引数のない文字列は複数の文をまとめて送れる (raw_sql の Future は起動時の Send の確認を通らない)
+        match sqlx::Executor::execute(&mut *tx, migration.sql.as_ref()).await {
+            Ok(_) => tracing::warn!(version = migration.version, description = %migration.description, destructive, "dry run: migration would succeed"),
+            Err(err) => {
+                tracing::error!(version = migration.version, description = %migration.description, destructive, "dry run: migration failed: {}", err);
+                break;
+            }
+        }
+    }
+    tx.rollback().await.expect("Failed to roll back dry run");
+}
+
+#[derive(FromRow)]
+struct AppliedMigration {
+    version: i64,
+    description: String,
+    installed_on: DateTime<Utc>,
+    success: bool,
+    checksum: Vec<u8>,
+    execution_time: i64, // ナノ秒
+}
+
+// 記録の表がまだなければ (新しいDB) 空
+async fn applied_migrations(pool: &PgPool) -> Vec<AppliedMigration> {
+    sqlx::query_as::<_, AppliedMigration>(
+        "SELECT version, description, installed_on, success, checksum, execution_time FROM _sqlx_migrations ORDER BY version"
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap_or(vec![])
+}
+
+struct MigrationView {
+    version: i64,
+    description: String,
+    status: &'static str,   // applied / failed / pending / unknown (このアプリにないもの。組み込み先のアプリのものなど)
+    installed: String,      // 適用した日時 (店舗の現地時刻)
+    execution_ms: i64,
+    destructive: bool,      // 既存のデータを消す・変える
+    modified: bool,         // 適用した後にファイルの中身が変わっている
+}
+
+async fn migrations_page(State(state): State<AppState>) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    let migrator = migrator();
+    let mut applied: HashMap<i64, AppliedMigration> =
+        applied_migrations(&state.pool).await.into_iter().map(|m| (m.version, m)).collect();
+
+    let mut migrations: Vec<MigrationView> = migrator
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+        .map(|m| {
+            let record = applied.remove(&m.version);
+            MigrationView {
+                version: m.version,
+                description: m.description.to_string(),
+                status: match &record {
+                    Some(r) if r.success => "applied",
+                    Some(_) => "failed",
+                    None => "pending",
+                },
+                installed: record.as_ref().map(|r| settings.format_local(&r.installed_on, "%Y/%m/%d %H:%M")).unwrap_or_default(),
+                execution_ms: record.as_ref().map_or(0, |r| r.execution_time / 1_000_000),
+                destructive: is_destructive_migration(&m.sql),
+                modified: record.is_some_and(|r| r.checksum != *m.checksum),
+            }
+        })
+        .collect();
+    migrations.extend(applied.into_values().map(|r| MigrationView {
+        version: r.version,
+        description: r.description,
+        status: "unknown",
+        installed: settings.format_local(&r.installed_on, "%Y/%m/%d %H:%M"),
+        execution_ms: r.execution_time / 1_000_000,
+        destructive: false,
+        modified: false,
+    }));
+    migrations.sort_by_key(|m| std::cmp::Reverse(m.version));
+
+    let schema_version = migrations.iter().filter(|m| m.status == "applied").map(|m| m.version).max();
+    HtmlTemplate(AdminMigrationsTemplate {
+        schema_version,
+        migrations,
+        confirmed: state.migrations_confirmed.clone(),
+    })
+}
+
+// --- Google スプレッドシートへの書き出し ---
+// 完了した整理券を、サービスアカウントで指定のシートに1行ずつ追加する
+const SHEETS_API: &str = "https://sheets.googleapis.com/v4/spreadsheets";
+const SHEETS_SCOPE: &str = "https://www.googleapis.com/auth/spreadsheets";
+const SHEETS_BATCH_SIZE: i64 = 500;
+
+// サービスアカウントの鍵ファイル (JSON) のうち使う項目
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+struct SheetsConfig {
+    spreadsheet_id: String,
+    range: String, // 追加先 (例: "Sheet1!A:H")
+    key: ServiceAccountKey,
+}
+
+#[derive(Serialize)]
+struct GoogleClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+// 署名したJWTをアクセストークンに交換する (有効期限の少し前に取り直せるよう、期限も返す)
+async fn google_access_token(http: &reqwest::Client, key: &ServiceAccountKey) -> Result<(String, DateTime<Utc>), String> {
+    let now = Utc::now().timestamp();
+    let claims = GoogleClaims { iss: &key.client_email, scope: SHEETS_SCOPE, aud: &key.token_uri, iat: now, exp: now + 3600 };
+    let encoding_key = jsonwebtoken::EncodingKey::from_rsa_pem(key.private_key.as_bytes()).map_err(|e| e.to_string())?;
+    let assertion = jsonwebtoken::encode(&jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256), &claims, &encoding_key)
+        .map_err(|e| e.to_string())?;
+
+    let res: serde_json::Value = http
+        .post(&key.token_uri)
+        .form(&[("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"), ("assertion", assertion.as_str())])
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let token = res["access_token"].as_str().ok_or("access_token is missing")?.to_string();
+    let expires_in = res["expires_in"].as_i64().unwrap_or(3600);
+    Ok((token, Utc::now() + chrono::Duration::seconds(expires_in - 60)))
+}
+
+async fn sheets_worker(state: AppState, config: SheetsConfig) {
+    let mut token = None;
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        // 複数台で動かしている場合に、同じ行を二重に追加しないよう1台だけで書き出す
+        let Some(_lock) = try_job_lock(&state.pool, "sheets_worker").await else {
+            continue;
+        };
+        // 失敗した分は書き出し済みにしないので、次の回にまとめて送り直す
+        if let Err(e) = export_completed_to_sheet(&state, &config, &mut token).await {
+            tracing::warn!("Failed to export completed tickets to Google Sheets: {}", e);
+            notify_staff(&state.pool, "sheets_failed", "Google スプレッドシートへの書き出しに失敗しています。次の回に送り直します。", None).await;
+        }
+    }
+}
+
+async fn export_completed_to_sheet(
+    state: &AppState,
+    config: &SheetsConfig,
+    token: &mut Option<(String, DateTime<Utc>)>,
+) -> Result<(), String> {
+    let tickets = sqlx::query_as::<_, Ticket>(
+        "SELECT * FROM tickets t 
+         WHERE t.status = 'completed' AND NOT t.is_test 
+           AND NOT EXISTS (SELECT 1 FROM sheet_exports e WHERE e.ticket_id = t.id) 
+         ORDER BY t.completed_at ASC LIMIT $1"
+    )
+    .bind(SHEETS_BATCH_SIZE)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|e| e.to_string())?;
+    if tickets.is_empty() {
+        return Ok(());
+    }
+
+    if token.as_ref().is_none_or(|(_, expires_at)| *expires_at <= Utc::now()) {
+        *token = Some(google_access_token(&state.http, &config.key).await?);
+    }
+    let Some((access_token, _)) = token.as_ref() else { return Ok(()) };
+
+    // 列: 整理番号, 人数, 発券時刻, 呼出時刻, 完了時刻, 待ち時間(分), 受付, 列, 営業日
+    let settings = Settings::load(&state.pool).await;
+    let queues = fetch_queues(&state.pool).await;
+    let format = |at: Option<DateTime<Utc>>| at.map(|at| settings.format_local(&at, "%Y-%m-%d %H:%M:%S")).unwrap_or_default();
+    let values: Vec<serde_json::Value> = tickets
+        .iter()
+        .map(|t| {
+            serde_json::json!([
+                t.number,
+                t.group_size,
+                format(Some(t.created_at)),
+                format(t.called_at),
+                format(t.completed_at),
+                t.called_at.map(|at| (at - t.created_at).num_minutes()),
+                if t.remote { "リモート" } else { "店頭" },
+                queues.iter().find(|q| q.id == t.queue_id).map(|q| q.name.as_str()).unwrap_or(""),
+                settings.business_date(&t.created_at).to_string(),
+            ])
+        })
+        .collect();
+
+    let mut url = reqwest::Url::parse(SHEETS_API).map_err(|e| e.to_string())?;
+    url.path_segments_mut()
+        .map_err(|_| "invalid Sheets API URL".to_string())?
+        .push(&config.spreadsheet_id)
+        .push("values")
+        .push(&format!("{}:append", config.range));
+
+    state
+        .http
+        .post(url)
+        .bearer_auth(access_token)
+        .query(&[("valueInputOption", "USER_ENTERED"), ("insertDataOption", "INSERT_ROWS")])
+        .json(&serde_json::json!({ "values": values }))
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?;
+
+    let ids: Vec<Uuid> = tickets.iter().map(|t| t.id).collect();
+    sqlx::query("INSERT INTO sheet_exports (ticket_id) SELECT * FROM UNNEST($1::uuid[]) ON CONFLICT DO NOTHING")
+        .bind(&ids)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Webhook の署名の許容時差 (Stripe の推奨値)
+const STRIPE_WEBHOOK_TOLERANCE_SECONDS: i64 = 300;
+
+// リモート受付の発券 (デポジットが必要な人数なら、先に決済画面へ案内する)
+async fn issue_remote_ticket(state: &AppState, settings: &Settings, headers: &HeaderMap, new: NewTicket) -> Response {
+    // 来店しなかった回数が基準に達したお客様は、設定によって人数に関係なくデポジットを求める
+    let no_shows = match &new.phone {
+        Some(phone) if settings.no_show_threshold > 0 => count_recent_no_shows(&state.pool, phone).await,
+        _ => 0,
+    };
+    let repeat_no_show = settings.is_repeat_no_show(no_shows);
+
+    let needs_deposit = (settings.deposit_min_group_size > 0 && new.group_size >= settings.deposit_min_group_size)
+        || (repeat_no_show && settings.no_show_policy == "deposit");
+    let Some(stripe) = state.stripe.as_ref().filter(|_| needs_deposit) else {
+        let ticket = issue_ticket(state, new, None).await;
+        if repeat_no_show {
+            let detail = format!("直近{}日に{}回来店なし", NO_SHOW_WINDOW_DAYS, no_shows);
+            record_event_detail(&state.pool, ticket.id, "no_show_flag", None, Some(&detail)).await;
+        }
+        let redirect = Redirect::to(&guest_path(state, &ticket));
+        // 発券した端末を1端末1枚ガードで追跡する (既に有効な整理券を持つ端末では、最初の1枚を追跡し続ける)
+        if state.device_guard != DeviceGuardMode::Off && device_held_ticket(state, headers, None).await.is_none() {
+            let cookie = signed_cookie(state, DEVICE_COOKIE, &ticket.id.to_string(), 86400, "Lax");
+            return ([(SET_COOKIE, cookie)], redirect).into_response();
+        }
+        return redirect.into_response();
+    };
+
+    let id: Uuid = sqlx::query_scalar(
+        "INSERT INTO deposits (group_size, phone, amount) VALUES ($1, $2, $3) RETURNING id"
+    )
+    .bind(new.group_size)
+    .bind(&new.phone)
+    .bind(settings.deposit_amount)
+    .fetch_one(&state.pool)
+    .await
+    .expect("Failed to create deposit");
+
+    let params = [
+        ("mode", "payment".to_string()),
+        ("success_url", format!("{}{}", state.base_url, state.url(&format!("/join/deposit/{}", id)))),
+        ("cancel_url", format!("{}{}", state.base_url, state.url("/join"))),
+        ("client_reference_id", id.to_string()),
+        ("line_items[0][quantity]", "1".to_string()),
+        ("line_items[0][price_data][currency]", "jpy".to_string()),
+        ("line_items[0][price_data][unit_amount]", settings.deposit_amount.to_string()),
+        ("line_items[0][price_data][product_data][name]", format!("整理券のデポジット ({}名)", new.group_size)),
+        // 決済画面の有効期限 (Stripe で指定できる最短の30分)
+        ("expires_at", (Utc::now() + chrono::Duration::minutes(30)).timestamp().to_string()),
+    ];
+
+    let session = state
+        .http
+        .post(format!("{}/checkout/sessions", STRIPE_API))
+        .bearer_auth(&stripe.secret_key)
+        .form(&params)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status());
+    let session: Option<serde_json::Value> = match session {
+        Ok(res) => res.json().await.ok(),
+        Err(err) => {
+            tracing::error!("Failed to create checkout session: {}", err);
+            None
+        }
+    };
+
+    let (Some(session_id), Some(url)) = (
+        session.as_ref().and_then(|s| s["id"].as_str()),
+        session.as_ref().and_then(|s| s["url"].as_str()),
+    ) else {
+        sqlx::query("UPDATE deposits SET status = 'expired', updated_at = NOW() WHERE id = $1")
+            .bind(id)
+            .execute(&state.pool)
+            .await
+            .expect("Failed to expire deposit");
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "お支払いの準備ができませんでした。受付で整理券をお受け取りください。",
+        )
+            .into_response();
+    };
+
+    sqlx::query("UPDATE deposits SET checkout_session_id = $2, updated_at = NOW() WHERE id = $1")
+        .bind(id)
+        .bind(session_id)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to save checkout session");
+
+    Redirect::to(url).into_response()
+}
+
+#[derive(FromRow)]
+struct Deposit {
+    status: String,
+    amount: i32,
+    ticket_id: Option<Uuid>,
+}
+
+// 決済後の戻り先。Webhook で発券されるまでは自動で再読み込みして待つ
+async fn deposit_status(Path(id): Path<Uuid>, State(state): State<AppState>) -> impl IntoResponse {
+    let Some(deposit) = sqlx::query_as::<_, Deposit>("SELECT status, amount, ticket_id FROM deposits WHERE id = $1")
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await
+        .unwrap_or(None)
+    else {
+        return (StatusCode::NOT_FOUND, "お支払いの情報が見つかりません。").into_response();
+    };
+
+    if let Some(ticket_id) = deposit.ticket_id {
+        return redirect_to_guest(&state, ticket_id).await;
+    }
+
+    HtmlTemplate(JoinDepositTemplate { status: deposit.status, amount: deposit.amount }).into_response()
+}
+
+// Stripe-Signature ヘッダー (t=時刻,v1=署名,...) を検証する
+fn verify_stripe_signature(secret: &str, header: &str, payload: &str) -> bool {
+    let mut timestamp = None;
+    let mut signatures = vec![];
+    for part in header.split(',') {
+        match part.split_once('=') {
+            Some(("t", v)) => timestamp = v.parse::<i64>().ok(),
+            Some(("v1", v)) => signatures.push(v),
+            _ => {}
+        }
+    }
+
+    let Some(timestamp) = timestamp else { return false };
+    if (Utc::now().timestamp() - timestamp).abs() > STRIPE_WEBHOOK_TOLERANCE_SECONDS {
+        return false;
+    }
+
+    let expected = hmac_hex(secret.as_bytes(), &format!("{}.{}", timestamp, payload));
+    signatures.iter().any(|sig| constant_time_eq(expected.as_bytes(), sig.as_bytes()))
+}
+
+#[derive(FromRow)]
+struct PaidDeposit {
+    id: Uuid,
+    group_size: i32,
+    phone: Option<String>,
+}
+
+async fn stripe_webhook(State(state): State<AppState>, headers: HeaderMap, body: String) -> impl IntoResponse {
+    let Some(stripe) = &state.stripe else {
+        return StatusCode::NOT_FOUND;
+    };
+    let signature = headers.get("Stripe-Signature").and_then(|v| v.to_str().ok()).unwrap_or("");
+    if !verify_stripe_signature(&stripe.webhook_secret, signature, &body) {
+        return StatusCode::BAD_REQUEST;
+    }
+    let Ok(event) = serde_json::from_str::<serde_json::Value>(&body) else {
+        return StatusCode::BAD_REQUEST;
+    };
+
+    let session = &event["data"]["object"];
+    let session_id = session["id"].as_str().unwrap_or("");
+    match event["type"].as_str() {
+        Some("checkout.session.completed") if session["payment_status"] == "paid" => {
+            // Webhook は再送されることがあるので、未払いから支払い済みにできた時だけ発券する
+            let paid = sqlx::query_as::<_, PaidDeposit>(
+                "UPDATE deposits SET status = 'paid', payment_intent_id = $2, updated_at = NOW() 
+                 WHERE checkout_session_id = $1 AND status = 'pending' 
+                 RETURNING id, group_size, phone"
+            )
+            .bind(session_id)
+            .bind(session["payment_intent"].as_str())
+            .fetch_optional(&state.pool)
+            .await
+            .expect("Failed to mark deposit as paid");
+
+            if let Some(deposit) = paid {
+                let new = NewTicket {
+                    group_size: deposit.group_size,
+                    remote: true,
+                    phone: deposit.phone,
+                    ..Default::default()
+                };
+                let ticket = issue_ticket(&state, new, None).await;
+                sqlx::query("UPDATE deposits SET ticket_id = $2, updated_at = NOW() WHERE id = $1")
+                    .bind(deposit.id)
+                    .bind(ticket.id)
+                    .execute(&state.pool)
+                    .await
+                    .expect("Failed to link deposit to ticket");
+            }
+        }
+        Some("checkout.session.expired") => {
+            sqlx::query("UPDATE deposits SET status = 'expired', updated_at = NOW() WHERE checkout_session_id = $1 AND status = 'pending'")
+                .bind(session_id)
+                .execute(&state.pool)
+                .await
+                .expect("Failed to expire deposit");
+        }
+        _ => {}
+    }
+
+    StatusCode::OK
+}
+
+// 到着したお客様のデポジットを返金する
+async fn refund_deposit(state: &AppState, ticket_id: Uuid) {
+    let Some(stripe) = &state.stripe else { return };
+
+    // 二重に返金しないよう、先に返金済みにしてから Stripe に依頼する
+    // (合流したチケットでは、1枚に複数のデポジットがつくことがある)
+    let claimed: Vec<(Uuid, Option<String>)> = sqlx::query_as(
+        "UPDATE deposits SET status = 'refunded', updated_at = NOW() 
+         WHERE ticket_id = $1 AND status = 'paid' 
+         RETURNING id, payment_intent_id"
+    )
+    .bind(ticket_id)
+    .fetch_all(&state.pool)
+    .await
+    .expect("Failed to claim deposit refund");
+
+    for (id, payment_intent) in claimed {
+        let Some(payment_intent) = payment_intent else { continue };
+        let result = state
+            .http
+            .post(format!("{}/refunds", STRIPE_API))
+            .bearer_auth(&stripe.secret_key)
+            .form(&[("payment_intent", payment_intent.as_str())])
+            .send()
+            .await
+            .and_then(|res| res.error_for_status());
+
+        // 失敗したら支払い済みに戻し、Stripe のダッシュボードから対応できるようにする
+        if let Err(err) = result {
+            tracing::error!("Failed to refund deposit {}: {}", id, err);
+            notify_staff(&state.pool, "refund_failed", "デポジットの返金に失敗しました。Stripe のダッシュボードから返金してください。", None).await;
+            sqlx::query("UPDATE deposits SET status = 'paid', updated_at = NOW() WHERE id = $1")
+                .bind(id)
+                .execute(&state.pool)
+                .await
+                .expect("Failed to restore deposit status");
+        }
+    }
+}
+
+// 到着確認をしないまま完了 (またはリセットで削除) されたチケットのデポジットは没収する
+// (合流したチケットのデポジットは、合流先のチケットに付け替えてある)
+async fn forfeit_no_show_deposits(state: &AppState) {
+    sqlx::query(
+        "UPDATE deposits d SET status = 'forfeited', updated_at = NOW() 
+         WHERE d.status = 'paid' 
+           AND NOT EXISTS (
+               SELECT 1 FROM tickets t 
+               WHERE t.id = d.ticket_id AND (t.status IN ('waiting', 'called', 'deleted') OR t.checked_in_at IS NOT NULL)
+           )"
+    )
+    .execute(&state.pool)
+    .await
+    .expect("Failed to forfeit deposits");
+}
+
+// --- 来店しなかったお客様の記録 ---
+const NO_SHOW_WINDOW_DAYS: i32 = 30;
+
+async fn count_recent_no_shows(pool: &PgPool, phone: &str) -> i64 {
+    sqlx::query_scalar(
+        "SELECT COUNT(*) FROM no_shows WHERE phone = $1 AND created_at > NOW() - make_interval(days => $2)"
+    )
+    .bind(phone)
+    .bind(NO_SHOW_WINDOW_DAYS)
+    .fetch_one(pool)
+    .await
+    .unwrap_or(0)
+}
+
+// リモート受付で、到着確認をしないまま完了したお客様を記録する (デポジットの没収と同じ基準)
+async fn record_remote_no_shows(state: &AppState) {
+    sqlx::query(
+        "INSERT INTO no_shows (phone, ticket_id) 
+         SELECT phone, id FROM tickets 
+         WHERE remote AND phone IS NOT NULL AND status = 'completed' AND checked_in_at IS NULL AND NOT is_test 
+         ON CONFLICT (ticket_id) DO NOTHING"
+    )
+    .execute(&state.pool)
+    .await
+    .expect("Failed to record no-shows");
+}
+
+#[derive(Deserialize)]
+struct NoShowForm {
+    id: Uuid,
+}
+
+// 呼び出しても来なかったお客様を完了にし、電話番号があれば記録する
+async fn mark_no_show(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<NoShowForm>,
+) -> impl IntoResponse {
+    release_table_of(&state.pool, form.id).await;
+    let ticket = sqlx::query_as::<_, Ticket>(
+        "UPDATE tickets SET status = 'completed', completed_at = NOW() 
+         WHERE id = $1 AND status = 'called' RETURNING *"
+    )
+    .bind(form.id)
+    .fetch_optional(&state.pool)
+    .await
+    .expect("Failed to mark no-show");
+
+    if let Some(ticket) = &ticket {
+        sqlx::query(
+            "INSERT INTO no_shows (phone, ticket_id) 
+             SELECT phone, id FROM tickets WHERE id = $1 AND phone IS NOT NULL AND NOT is_test 
+             ON CONFLICT (ticket_id) DO NOTHING"
+        )
+        .bind(ticket.id)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to record no-show");
+
+        let acting = acting_user(&state, &headers).await;
+        record_event(&state.pool, ticket.id, "no_show", acting.as_ref()).await;
+        state.snapshot.invalidate();
+        state.run_hooks(TicketLifecycle::Cancelled, ticket);
+    }
+
+    if headers.contains_key("HX-Request") {
+        return ([("HX-Trigger", "live-update")], "").into_response();
+    }
+    Redirect::to(&url("/admin/call")).into_response()
+}
+
+// --- ゴミ箱 ---
+#[derive(Deserialize)]
+struct DeleteTicketForm {
+    id: Uuid,
+}
+
+// 整理券は行ごと消さずに status = 'deleted' にする (誤って消しても番号のまま戻せるように)
+// 待機中・呼出中のもののみ。呼び出しで使っていたテーブルは空席に戻す
+async fn delete_ticket(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<DeleteTicketForm>,
+) -> impl IntoResponse {
+    // ゴミ箱に入れられた時だけテーブルを空席に戻すよう、同じトランザクションで行う
+    let mut tx = state.pool.begin().await.expect("Failed to begin transaction");
+    let table_id = called_table_of(&mut tx, form.id).await;
+    let ticket = sqlx::query_as::<_, Ticket>(
+        "UPDATE tickets SET status = 'deleted', deleted_at = NOW(), table_id = NULL 
+         WHERE id = $1 AND status IN ('waiting', 'called') RETURNING *"
+    )
+    .bind(form.id)
+    .fetch_optional(&mut *tx)
+    .await
+    .expect("Failed to delete ticket");
+    if let (Some(_), Some(table_id)) = (&ticket, table_id) {
+        free_table(&mut *tx, table_id).await;
+    }
+    tx.commit().await.expect("Failed to commit transaction");
+
+    let Some(ticket) = &ticket else {
+        return call_form_error(&state, &headers, StatusCode::CONFLICT, "この整理券は、すでに状態が変わっています。").await;
+    };
+    let acting = acting_user(&state, &headers).await;
+    record_event(&state.pool, ticket.id, "deleted", acting.as_ref()).await;
+    state.snapshot.invalidate();
+    state.run_hooks(TicketLifecycle::Cancelled, ticket);
+
+    if headers.contains_key("HX-Request") {
+        return ([("HX-Trigger", "live-update")], "").into_response();
+    }
+    Redirect::to(&url("/admin/call")).into_response()
+}
+
+// ゴミ箱に表示する件数
+const TRASH_LIMIT: i64 = 200;
+
+async fn trash_page(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let flash = read_flash(&state, &headers);
+    let settings = Settings::load(&state.pool).await;
+    let queues = fetch_queues(&state.pool).await;
+    let tickets = sqlx::query_as::<_, Ticket>(
+        "SELECT * FROM tickets WHERE status = 'deleted' ORDER BY deleted_at DESC LIMIT $1"
+    )
+    .bind(TRASH_LIMIT)
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![])
+    .into_iter()
+    .map(|ticket| {
+        let deleted_at = ticket.deleted_at.map(|at| settings.format_local(&at, "%m/%d %H:%M")).unwrap_or_default();
+        let queue = queues.iter().find(|q| q.id == ticket.queue_id).filter(|_| queues.len() > 1).map(|q| q.name.clone());
+        TrashItem { issued_at: settings.format_local(&ticket.created_at, "%m/%d %H:%M"), ticket, deleted_at, queue }
+    })
+    .collect();
+
+    clear_flash(&state, &flash, HtmlTemplate(AdminTrashTemplate { tickets, flash: flash.clone() }))
+}
+
+// ゴミ箱から戻す。番号と発券時刻はそのままで、待機中として元の順番に並び直す
+// (削除している間に同じ頭文字・番号が発券されていたら、呼び間違えないよう戻さない)
+async fn restore_ticket(Path(id): Path<Uuid>, State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let ticket = sqlx::query_as::<_, Ticket>(
+        "UPDATE tickets t SET status = 'waiting', deleted_at = NULL, called_at = NULL, counter_id = NULL 
+         WHERE t.id = $1 AND t.status = 'deleted' 
+           AND NOT EXISTS (SELECT 1 FROM tickets o WHERE o.number = t.number AND o.number_prefix = t.number_prefix AND o.status IN ('waiting', 'called')) 
+         RETURNING *"
+    )
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await
+    .expect("Failed to restore ticket");
+
+    let Some(ticket) = ticket else {
+        let message = "同じ番号のお客様が待機中・呼出中のため戻せません (すでに戻した場合も含みます)。";
+        return flash_redirect(&state, &headers, "/admin/trash", Flash::error(message));
+    };
+    let acting = acting_user(&state, &headers).await;
+    record_event(&state.pool, ticket.id, "restored", acting.as_ref()).await;
+    state.snapshot.invalidate();
+    state.run_hooks(TicketLifecycle::Created, &ticket);
+    flash_redirect(&state, &headers, "/admin/trash", Flash::notice(&format!("整理番号 {} を待機中に戻しました。", ticket.number)))
+}
+
+// --- テーブル管理 ---
+async fn set_table_status(state: &AppState, id: Uuid, status: &str) {
+    sqlx::query("UPDATE tables SET status = $2, updated_at = NOW() WHERE id = $1")
+        .bind(id)
+        .bind(status)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to update table status");
+    state.snapshot.invalidate();
+}
+
+// 呼び出しを取り消した時に、そのチケットに案内したテーブルを空席に戻す
+async fn release_table_of<'e>(executor: impl sqlx::PgExecutor<'e>, ticket_id: Uuid) {
+    sqlx::query(
+        "UPDATE tables SET status = 'free', updated_at = NOW() 
+         WHERE id = (SELECT table_id FROM tickets WHERE id = $1 AND status = 'called')"
+    )
+    .bind(ticket_id)
+    .execute(executor)
+    .await
+    .expect("Failed to release table");
+}
+
+// 呼出中のチケットに案内したテーブル。取り消しが終わるまで、そのチケットの行をロックしておく
+async fn called_table_of(conn: &mut sqlx::PgConnection, ticket_id: Uuid) -> Option<Uuid> {
+    sqlx::query_scalar::<_, Option<Uuid>>("SELECT table_id FROM tickets WHERE id = $1 AND status = 'called' FOR UPDATE")
+        .bind(ticket_id)
+        .fetch_optional(conn)
+        .await
+        .expect("Failed to load ticket table")
+        .flatten()
+}
+
+async fn free_table<'e>(executor: impl sqlx::PgExecutor<'e>, table_id: Uuid) {
+    sqlx::query("UPDATE tables SET status = 'free', updated_at = NOW() WHERE id = $1")
+        .bind(table_id)
+        .execute(executor)
+        .await
+        .expect("Failed to release table");
+}
+
+async fn render_admin_tables(state: &AppState, error: Option<String>) -> AdminTablesTemplate {
+    // 使用中のテーブルには、最後に案内したチケットの番号を添える
+    let tables = sqlx::query_as::<_, TableRow>(
+        "SELECT t.id, t.name, t.capacity, t.status, 
+                (SELECT k.number FROM tickets k 
+                 WHERE k.table_id = t.id AND t.status = 'occupied' 
+                 ORDER BY k.called_at DESC NULLS LAST LIMIT 1) AS ticket_number 
+         FROM tables t 
+         ORDER BY t.name ASC"
+    )
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![]);
+
+    AdminTablesTemplate { tables, error, flash: None }
+}
+
+async fn admin_tables(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let flash = read_flash(&state, &headers);
+    let page = AdminTablesTemplate { flash: flash.clone(), ..render_admin_tables(&state, None).await };
+    clear_flash(&state, &flash, HtmlTemplate(page))
+}
+
+// 席数は空欄でも送信されるので、文字列で受けてから解釈する
+#[derive(Deserialize)]
+struct CreateTableForm {
+    name: String,
+    capacity: String,
+}
+
+async fn create_table(State(state): State<AppState>, headers: HeaderMap, Form(form): Form<CreateTableForm>) -> impl IntoResponse {
+    let name = form.name.trim();
+    let capacity = form.capacity.trim().parse::<i32>().unwrap_or(0);
+    if name.is_empty() || capacity < 1 {
+        let error = Some("テーブル名と1以上の席数を入力してください。".to_string());
+        return (StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(render_admin_tables(&state, error).await)).into_response();
+    }
+
+    sqlx::query("INSERT INTO tables (name, capacity) VALUES ($1, $2)")
+        .bind(name)
+        .bind(capacity)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to create table");
+    state.snapshot.invalidate();
+
+    flash_redirect(&state, &headers, "/admin/tables", Flash::notice(&format!("「{}」を追加しました。", name)))
+}
+
+#[derive(Deserialize)]
+struct TableStatusForm {
+    status: String,
+}
+
+async fn update_table_status(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    Form(form): Form<TableStatusForm>,
+) -> impl IntoResponse {
+    let status = match form.status.as_str() {
+        "occupied" => "occupied",
+        "free" => "free",
+        _ => {
+            let error = Some("テーブルの状態が正しくありません。".to_string());
+            return (StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(render_admin_tables(&state, error).await)).into_response();
+        }
+    };
+    set_table_status(&state, id, status).await;
+    Redirect::to(&url("/admin/tables")).into_response()
+}
+
+async fn delete_table(Path(id): Path<Uuid>, State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    sqlx::query("DELETE FROM tables WHERE id = $1")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to delete table");
+    state.snapshot.invalidate();
+    flash_redirect(&state, &headers, "/admin/tables", Flash::notice("テーブルを削除しました。"))
+}
+
+// --- スタッフ (担当者の切り替え) ---
+const STAFF_COOKIE: &str = "qt_staff";
+
+// PINはユーザーIDと組み合わせてHMACを取る (同じPINでもユーザーごとに値が変わる)
+fn hash_pin(key: &[u8], user_id: Uuid, pin: &str) -> String {
+    hmac_hex(key, &format!("{}:{}", user_id, pin))
+}
+
+fn is_valid_pin(pin: &str) -> bool {
+    (4..=8).contains(&pin.len()) && pin.chars().all(|c| c.is_ascii_digit())
+}
+
+// Cookieから現在操作中のスタッフを取得する (無効化されたスタッフは未選択扱い)
+async fn acting_user(state: &AppState, headers: &HeaderMap) -> Option<User> {
+    let id = get_cookie(headers, STAFF_COOKIE)
+        .and_then(|v| verify_cookie_value(&state.cookie_key, v))
+        .and_then(|v| Uuid::parse_str(&v).ok())?;
+
+    sqlx::query_as::<_, User>("SELECT id, name, role, active FROM users WHERE id = $1 AND active")
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await
+        .unwrap_or(None)
+}
+
+// 操作履歴を記録する (トランザクション内でも使えるよう Executor を受け取る)
+async fn record_event<'e>(
+    executor: impl sqlx::PgExecutor<'e>,
+    ticket_id: Uuid,
+    event_type: &str,
+    user: Option<&User>,
+) {
+    record_event_detail(executor, ticket_id, event_type, user, None).await;
+}
+
+// 補足 (列の移動元と移動先など) つきで操作履歴を残す
+async fn record_event_detail<'e>(
+    executor: impl sqlx::PgExecutor<'e>,
+    ticket_id: Uuid,
+    event_type: &str,
+    user: Option<&User>,
+    detail: Option<&str>,
+) {
+    sqlx::query("INSERT INTO ticket_events (ticket_id, event_type, user_id, detail) VALUES ($1, $2, $3, $4)")
+        .bind(ticket_id)
+        .bind(event_type)
+        .bind(user.map(|u| u.id))
+        .bind(detail)
+        .execute(executor)
+        .await
+        .expect("Failed to record ticket event");
+}
+
+async fn render_staff_page(state: &AppState, headers: &HeaderMap, error: Option<String>) -> StaffTemplate {
+    let users = sqlx::query_as::<_, User>("SELECT id, name, role, active FROM users ORDER BY active DESC, name ASC")
+        .fetch_all(&state.pool)
+        .await
+        .unwrap_or(vec![]);
+
+    let acting = acting_user(state, headers).await;
+    let station_theme = match station_id(state, headers) {
+        Some(id) => sqlx::query_scalar("SELECT theme FROM station_preferences WHERE station_id = $1")
+            .bind(id)
+            .fetch_optional(&state.pool)
+            .await
+            .unwrap_or(None),
+        None => None,
+    };
+    let user_theme = match &acting {
+        Some(user) => sqlx::query_scalar("SELECT theme FROM users WHERE id = $1")
+            .bind(user.id)
+            .fetch_one(&state.pool)
+            .await
+            .unwrap_or(None),
+        None => None,
+    };
+    let settings = Settings::load(&state.pool).await;
+    let observer_links = sqlx::query_as::<_, ObserverLink>(
+        "SELECT id, label, expires_at FROM observer_links WHERE revoked_at IS NULL AND expires_at > NOW() ORDER BY expires_at ASC"
+    )
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![])
+    .into_iter()
+    .map(|link| {
+        let link_url = format!("{}{}", state.base_url, state.url(&format!("/observe/{}?sig={}", link.id, observer_sig(state, link.id))));
+        let expires = settings.format_local(&link.expires_at, "%m/%d %H:%M");
+        (link, link_url, expires)
+    })
+    .collect();
+    let can_share = acting.as_ref().is_some_and(|u| u.role == "manager");
+    let invites = sqlx::query_as::<_, StaffInvite>(
+        "SELECT id, name, role, expires_at FROM staff_invites
+         WHERE used_at IS NULL AND revoked_at IS NULL AND expires_at > NOW() ORDER BY expires_at ASC"
+    )
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![])
+    .into_iter()
+    .map(|invite| {
+        let invite_url = can_share.then(|| format!("{}{}", state.base_url, state.url(&format!("/invite/{}?sig={}", invite.id, invite_sig(state, invite.id)))));
+        let expires = settings.format_local(&invite.expires_at, "%m/%d %H:%M");
+        (invite, invite_url, expires)
+    })
+    .collect();
+    StaffTemplate {
+        users,
+        theme: staff_theme(state, headers, acting.as_ref()).await,
+        can_share,
+        can_manage_staff: can_manage_staff(state, acting.as_ref()).await,
+        observer_links,
+        invites,
+        acting,
+        error,
+        flash: None,
+        station_theme,
+        user_theme,
+        themes: STAFF_THEMES,
+    }
+}
+
+async fn staff_page(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let flash = read_flash(&state, &headers);
+    let mut page = render_staff_page(&state, &headers, None).await;
+    page.flash = flash.clone();
+    clear_flash(&state, &flash, HtmlTemplate(page))
+}
+
+#[derive(Deserialize)]
+struct CreateStaffForm {
+    name: String,
+    pin: String,
+    role: String,
+}
+
+// スタッフの登録・無効化は責任者だけ。責任者をまだ登録していない時は、最初の1人を登録できるよう誰でも使える
+// (責任者でない担当者が、責任者を登録して切り替えることで決まりを抜けられないようにする)
+async fn can_manage_staff(state: &AppState, acting: Option<&User>) -> bool {
+    if acting.is_some_and(|u| u.role == "manager") {
+        return true;
+    }
+    let has_manager: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM users WHERE role = 'manager' AND active)")
+        .fetch_one(&state.pool)
+        .await
+        .unwrap_or(true);
+    !has_manager
+}
+
+async fn create_staff(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<CreateStaffForm>,
+) -> impl IntoResponse {
+    let acting = acting_user(&state, &headers).await;
+    if !can_manage_staff(&state, acting.as_ref()).await {
+        let error = Some("スタッフを登録できるのは責任者だけです。".to_string());
+        return (StatusCode::FORBIDDEN, HtmlTemplate(render_staff_page(&state, &headers, error).await)).into_response();
+    }
+    let name = form.name.trim();
+    if name.is_empty() || !is_valid_pin(&form.pin) {
+        let error = Some("名前と4〜8桁の数字のPINを入力してください。".to_string());
+        return (StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(render_staff_page(&state, &headers, error).await)).into_response();
+    }
+    let role = match form.role.as_str() {
+        "manager" => "manager",
+        "observer" => "observer",
+        _ => "staff",
+    };
+
+    // PINのハッシュにIDを使うので、先にIDを決めてから登録する
+    let id = Uuid::new_v4();
+    sqlx::query("INSERT INTO users (id, name, pin_hash, role) VALUES ($1, $2, $3, $4)")
+        .bind(id)
+        .bind(name)
+        .bind(hash_pin(&state.cookie_key, id, &form.pin))
+        .bind(role)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to create staff");
+
+    Redirect::to(&url("/admin/staff")).into_response()
+}
+
+async fn deactivate_staff(Path(id): Path<Uuid>, State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let acting = acting_user(&state, &headers).await;
+    if !can_manage_staff(&state, acting.as_ref()).await {
+        return flash_redirect(&state, &headers, "/admin/staff", Flash::error("スタッフを無効にできるのは責任者だけです。"));
+    }
+    sqlx::query("UPDATE users SET active = FALSE WHERE id = $1")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to deactivate staff");
+
+    Redirect::to(&url("/admin/staff")).into_response()
+}
+
+#[derive(Deserialize)]
+struct SwitchStaffForm {
+    user_id: Uuid,
+    pin: String,
+}
+
+// 担当者ごとに、15分間でPINを間違えられる回数 (端末や送信元によらない)
+const PIN_MISS_LIMIT: usize = 5;
+
+async fn switch_staff(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<SwitchStaffForm>,
+) -> impl IntoResponse {
+    // 4〜8桁のPINを総当たりで試されないよう、間違えた回数が上限に達した担当者にはしばらく切り替えさせない
+    let miss_key = format!("pin_miss:{}", form.user_id);
+    if rate_limit_reached(&state.pool, &miss_key, PIN_MISS_LIMIT, Duration::from_secs(900)).await {
+        let error = Some("PINを続けて間違えたため、この担当者にはしばらく切り替えられません。時間をおいてやり直してください。".to_string());
+        return (StatusCode::TOO_MANY_REQUESTS, HtmlTemplate(render_staff_page(&state, &headers, error).await)).into_response();
+    }
+
+    let user: Option<(String, String)> = sqlx::query_as("SELECT pin_hash, role FROM users WHERE id = $1 AND active")
+        .bind(form.user_id)
+        .fetch_optional(&state.pool)
+        .await
+        .unwrap_or(None);
+
+    let expected = hash_pin(&state.cookie_key, form.user_id, &form.pin);
+    let Some((_, role)) = user.filter(|(h, _)| constant_time_eq(h.as_bytes(), expected.as_bytes())) else {
+        record_rate_limit_hit(&state.pool, &miss_key).await;
+        // 401 を返すとブラウザがBasic認証をやり直してしまうので 403 にする
+        let error = Some("PINが正しくありません。".to_string());
+        return (StatusCode::FORBIDDEN, HtmlTemplate(render_staff_page(&state, &headers, error).await)).into_response();
+    };
+
+    // 見るだけの担当者に切り替えたら、セッションごと見るだけにする (外せるのは別の担当者のPINだけ)
+    if let Some(session_id) = admin_session_id(&state, &headers) {
+        sqlx::query("UPDATE admin_sessions SET read_only = $2 WHERE id = $1")
+            .bind(session_id)
+            .bind(role == "observer")
+            .execute(&state.pool)
+            .await
+            .expect("Failed to update admin session");
+    }
+
+    // 12時間で自動的に未選択に戻る
+    let cookie = signed_cookie(&state, STAFF_COOKIE, &form.user_id.to_string(), 43200, "Strict");
+    ([(SET_COOKIE, cookie)], Redirect::to(&url("/admin/call"))).into_response()
+}
+
+async fn signout_staff(State(state): State<AppState>) -> impl IntoResponse {
+    let cookie = signed_cookie(&state, STAFF_COOKIE, "", 0, "Strict");
+    ([(SET_COOKIE, cookie)], Redirect::to(&url("/admin/staff")))
+}
+
+// --- 見るだけの共有リンク ---
+#[derive(Deserialize)]
+struct CreateObserverLinkForm {
+    label: String,
+    hours: String,
+}
+
+async fn create_observer_link(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<CreateObserverLinkForm>,
+) -> impl IntoResponse {
+    let Some(acting) = acting_user(&state, &headers).await.filter(|u| u.role == "manager") else {
+        return flash_redirect(&state, &headers, "/admin/staff", Flash::error("共有リンクを発行できるのは責任者だけです。"));
+    };
+    let label = form.label.trim();
+    let hours = form.hours.trim().parse::<i64>().unwrap_or(0);
+    if label.is_empty() || !(1..=OBSERVER_LINK_MAX_HOURS).contains(&hours) {
+        let message = format!("渡す相手の名前と、1〜{}時間の有効期間を入力してください。", OBSERVER_LINK_MAX_HOURS);
+        return flash_redirect(&state, &headers, "/admin/staff", Flash::error(&message));
+    }
+    sqlx::query(
+        "INSERT INTO observer_links (id, label, created_by, expires_at) VALUES ($1, $2, $3, NOW() + make_interval(hours => $4))"
+    )
+    .bind(Uuid::new_v4())
+    .bind(label)
+    .bind(acting.id)
+    .bind(hours as i32)
+    .execute(&state.pool)
+    .await
+    .expect("Failed to create observer link");
+
+    flash_redirect(&state, &headers, "/admin/staff", Flash::notice(&format!("{}さん用の共有リンクを発行しました。", label)))
+}
+
+async fn revoke_observer_link(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if acting_user(&state, &headers).await.filter(|u| u.role == "manager").is_none() {
+        return flash_redirect(&state, &headers, "/admin/staff", Flash::error("共有リンクを取り消せるのは責任者だけです。"));
+    }
+    sqlx::query("UPDATE observer_links SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to revoke observer link");
+
+    flash_redirect(&state, &headers, "/admin/staff", Flash::notice("共有リンクを取り消しました。開いている画面もすぐに見られなくなります。"))
+}
+
+// --- スタッフの招待リンク ---
+// 責任者がPINを決めてチャットで伝える代わりに、新しいスタッフが自分でPINを決めて登録する
+// 招待リンクの有効期間の上限 (時間)
+const INVITE_MAX_HOURS: i64 = 72;
+
+#[derive(Clone, FromRow)]
+struct StaffInvite {
+    id: Uuid,
+    name: String,
+    role: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl StaffInvite {
+    fn role_label(&self) -> &'static str {
+        match self.role.as_str() {
+            "manager" => "責任者",
+            "observer" => "見るだけ",
+            _ => "スタッフ",
+        }
+    }
+}
+
+fn invite_sig(state: &AppState, id: Uuid) -> String {
+    hmac_hex(&state.cookie_key, &format!("invite:{}", id))[..32].to_string()
+}
+
+// 署名が合い、まだ使える招待
+async fn find_invite(state: &AppState, id: Uuid, sig: &str) -> Option<StaffInvite> {
+    if !constant_time_eq(sig.as_bytes(), invite_sig(state, id).as_bytes()) {
+        return None;
+    }
+    sqlx::query_as::<_, StaffInvite>(
+        "SELECT id, name, role, expires_at FROM staff_invites
+         WHERE id = $1 AND used_at IS NULL AND revoked_at IS NULL AND expires_at > NOW()"
+    )
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await
+    .unwrap_or(None)
+}
+
+#[derive(Deserialize)]
+struct CreateInviteForm {
+    name: String,
+    role: String,
+    hours: String,
+}
+
+async fn create_invite(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<CreateInviteForm>,
+) -> impl IntoResponse {
+    let Some(acting) = acting_user(&state, &headers).await.filter(|u| u.role == "manager") else {
+        return flash_redirect(&state, &headers, "/admin/staff", Flash::error("招待リンクを発行できるのは責任者だけです。"));
+    };
+    let name = form.name.trim();
+    let hours = form.hours.trim().parse::<i64>().unwrap_or(0);
+    if name.is_empty() || !(1..=INVITE_MAX_HOURS).contains(&hours) {
+        let message = format!("スタッフの名前と、1〜{}時間の有効期間を入力してください。", INVITE_MAX_HOURS);
+        return flash_redirect(&state, &headers, "/admin/staff", Flash::error(&message));
+    }
+    let role = match form.role.as_str() {
+        "manager" => "manager",
+        "observer" => "observer",
+        _ => "staff",
+    };
+    sqlx::query(
+        "INSERT INTO staff_invites (id, name, role, created_by, expires_at) VALUES ($1, $2, $3, $4, NOW() + make_interval(hours => $5))"
+    )
+    .bind(Uuid::new_v4())
+    .bind(name)
+    .bind(role)
+    .bind(acting.id)
+    .bind(hours as i32)
+    .execute(&state.pool)
+    .await
+    .expect("Failed to create staff invite");
+
+    let message = format!("{}さんへの招待リンクを発行しました。下の一覧からリンクをコピーして本人に渡してください。", name);
+    flash_redirect(&state, &headers, "/admin/staff", Flash::notice(&message))
+}
+
+async fn revoke_invite(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if acting_user(&state, &headers).await.filter(|u| u.role == "manager").is_none() {
+        return flash_redirect(&state, &headers, "/admin/staff", Flash::error("招待リンクを取り消せるのは責任者だけです。"));
+    }
+    sqlx::query("UPDATE staff_invites SET revoked_at = NOW() WHERE id = $1 AND used_at IS NULL AND revoked_at IS NULL")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .expect("Failed to revoke staff invite");
+
+    flash_redirect(&state, &headers, "/admin/staff", Flash::notice("招待リンクを取り消しました。"))
+}
+
+#[derive(Deserialize)]
+struct InviteQuery {
+    sig: Option<String>,
+}
+
+async fn invite_page(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    Query(query): Query<InviteQuery>,
+) -> impl IntoResponse {
+    let sig = query.sig.unwrap_or_default();
+    let invite = find_invite(&state, id, &sig).await;
+    let status = if invite.is_some() { StatusCode::OK } else { StatusCode::FORBIDDEN };
+    (status, HtmlTemplate(StaffInviteTemplate { invite, sig, error: None, done: false }))
+}
+
+#[derive(Deserialize)]
+struct AcceptInviteForm {
+    sig: String,
+    pin: String,
+    pin_confirm: String,
+}
+
+// 招待を使ってスタッフを登録する (同じリンクで2人登録できないよう、使用済みにしてから登録する)
+async fn accept_invite(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<AcceptInviteForm>,
+) -> impl IntoResponse {
+    let page = |status: StatusCode, invite: Option<StaffInvite>, error: Option<&str>, done: bool| {
+        let template = StaffInviteTemplate { invite, sig: form.sig.clone(), error: error.map(str::to_string), done };
+        (status, HtmlTemplate(template)).into_response()
+    };
+
+    if !check_rate_limit(&state.pool, &format!("invite:{}", client_ip(&headers)), 10, Duration::from_secs(600)).await {
+        return page(StatusCode::TOO_MANY_REQUESTS, None, Some("お試しの回数が多すぎます。しばらく時間をおいてからやり直してください。"), false);
+    }
+    let Some(invite) = find_invite(&state, id, &form.sig).await else {
+        return page(StatusCode::FORBIDDEN, None, None, false);
+    };
+    if !is_valid_pin(&form.pin) {
+        return page(StatusCode::UNPROCESSABLE_ENTITY, Some(invite), Some("PINは4〜8桁の数字で決めてください。"), false);
+    }
+    if form.pin != form.pin_confirm {
+        return page(StatusCode::UNPROCESSABLE_ENTITY, Some(invite), Some("確認のために入力したPINが一致しません。"), false);
+    }
+
+    let mut tx = state.pool.begin().await.expect("Failed to begin transaction");
+    let user_id = Uuid::new_v4();
+    let claimed = sqlx::query(
+        "UPDATE staff_invites SET used_at = NOW() 
+         WHERE id = $1 AND used_at IS NULL AND revoked_at IS NULL AND expires_at > NOW()"
+    )
+    .bind(id)
+    .execute(&mut *tx)
+    .await
+    .expect("Failed to claim staff invite")
+    .rows_affected();
+    if claimed == 0 {
+        return page(StatusCode::FORBIDDEN, None, None, false);
+    }
+    // PINのハッシュにIDを使うので、先にIDを決めてから登録する
+    sqlx::query("INSERT INTO users (id, name, pin_hash, role) VALUES ($1, $2, $3, $4)")
+        .bind(user_id)
+        .bind(&invite.name)
+        .bind(hash_pin(&state.cookie_key, user_id, &form.pin))
+        .bind(&invite.role)
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to create staff");
+    sqlx::query("UPDATE staff_invites SET user_id = $2 WHERE id = $1")
+        .bind(id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to update staff invite");
+    tx.commit().await.expect("Failed to commit transaction");
+
+    page(StatusCode::OK, Some(invite), None, true)
+}
+
+#[derive(Deserialize)]
+struct ObserverLinkQuery {
+    sig: Option<String>,
+}
+
+// 共有リンクを開いた端末に、期限までのCookieを置いて呼び出し管理画面へ送る
+async fn open_observer_link(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    Query(query): Query<ObserverLinkQuery>,
+) -> impl IntoResponse {
+    let signed = query
+        .sig
+        .is_some_and(|sig| constant_time_eq(sig.as_bytes(), observer_sig(&state, id).as_bytes()));
+    let link = if signed {
+        sqlx::query_as::<_, ObserverLink>(
+            "SELECT id, label, expires_at FROM observer_links WHERE id = $1 AND revoked_at IS NULL AND expires_at > NOW()"
+        )
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await
+        .unwrap_or(None)
+    } else {
+        None
+    };
+    let Some(link) = link else {
+        return (StatusCode::FORBIDDEN, "このリンクは期限が切れているか、取り消されています。").into_response();
+    };
+
+    let max_age = (link.expires_at - Utc::now()).num_seconds().max(0);
+    // メッセージアプリなど他のサイトから開くので Lax にする
+    let cookie = signed_cookie(&state, OBSERVER_COOKIE, &link.id.to_string(), max_age, "Lax");
+    ([(SET_COOKIE, cookie)], Redirect::to(&url("/admin/call"))).into_response()
+}
+
+// --- スタッフ向け画面の配色 ---
+// (キー, 表示名)。キーは <html data-theme> と static/staff-theme.css で使う
+const STAFF_THEMES: [(&str, &str); 3] = [
+    ("light", "標準 (明るい)"),
+    ("dark", "ダーク"),
+    ("contrast", "ハイコントラスト"),
+];
+// 端末ごとの配色の設定に使うIDのCookie
+const STATION_COOKIE: &str = "qt_station";
+
+fn station_id(state: &AppState, headers: &HeaderMap) -> Option<Uuid> {
+    get_cookie(headers, STATION_COOKIE)
+        .and_then(|v| verify_cookie_value(&state.cookie_key, v))
+        .and_then(|v| Uuid::parse_str(&v).ok())
+}
+
+// この端末の設定 → 操作中のスタッフの設定 → 標準 の順に決める
+// (呼び出し画面のタブレットは置き場所で見やすさが決まるので、端末の設定を優先する)
+async fn staff_theme(state: &AppState, headers: &HeaderMap, acting: Option<&User>) -> String {
+    let station: Option<String> = match station_id(state, headers) {
+        Some(id) => sqlx::query_scalar("SELECT theme FROM station_preferences WHERE station_id = $1")
+            .bind(id)
+            .fetch_optional(&state.pool)
+            .await
+            .unwrap_or(None),
+        None => None,
+    };
+    let theme = match (station, acting) {
+        (Some(theme), _) => Some(theme),
+        (None, Some(user)) => sqlx::query_scalar("SELECT theme FROM users WHERE id = $1")
+            .bind(user.id)
+            .fetch_one(&state.pool)
+            .await
+            .unwrap_or(None),
+        (None, None) => None,
+    };
+    theme.filter(|t| STAFF_THEMES.iter().any(|(key, _)| key == t)).unwrap_or_else(|| "light".to_string())
+}
+
+#[derive(Deserialize)]
+struct ThemeForm {
+    theme: String,        // STAFF_THEMES のキー (空なら設定を外す)
+    scope: String,        // "device" (この端末) / "user" (操作中のスタッフ)
+    back: Option<String>, // 設定後に戻る画面 (/admin 以下のみ)
+}
+
+async fn set_theme(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<ThemeForm>,
+) -> impl IntoResponse {
+    let back = form
+        .back
+        .as_deref()
+        .filter(|b| b.starts_with("/admin") && !b.contains("//"))
+        .unwrap_or("/admin/staff")
+        .to_string();
+    let theme = Some(form.theme.as_str()).filter(|t| STAFF_THEMES.iter().any(|(key, _)| key == t));
+
+    if form.scope == "user" {
+        let Some(acting) = acting_user(&state, &headers).await else {
+            return flash_redirect(&state, &headers, &back, Flash::error("担当者を選んでから設定してください。"));
+        };
+        sqlx::query("UPDATE users SET theme = $2 WHERE id = $1")
+            .bind(acting.id)
+            .bind(theme)
+            .execute(&state.pool)
+            .await
+            .expect("Failed to save user theme");
+        return flash_redirect(&state, &headers, &back, Flash::notice(&format!("{}さんの配色を保存しました。", acting.name)));
+    }
+
+    // 端末のIDは初めて設定する時に発行し、1年間覚えておく
+    let id = station_id(&state, &headers).unwrap_or_else(Uuid::new_v4);
+    match theme {
+        Some(theme) => sqlx::query(
+            "INSERT INTO station_preferences (station_id, theme) VALUES ($1, $2) 
+             ON CONFLICT (station_id) DO UPDATE SET theme = EXCLUDED.theme, updated_at = NOW()"
+        )
+        .bind(id)
+        .bind(theme),
+        None => sqlx::query("DELETE FROM station_preferences WHERE station_id = $1").bind(id),
+    }
+    .execute(&state.pool)
+    .await
+    .expect("Failed to save station theme");
+
+    let mut res = flash_redirect(&state, &headers, &back, Flash::notice("この端末の配色を保存しました。"));
+    if let Ok(cookie) = axum::http::HeaderValue::from_str(&signed_cookie(&state, STATION_COOKIE, &id.to_string(), 31_536_000, "Lax")) {
+        res.headers_mut().append(SET_COOKIE, cookie);
+    }
+    res
+}
+
+#[derive(Deserialize)]
+struct GuestPageQuery {
+    checkin: Option<String>, // "failed" ならセルフチェックインの失敗を表示
+    resize: Option<String>,  // 人数変更の結果 ("done" / "invalid" / "closed" / "limited")
+    handoff: Option<String>, // 受け渡しの失敗 ("closed" / "limited")
+    sig: Option<String>,     // リンクの署名
+}
+
+// 1端末1枚ガード: この端末の Cookie が指す、待機中・呼出中の整理券 (except は今開いている整理券)
+async fn device_held_ticket(state: &AppState, headers: &HeaderMap, except: Option<Uuid>) -> Option<Ticket> {
+    let previous_id = get_cookie(headers, DEVICE_COOKIE)
+        .and_then(|v| verify_cookie_value(&state.cookie_key, v))
+        .and_then(|v| Uuid::parse_str(&v).ok())
+        .filter(|prev| Some(*prev) != except)?;
+
+    sqlx::query_as::<_, Ticket>("SELECT * FROM tickets WHERE id = $1 AND status IN ('waiting', 'called')")
+        .bind(previous_id)
+        .fetch_optional(&state.pool)
+        .await
+        .unwrap_or(None)
+}
+
+// 1端末1枚ガード (block): 既に有効な整理券を持つ端末には、列に加える前に断る
+async fn device_guard_refusal(state: &AppState, headers: &HeaderMap) -> Option<String> {
+    if state.device_guard != DeviceGuardMode::Block {
+        return None;
+    }
+    let other = device_held_ticket(state, headers, None).await?;
+    Some(format!("この端末では既に有効な整理券 ({}) をお持ちです。整理券は1グループ1枚までです。", other.label()))
+}
+
+async fn guest_page(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    Query(query): Query<GuestPageQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let Some(GuestView { ticket, waiting_count, called_count, table_name, counter_name }) =
+        fetch_guest_view(&state, id, query.sig.as_deref()).await
+    else {
+        return guest_link_invalid();
+    };
+
+    // 1端末1枚ガード: この端末が既に別の有効な整理券を持っていないか確認
+    // (受付で渡した紙の整理券は発券時に端末が分からないので、画面を開いた時に確かめる)
+    let mut other_ticket = None;
+    if state.device_guard != DeviceGuardMode::Off {
+        other_ticket = device_held_ticket(&state, &headers, Some(ticket.id)).await;
+
+        if let (DeviceGuardMode::Block, Some(other)) = (state.device_guard, &other_ticket) {
+            return (
+                StatusCode::CONFLICT,
+                HtmlTemplate(GuestBlockedTemplate { other_ticket: other.clone(), other_link: guest_path(&state, other) }),
+            )
+                .into_response();
+        }
+    }
+
+    let skip_cookie = other_ticket.is_some() || state.device_guard == DeviceGuardMode::Off;
+    let ticket_id = ticket.id;
+    let settings = Settings::load(&state.pool).await;
+    let sig = guest_sig(&state, &ticket);
+    let other_link = other_ticket.as_ref().map(|other| guest_path(&state, other));
+    let snapshot = state.snapshot.get(&state.pool).await;
+    let can_rename: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM reservations WHERE ticket_id = $1)")
+        .bind(ticket_id)
+        .fetch_one(&state.pool)
+        .await
+        .unwrap_or(false);
+    let stage = settings.stage_of(&ticket);
+    let estimate = CallEstimate::new(&settings, &snapshot, &ticket, waiting_count);
+    let called_ahead = settings.called_ahead(&ticket, called_count);
+    let now_serving = snapshot.now_serving(Some(ticket.queue_id));
+    let vars = guest_template_vars(
+        &settings, &ticket, stage.as_ref(), waiting_count, called_ahead,
+        table_name.as_deref(), counter_name.as_deref(), estimate.as_ref(), now_serving,
+    );
+    let (custom_body, called_message) = guest_overrides(&*state.template_overrides.get(&state.pool).await, &ticket, &vars);
+    let page = HtmlTemplate(GuestTemplate {
+        stage,
+        estimate,
+        called_ahead,
+        now_serving,
+        custom_body,
+        called_message,
+        ticket,
+        waiting_count,
+        table_name,
+        counter_name,
+        sig,
+        other_ticket,
+        other_link,
+        self_checkin: !settings.checkin_code.is_empty(),
+        checkin_failed: query.checkin.as_deref() == Some("failed"),
+        resize_message: query.resize.as_deref().map(|result| match result {
+            "done" => (false, "人数を変更しました。スタッフにも伝わっています。".to_string()),
+            "closed" => (true, "お呼び出し後は人数を変更できません。スタッフにお声がけください。".to_string()),
+            "limited" => (true, "変更の回数が多すぎます。スタッフにお声がけください。".to_string()),
+            "disabled" => (true, "この画面からは人数を変更できません。スタッフにお声がけください。".to_string()),
+            _ => (true, format!("人数は{}〜{}名で入力してください。", settings.min_group_size, settings.max_group_size)),
+        }),
+        handoff_error: query.handoff.as_deref().map(|result| match result {
+            "limited" => "受け渡しの回数が多すぎます。スタッフにお声がけください。".to_string(),
+            "disabled" => "この画面からは受け渡しできません。スタッフにお声がけください。".to_string(),
+            _ => "ご案内が終わった整理券は受け渡しできません。".to_string(),
+        }),
+        can_rename,
+        can_resize: settings.guest_resize_enabled,
+        can_handoff: settings.guest_handoff_enabled,
+        maintenance: settings.maintenance_notice(),
+        min_group_size: settings.min_group_size,
+        max_group_size: settings.max_group_size,
+        poll_seconds: settings.guest_poll_seconds,
+    });
+
+    // 既に有効な整理券を持っている端末では、Cookieを上書きしない（最初の1枚を追跡し続ける）
+    if skip_cookie {
+        return page.into_response();
+    }
+
+    let cookie = signed_cookie(&state, DEVICE_COOKIE, &ticket_id.to_string(), 86400, "Lax");
+    ([(SET_COOKIE, cookie)], page).into_response()
+}
+
+async fn guest_content(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    Query(query): Query<GuestLinkQuery>,
+) -> impl IntoResponse {
+    let Some(GuestView { ticket, waiting_count, called_count, table_name, counter_name }) =
+        fetch_guest_view(&state, id, query.sig.as_deref()).await
+    else {
+        return guest_link_invalid();
+    };
+
+    let settings = Settings::load(&state.pool).await;
+    let stage = settings.stage_of(&ticket);
+    let snapshot = state.snapshot.get(&state.pool).await;
+    let estimate = CallEstimate::new(&settings, &snapshot, &ticket, waiting_count);
+    let now_serving = snapshot.now_serving(Some(ticket.queue_id));
+    let called_ahead = settings.called_ahead(&ticket, called_count);
+    let vars = guest_template_vars(
+        &settings, &ticket, stage.as_ref(), waiting_count, called_ahead,
+        table_name.as_deref(), counter_name.as_deref(), estimate.as_ref(), now_serving,
+    );
+    let (custom_body, called_message) = guest_overrides(&*state.template_overrides.get(&state.pool).await, &ticket, &vars);
+    let poll = poll_header(settings.guest_poll_seconds);
+    let page = HtmlTemplate(GuestContentTemplate {
+        ticket,
+        stage,
+        waiting_count,
+        called_ahead,
+        table_name,
+        counter_name,
+        estimate,
+        now_serving,
+        custom_body,
+        called_message,
+    });
+    (poll, page).into_response()
+}
+
+// 来場者の状態をJSONで返す (お店のアプリなどから使う。来場者画面と同じ署名が必要)
+// raw_position: 今日発券した順番で数えた位置。前にいた組が来店しなかった・合流した場合も数えたまま
+// effective_position: 実際に前で待っている組だけで数えた位置。抜けた組の分だけ前に進む
+async fn guest_status_json(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+    Query(query): Query<GuestLinkQuery>,
+) -> impl IntoResponse {
+    let Some(GuestView { ticket, waiting_count, called_count, .. }) =
+        fetch_guest_view(&state, id, query.sig.as_deref()).await
+    else {
+        return AppError::TicketNotFound.into_response();
+    };
+
+    let settings = Settings::load(&state.pool).await;
+    let waiting = ticket.status == "waiting";
+
+    // 同じ列で自分より前に発券され、まだ案内していない組 (案内せずに抜けた組も含む) を数える
+    let raw_ahead: Option<i64> = if waiting {
+        sqlx::query_scalar(
+            "SELECT COUNT(*) FROM tickets o 
+             JOIN tickets t ON t.id = $1 
+             WHERE o.queue_id = t.queue_id AND o.is_test = t.is_test AND o.created_at >= $2 
+               AND (o.priority > t.priority 
+                    OR (o.priority = t.priority AND (o.created_at, o.id) < (t.created_at, t.id))) 
+               AND o.deleted_at IS NULL 
+               AND (o.status != 'completed' OR o.called_at IS NULL 
+                    OR EXISTS (SELECT 1 FROM ticket_events e WHERE e.ticket_id = o.id AND e.event_type = 'no_show'))"
+        )
+        .bind(ticket.id)
+        .bind(settings.today_start(&Utc::now()))
+        .fetch_one(&state.pool)
+        .await
+        .ok()
+    } else {
+        None
+    };
+
+    let snapshot = state.snapshot.get(&state.pool).await;
+    let estimate = CallEstimate::new(&settings, &snapshot, &ticket, waiting_count);
+    Json(serde_json::json!({
+        "number": ticket.number,
+        "status": ticket.status,
+        "stage": ticket.stage,
+        "raw_position": raw_ahead.map(|ahead| ahead + 1),
+        "effective_position": waiting.then_some(waiting_count + 1),
+        "groups_ahead": waiting.then_some(waiting_count + settings.called_ahead(&ticket, called_count)),
+        "estimate": estimate,
+        "now_serving": snapshot.now_serving(Some(ticket.queue_id)),
+    }))
+    .into_response()
+}
+
+// QRコードの控えをなくした・読み取れないお客様が、整理番号から自分の画面を開く
+async fn lookup_page(State(state): State<AppState>) -> impl IntoResponse {
+    HtmlTemplate(LookupTemplate { queues: fetch_queues(&state.pool).await, error: None })
+}
+
+// 番号は空欄や数字以外でも送信されるので、文字列で受けてから解釈する
+#[derive(Deserialize)]
+struct LookupForm {
+    number: String,
+    queue_id: Option<i32>,
+}
+
+// 番号を順に試して他のお客様の画面を開かれないよう、送信元ごとに回数を制限し、
+// 今日発券した待機中・呼出中の整理券だけを対象にする
+// 列ごとに、10分間で見つからなかった回数の上限 (送信元によらない)
+const LOOKUP_MISS_LIMIT: usize = 20;
+
+async fn lookup_ticket(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<LookupForm>,
+) -> impl IntoResponse {
+    let queues = fetch_queues(&state.pool).await;
+    let error_page = |status: StatusCode, message: &str, queues: Vec<Queue>| {
+        (status, HtmlTemplate(LookupTemplate { queues, error: Some(message.to_string()) })).into_response()
+    };
+
+    if !check_rate_limit(&state.pool, &format!("lookup:{}", client_ip(&headers)), 5, Duration::from_secs(600)).await {
+        return error_page(StatusCode::TOO_MANY_REQUESTS, "お試しの回数が多すぎます。しばらく時間をおくか、受付スタッフにお声がけください。", queues);
+    }
+    // 頭文字つき (例: T12) で入力されても、列は別に選ぶので番号だけを見る
+    let Ok(number) = form.number.trim().trim_start_matches(|c: char| c.is_ascii_alphabetic()).parse::<i32>() else {
+        return error_page(StatusCode::UNPROCESSABLE_ENTITY, "整理番号を数字で入力してください。", queues);
+    };
+    let queue_id = form.queue_id.unwrap_or(DEFAULT_QUEUE_ID);
+    if !queues.iter().any(|q| q.id == queue_id) {
+        return error_page(StatusCode::UNPROCESSABLE_ENTITY, "選んだ列が見つかりません。画面を読み込み直してください。", queues);
+    }
+    // 回線を替えながら番号を順に試されても止まるよう、列ごとの見つからなかった回数でも制限する
+    let miss_key = format!("lookup_miss:{}", queue_id);
+    if rate_limit_reached(&state.pool, &miss_key, LOOKUP_MISS_LIMIT, Duration::from_secs(600)).await {
+        return error_page(StatusCode::TOO_MANY_REQUESTS, "ただいま番号での確認を止めています。受付スタッフにお声がけください。", queues);
+    }
+
+    let settings = Settings::load(&state.pool).await;
+    let ticket = sqlx::query_as::<_, Ticket>(
+        "SELECT * FROM tickets 
+         WHERE number = $1 AND queue_id = $2 AND created_at >= $3 AND status IN ('waiting', 'called') 
+         ORDER BY created_at DESC LIMIT 1"
+    )
+    .bind(number)
+    .bind(queue_id)
+    .bind(settings.today_start(&Utc::now()))
+    .fetch_optional(&state.pool)
+    .await
+    .unwrap_or(None)
+    .filter(|ticket| Utc::now() < guest_link_expires_at(&state, ticket));
+
+    match ticket {
+        Some(ticket) => Redirect::to(&guest_path(&state, &ticket)).into_response(),
+        None => {
+            record_rate_limit_hit(&state.pool, &miss_key).await;
+            error_page(StatusCode::NOT_FOUND, "この番号の整理券は見つかりませんでした。番号と列をご確認ください。", queues)
+        }
+    }
+}
+
+// CSVダウンロード用ハンドラ
+async fn download_csv(State(state): State<AppState>) -> impl IntoResponse {
+    // 全データを取得（番号順）。練習用の整理券は含めない
+    let tickets = sqlx::query_as::<_, Ticket>(
+        "SELECT * FROM tickets WHERE NOT is_test AND deleted_at IS NULL ORDER BY number ASC"
+    )
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![]);
+    let settings = Settings::load(&state.pool).await;
+
+    // レスポンス生成: ヘッダーをつけてファイルとしてダウンロードさせる
+    (
+        [
+            (CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (axum::http::header::CONTENT_DISPOSITION, "attachment; filename=\"tickets_log.csv\""),
+        ],
+        tickets_csv(&tickets, &settings)
+    )
+}
+
+// --- 利用量の記録 (フランチャイズ店への請求) ---
+// 1つのデプロイが1店舗なので、店舗ごとの利用量はこのDBに月ごとに数える
+// 本部は各店舗の /admin/usage.csv (STORE_CODE 入り) を集めて請求する
+const USAGE_MONTHS: i64 = 12; // 画面に出す月の数
+const USAGE_METRICS: [(&str, &str); 3] = [
+    ("tickets", "発券した整理券"),
+    ("notifications", "送ったSMS"),
+    ("storage_bytes", "DBの容量 (その月の最大)"),
+];
+
+// 今の営業日の月に amount を足し込む
+async fn record_usage<'e>(executor: impl sqlx::PgExecutor<'e>, settings: &Settings, metric: &str, amount: i64) {
+    let month = settings.business_date(&Utc::now()).with_day(1).expect("the first day exists in every month");
+    sqlx::query(
+        "INSERT INTO usage_meter (month, metric, value) VALUES ($1, $2, $3) 
+         ON CONFLICT (month, metric) DO UPDATE SET value = usage_meter.value + EXCLUDED.value, updated_at = NOW()"
+    )
+    .bind(month)
+    .bind(metric)
+    .bind(amount)
+    .execute(executor)
+    .await
+    .expect("Failed to record usage");
+}
+
+// 容量は足し込まず、その月に測った中で最も大きい値を残す
+async fn record_storage_usage(pool: &PgPool, settings: &Settings) {
+    let month = settings.business_date(&Utc::now()).with_day(1).expect("the first day exists in every month");
+    sqlx::query(
+        "INSERT INTO usage_meter (month, metric, value) VALUES ($1, 'storage_bytes', pg_database_size(current_database())) 
+         ON CONFLICT (month, metric) DO UPDATE SET value = GREATEST(usage_meter.value, EXCLUDED.value), updated_at = NOW()"
+    )
+    .bind(month)
+    .execute(pool)
+    .await
+    .expect("Failed to record storage usage");
+}
+
+struct UsageMonth {
+    month: NaiveDate,
+    tickets: i64,
+    notifications: i64,
+    storage_bytes: i64,
+}
+
+impl UsageMonth {
+    fn label(&self) -> String {
+        self.month.format("%Y年%m月").to_string()
+    }
+
+    fn storage_mb(&self) -> String {
+        format!("{:.1}", self.storage_bytes as f64 / 1024.0 / 1024.0)
+    }
+}
+
+// 新しい月から順に。limit がなければすべての月
+async fn fetch_usage(pool: &PgPool, limit: Option<i64>) -> Vec<UsageMonth> {
+    let rows = sqlx::query_as::<_, (NaiveDate, String, i64)>(
+        "SELECT month, metric, value FROM usage_meter 
+         WHERE month IN (SELECT DISTINCT month FROM usage_meter ORDER BY month DESC LIMIT $1) 
+         ORDER BY month DESC"
+    )
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+    .unwrap_or(vec![]);
+
+    let mut months: Vec<UsageMonth> = Vec::new();
+    for (month, metric, value) in rows {
+        if months.last().is_none_or(|m| m.month != month) {
+            months.push(UsageMonth { month, tickets: 0, notifications: 0, storage_bytes: 0 });
+        }
+        let current = months.last_mut().expect("pushed above");
+        match metric.as_str() {
+            "tickets" => current.tickets = value,
+            "notifications" => current.notifications = value,
+            "storage_bytes" => current.storage_bytes = value,
+            _ => {}
+        }
+    }
+    months
+}
+
+async fn usage_page(State(state): State<AppState>) -> impl IntoResponse {
+    HtmlTemplate(AdminUsageTemplate {
+        months: fetch_usage(&state.pool, Some(USAGE_MONTHS)).await,
+        metrics: USAGE_METRICS,
+        store_code: state.store_code.clone(),
+    })
+}
+
+// 請求用のCSV。本部で複数の店舗を1つの表にまとめられるよう、各行に店舗のコードを入れる
+async fn download_usage_csv(State(state): State<AppState>) -> impl IntoResponse {
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    wtr.write_record(["店舗コード", "月", "発券した整理券", "送ったSMS", "DBの容量(バイト)"]).expect("Failed to write usage CSV");
+    for month in fetch_usage(&state.pool, None).await {
+        wtr.write_record([
+            state.store_code.clone(),
+            month.month.format("%Y-%m").to_string(),
+            month.tickets.to_string(),
+            month.notifications.to_string(),
+            month.storage_bytes.to_string(),
+        ])
+        .expect("Failed to write usage CSV");
+    }
+    let body = String::from_utf8(wtr.into_inner().expect("Failed to write usage CSV")).expect("CSV is UTF-8");
+
+    (
+        [
+            (CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (axum::http::header::CONTENT_DISPOSITION, "attachment; filename=\"usage.csv\""),
+        ],
+        body,
+    )
+}
+
+// --- 画面の差し替え (DBに置いたテンプレートで組み込みの画面を上書きする) ---
+// 組み込みの画面はビルド時に固まるので、店舗ごとの文言や見た目の調整は Jinja 形式のテンプレートで上書きする
+// 上書きが描画に失敗した時は、組み込みの画面に戻す (お客様に壊れた画面を出さない)
+struct TemplateSlot {
+    name: &'static str,
+    label: &'static str,
+    description: &'static str,
+    variables: &'static [(&'static str, &'static str)], // (変数名, 説明)
+}
+
+const GUEST_TEMPLATE_VARIABLES: &[(&str, &str)] = &[
+    ("number", "整理番号 (列の接頭辞つき)"),
+    ("status", "状態 (waiting / called / completed / deleted / merged)"),
+    ("stage", "店舗独自の状態の表示名 (なければ空)"),
+    ("group_size", "人数"),
+    ("ahead", "前で待っている組数"),
+    ("called_ahead", "前にいる呼出中の組数 (ahead に含む)"),
+    ("table", "案内先のテーブル"),
+    ("counter", "呼び出した窓口"),
+    ("now_serving", "ただいまご案内中の番号"),
+    ("estimate_from", "呼び出しの見込み (早い方, HH:MM)"),
+    ("estimate_to", "呼び出しの見込み (遅い方, HH:MM)"),
+    ("after_closing", "見込みが閉店時刻を過ぎる (今日のご案内が難しいかもしれない)"),
+    ("near_closing", "遅い方の見込みだけが閉店時刻を過ぎる"),
+    ("closing", "閉店時刻 (HH:MM)"),
+    ("store_name", "店舗名"),
+];
+
+static TEMPLATE_SLOTS: [TemplateSlot; 3] = [
+    TemplateSlot {
+        name: "guest_body",
+        label: "来場者画面の本文",
+        description: "マイページの整理番号と状態の表示を置き換えます。自動更新のたびに描き直します。",
+        variables: GUEST_TEMPLATE_VARIABLES,
+    },
+    TemplateSlot {
+        name: "called_message",
+        label: "呼び出し時の案内文",
+        description: "呼び出された時の「受付までお越しください」などの案内を置き換えます。本文を差し替えている時は使いません。",
+        variables: GUEST_TEMPLATE_VARIABLES,
+    },
+    TemplateSlot {
+        name: "slip",
+        label: "整理券の印刷レイアウト",
+        description: "印刷する整理券の枠の中身を置き換えます。",
+        variables: &[
+            ("number", "整理番号 (列の接頭辞つき)"),
+            ("queue_name", "列の名前"),
+            ("queue_icon", "列のアイコン"),
+            ("group_size", "人数"),
+            ("issued_at", "発券時刻"),
+            ("expires_at", "リンクの有効期限"),
+            ("qr_code", "QRコード (SVG)"),
+            ("url", "マイページのURL"),
+            ("lookup_url", "番号で開くページのURL"),
+            ("store_name", "店舗名"),
+        ],
+    },
+];
+
+const MAX_TEMPLATE_OVERRIDE_CHARS: usize = 20_000;
+
+// 差し替えたテンプレートを読み取り済みの状態で持つ
+struct TemplateOverrides {
+    env: minijinja::Environment<'static>,
+}
+
+impl TemplateOverrides {
+    async fn load(pool: &PgPool) -> Self {
+        let rows: Vec<(String, String)> = sqlx::query_as("SELECT name, source FROM template_overrides")
+            .fetch_all(pool)
+            .await
+            .unwrap_or(vec![]);
+        let mut env = template_override_env();
+        for (name, source) in rows {
+            // 保存する時に確かめているが、読めなかったものは組み込みの画面を使う
+            if let Err(err) = env.add_template_owned(name.clone(), source) {
+                tracing::warn!("Failed to parse template override {}: {}", name, err);
+            }
+        }
+        TemplateOverrides { env }
+    }
+
+    // 差し替えがない、または描画に失敗したら None (組み込みの画面を使う)
+    fn render(&self, name: &str, vars: &minijinja::Value) -> Option<String> {
+        let template = self.env.get_template(name).ok()?;
+        match template.render(vars) {
+            Ok(html) => Some(html),
+            Err(err) => {
+                tracing::warn!("Failed to render template override {}: {}", name, err);
+                None
+            }
+        }
+    }
+}
+
+// 来場者画面の自動更新のたびに DB を読んでテンプレートを読み取り直さないよう、保存されるまで使い回す
+#[derive(Clone, Default)]
+struct TemplateOverrideCache {
+    entry: Arc<Mutex<(u64, Option<Arc<TemplateOverrides>>)>>, // (保存した回数, 読み込んだもの)
+}
+
+impl TemplateOverrideCache {
+    async fn get(&self, pool: &PgPool) -> Arc<TemplateOverrides> {
+        let generation = {
+            let entry = self.entry.lock().expect("template override cache lock poisoned");
+            if let Some(overrides) = &entry.1 {
+                return overrides.clone();
+            }
+            entry.0
+        };
+        let overrides = Arc::new(TemplateOverrides::load(pool).await);
+        let mut entry = self.entry.lock().expect("template override cache lock poisoned");
+        // 読んでいる間に保存された時は、古い内容を残さない
+        if entry.0 == generation {
+            entry.1 = Some(overrides.clone());
+        }
+        overrides
+    }
+
+    fn invalidate(&self) {
+        let mut entry = self.entry.lock().expect("template override cache lock poisoned");
+        entry.0 += 1;
+        entry.1 = None;
+    }
+}
+
+// 値は HTML としてエスケープする (予約者名などお客様の入力が入っても画面を壊さない)
+fn template_override_env() -> minijinja::Environment<'static> {
+    let mut env = minijinja::Environment::new();
+    env.set_auto_escape_callback(|_| minijinja::AutoEscape::Html);
+    env
+}
+
+fn render_template_override(source: &str, vars: &minijinja::Value) -> Result<String, minijinja::Error> {
+    template_override_env().render_str(source, vars)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn guest_template_vars(
+    settings: &Settings,
+    ticket: &Ticket,
+    stage: Option<&CustomStatus>,
+    waiting_count: i64,
+    called_ahead: i64,
+    table_name: Option<&str>,
+    counter_name: Option<&str>,
+    estimate: Option<&CallEstimate>,
+    now_serving: Option<i32>,
+) -> minijinja::Value {
+    minijinja::context! {
+        number => ticket.label(),
+        status => ticket.status.as_str(),
+        stage => stage.map(|s| s.label.as_str()),
+        group_size => ticket.group_size,
+        ahead => waiting_count + called_ahead,
+        called_ahead,
+        table => table_name,
+        counter => counter_name,
+        now_serving,
+        estimate_from => estimate.filter(|e| !e.after_closing).map(|e| e.from.as_str()),
+        estimate_to => estimate.filter(|e| !e.after_closing).map(|e| e.to.as_str()),
+        after_closing => estimate.is_some_and(|e| e.after_closing),
+        near_closing => estimate.is_some_and(|e| e.near_closing),
+        closing => settings.closing_time.as_str(),
+        store_name => settings.store_name.as_str(),
+    }
+}
+
+// 来場者画面の差し替え (本文, 呼び出し時の案内文)
+fn guest_overrides(overrides: &TemplateOverrides, ticket: &Ticket, vars: &minijinja::Value) -> (Option<String>, Option<String>) {
+    let called_message = if ticket.status == "called" { overrides.render("called_message", vars) } else { None };
+    (overrides.render("guest_body", vars), called_message)
+}
+
+// 管理画面の見本の表示用
+fn sample_template_vars(name: &str, settings: &Settings) -> minijinja::Value {
+    match name {
+        "slip" => minijinja::context! {
+            number => "A12",
+            queue_name => "店内",
+            queue_icon => "🍽",
+            group_size => 2,
+            issued_at => "10/16 12:34",
+            expires_at => "10/17 12:34",
+            qr_code => minijinja::Value::from_safe_string("<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 1 1\"><rect width=\"1\" height=\"1\" fill=\"#ccc\"/></svg>".to_string()),
+            url => "https://example.com/guest/...",
+            lookup_url => "https://example.com/lookup",
+            store_name => settings.store_name.as_str(),
+        },
+        _ => minijinja::context! {
+            number => "A12",
+            status => if name == "called_message" { "called" } else { "waiting" },
+            stage => None::<&str>,
+            group_size => 2,
+            ahead => 3,
+            called_ahead => 0,
+            table => if name == "called_message" { Some("5") } else { None },
+            counter => None::<&str>,
+            now_serving => 9,
+            estimate_from => "12:40",
+            estimate_to => "12:55",
+            after_closing => false,
+            near_closing => false,
+            closing => "21:00",
+            store_name => settings.store_name.as_str(),
+        },
+    }
+}
+
+struct TemplateSlotView {
+    slot: &'static TemplateSlot,
+    source: Option<String>, // 差し替えていなければ None
+    updated: String,        // 最後に変更した日時と担当者
+    preview: Option<String>, // 見本の値で描画した結果
+}
+
+#[derive(FromRow)]
+struct TemplateOverrideRow {
+    name: String,
+    source: String,
+    user_name: Option<String>,
+    updated_at: DateTime<Utc>,
+}
+
+async fn templates_page(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+    let mut rows: HashMap<String, TemplateOverrideRow> = sqlx::query_as::<_, TemplateOverrideRow>(
+        "SELECT t.name, t.source, u.name AS user_name, t.updated_at 
+         FROM template_overrides t LEFT JOIN users u ON u.id = t.updated_by"
+    )
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![])
+    .into_iter()
+    .map(|row| (row.name.clone(), row))
+    .collect();
+
+    let slots = TEMPLATE_SLOTS
+        .iter()
+        .map(|slot| match rows.remove(slot.name) {
+            Some(row) => TemplateSlotView {
+                slot,
+                updated: format!(
+                    "{} {}",
+                    settings.format_local(&row.updated_at, "%m/%d %H:%M"),
+                    row.user_name.as_deref().unwrap_or("管理者")
+                ),
+                preview: render_template_override(&row.source, &sample_template_vars(slot.name, &settings)).ok(),
+                source: Some(row.source),
+            },
+            None => TemplateSlotView { slot, source: None, updated: String::new(), preview: None },
+        })
+        .collect();
+
+    let flash = read_flash(&state, &headers);
+    let page = AdminTemplatesTemplate { slots, flash: flash.clone() };
+    clear_flash(&state, &flash, HtmlTemplate(page))
+}
+
+#[derive(Deserialize)]
+struct TemplateOverrideForm {
+    source: String,
+    reset: Option<String>, // 組み込みの画面に戻す
+}
+
+async fn save_template_override(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<TemplateOverrideForm>,
+) -> impl IntoResponse {
+    const BACK: &str = "/admin/templates";
+    let Some(slot) = TEMPLATE_SLOTS.iter().find(|slot| slot.name == name) else {
+        return flash_redirect(&state, &headers, BACK, Flash::error("差し替えできない画面です。"));
+    };
+
+    let source = form.source.trim();
+    if form.reset.is_some() || source.is_empty() {
+        sqlx::query("DELETE FROM template_overrides WHERE name = $1")
+            .bind(slot.name)
+            .execute(&state.pool)
+            .await
+            .expect("Failed to reset template override");
+        state.template_overrides.invalidate();
+        let message = format!("「{}」を組み込みの表示に戻しました。", slot.label);
+        return flash_redirect(&state, &headers, BACK, Flash::notice(&message));
+    }
+
+    if source.chars().count() > MAX_TEMPLATE_OVERRIDE_CHARS {
+        let message = format!("テンプレートは{}文字以内にしてください。", MAX_TEMPLATE_OVERRIDE_CHARS);
+        return flash_redirect(&state, &headers, BACK, Flash::error(&message));
+    }
+    // 保存する前に見本の値で描画してみて、書き間違いがあれば断る
+    let settings = Settings::load(&state.pool).await;
+    if let Err(err) = render_template_override(source, &sample_template_vars(slot.name, &settings)) {
+        let message = format!("「{}」のテンプレートを読み取れません: {}", slot.label, err);
+        return flash_redirect(&state, &headers, BACK, Flash::error(&message));
+    }
+
+    let acting = acting_user(&state, &headers).await;
+    sqlx::query(
+        "INSERT INTO template_overrides (name, source, updated_by, updated_at) VALUES ($1, $2, $3, NOW()) 
+         ON CONFLICT (name) DO UPDATE SET source = EXCLUDED.source, updated_by = EXCLUDED.updated_by, updated_at = NOW()"
+    )
+    .bind(slot.name)
+    .bind(source)
+    .bind(acting.as_ref().map(|u| u.id))
+    .execute(&state.pool)
+    .await
+    .expect("Failed to save template override");
+    state.template_overrides.invalidate();
+
+    let message = format!("「{}」を差し替えました。", slot.label);
+    flash_redirect(&state, &headers, BACK, Flash::notice(&message))
+}
+
+// --- 個人データの開示・削除 (データ主体からの請求への対応) ---
+// 来場者の連絡先は、リモート受付・デポジットで確認した電話番号だけを保存している
+#[derive(FromRow)]
+struct GuestDataSummary {
+    tickets: i64,
+    ticket_events: i64,
+    reservations: i64,
+    phone_verifications: i64,
+    deposits: i64,
+    no_shows: i64,
+    active_tickets: i64, // 待機中・呼出中 (削除すると案内できなくなる)
+    paid_deposits: i64,  // 返金前のデポジット (削除すると返金できなくなる)
+}
+
+impl GuestDataSummary {
+    fn is_empty(&self) -> bool {
+        self.tickets + self.reservations + self.phone_verifications + self.deposits + self.no_shows == 0
+    }
+
+    fn blocks_deletion(&self) -> bool {
+        self.active_tickets > 0 || self.paid_deposits > 0
+    }
+}
+
+async fn guest_data_summary<'e>(executor: impl sqlx::PgExecutor<'e>, phone: &str) -> GuestDataSummary {
+    sqlx::query_as::<_, GuestDataSummary>(
+        "WITH t AS (SELECT id, status FROM tickets WHERE phone = $1) 
+         SELECT (SELECT COUNT(*) FROM t) AS tickets, 
+                (SELECT COUNT(*) FROM ticket_events WHERE ticket_id IN (SELECT id FROM t)) AS ticket_events, 
+                (SELECT COUNT(*) FROM reservations WHERE ticket_id IN (SELECT id FROM t)) AS reservations, 
+                (SELECT COUNT(*) FROM phone_verifications WHERE phone = $1) AS phone_verifications, 
+                (SELECT COUNT(*) FROM deposits WHERE phone = $1) AS deposits, 
+                (SELECT COUNT(*) FROM no_shows WHERE phone = $1) AS no_shows, 
+                (SELECT COUNT(*) FROM t WHERE status IN ('waiting', 'called')) AS active_tickets, 
+                (SELECT COUNT(*) FROM deposits WHERE phone = $1 AND status = 'paid') AS paid_deposits"
+    )
+    .bind(phone)
+    .fetch_one(executor)
+    .await
+    .expect("Failed to summarize guest data")
+}
+
+#[derive(Deserialize)]
+struct PrivacyQuery {
+    phone: Option<String>,
+}
+
+async fn privacy_page(State(state): State<AppState>, Query(query): Query<PrivacyQuery>) -> impl IntoResponse {
+    let input = query.phone.unwrap_or_default();
+    if input.trim().is_empty() {
+        return HtmlTemplate(AdminPrivacyTemplate { phone: String::new(), summary: None, message: None, error: None });
+    }
+
+    let Some(phone) = normalize_phone(&input) else {
+        return HtmlTemplate(AdminPrivacyTemplate {
+            phone: input,
+            summary: None,
+            message: None,
+            error: Some("電話番号の形式が正しくありません。".to_string()),
+        });
+    };
+
+    let summary = guest_data_summary(&state.pool, &phone).await;
+    HtmlTemplate(AdminPrivacyTemplate { phone, summary: Some(summary), message: None, error: None })
+}
+
+// 電話番号に結びつく全てのデータを JSON で書き出す
+async fn export_guest_data(State(state): State<AppState>, Query(query): Query<PrivacyQuery>) -> impl IntoResponse {
+    let Some(phone) = query.phone.as_deref().and_then(normalize_phone) else {
+        return (StatusCode::BAD_REQUEST, "電話番号の形式が正しくありません。").into_response();
+    };
+
+    // 確認コードのハッシュは本人のデータではないので除く
+    let sections = [
+        ("tickets", "SELECT COALESCE(json_agg(t ORDER BY t.created_at), '[]')::text FROM tickets t WHERE t.phone = $1"),
+        ("ticket_events", "SELECT COALESCE(json_agg(e ORDER BY e.id), '[]')::text FROM ticket_events e 
+                           WHERE e.ticket_id IN (SELECT id FROM tickets WHERE phone = $1)"),
+        ("reservations", "SELECT COALESCE(json_agg(r ORDER BY r.created_at), '[]')::text FROM reservations r 
+                          WHERE r.ticket_id IN (SELECT id FROM tickets WHERE phone = $1)"),
+        ("phone_verifications", "SELECT COALESCE(json_agg(to_jsonb(v) - 'code_hash' ORDER BY v.created_at), '[]')::text 
+                                 FROM phone_verifications v WHERE v.phone = $1"),
+        ("deposits", "SELECT COALESCE(json_agg(d ORDER BY d.created_at), '[]')::text FROM deposits d WHERE d.phone = $1"),
+        ("no_shows", "SELECT COALESCE(json_agg(n ORDER BY n.created_at), '[]')::text FROM no_shows n WHERE n.phone = $1"),
+    ];
+
+    let mut export = serde_json::Map::new();
+    export.insert("phone".to_string(), serde_json::json!(phone));
+    export.insert("exported_at".to_string(), serde_json::json!(Utc::now()));
+    for (name, sql) in sections {
+        let rows: String = sqlx::query_scalar(sql)
+            .bind(&phone)
+            .fetch_one(&state.pool)
+            .await
+            .expect("Failed to export guest data");
+        export.insert(name.to_string(), serde_json::from_str(&rows).unwrap_or_default());
+    }
+
+    let disposition = format!("attachment; filename=\"guest_data_{}.json\"", Utc::now().format("%Y%m%d%H%M%S"));
+    (
+        [
+            (CONTENT_TYPE, "application/json; charset=utf-8".to_string()),
+            (axum::http::header::CONTENT_DISPOSITION, disposition),
+        ],
+        serde_json::to_string_pretty(&export).unwrap_or_default(),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct PrivacyDeleteForm {
+    phone: String,
+}
+
+// 電話番号に結びつく全てのデータを完全に削除する (控えやリセットの控えのCSVには電話番号は含まれない)
+async fn delete_guest_data(
+    State(state): State<AppState>,
+    Form(form): Form<PrivacyDeleteForm>,
+) -> impl IntoResponse {
+    let Some(phone) = normalize_phone(&form.phone) else {
+        return (StatusCode::BAD_REQUEST, "電話番号の形式が正しくありません。").into_response();
+    };
+
+    let mut tx = state.pool.begin().await.expect("Failed to begin transaction");
+
+    let summary = guest_data_summary(&mut *tx, &phone).await;
+    if summary.blocks_deletion() {
+        let error = "案内中のチケット、または返金前のデポジットがあります。案内・返金を済ませてから削除してください。";
+        return (
+            StatusCode::CONFLICT,
+            HtmlTemplate(AdminPrivacyTemplate { phone, summary: Some(summary), message: None, error: Some(error.to_string()) }),
+        )
+            .into_response();
+    }
+
+    // 予約は tickets への外部キーがないので先に消す。操作履歴はチケットと一緒に消える
+    let statements = [
+        "DELETE FROM reservations WHERE ticket_id IN (SELECT id FROM tickets WHERE phone = $1)",
+        "DELETE FROM tickets WHERE phone = $1",
+        "DELETE FROM phone_verifications WHERE phone = $1",
+        "DELETE FROM deposits WHERE phone = $1",
+        "DELETE FROM no_shows WHERE phone = $1",
+    ];
+    for sql in statements {
+        sqlx::query(sql)
+            .bind(&phone)
+            .execute(&mut *tx)
+            .await
+            .expect("Failed to delete guest data");
+    }
+
+    tx.commit().await.expect("Failed to commit transaction");
+
+    let message = format!(
+        "整理券 {} 件 (操作履歴 {} 件)、予約 {} 件、電話番号の確認 {} 件、デポジット {} 件、来店なしの記録 {} 件を削除しました。",
+        summary.tickets, summary.ticket_events, summary.reservations, summary.phone_verifications, summary.deposits, summary.no_shows
+    );
+    let remaining = guest_data_summary(&state.pool, &phone).await;
+    HtmlTemplate(AdminPrivacyTemplate { phone, summary: Some(remaining), message: Some(message), error: None }).into_response()
+}
+
+// --- 案内済みの整理券の履歴 ---
+// 「先週の火曜日にクレームのあったお客様に何があったか」をSQLなしで調べられるようにする
+const HISTORY_PAGE_SIZE: i64 = 200;
+
+#[derive(FromRow)]
+struct HistoryRow {
+    #[sqlx(flatten)]
+    ticket: Ticket,
+    reservation_name: Option<String>, // 予約から発券した場合の予約者名
+}
+
+// 表示用に時刻を店舗の現地時刻へ整形したもの
+struct HistoryItem {
+    row: HistoryRow,
+    issued: String,
+    called: String,
+    completed: String,
+    wait_minutes: Option<i64>, // 発券から呼び出しまで
+}
+
+impl HistoryItem {
+    fn new(mut row: HistoryRow, settings: &Settings) -> Self {
+        let format = |at: Option<DateTime<Utc>>| at.map(|at| settings.format_local(&at, "%m/%d %H:%M")).unwrap_or_default();
+        row.reservation_name = row.reservation_name.map(|name| settings.staff_name(&name));
+        HistoryItem {
+            issued: format(Some(row.ticket.created_at)),
+            called: format(row.ticket.called_at),
+            completed: format(row.ticket.completed_at),
+            wait_minutes: row.ticket.called_at.map(|at| (at - row.ticket.created_at).num_minutes()),
+            row,
+        }
+    }
+
+    fn status_label(&self) -> &'static str {
+        match self.row.ticket.status.as_str() {
+            "waiting" => "待機中",
+            "called" => "呼出中",
+            "merged" => "合流",
+            "deleted" => "削除 (ゴミ箱)",
+            _ => "完了",
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct TimelineEvent {
+    event_type: String,
+    detail: Option<String>,
+    user_name: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl TimelineEvent {
+    fn label(&self) -> &str {
+        match self.event_type.as_str() {
+            "created" => "発券",
+            "called" => "呼び出し",
+            "completed" => "完了",
+            "reverted" => "取り消し",
+            "checked_in" => "到着確認",
+            "transferred" => "列の移動",
+            "split" => "分割",
+            "merged" => "合流",
+            "seated" => "着席 (POS)",
+            "no_show" => "来店なし",
+            "no_show_flag" => "来店なしの履歴あり",
+            "link_rotated" => "リンクの再発行",
+            "slip_reprinted" => "整理券の再印刷",
+            "tagged" => "タグの変更",
+            "note_changed" => "メモの変更",
+            "offline_synced" => "発券機からの送信 (オフライン中の受付)",
+            "stage_changed" => "状態の変更",
+            "resized" => "人数の変更 (お客様)",
+            "deleted" => "削除 (ゴミ箱へ)",
+            "restored" => "ゴミ箱から復元",
+            "handed_off" => "受け渡し (お客様)",
+            "reservation_cancelled" => "予約の取り消し (予約サービス)",
+            "imported" => "別の環境からの取り込み",
+            other => other,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct HistoryFilter {
+    from: Option<String>, // 発券日 (店舗の現地日付 YYYY-MM-DD)
+    to: Option<String>,
+    q: Option<String>,    // 整理番号 (完全一致) または予約者名 (部分一致)
+}
+
+impl HistoryFilter {
+    fn q(&self) -> &str {
+        self.q.as_deref().map(str::trim).unwrap_or("")
+    }
+
+    // 指定した営業日の始まり (営業日の切り替わる時刻) を UTC で
+    fn day_start(value: Option<&str>, settings: &Settings, days_after: i64) -> Option<DateTime<Utc>> {
+        let date = NaiveDate::parse_from_str(value?.trim(), "%Y-%m-%d").ok()? + chrono::Duration::days(days_after);
+        settings.business_day_start(date)
+    }
+
+    fn start_input(&self) -> &str {
+        self.from.as_deref().unwrap_or("")
+    }
+
+    fn end_input(&self) -> &str {
+        self.to.as_deref().unwrap_or("")
+    }
+}
+
+async fn history_page(State(state): State<AppState>, Query(filter): Query<HistoryFilter>) -> impl IntoResponse {
+    let settings = Settings::load(&state.pool).await;
+
+    let rows = sqlx::query_as::<_, HistoryRow>(
+        "SELECT t.*, r.name AS reservation_name 
+         FROM tickets t LEFT JOIN reservations r ON r.ticket_id = t.id 
+         WHERE t.status IN ('completed', 'merged') AND NOT t.is_test 
+           AND ($1::timestamptz IS NULL OR t.created_at >= $1) 
+           AND ($2::timestamptz IS NULL OR t.created_at < $2) 
+           AND ($3 = '' OR t.number::text = $3 OR r.name ILIKE '%' || $3 || '%') 
+         ORDER BY t.created_at DESC LIMIT $4"
+    )
+    .bind(HistoryFilter::day_start(filter.from.as_deref(), &settings, 0))
+    .bind(HistoryFilter::day_start(filter.to.as_deref(), &settings, 1)) // 終了日はその日の終わりまで含める
+    .bind(filter.q())
+    .bind(HISTORY_PAGE_SIZE)
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![]);
+
+    let items = rows.into_iter().map(|row| HistoryItem::new(row, &settings)).collect();
+    HtmlTemplate(AdminHistoryTemplate { items, filter, limit: HISTORY_PAGE_SIZE })
+}
+
+// 1枚の整理券の操作履歴 (誰がいつ何をしたか)
+async fn history_ticket(Path(id): Path<Uuid>, State(state): State<AppState>) -> impl IntoResponse {
+    let Some(row) = sqlx::query_as::<_, HistoryRow>(
+        "SELECT t.*, r.name AS reservation_name 
+         FROM tickets t LEFT JOIN reservations r ON r.ticket_id = t.id 
+         WHERE t.id = $1"
+    )
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await
+    .unwrap_or(None)
+    else {
+        return (StatusCode::NOT_FOUND, "整理券が見つかりません。").into_response();
+    };
+
+    let settings = Settings::load(&state.pool).await;
+    let events = sqlx::query_as::<_, TimelineEvent>(
+        "SELECT e.event_type, e.detail, u.name AS user_name, e.created_at 
+         FROM ticket_events e LEFT JOIN users u ON u.id = e.user_id 
+         WHERE e.ticket_id = $1 ORDER BY e.id ASC"
+    )
+    .bind(id)
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or(vec![])
+    .into_iter()
+    .map(|event| {
+        let label = settings.format_local(&event.created_at, "%m/%d %H:%M:%S");
+        (event, label)
+    })
+    .collect();
+
+    HtmlTemplate(AdminHistoryTicketTemplate { item: HistoryItem::new(row, &settings), events }).into_response()
+}
+
+// CSVダウンロードとリセット前の控えで共通
+fn tickets_csv(tickets: &[Ticket], settings: &Settings) -> String {
+    // CSVのヘッダー行 (営業日は、深夜0時をまたいだ分も同じ日にまとめた日付)
+    let mut csv_data = String::from("整理番号,人数,ステータス,発券時刻,呼出時刻,完了時刻,営業日\n");
+
+    // データ行の生成
+    for t in tickets {
+        // 時刻を店舗の現地時刻で文字列化。データがない場合は空文字。
+        let created = settings.format_local(&t.created_at, "%H:%M:%S");
+        
+        let called = t.called_at
+            .map(|d| settings.format_local(&d, "%H:%M:%S"))
+            .unwrap_or_default();
+            
+        let completed = t.completed_at
+            .map(|d| settings.format_local(&d, "%H:%M:%S"))
+            .unwrap_or_default();
+
+        // 1行追加
+        use std::fmt::Write;
+        let _ = writeln!(
+            csv_data, 
+            "{},{},{},{},{},{},{}", 
+            t.number, t.group_size, t.status, created, called, completed, settings.business_date(&t.created_at)
+        );
+    }
+    csv_data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn additive_migrations_are_not_destructive() {
+        assert!(!is_destructive_migration(
+            "CREATE TABLE IF NOT EXISTS notes (id SERIAL PRIMARY KEY, body TEXT NOT NULL);\n\
+             ALTER TABLE tickets ADD COLUMN IF NOT EXISTS note TEXT;\n\
+             CREATE INDEX IF NOT EXISTS notes_body ON notes (body);\n\
+             INSERT INTO settings (key, value) VALUES ('note', 'DROP TABLE tickets; UPDATE x') ON CONFLICT DO NOTHING;"
+        ));
+    }
+
+    #[test]
+    fn comments_are_ignored() {
+        assert!(!is_destructive_migration("-- DROP TABLE tickets;\n/* DELETE FROM tickets; */\nSELECT 1;"));
+    }
+
+    #[test]
+    fn dropping_and_clearing_data_is_destructive() {
+        assert!(is_destructive_migration("DROP TABLE old_tickets;"));
+        assert!(is_destructive_migration("truncate tickets;"));
+        assert!(is_destructive_migration("DELETE FROM tickets WHERE status = 'cancelled';"));
+        assert!(is_destructive_migration("DROP INDEX IF EXISTS tickets_number;"));
+        assert!(is_destructive_migration("ALTER TABLE tickets DROP COLUMN note;"));
+        assert!(is_destructive_migration("ALTER TABLE tickets ALTER COLUMN note TYPE VARCHAR(10);"));
+        assert!(is_destructive_migration("ALTER TABLE tickets RENAME COLUMN note TO memo;"));
+    }
+
+    #[test]
+    fn data_rewrites_are_destructive() {
+        assert!(is_destructive_migration("UPDATE tickets SET status = 'done' WHERE status = 'called';"));
+        assert!(is_destructive_migration("WITH old AS (SELECT id FROM tickets) UPDATE tickets SET note = NULL;"));
+        assert!(is_destructive_migration(
+            "INSERT INTO settings (key, value) VALUES ('a', 'b') ON CONFLICT (key) DO UPDATE SET value = 'b';"
+        ));
+    }
+
+    #[test]
+    fn statements_inside_do_blocks_are_checked() {
+        assert!(is_destructive_migration(
+            "DO $$\nBEGIN\n  IF EXISTS (SELECT 1 FROM tickets) THEN\n    UPDATE tickets SET stage = 'waiting';\n  END IF;\nEND\n$$;"
+        ));
+        assert!(is_destructive_migration("DO $body$ BEGIN EXECUTE 'DROP INDEX tickets_number'; END $body$;"));
+        assert!(!is_destructive_migration(
+            "DO $$ BEGIN IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'stage') THEN \
+             CREATE TYPE stage AS ENUM ('waiting'); END IF; END $$;"
+        ));
+    }
+
+    #[test]
+    fn function_bodies_are_not_run_when_applied() {
+        assert!(!is_destructive_migration(
+            "CREATE OR REPLACE FUNCTION clear_notes() RETURNS trigger AS $$\n\
+             BEGIN\n  DELETE FROM notes WHERE ticket_id = OLD.id;\n  RETURN OLD;\nEND;\n$$ LANGUAGE plpgsql;\n\
+             CREATE TRIGGER tickets_clear_notes AFTER DELETE ON tickets FOR EACH ROW EXECUTE FUNCTION clear_notes();"
+        ));
+    }
+
+    fn policy(rules: &str) -> RoutePolicy {
+        let mut policy = RoutePolicy::default();
+        policy.add(rules);
+        policy
+    }
+
+    #[test]
+    fn policy_rules_are_parsed() {
+        let rule = PolicyRule::parse(" post /admin/staff* = manager, staff ").unwrap();
+        assert_eq!(rule.method, Some(Method::POST));
+        assert_eq!(rule.path, "/admin/staff");
+        assert!(rule.prefix);
+        assert_eq!(rule.roles, ["manager", "staff"]);
+
+        let rule = PolicyRule::parse("/admin/stats=observer").unwrap();
+        assert_eq!(rule.method, None);
+        assert!(!rule.prefix);
+    }
+
+    #[test]
+    fn malformed_policy_rules_are_rejected() {
+        for rule in [
+            "/admin/stats",               // 役割がない
+            "/admin/stats=",              // 役割が空
+            "/admin/stats=owner",         // 知らない役割
+            "/admin/stats=manager,owner", // 1つでも知らない役割があれば読まない
+            "/guest/*=manager",           // 管理画面の外
+            "P@ST /admin/reset=manager",  // メソッドとして読めない
+        ] {
+            assert!(PolicyRule::parse(rule).is_none(), "{rule}");
+        }
+
+        let policy = policy("POST /admin/reset=manager; /admin/stats=owner
+/guest=staff");
+        assert_eq!(policy.rules.len(), 1);
+        assert_eq!(policy.invalid, ["/admin/stats=owner", "/guest=staff"]);
+    }
+
+    #[test]
+    fn wildcard_rules_match_by_prefix() {
+        let policy = policy("/admin/credentials*=manager; /admin/stats=observer");
+        assert!(policy.roles_for(&Method::POST, "/admin/credentials").is_some());
+        assert!(policy.roles_for(&Method::POST, "/admin/credentials/rotate").is_some());
+        assert!(policy.roles_for(&Method::GET, "/admin/stats").is_some());
+        assert!(policy.roles_for(&Method::GET, "/admin/stats/export").is_none());
+        assert!(policy.roles_for(&Method::GET, "/admin/call").is_none());
+    }
+
+    #[test]
+    fn rules_without_a_method_apply_to_every_method() {
+        let policy = policy("/admin/maintenance*=manager; GET /admin/stats=observer");
+        assert!(policy.roles_for(&Method::GET, "/admin/maintenance").is_some());
+        assert!(policy.roles_for(&Method::POST, "/admin/maintenance/queue_state").is_some());
+        // GET の決まりは HEAD にも当てはまるが、POST には当てはまらない
+        assert!(policy.roles_for(&Method::HEAD, "/admin/stats").is_some());
+        assert!(policy.roles_for(&Method::POST, "/admin/stats").is_none());
+    }
+
+    #[test]
+    fn the_most_specific_rule_wins() {
+        let policy = policy(
+            "/admin*=staff; /admin/staff*=manager; /admin/staff/invites*=manager; /admin/staff/invites=observer; \
+             POST /admin/staff*=staff,manager",
+        );
+        // 長いパスが優先
+        assert_eq!(policy.roles_for(&Method::GET, "/admin/staff/1").unwrap(), ["manager"]);
+        assert_eq!(policy.roles_for(&Method::GET, "/admin/call").unwrap(), ["staff"]);
+        // 同じ長さなら完全一致が優先
+        assert_eq!(policy.roles_for(&Method::GET, "/admin/staff/invites").unwrap(), ["observer"]);
+        assert_eq!(policy.roles_for(&Method::GET, "/admin/staff/invites/1").unwrap(), ["manager"]);
+        // パスが同じならメソッドつきが優先
+        assert_eq!(policy.roles_for(&Method::POST, "/admin/staff/1").unwrap(), ["staff", "manager"]);
+    }
+
+    #[test]
+    fn later_policy_rules_replace_the_same_target() {
+        let mut policy = policy(DEFAULT_ROUTE_POLICY);
+        policy.add("POST /admin/reset=manager,staff");
+        assert_eq!(policy.roles_for(&Method::POST, "/admin/reset").unwrap(), ["manager", "staff"]);
+        assert_eq!(policy.rules.iter().filter(|r| r.path == "/admin/reset").count(), 1);
+        assert!(policy.invalid.is_empty());
+    }
+
+    fn device(kind: &str, allow_unsigned: bool) -> Device {
+        Device {
+            id: 1,
+            name: "入口".to_string(),
+            kind: kind.to_string(),
+            last_seen_at: None,
+            last_status: None,
+            queue_ids: vec![],
+            group_sizes: vec![],
+            language: "ja".to_string(),
+            auto_print: false,
+            allow_unsigned,
+        }
+    }
+
+    #[test]
+    fn display_devices_sign_only_grpc_requests() {
+        assert!(!device("display", false).signs_requests(false));
+        assert!(device("display", false).signs_requests(true));
+        assert!(device("kiosk", false).signs_requests(false));
+        assert!(device("printer", false).signs_requests(true));
+        // 署名の仕組みより前に登録した端末は、トークンを発行し直すまで求めない
+        assert!(!device("kiosk", true).signs_requests(false));
+        assert!(!device("kiosk", true).signs_requests(true));
+    }
+
+    const SIGNING_KEY: &str = "signing-key";
+    const BODY: &[u8] = br#"{"group_size":2}"#;
+
+    fn signed_headers(timestamp: i64, signature: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-device-timestamp", timestamp.to_string().parse().unwrap());
+        headers.insert("x-device-signature", signature.parse().unwrap());
+        headers
+    }
+
+    fn sign(timestamp: i64, method: &str, path: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SIGNING_KEY.as_bytes()).unwrap();
+        mac.update(format!("{}\n{}\n{}\n", timestamp, method, path).as_bytes());
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    // 送り直しの判定は、DB の代わりに見た署名を覚えておく
+    fn verify(headers: &HeaderMap, now: DateTime<Utc>, seen: &Mutex<HashSet<String>>) -> Result<(), AppError> {
+        let request = SignedRequest { headers, method: "POST", path: "/api/devices/tickets", body: BODY };
+        tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(verify_signed_request(
+            SIGNING_KEY,
+            &request,
+            now,
+            |signature| async move { seen.lock().unwrap().insert(signature) },
+        ))
+    }
+
+    #[test]
+    fn valid_device_signatures_are_accepted() {
+        let now = Utc::now();
+        let seen = Mutex::new(HashSet::new());
+        // 時計のずれを許す幅の中なら受け付ける
+        let sent_at = now.timestamp() - 30;
+        let signature = sign(sent_at, "POST", "/api/devices/tickets", BODY);
+        assert_eq!(verify(&signed_headers(sent_at, &signature), now, &seen), Ok(()));
+        // 16進数の大文字・小文字は問わない
+        let signature = sign(now.timestamp(), "POST", "/api/devices/tickets", BODY);
+        assert_eq!(verify(&signed_headers(now.timestamp(), &signature.to_ascii_uppercase()), now, &seen), Ok(()));
+    }
+
+    #[test]
+    fn tampered_device_requests_are_rejected() {
+        let now = Utc::now();
+        let seen = Mutex::new(HashSet::new());
+        for signature in [
+            sign(now.timestamp(), "POST", "/api/devices/call", BODY),                // 別のパスへの署名
+            sign(now.timestamp(), "POST", "/api/devices/tickets", b"{\"group_size\":9}"), // 本文が違う
+            "not-hex".to_string(),
+        ] {
+            assert_eq!(verify(&signed_headers(now.timestamp(), &signature), now, &seen), Err(AppError::InvalidSignature));
+        }
+        assert_eq!(verify(&HeaderMap::new(), now, &seen), Err(AppError::InvalidSignature));
+    }
+
+    #[test]
+    fn stale_device_signatures_are_rejected() {
+        let now = Utc::now();
+        let seen = Mutex::new(HashSet::new());
+        for sent_at in [now.timestamp() - DEVICE_SIGNATURE_WINDOW_SECONDS - 1, now.timestamp() + DEVICE_SIGNATURE_WINDOW_SECONDS + 1] {
+            let headers = signed_headers(sent_at, &sign(sent_at, "POST", "/api/devices/tickets", BODY));
+            assert_eq!(verify(&headers, now, &seen), Err(AppError::InvalidSignature));
+        }
+        // 時刻で断ったものは、送り直しの記録にも残さない
+        assert!(seen.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn replayed_device_signatures_are_rejected() {
+        let now = Utc::now();
+        let signature = sign(now.timestamp(), "POST", "/api/devices/tickets", BODY);
+        let seen = Mutex::new(HashSet::new());
+        assert_eq!(verify(&signed_headers(now.timestamp(), &signature), now, &seen), Ok(()));
+        assert_eq!(verify(&signed_headers(now.timestamp(), &signature), now, &seen), Err(AppError::InvalidSignature));
+        // 大文字にしても同じ署名として扱う
+        assert_eq!(
+            verify(&signed_headers(now.timestamp(), &signature.to_ascii_uppercase()), now, &seen),
+            Err(AppError::InvalidSignature)
+        );
+    }
+}
@@ -0,0 +1,48 @@
+// 来場者の画面と表示用モニターの読み上げ・文字サイズ調整
+// <head> で読み込む (描画前に文字サイズを当てて、ちらつかないようにする)
+(function () {
+    var KEY = 'qt_font_scale';
+    var SCALES = [1, 1.25, 1.5, 1.75, 2];
+    var root = document.documentElement;
+
+    function load() {
+        var v = 1;
+        try { v = parseFloat(localStorage.getItem(KEY)); } catch (e) {}
+        return SCALES.indexOf(v) >= 0 ? v : 1;
+    }
+
+    function apply(scale) {
+        root.style.setProperty('--font-scale', scale);
+        var label = document.getElementById('font-size-current');
+        if (label) label.textContent = Math.round(scale * 100) + '%';
+    }
+
+    function change(step) {
+        var i = SCALES.indexOf(load()) + step;
+        var scale = step === 0 ? 1 : SCALES[Math.max(0, Math.min(SCALES.length - 1, i))];
+        try { localStorage.setItem(KEY, scale); } catch (e) {}
+        apply(scale);
+    }
+
+    apply(load());
+
+    // 画面の変化を読み上げる (role="status" / role="alert" の領域に文を入れ直す)
+    // 同じ文を続けて入れても読まれないので、一度空にしてから入れる
+    function announce(message, urgent) {
+        var region = document.getElementById(urgent ? 'announce-alert' : 'announce-status');
+        if (!region || !message) return;
+        region.textContent = '';
+        setTimeout(function () { region.textContent = message; }, 100);
+    }
+
+    document.addEventListener('DOMContentLoaded', function () {
+        apply(load());
+        document.querySelectorAll('[data-font-step]').forEach(function (button) {
+            button.addEventListener('click', function () {
+                change(parseInt(button.dataset.fontStep, 10));
+            });
+        });
+    });
+
+    window.QueueA11y = { announce: announce };
+})();
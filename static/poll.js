@@ -0,0 +1,16 @@
+// 自動更新の間隔を、サーバーが返した値 (X-Poll-Seconds) に合わせる
+// 混雑時に運用設定で間隔を変えると、開いたままの画面も次の更新から新しい間隔になる
+document.addEventListener('htmx:afterRequest', function (event) {
+    var xhr = event.detail.xhr;
+    var elt = event.detail.elt;
+    var seconds = parseInt(xhr && xhr.getResponseHeader('X-Poll-Seconds'), 10);
+    if (!seconds || !elt || !elt.hasAttribute('hx-trigger')) return;
+
+    var trigger = elt.getAttribute('hx-trigger');
+    var next = trigger.replace(/every \d+s/, 'every ' + seconds + 's');
+    if (next !== trigger) {
+        // 属性を変えてから処理し直すと、htmx が新しい間隔で数え直す
+        elt.setAttribute('hx-trigger', next);
+        htmx.process(elt);
+    }
+});